@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE gtfs.admin_audit_log;
+DROP TABLE gtfs.admin_sessions;
+ALTER TABLE gtfs.admin_credentials DROP COLUMN disabled;
+ALTER TABLE gtfs.admin_credentials DROP COLUMN role;
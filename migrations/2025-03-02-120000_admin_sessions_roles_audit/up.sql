@@ -0,0 +1,25 @@
+-- Your SQL goes here
+ALTER TABLE gtfs.admin_credentials ADD COLUMN role text NOT NULL DEFAULT 'feed-admin';
+ALTER TABLE gtfs.admin_credentials ADD COLUMN disabled boolean NOT NULL DEFAULT false;
+
+-- only a hash of the session token is stored
+CREATE TABLE gtfs.admin_sessions (
+    token_hash text NOT NULL PRIMARY KEY,
+    email text NOT NULL,
+    created_ms bigint NOT NULL,
+    expires_ms bigint NOT NULL
+);
+
+CREATE INDEX admin_sessions_email_idx ON gtfs.admin_sessions (email);
+
+CREATE TABLE gtfs.admin_audit_log (
+    audit_id text NOT NULL PRIMARY KEY,
+    email text NOT NULL,
+    onestop_feed_id text NOT NULL,
+    action text NOT NULL,
+    old_value text,
+    new_value text,
+    changed_ms bigint NOT NULL
+);
+
+CREATE INDEX admin_audit_log_feed_idx ON gtfs.admin_audit_log (onestop_feed_id, changed_ms);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE gtfs.public_api_key_usage;
+DROP TABLE gtfs.public_api_keys;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE gtfs.public_api_keys (
+    api_key text NOT NULL PRIMARY KEY,
+    name text NOT NULL,
+    owner_email text NOT NULL,
+    daily_quota bigint,
+    tiles_per_minute int,
+    realtime_per_minute int,
+    departures_per_minute int,
+    disabled boolean NOT NULL DEFAULT false,
+    created_ms bigint NOT NULL
+);
+
+CREATE TABLE gtfs.public_api_key_usage (
+    api_key text NOT NULL,
+    usage_date date NOT NULL,
+    endpoint_class text NOT NULL,
+    request_count bigint NOT NULL,
+    rejected_count bigint NOT NULL,
+    PRIMARY KEY (api_key, usage_date, endpoint_class)
+);
@@ -0,0 +1,13 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE gtfs.gtfs_validation_counts;
+DROP TABLE gtfs.gtfs_validation_reports;
+DROP TABLE gtfs.gtfs_errors;
+
+CREATE TABLE gtfs.gtfs_errors (
+onestop_feed_id text NOT NULL,
+error text NOT NULL,
+attempt_id text,
+file_hash text,
+chateau text NOT NULL,
+PRIMARY KEY (onestop_feed_id, attempt_id)
+);
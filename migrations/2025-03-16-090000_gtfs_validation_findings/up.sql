@@ -0,0 +1,44 @@
+-- Your SQL goes here
+-- nothing ever wrote to the old table, and one row per attempt can't hold a report
+DROP TABLE gtfs.gtfs_errors;
+
+CREATE TABLE gtfs.gtfs_errors (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    error_index int NOT NULL,
+    chateau text NOT NULL,
+    file_hash text,
+    severity text NOT NULL,
+    code text NOT NULL,
+    file text NOT NULL,
+    row_number bigint,
+    entity_id text,
+    error text NOT NULL,
+    validated_ms bigint NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, error_index)
+);
+
+CREATE INDEX gtfs_errors_feed_validated_idx ON gtfs.gtfs_errors (onestop_feed_id, validated_ms);
+
+-- one row per validated attempt, so an attempt without findings still shows up as the latest report
+CREATE TABLE gtfs.gtfs_validation_reports (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    chateau text NOT NULL,
+    file_hash text,
+    validated_ms bigint NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id)
+);
+
+CREATE INDEX gtfs_validation_reports_feed_validated_idx ON gtfs.gtfs_validation_reports (onestop_feed_id, validated_ms);
+
+-- gtfs_errors only keeps the first findings of each code, these are the full counts
+CREATE TABLE gtfs.gtfs_validation_counts (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    code text NOT NULL,
+    severity text NOT NULL,
+    file text NOT NULL,
+    finding_count bigint NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, code, file)
+);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE gtfs.realtime_fan_out_rules;
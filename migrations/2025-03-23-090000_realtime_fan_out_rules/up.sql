@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- rule_type is one of agency, route_prefix or static_trip_ownership
+-- rules are evaluated in rule_index order and the first match wins
+CREATE TABLE gtfs.realtime_fan_out_rules (
+    aggregate_feed_id text NOT NULL,
+    rule_index integer NOT NULL,
+    rule_type text NOT NULL,
+    match_value text,
+    chateau text NOT NULL,
+    PRIMARY KEY (aggregate_feed_id, rule_index)
+);
+
+CREATE INDEX realtime_fan_out_rules_chateau ON gtfs.realtime_fan_out_rules (chateau);
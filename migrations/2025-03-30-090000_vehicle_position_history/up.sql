@@ -0,0 +1,19 @@
+-- Your SQL goes here
+-- Downsampled vehicle positions written by aspen, kept for VEHICLE_HISTORY_RETENTION_DAYS
+CREATE TABLE gtfs.vehicle_position_history (
+    chateau text NOT NULL,
+    vehicle_id text NOT NULL,
+    recorded_at_ms bigint NOT NULL,
+    vehicle_label text,
+    trip_id text,
+    route_id text,
+    latitude real NOT NULL,
+    longitude real NOT NULL,
+    bearing real,
+    speed real,
+    PRIMARY KEY (chateau, vehicle_id, recorded_at_ms)
+);
+
+CREATE INDEX vehicle_position_history_trip ON gtfs.vehicle_position_history (chateau, trip_id, recorded_at_ms);
+CREATE INDEX vehicle_position_history_time ON gtfs.vehicle_position_history (chateau, recorded_at_ms);
+CREATE INDEX vehicle_position_history_recorded_at ON gtfs.vehicle_position_history (recorded_at_ms);
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Hash of every file in an ingested feed, so maple can tell which parts of a new zip are unchanged
+CREATE TABLE gtfs.static_file_hashes (
+    onestop_feed_id text NOT NULL,
+    attempt_id text NOT NULL,
+    file_name text NOT NULL,
+    file_hash text NOT NULL,
+    PRIMARY KEY (onestop_feed_id, attempt_id, file_name)
+);
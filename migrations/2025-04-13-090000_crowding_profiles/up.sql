@@ -0,0 +1,18 @@
+-- Your SQL goes here
+-- Running totals of occupancy observed by aspen, per route, direction, stop and hour of the week
+-- direction_id is -1 when the trip has none
+CREATE TABLE gtfs.crowding_profiles (
+    chateau text NOT NULL,
+    route_id text NOT NULL,
+    direction_id smallint NOT NULL,
+    stop_id text NOT NULL,
+    time_of_week_bucket smallint NOT NULL,
+    occupancy_status_sum bigint NOT NULL,
+    occupancy_status_count bigint NOT NULL,
+    occupancy_percentage_sum bigint NOT NULL,
+    occupancy_percentage_count bigint NOT NULL,
+    last_observed_ms bigint NOT NULL,
+    PRIMARY KEY (chateau, route_id, direction_id, stop_id, time_of_week_bucket)
+);
+
+CREATE INDEX crowding_profiles_stop ON gtfs.crowding_profiles (chateau, stop_id, time_of_week_bucket);
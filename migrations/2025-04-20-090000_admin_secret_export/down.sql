@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE gtfs.admin_credentials DROP COLUMN can_export_secrets;
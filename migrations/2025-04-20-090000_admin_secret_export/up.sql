@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Bulk exports of realtime credentials are redacted unless the admin is granted this explicitly
+ALTER TABLE gtfs.admin_credentials ADD COLUMN can_export_secrets boolean NOT NULL DEFAULT false;
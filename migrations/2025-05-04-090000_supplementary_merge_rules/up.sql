@@ -0,0 +1,19 @@
+-- Your SQL goes here
+-- field is one of position, speed, occupancy or platform
+-- mode is one of fill_missing or override
+-- rules are applied in rule_index order, so a later override wins
+CREATE TABLE gtfs.supplementary_merge_rules (
+    chateau text NOT NULL,
+    rule_index integer NOT NULL,
+    source_feed_id text NOT NULL,
+    field text NOT NULL,
+    mode text NOT NULL,
+    PRIMARY KEY (chateau, rule_index)
+);
+
+-- Metrolink's GTFS-RT feed sometimes drops positions that the train list still has,
+-- and tracks only come from the station board
+INSERT INTO gtfs.supplementary_merge_rules (chateau, rule_index, source_feed_id, field, mode) VALUES
+    ('metrolinktrains', 0, 'f-metrolinktrains~extra~rt', 'position', 'fill_missing'),
+    ('metrolinktrains', 1, 'f-metrolinktrains~extra~rt', 'speed', 'fill_missing'),
+    ('metrolinktrains', 2, 'f-metrolinktrains~extra~rt', 'platform', 'fill_missing');
@@ -0,0 +1,316 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// AGPL 3.0
+
+use crate::models::{AdminAuditLogRow, AdminCredentials, AdminSession};
+use argon2::{
+    password_hash::{PasswordHash, PasswordVerifier},
+    Argon2,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::str::FromStr;
+
+/// Sessions are valid for 12 hours after login
+pub const SESSION_LIFETIME_MS: i64 = 12 * 60 * 60 * 1000;
+
+const SESSION_TOKEN_LENGTH: usize = 64;
+
+type PooledPgConnection<'a> =
+    bb8::PooledConnection<'a, AsyncDieselConnectionManager<AsyncPgConnection>>;
+
+/// Roles are ordered, each role can do everything the roles before it can do
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum AdminRole {
+    /// Can read feed credentials and fetch intervals
+    KeyViewer,
+    /// Can also change feed credentials and fetch intervals
+    KeyEditor,
+    /// Can also read the audit log
+    FeedAdmin,
+}
+
+impl AdminRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminRole::KeyViewer => "key-viewer",
+            AdminRole::KeyEditor => "key-editor",
+            AdminRole::FeedAdmin => "feed-admin",
+        }
+    }
+
+    pub fn permits(&self, required: AdminRole) -> bool {
+        *self >= required
+    }
+}
+
+impl FromStr for AdminRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "key-viewer" => Ok(AdminRole::KeyViewer),
+            "key-editor" => Ok(AdminRole::KeyEditor),
+            "feed-admin" => Ok(AdminRole::FeedAdmin),
+            _ => Err(format!("unknown admin role {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AdminAuditAction {
+    SetRealtimeCredentials,
+    SetFetchInterval,
+    ExportRealtimeCredentials,
+}
+
+impl AdminAuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminAuditAction::SetRealtimeCredentials => "set_realtime_credentials",
+            AdminAuditAction::SetFetchInterval => "set_fetch_interval",
+            AdminAuditAction::ExportRealtimeCredentials => "export_realtime_credentials",
+        }
+    }
+}
+
+/// A logged in admin, resolved from a session token
+#[derive(Clone, Debug)]
+pub struct AuthenticatedAdmin {
+    pub email: String,
+    pub role: AdminRole,
+    pub session_expires_ms: i64,
+    pub can_export_secrets: bool,
+}
+
+pub fn make_session_token() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(SESSION_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Sessions are stored and looked up by this hash, so a leaked table cannot be used to log in
+pub fn hash_session_token(token: &str) -> String {
+    STANDARD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Checks the email and password against `admin_credentials`.
+/// Returns the credentials row if the password matches and the admin is not disabled.
+pub async fn verify_admin_password(
+    conn: &mut PooledPgConnection<'_>,
+    email: &str,
+    password: &str,
+) -> Result<Option<AdminCredentials>, Box<dyn Error + Send + Sync>> {
+    use crate::schema::gtfs::admin_credentials as admin_credentials_table;
+
+    let admin_credentials = admin_credentials_table::table
+        .filter(admin_credentials_table::email.eq(email))
+        .select(AdminCredentials::as_select())
+        .load::<AdminCredentials>(conn)
+        .await?;
+
+    let admin_credentials = match admin_credentials.into_iter().next() {
+        Some(admin_credentials) => admin_credentials,
+        None => return Ok(None),
+    };
+
+    if admin_credentials.disabled {
+        return Ok(None);
+    }
+
+    let parsed_hash = match PasswordHash::new(&admin_credentials.hash) {
+        Ok(parsed_hash) => parsed_hash,
+        Err(e) => {
+            eprintln!("Stored hash for {} is not valid: {}", email, e);
+            return Ok(None);
+        }
+    };
+
+    match Argon2::default().verify_password(password.as_bytes(), &parsed_hash) {
+        Ok(()) => Ok(Some(admin_credentials)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Returns the token to give to the client together with the stored session
+pub async fn create_session(
+    conn: &mut PooledPgConnection<'_>,
+    email: &str,
+) -> Result<(String, AdminSession), Box<dyn Error + Send + Sync>> {
+    use crate::schema::gtfs::admin_sessions as admin_sessions_table;
+
+    let now = crate::duration_since_unix_epoch().as_millis() as i64;
+
+    //clear out this admin's expired sessions while we are here
+    diesel::delete(
+        admin_sessions_table::table
+            .filter(admin_sessions_table::email.eq(email))
+            .filter(admin_sessions_table::expires_ms.lt(now)),
+    )
+    .execute(conn)
+    .await?;
+
+    let token = make_session_token();
+
+    let session = AdminSession {
+        token_hash: hash_session_token(&token),
+        email: email.to_string(),
+        created_ms: now,
+        expires_ms: now + SESSION_LIFETIME_MS,
+    };
+
+    diesel::insert_into(admin_sessions_table::table)
+        .values(&session)
+        .execute(conn)
+        .await?;
+
+    Ok((token, session))
+}
+
+/// Resolves a session token into the admin it belongs to.
+/// Expired sessions, disabled admins, and unknown roles resolve to `None`.
+pub async fn admin_from_session_token(
+    conn: &mut PooledPgConnection<'_>,
+    token: &str,
+) -> Result<Option<AuthenticatedAdmin>, Box<dyn Error + Send + Sync>> {
+    use crate::schema::gtfs::admin_credentials as admin_credentials_table;
+    use crate::schema::gtfs::admin_sessions as admin_sessions_table;
+
+    let now = crate::duration_since_unix_epoch().as_millis() as i64;
+
+    let sessions = admin_sessions_table::table
+        .filter(admin_sessions_table::token_hash.eq(hash_session_token(token)))
+        .filter(admin_sessions_table::expires_ms.gt(now))
+        .select(AdminSession::as_select())
+        .load::<AdminSession>(conn)
+        .await?;
+
+    let session = match sessions.into_iter().next() {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+
+    let admin_credentials = admin_credentials_table::table
+        .filter(admin_credentials_table::email.eq(&session.email))
+        .select(AdminCredentials::as_select())
+        .load::<AdminCredentials>(conn)
+        .await?;
+
+    match admin_credentials.into_iter().next() {
+        Some(admin_credentials) if !admin_credentials.disabled => {
+            match AdminRole::from_str(&admin_credentials.role) {
+                Ok(role) => Ok(Some(AuthenticatedAdmin {
+                    email: admin_credentials.email,
+                    role,
+                    session_expires_ms: session.expires_ms,
+                    can_export_secrets: admin_credentials.can_export_secrets,
+                })),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Ok(None)
+                }
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+pub async fn delete_session(
+    conn: &mut PooledPgConnection<'_>,
+    token: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use crate::schema::gtfs::admin_sessions as admin_sessions_table;
+
+    diesel::delete(
+        admin_sessions_table::table
+            .filter(admin_sessions_table::token_hash.eq(hash_session_token(token))),
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn delete_all_sessions_for_admin(
+    conn: &mut PooledPgConnection<'_>,
+    email: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use crate::schema::gtfs::admin_sessions as admin_sessions_table;
+
+    diesel::delete(admin_sessions_table::table.filter(admin_sessions_table::email.eq(email)))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Takes a plain connection so the entry can be written in the same transaction as the change
+pub async fn insert_audit_log(
+    conn: &mut AsyncPgConnection,
+    email: &str,
+    onestop_feed_id: &str,
+    action: AdminAuditAction,
+    old_value: Option<String>,
+    new_value: Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use crate::schema::gtfs::admin_audit_log as admin_audit_log_table;
+
+    let row = AdminAuditLogRow {
+        audit_id: uuid::Uuid::new_v4().to_string(),
+        email: email.to_string(),
+        onestop_feed_id: onestop_feed_id.to_string(),
+        action: action.as_str().to_string(),
+        old_value,
+        new_value,
+        changed_ms: crate::duration_since_unix_epoch().as_millis() as i64,
+    };
+
+    diesel::insert_into(admin_audit_log_table::table)
+        .values(&row)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_ordering() {
+        assert!(AdminRole::FeedAdmin.permits(AdminRole::KeyEditor));
+        assert!(AdminRole::KeyEditor.permits(AdminRole::KeyViewer));
+        assert!(!AdminRole::KeyViewer.permits(AdminRole::KeyEditor));
+
+        for role in [
+            AdminRole::KeyViewer,
+            AdminRole::KeyEditor,
+            AdminRole::FeedAdmin,
+        ] {
+            assert_eq!(AdminRole::from_str(role.as_str()), Ok(role));
+        }
+    }
+
+    #[test]
+    fn session_tokens_are_not_stored_as_is() {
+        let token = make_session_token();
+
+        assert_ne!(hash_session_token(&token), token);
+        assert_eq!(hash_session_token(&token), hash_session_token(&token));
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// AGPL 3.0
+
+// Envelope encryption with AES-256-GCM.
+// Every secret is encrypted with its own random data key, and only the data key is encrypted
+// ("wrapped") with a master key. Rotating the master key rewraps the data keys and leaves the
+// secrets themselves alone.
+
+use super::keyring::MasterKeyring;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+pub const ENVELOPE_VERSION: u8 = 1;
+
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Serialize, Clone, Deserialize, Debug, Hash, PartialEq, Eq)]
+pub struct SealedSecret {
+    pub version: u8,
+    /// master key the data key is wrapped with
+    pub key_id: String,
+    pub wrapped_data_key: String,
+    pub wrapped_data_key_nonce: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+// the data key is bound to its master key and context, so it cannot be moved to another secret
+fn wrapping_aad(key_id: &str, context: &str) -> Vec<u8> {
+    format!("catenary-agency-secret:{}:{}", key_id, context).into_bytes()
+}
+
+fn decode_nonce(
+    encoded: &str,
+) -> Result<Nonce<<Aes256Gcm as AeadCore>::NonceSize>, Box<dyn Error + Send + Sync>> {
+    let bytes = STANDARD.decode(encoded)?;
+
+    match bytes.len() {
+        NONCE_LENGTH => Ok(*Nonce::from_slice(&bytes)),
+        _ => Err("sealed secret has a nonce of the wrong length".into()),
+    }
+}
+
+fn master_cipher(
+    keyring: &MasterKeyring,
+    key_id: &str,
+) -> Result<Aes256Gcm, Box<dyn Error + Send + Sync>> {
+    match keyring.key(key_id) {
+        Some(master_key) => Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(master_key))),
+        None => Err(format!("master key {} is not in the keyring", key_id).into()),
+    }
+}
+
+fn wrap_data_key(
+    keyring: &MasterKeyring,
+    context: &str,
+    data_key: &Key<Aes256Gcm>,
+) -> Result<(String, String, String), Box<dyn Error + Send + Sync>> {
+    let key_id = keyring.active_key_id();
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let wrapped_data_key = master_cipher(keyring, key_id)?
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: data_key.as_slice(),
+                aad: &wrapping_aad(key_id, context),
+            },
+        )
+        .map_err(|_| "could not wrap data key")?;
+
+    Ok((
+        key_id.to_string(),
+        STANDARD.encode(wrapped_data_key),
+        STANDARD.encode(nonce),
+    ))
+}
+
+impl SealedSecret {
+    /// `context` (the onestop feed id) has to be given again to open the secret
+    pub fn seal(
+        keyring: &MasterKeyring,
+        context: &str,
+        plaintext: &[u8],
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| "could not seal secret")?;
+
+        let (key_id, wrapped_data_key, wrapped_data_key_nonce) =
+            wrap_data_key(keyring, context, &data_key)?;
+
+        Ok(SealedSecret {
+            version: ENVELOPE_VERSION,
+            key_id,
+            wrapped_data_key,
+            wrapped_data_key_nonce,
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })
+    }
+
+    fn unwrap_data_key(
+        &self,
+        keyring: &MasterKeyring,
+        context: &str,
+    ) -> Result<Key<Aes256Gcm>, Box<dyn Error + Send + Sync>> {
+        if self.version != ENVELOPE_VERSION {
+            return Err(format!("unknown sealed secret version {}", self.version).into());
+        }
+
+        let data_key = master_cipher(keyring, &self.key_id)?
+            .decrypt(
+                &decode_nonce(&self.wrapped_data_key_nonce)?,
+                Payload {
+                    msg: &STANDARD.decode(&self.wrapped_data_key)?,
+                    aad: &wrapping_aad(&self.key_id, context),
+                },
+            )
+            .map_err(|_| "could not unwrap data key")?;
+
+        match data_key.len() {
+            32 => Ok(*Key::<Aes256Gcm>::from_slice(&data_key)),
+            _ => Err("unwrapped data key has the wrong length".into()),
+        }
+    }
+
+    pub fn open(
+        &self,
+        keyring: &MasterKeyring,
+        context: &str,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let data_key = self.unwrap_data_key(keyring, context)?;
+
+        let plaintext = Aes256Gcm::new(&data_key)
+            .decrypt(
+                &decode_nonce(&self.nonce)?,
+                Payload {
+                    msg: &STANDARD.decode(&self.ciphertext)?,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| "could not open secret")?;
+
+        Ok(plaintext)
+    }
+
+    /// Wraps the data key with the active master key, the ciphertext stays as it is
+    pub fn rewrap(
+        &self,
+        keyring: &MasterKeyring,
+        context: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let data_key = self.unwrap_data_key(keyring, context)?;
+
+        let (key_id, wrapped_data_key, wrapped_data_key_nonce) =
+            wrap_data_key(keyring, context, &data_key)?;
+
+        Ok(SealedSecret {
+            key_id,
+            wrapped_data_key,
+            wrapped_data_key_nonce,
+            ..self.clone()
+        })
+    }
+}
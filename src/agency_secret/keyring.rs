@@ -0,0 +1,156 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// AGPL 3.0
+
+// Master keys wrap the data key of every sealed secret and never touch postgres.
+// AGENCY_SECRET_MASTER_KEYS is a comma separated list of key_id:base64 keys of 32 bytes.
+// Without it, AGENCY_SECRET_LOCAL_KMS_DIR stands in for a key management service,
+// with one <key_id>.key file per master key holding the base64 key.
+// AGENCY_SECRET_ACTIVE_KEY_ID picks the key new secrets are sealed with,
+// the other keys are only kept to open secrets sealed before a rotation.
+
+use aes_gcm::aead::{KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::OnceLock;
+
+pub const MASTER_KEY_LENGTH: usize = 32;
+
+static MASTER_KEYRING: OnceLock<Option<MasterKeyring>> = OnceLock::new();
+
+#[derive(Clone)]
+pub struct MasterKeyring {
+    active_key_id: String,
+    keys: HashMap<String, [u8; MASTER_KEY_LENGTH]>,
+}
+
+// the keys themselves must never end up in logs
+impl std::fmt::Debug for MasterKeyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasterKeyring")
+            .field("active_key_id", &self.active_key_id)
+            .field("key_ids", &self.keys.keys().collect::<Vec<&String>>())
+            .finish()
+    }
+}
+
+impl MasterKeyring {
+    pub fn new(
+        active_key_id: &str,
+        keys: HashMap<String, [u8; MASTER_KEY_LENGTH]>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !keys.contains_key(active_key_id) {
+            return Err(
+                format!("active master key {} is not in the keyring", active_key_id).into(),
+            );
+        }
+
+        Ok(MasterKeyring {
+            active_key_id: active_key_id.to_string(),
+            keys,
+        })
+    }
+
+    pub fn from_env() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let keys =
+            match std::env::var("AGENCY_SECRET_MASTER_KEYS") {
+                Ok(key_list) => parse_key_list(&key_list)?,
+                Err(_) => match std::env::var("AGENCY_SECRET_LOCAL_KMS_DIR") {
+                    Ok(dir) => read_local_kms(&dir)?,
+                    Err(_) => return Err(
+                        "neither AGENCY_SECRET_MASTER_KEYS nor AGENCY_SECRET_LOCAL_KMS_DIR is set"
+                            .into(),
+                    ),
+                },
+            };
+
+        let active_key_id = match std::env::var("AGENCY_SECRET_ACTIVE_KEY_ID") {
+            Ok(active_key_id) => active_key_id,
+            Err(_) => match keys.len() {
+                1 => keys.keys().next().unwrap().clone(),
+                _ => return Err(
+                    "AGENCY_SECRET_ACTIVE_KEY_ID must be set when there are several master keys"
+                        .into(),
+                ),
+            },
+        };
+
+        Self::new(&active_key_id, keys)
+    }
+
+    pub fn active_key_id(&self) -> &str {
+        &self.active_key_id
+    }
+
+    pub fn key(&self, key_id: &str) -> Option<&[u8; MASTER_KEY_LENGTH]> {
+        self.keys.get(key_id)
+    }
+}
+
+fn decode_key(
+    key_id: &str,
+    encoded: &str,
+) -> Result<[u8; MASTER_KEY_LENGTH], Box<dyn Error + Send + Sync>> {
+    let bytes = STANDARD.decode(encoded.trim())?;
+
+    bytes
+        .try_into()
+        .map_err(|_| format!("master key {} must be {} bytes", key_id, MASTER_KEY_LENGTH).into())
+}
+
+fn parse_key_list(
+    key_list: &str,
+) -> Result<HashMap<String, [u8; MASTER_KEY_LENGTH]>, Box<dyn Error + Send + Sync>> {
+    key_list
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| match entry.trim().split_once(':') {
+            Some((key_id, encoded)) => Ok((key_id.to_string(), decode_key(key_id, encoded)?)),
+            None => Err(format!("master key entry {} is not key_id:base64", entry).into()),
+        })
+        .collect()
+}
+
+fn read_local_kms(
+    dir: &str,
+) -> Result<HashMap<String, [u8; MASTER_KEY_LENGTH]>, Box<dyn Error + Send + Sync>> {
+    let mut keys = HashMap::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.extension().and_then(|x| x.to_str()) != Some("key") {
+            continue;
+        }
+
+        if let Some(key_id) = path.file_stem().and_then(|x| x.to_str()) {
+            let encoded = std::fs::read_to_string(&path)?;
+            keys.insert(key_id.to_string(), decode_key(key_id, &encoded)?);
+        }
+    }
+
+    Ok(keys)
+}
+
+/// A new random master key, base64 encoded
+pub fn generate_master_key() -> String {
+    STANDARD.encode(Aes256Gcm::generate_key(OsRng))
+}
+
+/// Keyring of this process, `None` when no master key is configured
+pub fn master_keyring() -> Option<&'static MasterKeyring> {
+    MASTER_KEYRING
+        .get_or_init(|| match MasterKeyring::from_env() {
+            Ok(keyring) => Some(keyring),
+            Err(e) => {
+                eprintln!("Agency secrets cannot be sealed or opened: {}", e);
+                None
+            }
+        })
+        .as_ref()
+}
@@ -5,6 +5,16 @@
 // AGPL 3.0
 use serde::Deserialize;
 use serde::Serialize;
+use std::error::Error;
+
+pub mod envelope;
+pub mod keyring;
+
+use envelope::SealedSecret;
+use keyring::MasterKeyring;
+
+/// Shown in place of every password outside alpenrose
+pub const REDACTED: &str = "[redacted]";
 
 #[derive(Serialize, Clone, Deserialize, Debug, Hash, PartialEq, Eq)]
 pub struct PasswordFormat {
@@ -27,3 +37,374 @@ pub struct PasswordInfo {
     pub password: Vec<String>,
     pub creator_email: String,
 }
+
+/// A PasswordFormat with the passwords sealed, as kept in realtime_passwords and static_passwords.
+/// The rest stays readable so fetches can be planned and shown without opening anything.
+#[derive(Serialize, Clone, Deserialize, Debug, Hash, PartialEq, Eq)]
+pub struct SealedPasswordFormat {
+    pub key_formats: Vec<KeyFormat>,
+    // creator of each sealed password, in order
+    pub creator_emails: Vec<String>,
+    pub sealed_passwords: SealedSecret,
+    pub override_schedule_url: Option<String>,
+    pub override_realtime_vehicle_positions: Option<String>,
+    pub override_realtime_trip_updates: Option<String>,
+    pub override_alerts: Option<String>,
+}
+
+#[derive(Serialize, Clone, Deserialize, Debug, Hash, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StoredPasswordFormat {
+    Sealed(SealedPasswordFormat),
+    // rows written before credentials were encrypted, until `agency_secret_keys seal-plaintext`
+    Plaintext(PasswordFormat),
+}
+
+fn redact_passwords<'a>(
+    creator_emails: impl Iterator<Item = &'a String>,
+    key_count: usize,
+) -> Vec<PasswordInfo> {
+    creator_emails
+        .map(|creator_email| PasswordInfo {
+            password: vec![REDACTED.to_string(); key_count],
+            creator_email: creator_email.clone(),
+        })
+        .collect()
+}
+
+impl PasswordFormat {
+    pub fn seal(
+        &self,
+        keyring: &MasterKeyring,
+        onestop_feed_id: &str,
+    ) -> Result<SealedPasswordFormat, Box<dyn Error + Send + Sync>> {
+        let sealed_passwords = SealedSecret::seal(
+            keyring,
+            onestop_feed_id,
+            &serde_json::to_vec(&self.passwords)?,
+        )?;
+
+        Ok(SealedPasswordFormat {
+            key_formats: self.key_formats.clone(),
+            creator_emails: self
+                .passwords
+                .iter()
+                .map(|password_info| password_info.creator_email.clone())
+                .collect(),
+            sealed_passwords,
+            override_schedule_url: self.override_schedule_url.clone(),
+            override_realtime_vehicle_positions: self.override_realtime_vehicle_positions.clone(),
+            override_realtime_trip_updates: self.override_realtime_trip_updates.clone(),
+            override_alerts: self.override_alerts.clone(),
+        })
+    }
+
+    /// Whether any key is still the placeholder from a redacted view
+    pub fn contains_redacted(&self) -> bool {
+        self.passwords
+            .iter()
+            .flat_map(|password_info| password_info.password.iter())
+            .any(|password| password == REDACTED)
+    }
+
+    pub fn redacted(&self) -> PasswordFormat {
+        PasswordFormat {
+            passwords: redact_passwords(
+                self.passwords
+                    .iter()
+                    .map(|password_info| &password_info.creator_email),
+                self.key_formats.len(),
+            ),
+            ..self.clone()
+        }
+    }
+}
+
+/// Only for alpenrose, right before the credentials are used
+pub fn open_passwords(
+    sealed_passwords: &SealedSecret,
+    keyring: &MasterKeyring,
+    onestop_feed_id: &str,
+) -> Result<Vec<PasswordInfo>, Box<dyn Error + Send + Sync>> {
+    let plaintext = sealed_passwords.open(keyring, onestop_feed_id)?;
+
+    Ok(serde_json::from_slice::<Vec<PasswordInfo>>(&plaintext)?)
+}
+
+impl SealedPasswordFormat {
+    pub fn open_passwords(
+        &self,
+        keyring: &MasterKeyring,
+        onestop_feed_id: &str,
+    ) -> Result<Vec<PasswordInfo>, Box<dyn Error + Send + Sync>> {
+        open_passwords(&self.sealed_passwords, keyring, onestop_feed_id)
+    }
+
+    pub fn redacted(&self) -> PasswordFormat {
+        PasswordFormat {
+            key_formats: self.key_formats.clone(),
+            passwords: redact_passwords(self.creator_emails.iter(), self.key_formats.len()),
+            override_schedule_url: self.override_schedule_url.clone(),
+            override_realtime_vehicle_positions: self.override_realtime_vehicle_positions.clone(),
+            override_realtime_trip_updates: self.override_realtime_trip_updates.clone(),
+            override_alerts: self.override_alerts.clone(),
+        }
+    }
+}
+
+impl StoredPasswordFormat {
+    pub fn from_value(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value::<StoredPasswordFormat>(value)
+    }
+
+    /// Seals rows still in plaintext, sealed rows are returned as they are
+    pub fn into_sealed(
+        self,
+        keyring: &MasterKeyring,
+        onestop_feed_id: &str,
+    ) -> Result<SealedPasswordFormat, Box<dyn Error + Send + Sync>> {
+        match self {
+            StoredPasswordFormat::Sealed(sealed) => Ok(sealed),
+            StoredPasswordFormat::Plaintext(plaintext) => plaintext.seal(keyring, onestop_feed_id),
+        }
+    }
+
+    pub fn key_formats(&self) -> &[KeyFormat] {
+        match self {
+            StoredPasswordFormat::Sealed(sealed) => &sealed.key_formats,
+            StoredPasswordFormat::Plaintext(plaintext) => &plaintext.key_formats,
+        }
+    }
+
+    pub fn override_realtime_vehicle_positions(&self) -> Option<&String> {
+        match self {
+            StoredPasswordFormat::Sealed(sealed) => {
+                sealed.override_realtime_vehicle_positions.as_ref()
+            }
+            StoredPasswordFormat::Plaintext(plaintext) => {
+                plaintext.override_realtime_vehicle_positions.as_ref()
+            }
+        }
+    }
+
+    pub fn override_realtime_trip_updates(&self) -> Option<&String> {
+        match self {
+            StoredPasswordFormat::Sealed(sealed) => sealed.override_realtime_trip_updates.as_ref(),
+            StoredPasswordFormat::Plaintext(plaintext) => {
+                plaintext.override_realtime_trip_updates.as_ref()
+            }
+        }
+    }
+
+    pub fn override_alerts(&self) -> Option<&String> {
+        match self {
+            StoredPasswordFormat::Sealed(sealed) => sealed.override_alerts.as_ref(),
+            StoredPasswordFormat::Plaintext(plaintext) => plaintext.override_alerts.as_ref(),
+        }
+    }
+
+    pub fn redacted(&self) -> PasswordFormat {
+        match self {
+            StoredPasswordFormat::Sealed(sealed) => sealed.redacted(),
+            StoredPasswordFormat::Plaintext(plaintext) => plaintext.redacted(),
+        }
+    }
+
+    /// For credentials sent back from a redacted view with only the other settings changed.
+    /// Keeps these passwords with the key formats and override urls of `submitted`, `None` if the
+    /// accounts of `submitted` are not exactly the redacted accounts of these credentials.
+    pub fn keep_passwords_with_settings_of(
+        &self,
+        submitted: &PasswordFormat,
+    ) -> Option<StoredPasswordFormat> {
+        if self.redacted().passwords != submitted.passwords {
+            return None;
+        }
+
+        Some(match self {
+            StoredPasswordFormat::Sealed(sealed) => {
+                StoredPasswordFormat::Sealed(SealedPasswordFormat {
+                    key_formats: submitted.key_formats.clone(),
+                    creator_emails: sealed.creator_emails.clone(),
+                    sealed_passwords: sealed.sealed_passwords.clone(),
+                    override_schedule_url: submitted.override_schedule_url.clone(),
+                    override_realtime_vehicle_positions: submitted
+                        .override_realtime_vehicle_positions
+                        .clone(),
+                    override_realtime_trip_updates: submitted
+                        .override_realtime_trip_updates
+                        .clone(),
+                    override_alerts: submitted.override_alerts.clone(),
+                })
+            }
+            StoredPasswordFormat::Plaintext(plaintext) => {
+                StoredPasswordFormat::Plaintext(PasswordFormat {
+                    passwords: plaintext.passwords.clone(),
+                    ..submitted.clone()
+                })
+            }
+        })
+    }
+}
+
+/// Puts one account's keys where key_formats says, `None` if the number of keys does not match
+pub fn apply_password(
+    request: reqwest::RequestBuilder,
+    key_formats: &[KeyFormat],
+    password_info: &PasswordInfo,
+) -> Option<reqwest::RequestBuilder> {
+    if password_info.password.len() != key_formats.len() {
+        return None;
+    }
+
+    let mut request = request;
+    let mut url_parameter_seq: Vec<(String, String)> = vec![];
+
+    for (key_format, password) in key_formats.iter().zip(password_info.password.iter()) {
+        match key_format {
+            KeyFormat::Header(header) => {
+                request = request.header(header, password);
+            }
+            KeyFormat::UrlQuery(query) => {
+                url_parameter_seq.push((query.to_string(), password.to_string()));
+            }
+        }
+    }
+
+    Some(request.query(&url_parameter_seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn keyring(active_key_id: &str) -> MasterKeyring {
+        MasterKeyring::new(
+            active_key_id,
+            HashMap::from_iter([
+                (String::from("2025-01"), [1u8; 32]),
+                (String::from("2025-04"), [2u8; 32]),
+            ]),
+        )
+        .unwrap()
+    }
+
+    fn password_format() -> PasswordFormat {
+        PasswordFormat {
+            key_formats: vec![KeyFormat::Header(String::from("x-api-key"))],
+            passwords: vec![PasswordInfo {
+                password: vec![String::from("hunter2")],
+                creator_email: String::from("ops@catenarymaps.org"),
+            }],
+            override_schedule_url: None,
+            override_realtime_vehicle_positions: None,
+            override_realtime_trip_updates: None,
+            override_alerts: None,
+        }
+    }
+
+    #[test]
+    fn sealed_passwords_open_only_for_their_feed() {
+        let old_keyring = keyring("2025-01");
+        let sealed = password_format()
+            .seal(&old_keyring, "f-9q5-metro~losangeles~rt")
+            .unwrap();
+
+        let stored = serde_json::to_value(StoredPasswordFormat::Sealed(sealed.clone())).unwrap();
+        assert!(!stored.to_string().contains("hunter2"));
+        assert_eq!(
+            StoredPasswordFormat::from_value(stored).unwrap(),
+            StoredPasswordFormat::Sealed(sealed.clone())
+        );
+
+        assert_eq!(
+            sealed
+                .open_passwords(&old_keyring, "f-9q5-metro~losangeles~rt")
+                .unwrap(),
+            password_format().passwords
+        );
+        assert!(sealed.open_passwords(&old_keyring, "f-octa~rt").is_err());
+
+        // after a rotation the data key is wrapped by the new master key
+        let new_keyring = keyring("2025-04");
+        let rewrapped = SealedPasswordFormat {
+            sealed_passwords: sealed
+                .sealed_passwords
+                .rewrap(&new_keyring, "f-9q5-metro~losangeles~rt")
+                .unwrap(),
+            ..sealed.clone()
+        };
+
+        assert_eq!(rewrapped.sealed_passwords.key_id, "2025-04");
+        assert_eq!(
+            rewrapped.sealed_passwords.ciphertext,
+            sealed.sealed_passwords.ciphertext
+        );
+        assert_eq!(
+            rewrapped
+                .open_passwords(&new_keyring, "f-9q5-metro~losangeles~rt")
+                .unwrap(),
+            password_format().passwords
+        );
+    }
+
+    #[test]
+    fn redaction_keeps_the_shape() {
+        let plaintext =
+            StoredPasswordFormat::from_value(serde_json::to_value(password_format()).unwrap())
+                .unwrap();
+
+        let redacted = plaintext.redacted();
+
+        assert_eq!(redacted.passwords[0].password, vec![REDACTED.to_string()]);
+        assert_eq!(
+            redacted,
+            password_format()
+                .seal(&keyring("2025-01"), "f-octa~rt")
+                .unwrap()
+                .redacted()
+        );
+    }
+
+    #[test]
+    fn redacted_keys_are_only_kept_for_the_same_accounts() {
+        let stored = StoredPasswordFormat::Sealed(
+            password_format()
+                .seal(&keyring("2025-01"), "f-octa~rt")
+                .unwrap(),
+        );
+
+        // the override url changes, the keys are sent back redacted
+        let submitted = PasswordFormat {
+            override_realtime_vehicle_positions: Some(String::from(
+                "https://api.octa.net/vehicles",
+            )),
+            ..stored.redacted()
+        };
+
+        assert!(submitted.contains_redacted());
+
+        let kept = stored.keep_passwords_with_settings_of(&submitted).unwrap();
+
+        assert_eq!(kept.redacted(), submitted);
+        match (&kept, &stored) {
+            (StoredPasswordFormat::Sealed(kept), StoredPasswordFormat::Sealed(stored)) => {
+                assert_eq!(kept.sealed_passwords, stored.sealed_passwords)
+            }
+            _ => panic!("credentials are no longer sealed"),
+        }
+
+        // an account added next to the redacted one cannot be kept
+        let mut added_account = stored.redacted();
+        added_account.passwords.push(PasswordInfo {
+            password: vec![String::from("hunter3")],
+            creator_email: String::from("ops@catenarymaps.org"),
+        });
+
+        assert!(stored
+            .keep_passwords_with_settings_of(&added_account)
+            .is_none());
+        assert!(!password_format().contains_redacted());
+    }
+}
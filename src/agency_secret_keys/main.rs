@@ -0,0 +1,150 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+struct Flags {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print a new random master key, to be added to the keyring
+    GenerateKey,
+    /// Seal every agency credential that is still stored as plaintext
+    SealPlaintext,
+    /// Rewrap every sealed credential with the active master key
+    Rotate,
+}
+
+use catenary::agency_secret::keyring::{generate_master_key, MasterKeyring};
+use catenary::agency_secret::{SealedPasswordFormat, StoredPasswordFormat};
+use catenary::postgres_tools::{make_async_pool, CatenaryPostgresPool};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use serde_json::Value;
+use std::error::Error;
+
+// the new value of a row, or None if it can stay as it is
+fn transform_row(
+    command: &Command,
+    keyring: &MasterKeyring,
+    onestop_feed_id: &str,
+    passwords: Value,
+) -> Result<Option<SealedPasswordFormat>, Box<dyn Error + Send + Sync>> {
+    let stored = StoredPasswordFormat::from_value(passwords)?;
+
+    match (command, stored) {
+        (Command::SealPlaintext, StoredPasswordFormat::Plaintext(plaintext)) => {
+            Ok(Some(plaintext.seal(keyring, onestop_feed_id)?))
+        }
+        (Command::Rotate, StoredPasswordFormat::Sealed(sealed))
+            if sealed.sealed_passwords.key_id != keyring.active_key_id() =>
+        {
+            Ok(Some(SealedPasswordFormat {
+                sealed_passwords: sealed.sealed_passwords.rewrap(keyring, onestop_feed_id)?,
+                ..sealed
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn transform_rows(
+    command: &Command,
+    keyring: &MasterKeyring,
+    table_name: &str,
+    rows: Vec<(String, Option<Value>)>,
+) -> Vec<(String, Value)> {
+    rows.into_iter()
+        .filter_map(|(onestop_feed_id, passwords)| {
+            let passwords = passwords?;
+
+            match transform_row(command, keyring, &onestop_feed_id, passwords) {
+                Ok(Some(sealed)) => match serde_json::to_value(&sealed) {
+                    Ok(value) => Some((onestop_feed_id, value)),
+                    Err(e) => {
+                        eprintln!(
+                            "Could not serialise {} {}: {}",
+                            table_name, onestop_feed_id, e
+                        );
+                        None
+                    }
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    eprintln!("Skipping {} {}: {}", table_name, onestop_feed_id, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let flags: Flags = Flags::parse();
+
+    if let Command::GenerateKey = flags.command {
+        println!("{}", generate_master_key());
+        return Ok(());
+    }
+
+    let keyring = MasterKeyring::from_env()?;
+
+    println!("Active master key is {}", keyring.active_key_id());
+
+    let conn_pool: CatenaryPostgresPool = make_async_pool().await?;
+    let conn = &mut conn_pool.get().await?;
+
+    println!("Connected to postgres");
+
+    use catenary::schema::gtfs::realtime_passwords as rt_table;
+    use catenary::schema::gtfs::static_passwords as static_table;
+
+    let unix_time = catenary::duration_since_unix_epoch().as_millis() as i64;
+
+    let realtime_rows = rt_table::dsl::realtime_passwords
+        .select((rt_table::onestop_feed_id, rt_table::passwords))
+        .load::<(String, Option<Value>)>(conn)
+        .await?;
+
+    let realtime_updates = transform_rows(
+        &flags.command,
+        &keyring,
+        "realtime_passwords",
+        realtime_rows,
+    );
+
+    for (onestop_feed_id, passwords) in &realtime_updates {
+        diesel::update(rt_table::dsl::realtime_passwords.find(onestop_feed_id))
+            .set((
+                rt_table::passwords.eq(passwords),
+                rt_table::last_updated_ms.eq(unix_time),
+            ))
+            .execute(conn)
+            .await?;
+    }
+
+    println!("Updated {} realtime credentials", realtime_updates.len());
+
+    let static_rows = static_table::dsl::static_passwords
+        .select((static_table::onestop_feed_id, static_table::passwords))
+        .load::<(String, Option<Value>)>(conn)
+        .await?;
+
+    let static_updates = transform_rows(&flags.command, &keyring, "static_passwords", static_rows);
+
+    for (onestop_feed_id, passwords) in &static_updates {
+        diesel::update(static_table::dsl::static_passwords.find(onestop_feed_id))
+            .set((
+                static_table::passwords.eq(passwords),
+                static_table::last_updated_ms.eq(unix_time),
+            ))
+            .execute(conn)
+            .await?;
+    }
+
+    println!("Updated {} static credentials", static_updates.len());
+
+    Ok(())
+}
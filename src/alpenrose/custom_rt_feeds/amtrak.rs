@@ -1,14 +1,52 @@
+use catenary::aspen_dataset::{AspenisedCarriageDetails, VehicleConsistSupplement};
+use catenary::coordination::Coordinator;
 use catenary::duration_since_unix_epoch;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::rt_recording::RecordedFetch;
 use prost::Message;
+use std::collections::HashMap;
+
+// Amtrak's train data has no car level detail of its own, so the consist is whatever carriages
+// ended up on each vehicle position, counted so aspen gets an explicit consist length
+fn consist_supplements(
+    vehicle_positions: &gtfs_realtime::FeedMessage,
+) -> HashMap<String, VehicleConsistSupplement> {
+    vehicle_positions
+        .entity
+        .iter()
+        .filter_map(|entity| {
+            let vehicle = entity.vehicle.as_ref()?;
+
+            if vehicle.multi_carriage_details.is_empty() {
+                return None;
+            }
+
+            let carriages = vehicle
+                .multi_carriage_details
+                .iter()
+                .cloned()
+                .map(AspenisedCarriageDetails::from)
+                .collect::<Vec<AspenisedCarriageDetails>>();
+
+            Some((
+                entity.id.clone(),
+                VehicleConsistSupplement {
+                    consist_length: Some(carriages.len() as u32),
+                    scheduled_consist_length: None,
+                    carriages,
+                },
+            ))
+        })
+        .collect()
+}
 
 pub async fn fetch_amtrak_data(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     feed_id: &str,
     gtfs: &gtfs_structures::Gtfs,
     client: &reqwest::Client,
 ) {
-    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
+    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(coordinator, feed_id).await;
 
     if let Some(data) = fetch_assigned_node_meta {
         let worker_id = data.worker_id;
@@ -20,28 +58,32 @@ pub async fn fetch_amtrak_data(
             let vehicle_data = amtrak_gtfs_rt.vehicle_positions.encode_to_vec();
             let trip_data = amtrak_gtfs_rt.trip_updates.encode_to_vec();
             let alert_data = amtrak_gtfs_rt.alerts.encode_to_vec();
+            let vehicle_consists = consist_supplements(&amtrak_gtfs_rt.vehicle_positions);
 
             let aspen_client = catenary::aspen::lib::spawn_aspen_client_from_ip(&data.socket)
                 .await
                 .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    data.chateau_id.clone(),
-                    String::from(feed_id),
-                    Some(vehicle_data),
-                    Some(trip_data),
-                    Some(alert_data),
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    Some(200),
-                    Some(200),
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+            let tarpc_send_to_aspen = super::super::recording::send_and_record(
+                &aspen_client,
+                RecordedFetch {
+                    chateau_id: data.chateau_id.clone(),
+                    realtime_feed_id: String::from(feed_id),
+                    vehicles: Some(vehicle_data),
+                    trips: Some(trip_data),
+                    alerts: Some(alert_data),
+                    has_vehicles: true,
+                    has_trips: true,
+                    has_alerts: false,
+                    vehicles_response_code: Some(200),
+                    trips_response_code: Some(200),
+                    alerts_response_code: Some(200),
+                    vehicle_consists: Some(vehicle_consists),
+                    supplementary: None,
+                    fetched_at_ms: duration_since_unix_epoch().as_millis() as u64,
+                },
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
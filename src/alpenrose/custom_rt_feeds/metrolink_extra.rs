@@ -1,11 +1,178 @@
+use ahash::AHashMap;
+use catenary::aspen_dataset::{
+    CatenaryRtVehiclePosition, PlatformAssignment, PlatformConfidence, SupplementaryJoinKey,
+    SupplementaryRecord,
+};
+use catenary::coordination::Coordinator;
 use catenary::duration_since_unix_epoch;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::rt_recording::RecordedFetch;
+use compact_str::CompactString;
 use gtfs_realtime::FeedHeader;
 use gtfs_realtime::FeedMessage;
 use prost::Message;
+use serde::{Deserialize, Serialize};
 
-pub async fn fetch_data(etcd: &mut etcd_client::Client, feed_id: &str, client: &reqwest::Client) {
-    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MetrolinkPosRaw {
+    pub symbol: CompactString,
+    pub direction: CompactString,
+    pub lat: CompactString,
+    pub long: CompactString,
+    pub speed: CompactString,
+    pub line: CompactString,
+    pub ptc_time: CompactString,
+    pub ptc_status: CompactString,
+    pub delay_status: CompactString,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MetrolinkTrackData {
+    #[serde(rename = "TrainDesignation")]
+    train_designation: String,
+    #[serde(rename = "PlatformName")]
+    platform_name: String,
+    #[serde(rename = "FormattedTrackDesignation")]
+    formatted_track_designation: String,
+}
+
+fn mph_to_mps(mph: &CompactString) -> Option<f32> {
+    let mph: f32 = match mph.parse() {
+        Ok(mph) => mph,
+        Err(_) => return None,
+    };
+
+    Some(mph * 0.44704)
+}
+
+fn metrlink_coord_to_f32(coord: &CompactString) -> Option<f32> {
+    //Split into 3 parts based on :
+    let parts: Vec<&str> = coord.split(':').collect();
+
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let degrees: f32 = match parts[0].parse() {
+        Ok(degrees) => degrees,
+        Err(_) => return None,
+    };
+
+    let sign = if degrees < 0. { -1.0 } else { 1.0 };
+
+    let degrees = degrees.abs();
+
+    let minutes: f32 = match parts[1].parse() {
+        Ok(minutes) => minutes,
+        Err(_) => return None,
+    };
+
+    let seconds: f32 = match parts[2].parse() {
+        Ok(seconds) => seconds,
+        Err(_) => return None,
+    };
+
+    let mut decimal = degrees + minutes / 60.0 + seconds / 3600.0;
+
+    decimal = decimal * sign;
+
+    Some(decimal)
+}
+
+// Positions from the train list, joined onto the GTFS-RT vehicles by their id, which is the train symbol
+async fn fetch_train_list_records(
+    client: &reqwest::Client,
+) -> Result<Vec<SupplementaryRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let metrolink_data = client
+        .get("https://rtt.metrolinktrains.com/trainlist.json")
+        .send()
+        .await?
+        .json::<Vec<MetrolinkPosRaw>>()
+        .await?;
+
+    let records = metrolink_data
+        .into_iter()
+        .filter_map(|pos| {
+            let lat = metrlink_coord_to_f32(&pos.lat)?;
+            let lon = metrlink_coord_to_f32(&pos.long)?;
+            let speed = mph_to_mps(&pos.speed)?;
+
+            Some(SupplementaryRecord {
+                join_key: SupplementaryJoinKey::VehicleId(pos.symbol.to_string()),
+                position: Some(CatenaryRtVehiclePosition {
+                    latitude: lat,
+                    longitude: lon,
+                    bearing: None,
+                    odometer: None,
+                    speed: None,
+                }),
+                speed: Some(speed),
+                occupancy_status: None,
+                occupancy_percentage: None,
+                platforms: AHashMap::new(),
+            })
+        })
+        .collect::<Vec<SupplementaryRecord>>();
+
+    Ok(records)
+}
+
+// Tracks from the station board, joined onto the trip updates by trip short name.
+// The board is the only source, so there is nothing to compare against.
+async fn fetch_station_board_records(
+    client: &reqwest::Client,
+) -> Result<Vec<SupplementaryRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let track_data = client
+        .get("https://rtt.metrolinktrains.com/StationScheduleList.json")
+        .send()
+        .await?
+        .json::<Vec<MetrolinkTrackData>>()
+        .await?;
+
+    // trip short name -> gtfs stop id -> platform
+    let mut platforms_by_trip: AHashMap<String, AHashMap<String, PlatformAssignment>> =
+        AHashMap::new();
+
+    for track in track_data {
+        // the board calls train 123 M123
+        let trip_short_name = match track.train_designation.strip_prefix('M') {
+            Some(trip_short_name) => trip_short_name.to_string(),
+            None => continue,
+        };
+
+        let stop_id = catenary::metrolink_ptc_to_stop_id::METROLINK_STOP_LIST
+            .iter()
+            .find(|x| x.1 == &track.platform_name)
+            .map(|(stop_id, _)| stop_id.to_string());
+
+        if let Some(stop_id) = stop_id {
+            platforms_by_trip
+                .entry(trip_short_name)
+                .or_default()
+                .entry(stop_id)
+                .or_insert_with(|| PlatformAssignment {
+                    platform: track.formatted_track_designation.replace("Platform ", ""),
+                    confidence: PlatformConfidence::Posted,
+                    changed_from_scheduled: false,
+                });
+        }
+    }
+
+    Ok(platforms_by_trip
+        .into_iter()
+        .map(|(trip_short_name, platforms)| SupplementaryRecord {
+            join_key: SupplementaryJoinKey::TripShortName(trip_short_name),
+            position: None,
+            speed: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            platforms,
+        })
+        .collect())
+}
+
+pub async fn fetch_data(coordinator: &Coordinator, feed_id: &str, client: &reqwest::Client) {
+    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(coordinator, feed_id).await;
 
     if let Some(assigned_chateau_data) = fetch_assigned_node_meta {
         let worker_id = assigned_chateau_data.worker_id;
@@ -16,61 +183,99 @@ pub async fn fetch_data(etcd: &mut etcd_client::Client, feed_id: &str, client: &
             )
             .await;
 
-        if let Ok(metrolink_extra_gtfs_rt_data) = metrolink_extra_gtfs_rt_data {
-            //extract the binary data
-            let alerts_msg = FeedMessage {
-                header: FeedHeader {
-                    gtfs_realtime_version: String::from("2.0"),
-                    incrementality: Some(
-                        gtfs_realtime::feed_header::Incrementality::FullDataset.into(),
-                    ),
-                    timestamp: Some(duration_since_unix_epoch().as_secs() as u64),
-                },
-                entity: metrolink_extra_gtfs_rt_data,
-            };
-
-            let alerts_proto = alerts_msg.encode_to_vec();
-
-            let aspen_client =
-                catenary::aspen::lib::spawn_aspen_client_from_ip(&assigned_chateau_data.socket)
-                    .await
-                    .unwrap();
-
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    assigned_chateau_data.chateau_id.clone(),
-                    String::from(feed_id),
-                    None,
-                    None,
-                    Some(alerts_proto),
-                    false,
-                    false,
-                    true,
-                    None,
-                    None,
-                    Some(200),
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
-
-            match tarpc_send_to_aspen {
-                Ok(_) => {
-                    println!(
-                        "Successfully sent Metrolink Alerts Extra feed data sent to {}",
-                        feed_id
-                    );
-                }
-                Err(e) => {
-                    eprintln!(
-                        "{}: Error sending Metrolink Alerts Extra feed data to {}: {}",
-                        feed_id, worker_id, e
-                    );
-                }
+        let alerts_proto = match metrolink_extra_gtfs_rt_data {
+            Ok(metrolink_extra_gtfs_rt_data) => {
+                //extract the binary data
+                let alerts_msg = FeedMessage {
+                    header: FeedHeader {
+                        gtfs_realtime_version: String::from("2.0"),
+                        incrementality: Some(
+                            gtfs_realtime::feed_header::Incrementality::FullDataset.into(),
+                        ),
+                        timestamp: Some(duration_since_unix_epoch().as_secs() as u64),
+                    },
+                    entity: metrolink_extra_gtfs_rt_data,
+                };
+
+                Some(alerts_msg.encode_to_vec())
+            }
+            Err(e) => {
+                eprintln!("Failed to fetch Metrolink Alerts Extra data");
+                eprintln!("{:?}", e);
+                None
+            }
+        };
+
+        let train_list_records = match fetch_train_list_records(client).await {
+            Ok(records) => Some(records),
+            Err(e) => {
+                eprintln!("Failed to fetch Metrolink train list: {}", e);
+                None
+            }
+        };
+
+        let station_board_records = match fetch_station_board_records(client).await {
+            Ok(records) => Some(records),
+            Err(e) => {
+                eprintln!("Failed to fetch Metrolink station board: {}", e);
+                None
+            }
+        };
+
+        let supplementary = match (train_list_records, station_board_records) {
+            (None, None) => None,
+            (train_list_records, station_board_records) => Some(
+                train_list_records
+                    .into_iter()
+                    .flatten()
+                    .chain(station_board_records.into_iter().flatten())
+                    .collect::<Vec<SupplementaryRecord>>(),
+            ),
+        };
+
+        if alerts_proto.is_none() && supplementary.is_none() {
+            return;
+        }
+
+        let aspen_client =
+            catenary::aspen::lib::spawn_aspen_client_from_ip(&assigned_chateau_data.socket)
+                .await
+                .unwrap();
+
+        let tarpc_send_to_aspen = super::super::recording::send_and_record(
+            &aspen_client,
+            RecordedFetch {
+                chateau_id: assigned_chateau_data.chateau_id.clone(),
+                realtime_feed_id: String::from(feed_id),
+                vehicles: None,
+                trips: None,
+                alerts_response_code: alerts_proto.as_ref().map(|_| 200),
+                has_alerts: alerts_proto.is_some(),
+                alerts: alerts_proto,
+                has_vehicles: false,
+                has_trips: false,
+                vehicles_response_code: None,
+                trips_response_code: None,
+                vehicle_consists: None,
+                supplementary,
+                fetched_at_ms: duration_since_unix_epoch().as_millis() as u64,
+            },
+        )
+        .await;
+
+        match tarpc_send_to_aspen {
+            Ok(_) => {
+                println!(
+                    "Successfully sent Metrolink Alerts Extra feed data sent to {}",
+                    feed_id
+                );
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}: Error sending Metrolink Alerts Extra feed data to {}: {}",
+                    feed_id, worker_id, e
+                );
             }
-        } else {
-            eprintln!("Failed to fetch Metrolink Alerts Extra data");
-            eprintln!("{:?}", metrolink_extra_gtfs_rt_data.unwrap_err());
         }
     } else {
         println!("No assigned node found for Metrolink Alerts Extra");
@@ -1,5 +1,13 @@
+use ahash::AHashMap;
+use catenary::aspen_dataset::{
+    AspenisedCarriageDetails, PlatformAssignment, PlatformConfidence, SupplementaryJoinKey,
+    SupplementaryRecord, VehicleConsistSupplement,
+};
+use catenary::coordination::Coordinator;
+use catenary::rt_recording::RecordedFetch;
 use prost::Message;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 const LIRR_TRIPS_FEED: &str =
     "https://api-endpoint.mta.info/Dataservice/mtagtfsfeeds/lirr%2Fgtfs-lirr";
@@ -8,7 +16,7 @@ const MNR_TRIPS_FEED: &str =
     "https://api-endpoint.mta.info/Dataservice/mtagtfsfeeds/mnr%2Fgtfs-mnr";
 
 pub async fn fetch_mta_lirr_data(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     feed_id: &str,
     client: &reqwest::Client,
 ) {
@@ -31,6 +39,8 @@ pub async fn fetch_mta_lirr_data(
 
             if let Ok(import_data) = import_data {
                 let converted = convert(&import_data, MtaRailroad::LIRR, &gtfs_rt_trips);
+                let vehicle_consists = consist_supplements(&import_data, MtaRailroad::LIRR);
+                let platforms = platform_records(&import_data, MtaRailroad::LIRR);
 
                 let lirr_vehicle_position = catenary::make_feed_from_entity_vec(converted);
 
@@ -38,10 +48,12 @@ pub async fn fetch_mta_lirr_data(
                 let lirr_trip_updates_bytes = gtfs_rt_trips.encode_to_vec();
 
                 send_mta_rail_to_aspen(
-                    etcd,
+                    coordinator,
                     MtaRailroad::LIRR,
                     lirr_vehicle_position_bytes,
                     lirr_trip_updates_bytes,
+                    vehicle_consists,
+                    platforms,
                     feed_id,
                 )
                 .await;
@@ -51,7 +63,7 @@ pub async fn fetch_mta_lirr_data(
 }
 
 pub async fn fetch_mta_metronorth_data(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     feed_id: &str,
     client: &reqwest::Client,
 ) {
@@ -73,6 +85,8 @@ pub async fn fetch_mta_metronorth_data(
 
             if let Ok(import_data) = import_data {
                 let converted = convert(&import_data, MtaRailroad::MNR, &gtfs_rt_trips);
+                let vehicle_consists = consist_supplements(&import_data, MtaRailroad::MNR);
+                let platforms = platform_records(&import_data, MtaRailroad::MNR);
 
                 let mnr_vehicle_position = catenary::make_feed_from_entity_vec(converted);
 
@@ -80,10 +94,12 @@ pub async fn fetch_mta_metronorth_data(
                 let mnr_trip_updates_bytes = gtfs_rt_trips.encode_to_vec();
 
                 send_mta_rail_to_aspen(
-                    etcd,
+                    coordinator,
                     MtaRailroad::MNR,
                     mnr_vehicle_position_bytes,
                     mnr_trip_updates_bytes,
+                    vehicle_consists,
+                    platforms,
                     feed_id,
                 )
                 .await;
@@ -228,6 +244,122 @@ pub struct MtaTrain {
     status: TrainStatus,
 }
 
+fn occupancy_status_from_loading(loading: &str) -> Option<i32> {
+    use gtfs_realtime::vehicle_position::OccupancyStatus;
+
+    let occupancy_status = match loading.to_uppercase().as_str() {
+        "EMPTY" => OccupancyStatus::Empty,
+        "LOW" | "LIGHT" => OccupancyStatus::ManySeatsAvailable,
+        "MEDIUM" | "MODERATE" => OccupancyStatus::FewSeatsAvailable,
+        "HIGH" | "HEAVY" => OccupancyStatus::StandingRoomOnly,
+        "FULL" => OccupancyStatus::Full,
+        _ => return None,
+    };
+
+    Some(occupancy_status.into())
+}
+
+fn carriages_from_consist(consist: &TrainConsist) -> Vec<AspenisedCarriageDetails> {
+    consist
+        .cars
+        .iter()
+        .enumerate()
+        .map(|(index, car)| AspenisedCarriageDetails {
+            id: car.number.map(|number| number.to_string()),
+            label: car.number.map(|number| number.to_string()),
+            occupancy_status: occupancy_status_from_loading(&car.loading),
+            occupancy_percentage: None,
+            carriage_sequence: Some(index as u32 + 1),
+            car_type: Some(car.traintype.clone()),
+            restroom: car.restroom,
+            bike_capacity: car.bikes,
+            locomotive: Some(car.locomotive),
+            revenue: car.revenue,
+        })
+        .collect()
+}
+
+// Car types, amenities and the scheduled length have no place in GTFS-RT, so they are sent beside it
+fn consist_supplements(
+    mta: &[MtaTrain],
+    railroad: MtaRailroad,
+) -> HashMap<String, VehicleConsistSupplement> {
+    let railroad_str = match railroad {
+        MtaRailroad::LIRR => "LIRR",
+        MtaRailroad::MNR => "MNR",
+    };
+
+    mta.iter()
+        .filter(|mta| mta.railroad.as_str() == railroad_str)
+        .map(|mta| {
+            let carriages = carriages_from_consist(&mta.consist);
+
+            (
+                mta.train_id.clone(),
+                VehicleConsistSupplement {
+                    consist_length: match mta.consist.actual_len {
+                        Some(actual_len) => Some(actual_len as u32),
+                        None if !carriages.is_empty() => Some(carriages.len() as u32),
+                        None => None,
+                    },
+                    scheduled_consist_length: mta
+                        .consist
+                        .sched_len
+                        .map(|sched_len| sched_len as u32),
+                    carriages,
+                },
+            )
+        })
+        .collect()
+}
+
+// The posted sign track is what riders see, the internal assignment is only a prediction
+fn platform_from_stop(stop: &TrainStop) -> Option<PlatformAssignment> {
+    let changed_from_scheduled = stop.track_change.unwrap_or(false);
+
+    match &stop.sign_track {
+        Some(sign_track) if stop.posted && !sign_track.is_empty() => Some(PlatformAssignment {
+            platform: sign_track.clone(),
+            confidence: PlatformConfidence::Posted,
+            changed_from_scheduled,
+        }),
+        _ if !stop.t2s_track.is_empty() => Some(PlatformAssignment {
+            platform: stop.t2s_track.clone(),
+            confidence: PlatformConfidence::Predicted,
+            changed_from_scheduled,
+        }),
+        _ => None,
+    }
+}
+
+// Tracks by train number, with stops as station codes, which the GTFS publishes as stop_code
+fn platform_records(mta: &[MtaTrain], railroad: MtaRailroad) -> Vec<SupplementaryRecord> {
+    let railroad_str = match railroad {
+        MtaRailroad::LIRR => "LIRR",
+        MtaRailroad::MNR => "MNR",
+    };
+
+    mta.iter()
+        .filter(|mta| mta.railroad.as_str() == railroad_str)
+        .map(|mta| SupplementaryRecord {
+            join_key: SupplementaryJoinKey::TripShortName(mta.train_num.clone()),
+            position: None,
+            speed: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            platforms: mta
+                .details
+                .stops
+                .iter()
+                .filter_map(|stop| {
+                    platform_from_stop(stop).map(|platform| (stop.code.clone(), platform))
+                })
+                .collect::<AHashMap<String, PlatformAssignment>>(),
+        })
+        .filter(|record| !record.platforms.is_empty())
+        .collect()
+}
+
 fn convert(
     mta: &Vec<MtaTrain>,
     railroad: MtaRailroad,
@@ -368,8 +500,23 @@ fn convert(
                 },
                 timestamp: Some(mta.location.timestamp as u64),
                 congestion_level: None,
-                occupancy_status: None,
-                multi_carriage_details: vec![],
+                occupancy_status: mta
+                    .consist
+                    .occupancy
+                    .as_deref()
+                    .and_then(occupancy_status_from_loading),
+                multi_carriage_details: carriages_from_consist(&mta.consist)
+                    .into_iter()
+                    .map(
+                        |carriage| gtfs_realtime::vehicle_position::CarriageDetails {
+                            id: carriage.id,
+                            label: carriage.label,
+                            occupancy_status: carriage.occupancy_status,
+                            occupancy_percentage: None,
+                            carriage_sequence: carriage.carriage_sequence,
+                        },
+                    )
+                    .collect(),
                 occupancy_percentage: None,
             }),
         })
@@ -382,13 +529,16 @@ pub enum MtaRailroad {
 }
 
 pub async fn send_mta_rail_to_aspen(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     railroad: MtaRailroad,
     vehicle_position: Vec<u8>,
     trip_updates: Vec<u8>,
+    vehicle_consists: HashMap<String, VehicleConsistSupplement>,
+    platforms: Vec<SupplementaryRecord>,
     feed_id: &str,
 ) {
-    let fetch_assigned_node_meta = catenary::get_node_for_realtime_feed_id(etcd, feed_id).await;
+    let fetch_assigned_node_meta =
+        catenary::get_node_for_realtime_feed_id(coordinator, feed_id).await;
 
     if let Some(data) = fetch_assigned_node_meta {
         let worker_id = data.worker_id;
@@ -397,23 +547,26 @@ pub async fn send_mta_rail_to_aspen(
             .await
             .unwrap();
 
-        let tarpc_send_to_aspen = aspen_client
-            .from_alpenrose(
-                tarpc::context::current(),
-                data.chateau_id.clone(),
-                String::from(feed_id),
-                Some(vehicle_position),
-                Some(trip_updates),
-                None,
-                true,
-                true,
-                false,
-                Some(200),
-                Some(200),
-                None,
-                catenary::duration_since_unix_epoch().as_millis() as u64,
-            )
-            .await;
+        let tarpc_send_to_aspen = super::super::recording::send_and_record(
+            &aspen_client,
+            RecordedFetch {
+                chateau_id: data.chateau_id.clone(),
+                realtime_feed_id: String::from(feed_id),
+                vehicles: Some(vehicle_position),
+                trips: Some(trip_updates),
+                alerts: None,
+                has_vehicles: true,
+                has_trips: true,
+                has_alerts: false,
+                vehicles_response_code: Some(200),
+                trips_response_code: Some(200),
+                alerts_response_code: None,
+                vehicle_consists: Some(vehicle_consists),
+                supplementary: Some(platforms),
+                fetched_at_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
+            },
+        )
+        .await;
 
         match tarpc_send_to_aspen {
             Ok(_) => {
@@ -428,3 +581,39 @@ pub async fn send_mta_rail_to_aspen(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(sign_track: Option<&str>, t2s_track: &str, posted: bool) -> TrainStop {
+        TrainStop {
+            code: "NYK".to_string(),
+            sched_time: 0,
+            sign_track: sign_track.map(|track| track.to_string()),
+            avps_track_id: None,
+            posted,
+            t2s_track: t2s_track.to_string(),
+            stop_status: None,
+            stop_type: "REGULAR".to_string(),
+            track_change: Some(true),
+            local_cancel: None,
+            bus: false,
+            occupancy: String::new(),
+        }
+    }
+
+    #[test]
+    fn posted_sign_track_wins_over_internal_assignment() {
+        let posted = platform_from_stop(&stop(Some("19"), "17", true)).unwrap();
+        assert_eq!(posted.platform, "19");
+        assert_eq!(posted.confidence, PlatformConfidence::Posted);
+        assert!(posted.changed_from_scheduled);
+
+        let predicted = platform_from_stop(&stop(Some("19"), "17", false)).unwrap();
+        assert_eq!(predicted.platform, "17");
+        assert_eq!(predicted.confidence, PlatformConfidence::Predicted);
+
+        assert_eq!(platform_from_stop(&stop(None, "", false)), None);
+    }
+}
@@ -1,5 +1,7 @@
+use catenary::coordination::Coordinator;
 use catenary::duration_since_unix_epoch;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::rt_recording::RecordedFetch;
 use gtfs_realtime::FeedMessage;
 use prost::Message;
 
@@ -11,12 +13,8 @@ pub struct DresdenResults {
     pub trip_updates: FeedMessage,
 }
 
-pub async fn fetch_tlms_data(
-    etcd: &mut etcd_client::Client,
-    feed_id: &str,
-    client: &reqwest::Client,
-) {
-    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
+pub async fn fetch_tlms_data(coordinator: &Coordinator, feed_id: &str, client: &reqwest::Client) {
+    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(coordinator, feed_id).await;
 
     if let Some(worker_metadata) = fetch_assigned_node_meta {
         let worker_id = worker_metadata.worker_id;
@@ -41,23 +39,26 @@ pub async fn fetch_tlms_data(
                     .await
                     .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    worker_metadata.chateau_id.clone(),
-                    String::from(feed_id),
-                    Some(dresden_rt_data.vehicle_positions.encode_to_vec()),
-                    None,
-                    None,
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    None,
-                    None,
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+            let tarpc_send_to_aspen = super::super::recording::send_and_record(
+                &aspen_client,
+                RecordedFetch {
+                    chateau_id: worker_metadata.chateau_id.clone(),
+                    realtime_feed_id: String::from(feed_id),
+                    vehicles: Some(dresden_rt_data.vehicle_positions.encode_to_vec()),
+                    trips: None,
+                    alerts: None,
+                    has_vehicles: true,
+                    has_trips: true,
+                    has_alerts: false,
+                    vehicles_response_code: Some(200),
+                    trips_response_code: None,
+                    alerts_response_code: None,
+                    vehicle_consists: None,
+                    supplementary: None,
+                    fetched_at_ms: duration_since_unix_epoch().as_millis() as u64,
+                },
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
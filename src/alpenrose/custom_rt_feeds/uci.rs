@@ -1,10 +1,12 @@
+use catenary::coordination::Coordinator;
 use catenary::duration_since_unix_epoch;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::rt_recording::RecordedFetch;
 use prost::Message;
 use zotgtfs::get_gtfs_rt;
 
-pub async fn fetch_uci_data(etcd: &mut etcd_client::Client, feed_id: &str) {
-    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
+pub async fn fetch_uci_data(coordinator: &Coordinator, feed_id: &str) {
+    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(coordinator, feed_id).await;
 
     if let Some(data) = fetch_assigned_node_meta {
         let worker_id = data.worker_id;
@@ -19,23 +21,26 @@ pub async fn fetch_uci_data(etcd: &mut etcd_client::Client, feed_id: &str) {
                 .await
                 .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    data.chateau_id.clone(),
-                    String::from(feed_id),
-                    Some(vehicle_data.clone()),
-                    Some(vehicle_data),
-                    None,
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    Some(200),
-                    None,
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+            let tarpc_send_to_aspen = super::super::recording::send_and_record(
+                &aspen_client,
+                RecordedFetch {
+                    chateau_id: data.chateau_id.clone(),
+                    realtime_feed_id: String::from(feed_id),
+                    vehicles: Some(vehicle_data.clone()),
+                    trips: Some(vehicle_data),
+                    alerts: None,
+                    has_vehicles: true,
+                    has_trips: true,
+                    has_alerts: false,
+                    vehicles_response_code: Some(200),
+                    trips_response_code: Some(200),
+                    alerts_response_code: None,
+                    vehicle_consists: None,
+                    supplementary: None,
+                    fetched_at_ms: duration_since_unix_epoch().as_millis() as u64,
+                },
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
@@ -1,13 +1,47 @@
+use catenary::coordination::Coordinator;
 use catenary::duration_since_unix_epoch;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::rt_recording::RecordedFetch;
 use catenary::unzip_uk::get_raw_gtfs_rt;
 
+use super::super::fan_out::{decode_feed, send_fanned_out_data, FanOutContext};
+
 pub async fn fetch_dft_bus_data(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     feed_id: &str,
     client: &reqwest::Client,
+    fan_out: &FanOutContext,
 ) {
-    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(etcd, feed_id).await;
+    if fan_out.has_rules(feed_id).await {
+        //BODS only publishes vehicle positions, with the trip each vehicle is running
+        //the whole of Great Britain is split between the chateaus owning each operator's schedules
+        match get_raw_gtfs_rt(client).await {
+            Ok(uk_rt_data) => {
+                if let Some(uk_rt_feed) = decode_feed(feed_id, &uk_rt_data) {
+                    if let Err(e) = send_fanned_out_data(
+                        coordinator,
+                        fan_out,
+                        feed_id,
+                        Some(uk_rt_feed),
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        eprintln!("{}: Error fanning out UK data: {}", feed_id, e);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to fetch UK data");
+                eprintln!("{:?}", e);
+            }
+        }
+
+        return;
+    }
+
+    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(coordinator, feed_id).await;
 
     if let Some(data) = fetch_assigned_node_meta {
         let worker_id = data.worker_id;
@@ -19,23 +53,26 @@ pub async fn fetch_dft_bus_data(
                 .await
                 .unwrap();
 
-            let tarpc_send_to_aspen = aspen_client
-                .from_alpenrose(
-                    tarpc::context::current(),
-                    data.chateau_id.clone(),
-                    String::from(feed_id),
-                    Some(uk_rt_data.clone()),
-                    Some(uk_rt_data.clone()),
-                    None,
-                    true,
-                    true,
-                    false,
-                    Some(200),
-                    Some(200),
-                    None,
-                    duration_since_unix_epoch().as_millis() as u64,
-                )
-                .await;
+            let tarpc_send_to_aspen = super::super::recording::send_and_record(
+                &aspen_client,
+                RecordedFetch {
+                    chateau_id: data.chateau_id.clone(),
+                    realtime_feed_id: String::from(feed_id),
+                    vehicles: Some(uk_rt_data),
+                    trips: None,
+                    alerts: None,
+                    has_vehicles: true,
+                    has_trips: false,
+                    has_alerts: false,
+                    vehicles_response_code: Some(200),
+                    trips_response_code: None,
+                    alerts_response_code: None,
+                    vehicle_consists: None,
+                    supplementary: None,
+                    fetched_at_ms: duration_since_unix_epoch().as_millis() as u64,
+                },
+            )
+            .await;
 
             match tarpc_send_to_aspen {
                 Ok(_) => {
@@ -0,0 +1,466 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+use ahash::AHashMap;
+use catenary::coordination::Coordinator;
+use catenary::duration_since_unix_epoch;
+use catenary::get_node_for_chateau;
+use catenary::get_node_for_realtime_feed_id;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::realtime_fan_out::{
+    partition_feed_id, partition_feed_message, FanOutLookups, FanOutRule, PartitionedFeed,
+};
+use catenary::rt_recording::RecordedFetch;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use gtfs_realtime::FeedMessage;
+use prost::Message;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+const RULES_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
+const TRIP_LOOKUP_CHUNK_SIZE: usize = 5_000;
+
+#[derive(Default)]
+struct FanOutRuleSet {
+    // aggregate realtime feed id -> rules in evaluation order
+    rules_by_feed: HashMap<String, Vec<FanOutRule>>,
+    // aggregate realtime feed id -> route_id -> agency_id, only for feeds with agency rules
+    route_id_to_agency_id: HashMap<String, AHashMap<String, String>>,
+    refreshed_at: Option<Instant>,
+}
+
+pub struct FanOutContext {
+    pool: Arc<CatenaryPostgresPool>,
+    rule_set: RwLock<FanOutRuleSet>,
+    // (aggregate feed id, trip_id) -> owning chateau, None when no rule chateau has the trip
+    trip_owner_cache: RwLock<AHashMap<(String, String), Option<String>>>,
+}
+
+impl FanOutContext {
+    pub fn new(pool: Arc<CatenaryPostgresPool>) -> FanOutContext {
+        FanOutContext {
+            pool,
+            rule_set: RwLock::new(FanOutRuleSet::default()),
+            trip_owner_cache: RwLock::new(AHashMap::new()),
+        }
+    }
+
+    pub async fn refresh_if_stale(&self) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+        {
+            let rule_set = self.rule_set.read().await;
+            if let Some(refreshed_at) = rule_set.refreshed_at {
+                if refreshed_at.elapsed() < RULES_REFRESH_INTERVAL {
+                    return Ok(());
+                }
+            }
+        }
+
+        let conn_pool = self.pool.as_ref();
+        let conn_pre = conn_pool.get().await;
+        let conn = &mut conn_pre?;
+
+        use catenary::schema::gtfs::realtime_fan_out_rules as rules_pg_schema;
+        use catenary::schema::gtfs::routes as routes_pg_schema;
+
+        let rows = rules_pg_schema::dsl::realtime_fan_out_rules
+            .order((
+                rules_pg_schema::dsl::aggregate_feed_id.asc(),
+                rules_pg_schema::dsl::rule_index.asc(),
+            ))
+            .select(catenary::models::RealtimeFanOutRule::as_select())
+            .load::<catenary::models::RealtimeFanOutRule>(conn)
+            .await?;
+
+        let mut rules_by_feed: HashMap<String, Vec<FanOutRule>> = HashMap::new();
+
+        for row in rows.iter() {
+            match FanOutRule::from_row(row) {
+                Some(rule) => rules_by_feed
+                    .entry(row.aggregate_feed_id.clone())
+                    .or_default()
+                    .push(rule),
+                None => eprintln!(
+                    "Ignoring invalid fan out rule {} #{} of type {}",
+                    row.aggregate_feed_id, row.rule_index, row.rule_type
+                ),
+            }
+        }
+
+        let mut route_id_to_agency_id: HashMap<String, AHashMap<String, String>> = HashMap::new();
+
+        for (feed_id, rules) in rules_by_feed.iter() {
+            let agency_rules = rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    FanOutRule::Agency { agency_id, chateau } => {
+                        Some((agency_id.clone(), chateau.clone()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<(String, String)>>();
+
+            if agency_rules.is_empty() {
+                continue;
+            }
+
+            let agency_ids = agency_rules
+                .iter()
+                .map(|(agency_id, _)| agency_id.clone())
+                .collect::<Vec<String>>();
+            let chateaus = agency_rules
+                .iter()
+                .map(|(_, chateau)| chateau.clone())
+                .collect::<Vec<String>>();
+
+            let routes = routes_pg_schema::dsl::routes
+                .filter(routes_pg_schema::dsl::chateau.eq_any(&chateaus))
+                .filter(routes_pg_schema::dsl::agency_id.eq_any(&agency_ids))
+                .select((
+                    routes_pg_schema::dsl::route_id,
+                    routes_pg_schema::dsl::agency_id,
+                ))
+                .load::<(String, Option<String>)>(conn)
+                .await?;
+
+            route_id_to_agency_id.insert(
+                feed_id.clone(),
+                routes
+                    .into_iter()
+                    .filter_map(|(route_id, agency_id)| agency_id.map(|a| (route_id, a)))
+                    .collect(),
+            );
+        }
+
+        println!(
+            "Loaded fan out rules for {} aggregate realtime feeds",
+            rules_by_feed.len()
+        );
+
+        let mut rule_set = self.rule_set.write().await;
+        *rule_set = FanOutRuleSet {
+            rules_by_feed,
+            route_id_to_agency_id,
+            refreshed_at: Some(Instant::now()),
+        };
+
+        // static data may have been reingested since the last refresh
+        self.trip_owner_cache.write().await.clear();
+
+        Ok(())
+    }
+
+    pub async fn has_rules(&self, feed_id: &str) -> bool {
+        self.rule_set
+            .read()
+            .await
+            .rules_by_feed
+            .contains_key(feed_id)
+    }
+
+    async fn lookups_for_feeds(
+        &self,
+        feed_id: &str,
+        rules: &[FanOutRule],
+        feeds: &[&FeedMessage],
+    ) -> Result<FanOutLookups, Box<dyn std::error::Error + Sync + Send>> {
+        let route_id_to_agency_id = self
+            .rule_set
+            .read()
+            .await
+            .route_id_to_agency_id
+            .get(feed_id)
+            .cloned()
+            .unwrap_or_default();
+
+        let ownership_chateaus = rules
+            .iter()
+            .filter_map(|rule| match rule {
+                FanOutRule::StaticTripOwnership { chateau } => Some(chateau.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        let mut trip_ids: HashSet<String> = HashSet::new();
+
+        if !ownership_chateaus.is_empty() {
+            for feed in feeds {
+                for entity in feed.entity.iter() {
+                    let trip_id = match (&entity.trip_update, &entity.vehicle) {
+                        (Some(trip_update), _) => trip_update.trip.trip_id.as_ref(),
+                        (None, Some(vehicle)) => {
+                            vehicle.trip.as_ref().and_then(|trip| trip.trip_id.as_ref())
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(trip_id) = trip_id {
+                        trip_ids.insert(trip_id.clone());
+                    }
+
+                    if let Some(alert) = &entity.alert {
+                        for informed_entity in alert.informed_entity.iter() {
+                            if let Some(trip_id) = informed_entity
+                                .trip
+                                .as_ref()
+                                .and_then(|trip| trip.trip_id.as_ref())
+                            {
+                                trip_ids.insert(trip_id.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let unknown_trip_ids = {
+            let trip_owner_cache = self.trip_owner_cache.read().await;
+            trip_ids
+                .iter()
+                .filter(|trip_id| {
+                    !trip_owner_cache.contains_key(&(feed_id.to_string(), trip_id.to_string()))
+                })
+                .cloned()
+                .collect::<Vec<String>>()
+        };
+
+        if !unknown_trip_ids.is_empty() {
+            use catenary::schema::gtfs::trips_compressed as trips_pg_schema;
+
+            let conn_pool = self.pool.as_ref();
+            let conn_pre = conn_pool.get().await;
+            let conn = &mut conn_pre?;
+
+            let mut found: AHashMap<String, String> = AHashMap::new();
+
+            for chunk in unknown_trip_ids.chunks(TRIP_LOOKUP_CHUNK_SIZE) {
+                let owners = trips_pg_schema::dsl::trips_compressed
+                    .filter(trips_pg_schema::dsl::chateau.eq_any(&ownership_chateaus))
+                    .filter(trips_pg_schema::dsl::trip_id.eq_any(chunk))
+                    .select((trips_pg_schema::dsl::trip_id, trips_pg_schema::dsl::chateau))
+                    .load::<(String, String)>(conn)
+                    .await?;
+
+                for (trip_id, chateau) in owners {
+                    // the earliest rule wins when two chateaus have the same trip id
+                    let rank = |chateau: &str| ownership_chateaus.iter().position(|c| c == chateau);
+
+                    let keep_existing = match found.get(&trip_id) {
+                        Some(existing) => rank(existing) <= rank(&chateau),
+                        None => false,
+                    };
+
+                    if !keep_existing {
+                        found.insert(trip_id, chateau);
+                    }
+                }
+            }
+
+            let mut trip_owner_cache = self.trip_owner_cache.write().await;
+
+            for trip_id in unknown_trip_ids {
+                let owner = found.get(&trip_id).cloned();
+                trip_owner_cache.insert((feed_id.to_string(), trip_id), owner);
+            }
+        }
+
+        let trip_owner_cache = self.trip_owner_cache.read().await;
+
+        let trip_id_to_chateau = trip_ids
+            .into_iter()
+            .filter_map(|trip_id| {
+                trip_owner_cache
+                    .get(&(feed_id.to_string(), trip_id.clone()))
+                    .cloned()
+                    .flatten()
+                    .map(|chateau| (trip_id, chateau))
+            })
+            .collect::<AHashMap<String, String>>();
+
+        Ok(FanOutLookups {
+            route_id_to_agency_id,
+            trip_id_to_chateau,
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_to_chateau(
+    socket: std::net::SocketAddr,
+    chateau_id: String,
+    realtime_feed_id: String,
+    vehicles: Option<&FeedMessage>,
+    trips: Option<&FeedMessage>,
+    alerts: Option<&FeedMessage>,
+    has_vehicles: bool,
+    has_trips: bool,
+    has_alerts: bool,
+) {
+    let aspen_client = match catenary::aspen::lib::spawn_aspen_client_from_ip(&socket).await {
+        Ok(aspen_client) => aspen_client,
+        Err(e) => {
+            eprintln!("aspen connection error: {:#?}", e);
+            return;
+        }
+    };
+
+    let response_code = |data: Option<&FeedMessage>| data.map(|_| 200);
+
+    let tarpc_send_to_aspen = super::recording::send_and_record(
+        &aspen_client,
+        RecordedFetch {
+            chateau_id: chateau_id.clone(),
+            realtime_feed_id: realtime_feed_id.clone(),
+            vehicles: vehicles.map(|feed| feed.encode_to_vec()),
+            trips: trips.map(|feed| feed.encode_to_vec()),
+            alerts: alerts.map(|feed| feed.encode_to_vec()),
+            has_vehicles,
+            has_trips,
+            has_alerts,
+            vehicles_response_code: response_code(vehicles),
+            trips_response_code: response_code(trips),
+            alerts_response_code: response_code(alerts),
+            vehicle_consists: None,
+            supplementary: None,
+            fetched_at_ms: duration_since_unix_epoch().as_millis() as u64,
+        },
+    )
+    .await;
+
+    match tarpc_send_to_aspen {
+        Ok(_) => {
+            println!(
+                "feed {}|chateau {}: Successfully sent fanned out data to {}",
+                realtime_feed_id, chateau_id, socket
+            );
+        }
+        Err(e) => {
+            eprintln!(
+                "{}: Error sending fanned out data to {}: {}",
+                realtime_feed_id, socket, e
+            );
+        }
+    }
+}
+
+// Splits an aggregate feed by its fan out rules and sends every partition to the aspen worker
+// of the owning chateau. Entities no rule matched keep going to the chateau the aggregate
+// feed itself is assigned to, under the unpartitioned feed id.
+pub async fn send_fanned_out_data(
+    coordinator: &Coordinator,
+    fan_out: &FanOutContext,
+    feed_id: &str,
+    vehicles: Option<FeedMessage>,
+    trips: Option<FeedMessage>,
+    alerts: Option<FeedMessage>,
+) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+    let rules = match fan_out.rule_set.read().await.rules_by_feed.get(feed_id) {
+        Some(rules) => rules.clone(),
+        None => return Err(format!("{} has no fan out rules", feed_id).into()),
+    };
+
+    let feeds = [&vehicles, &trips, &alerts]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<&FeedMessage>>();
+
+    let lookups = fan_out.lookups_for_feeds(feed_id, &rules, &feeds).await?;
+
+    let partition = |feed: &Option<FeedMessage>| -> Option<PartitionedFeed> {
+        feed.as_ref()
+            .map(|feed| partition_feed_message(feed, &rules, &lookups))
+    };
+
+    let vehicles_partitioned = partition(&vehicles);
+    let trips_partitioned = partition(&trips);
+    let alerts_partitioned = partition(&alerts);
+
+    let chateaus = rules
+        .iter()
+        .map(|rule| rule.chateau().to_string())
+        .collect::<BTreeSet<String>>();
+
+    for chateau_id in chateaus.iter() {
+        let metadata = match get_node_for_chateau(coordinator, chateau_id).await {
+            Some(metadata) => metadata,
+            None => {
+                eprintln!(
+                    "{}: chateau {} is not assigned to an aspen worker",
+                    feed_id, chateau_id
+                );
+                continue;
+            }
+        };
+
+        let for_chateau = |partitioned: &Option<PartitionedFeed>| {
+            partitioned
+                .as_ref()
+                .and_then(|partitioned| partitioned.partitions.get(chateau_id))
+                .cloned()
+        };
+
+        let vehicles = for_chateau(&vehicles_partitioned);
+        let trips = for_chateau(&trips_partitioned);
+        let alerts = for_chateau(&alerts_partitioned);
+
+        send_to_chateau(
+            metadata.socket,
+            chateau_id.clone(),
+            partition_feed_id(feed_id, chateau_id),
+            vehicles.as_ref(),
+            trips.as_ref(),
+            alerts.as_ref(),
+            vehicles_partitioned.is_some(),
+            trips_partitioned.is_some(),
+            alerts_partitioned.is_some(),
+        )
+        .await;
+    }
+
+    match get_node_for_realtime_feed_id(coordinator, feed_id).await {
+        Some(data) => {
+            let unmatched = |partitioned: &Option<PartitionedFeed>| {
+                partitioned
+                    .as_ref()
+                    .map(|partitioned| partitioned.unmatched.clone())
+            };
+
+            let vehicles = unmatched(&vehicles_partitioned);
+            let trips = unmatched(&trips_partitioned);
+            let alerts = unmatched(&alerts_partitioned);
+
+            send_to_chateau(
+                data.socket,
+                data.chateau_id.clone(),
+                feed_id.to_string(),
+                vehicles.as_ref(),
+                trips.as_ref(),
+                alerts.as_ref(),
+                vehicles_partitioned.is_some(),
+                trips_partitioned.is_some(),
+                alerts_partitioned.is_some(),
+            )
+            .await;
+        }
+        None => {
+            eprintln!("{} was not assigned to a worker", feed_id);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn decode_feed(feed_id: &str, bytes: &[u8]) -> Option<FeedMessage> {
+    match FeedMessage::decode(bytes) {
+        Ok(feed) => Some(feed),
+        Err(e) => {
+            eprintln!("{}: could not decode feed for fan out: {}", feed_id, e);
+            None
+        }
+    }
+}
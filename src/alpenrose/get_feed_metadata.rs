@@ -1,9 +1,12 @@
-use catenary::agency_secret::KeyFormat;
-use catenary::agency_secret::PasswordFormat;
-use catenary::agency_secret::PasswordInfo;
+use catenary::agency_secret::envelope::SealedSecret;
+use catenary::agency_secret::keyring::master_keyring;
+use catenary::agency_secret::StoredPasswordFormat;
+use catenary::agency_secret::{KeyFormat, PasswordInfo};
 use catenary::postgres_tools::CatenaryPostgresPool;
 use diesel::query_dsl::methods::FilterDsl;
+use diesel::query_dsl::methods::FindDsl;
 use diesel::query_dsl::select_dsl::SelectDsl;
+use diesel::ExpressionMethods;
 use diesel::SelectableHelper;
 use diesel_async::RunQueryDsl;
 use dmfr_dataset_reader::read_folders;
@@ -19,7 +22,10 @@ pub struct RealtimeFeedFetch {
     pub realtime_trip_updates: Option<String>,
     pub realtime_alerts: Option<String>,
     pub key_formats: Vec<KeyFormat>,
-    pub passwords: Option<Vec<PasswordInfo>>,
+    // opened by the worker right before each request, never by the leader
+    pub sealed_passwords: Option<SealedSecret>,
+    // only for rows written before encryption while there is no master key to seal them with
+    pub plaintext_passwords: Option<Vec<PasswordInfo>>,
     pub fetch_interval_ms: Option<i32>,
 }
 
@@ -59,26 +65,63 @@ pub async fn get_feed_metadata(
 
     //format realtime passwords into HashMap
 
-    let mut realtime_passwords_hashmap: HashMap<String, PasswordFormat> = HashMap::new();
+    let mut realtime_passwords_hashmap: HashMap<String, StoredPasswordFormat> = HashMap::new();
 
     for realtime_password in realtime_passwords {
         let feed_id = realtime_password.onestop_feed_id.clone();
-        let password_raw_json = realtime_password.passwords.clone();
 
-        let password = password_raw_json.map(|password_format| {
-            serde_json::from_value::<PasswordFormat>(password_format).unwrap()
-        });
+        let password = match realtime_password
+            .passwords
+            .map(StoredPasswordFormat::from_value)
+            .transpose()
+        {
+            Ok(Some(password)) => password,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Credentials of {} could not be read: {}", feed_id, e);
+                continue;
+            }
+        };
 
-        if let Some(password) = password {
-            realtime_passwords_hashmap.insert(feed_id, password);
-        }
+        let stored = match password {
+            StoredPasswordFormat::Sealed(sealed) => StoredPasswordFormat::Sealed(sealed),
+            // written before encryption, seal it once and keep the sealed form
+            StoredPasswordFormat::Plaintext(plaintext) => match master_keyring() {
+                Some(keyring) => {
+                    let sealed = plaintext.seal(keyring, &feed_id)?;
+
+                    diesel::update(
+                        catenary::schema::gtfs::realtime_passwords::table.find(&feed_id),
+                    )
+                    .set(
+                        catenary::schema::gtfs::realtime_passwords::passwords.eq(Some(
+                            serde_json::to_value(StoredPasswordFormat::Sealed(sealed.clone()))?,
+                        )),
+                    )
+                    .execute(conn)
+                    .await?;
+
+                    StoredPasswordFormat::Sealed(sealed)
+                }
+                None => {
+                    eprintln!(
+                        "Credentials of {} are not sealed and there is no master key, using them as they are",
+                        feed_id
+                    );
+
+                    StoredPasswordFormat::Plaintext(plaintext)
+                }
+            },
+        };
+
+        realtime_passwords_hashmap.insert(feed_id, stored);
     }
 
     let mut realtime_feed_fetches: Vec<RealtimeFeedFetch> = Vec::new();
 
     for (feed_id, realtime_feed) in realtime_feeds_hashmap.iter() {
         let vehicles_url = match realtime_passwords_hashmap.get(feed_id) {
-            Some(password_format) => match &password_format.override_realtime_vehicle_positions {
+            Some(password_format) => match password_format.override_realtime_vehicle_positions() {
                 Some(url) => Some(url.to_string()),
                 None => realtime_feed
                     .realtime_vehicle_positions
@@ -92,7 +135,7 @@ pub async fn get_feed_metadata(
         };
 
         let trip_updates_url = match realtime_passwords_hashmap.get(feed_id) {
-            Some(password_format) => match &password_format.override_realtime_trip_updates {
+            Some(password_format) => match password_format.override_realtime_trip_updates() {
                 Some(url) => Some(url.to_string()),
                 None => realtime_feed
                     .realtime_trip_updates
@@ -106,7 +149,7 @@ pub async fn get_feed_metadata(
         };
 
         let alerts_url = match realtime_passwords_hashmap.get(feed_id) {
-            Some(password_format) => match &password_format.override_alerts {
+            Some(password_format) => match password_format.override_alerts() {
                 Some(url) => Some(url.to_string()),
                 None => realtime_feed
                     .realtime_alerts
@@ -125,12 +168,19 @@ pub async fn get_feed_metadata(
             realtime_trip_updates: trip_updates_url,
             realtime_alerts: alerts_url,
             key_formats: match realtime_passwords_hashmap.get(feed_id) {
-                Some(password_format) => password_format.key_formats.clone(),
+                Some(password_format) => password_format.key_formats().to_vec(),
                 None => vec![],
             },
-            passwords: realtime_passwords_hashmap
-                .get(feed_id)
-                .map(|password_format| password_format.passwords.clone()),
+            sealed_passwords: match realtime_passwords_hashmap.get(feed_id) {
+                Some(StoredPasswordFormat::Sealed(sealed)) => Some(sealed.sealed_passwords.clone()),
+                _ => None,
+            },
+            plaintext_passwords: match realtime_passwords_hashmap.get(feed_id) {
+                Some(StoredPasswordFormat::Plaintext(plaintext)) => {
+                    Some(plaintext.passwords.clone())
+                }
+                _ => None,
+            },
             fetch_interval_ms: match realtime_feeds_hashmap.get(feed_id) {
                 Some(realtime_feed) => realtime_feed.fetch_interval_ms,
                 None => None,
@@ -1,5 +1,6 @@
-use crate::get_feed_metadata::get_feed_metadata;
-use crate::RealtimeFeedFetch;
+use super::get_feed_metadata::get_feed_metadata;
+use super::RealtimeFeedFetch;
+use catenary::coordination::{Coordination, Coordinator};
 use catenary::fast_hash;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use dmfr_dataset_reader::read_folders;
@@ -9,7 +10,7 @@ use std::error::Error;
 use std::sync::Arc;
 
 pub async fn perform_leader_job(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     arc_conn_pool: Arc<CatenaryPostgresPool>,
     last_set_of_active_nodes_hash: &mut Option<u64>,
     last_updated_feeds_hash: &mut Option<u64>,
@@ -35,18 +36,14 @@ pub async fn perform_leader_job(
 
     //get list of worker nodes
 
-    let fetch_workers_hashmap = etcd
-        .get(
-            "/alpenrose_workers/",
-            Some(etcd_client::GetOptions::new().with_prefix()),
-        )
+    let fetch_workers_hashmap = coordinator
+        .get_prefix("/alpenrose_workers/")
         .await?
-        .take_kvs()
         .into_iter()
-        .map(|kv| {
+        .map(|(key, value)| {
             (
-                kv.key_str().unwrap().replace("/alpenrose_workers/", ""),
-                bincode::deserialize::<i64>(kv.value()).unwrap(),
+                key.replace("/alpenrose_workers/", ""),
+                bincode::deserialize::<i64>(&value).unwrap(),
             )
         })
         .collect::<HashMap<String, i64>>();
@@ -95,15 +92,14 @@ pub async fn perform_leader_job(
         let assignments = assignments;
 
         for (worker_id, instructions_hashmap) in assignments.iter() {
-            let lease_option = etcd_client::PutOptions::new()
-                .with_lease(*fetch_workers_hashmap.get(worker_id).unwrap());
+            let lease_option = *fetch_workers_hashmap.get(worker_id).unwrap();
 
             for (feed_id, realtime_instruction) in instructions_hashmap {
-                let set_assignment = etcd
+                let set_assignment = coordinator
                     .put(
                         format!("/alpenrose_assignments/{}/{}", worker_id, feed_id).as_str(),
                         bincode::serialize(&realtime_instruction).unwrap(),
-                        Some(lease_option.clone()),
+                        Some(lease_option),
                     )
                     .await;
 
@@ -113,7 +109,7 @@ pub async fn perform_leader_job(
             }
             //update the last updated time
 
-            let set_metadata_updated_time = etcd
+            let set_metadata_updated_time = coordinator
                 .put(
                     format!("/alpenrose_assignments_last_updated/{}", worker_id).as_str(),
                     bincode::serialize(&catenary::duration_since_unix_epoch().as_millis()).unwrap(),
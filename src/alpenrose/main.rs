@@ -35,6 +35,7 @@
 
 // https://en.wikipedia.org/wiki/Rhododendron_ferrugineum
 use catenary::agency_secret::*;
+use catenary::coordination::{Coordination, Coordinator};
 use catenary::postgres_tools::{make_async_pool, CatenaryPostgresPool};
 use dashmap::DashMap;
 use futures::prelude::*;
@@ -47,8 +48,10 @@ use std::time::Instant;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 mod custom_rt_feeds;
+mod fan_out;
 pub mod get_feed_metadata;
 mod leader_job;
+mod recording;
 use std::io;
 use zip::ZipArchive;
 mod single_fetch_time;
@@ -56,6 +59,12 @@ use get_feed_metadata::RealtimeFeedFetch;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let coordinator = Coordinator::from_env().await?;
+
+    run(coordinator).await
+}
+
+pub async fn run(coordinator: Coordinator) -> Result<(), Box<dyn Error + Sync + Send>> {
     let this_worker_id = Arc::new(Uuid::new_v4().to_string());
 
     let start = Instant::now();
@@ -88,6 +97,13 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     let conn_pool: CatenaryPostgresPool = make_async_pool().await?;
     let arc_conn_pool: Arc<CatenaryPostgresPool> = Arc::new(conn_pool);
 
+    tokio::spawn(catenary::metrics::serve_metrics(
+        catenary::metrics::metrics_addr_from_env(9101),
+    ));
+    tokio::spawn(catenary::metrics::keep_pool_metrics_fresh(Arc::clone(
+        &arc_conn_pool,
+    )));
+
     let conn_pool = arc_conn_pool.as_ref();
     let conn_pre = conn_pool.get().await;
     let conn = &mut conn_pre?;
@@ -99,6 +115,8 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
     let last_fetch_per_feed: Arc<DashMap<String, Instant>> = Arc::new(DashMap::new());
 
+    let fan_out = Arc::new(fan_out::FanOutContext::new(Arc::clone(&arc_conn_pool)));
+
     //make client for reqwest
     //allow various compression algorithms to be used during the download process, as enabled in Cargo.toml
     let client = reqwest::ClientBuilder::new()
@@ -113,38 +131,10 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
         .build()
         .unwrap();
 
-    let etcd_urls_original =
-        std::env::var("ETCD_URLS").unwrap_or_else(|_| "localhost:2379".to_string());
-    let etcd_urls = etcd_urls_original.split(',').collect::<Vec<&str>>();
-
-    let etcd_username = std::env::var("ETCD_USERNAME");
-
-    let etcd_password = std::env::var("ETCD_PASSWORD");
-
-    let etcd_connection_options: Option<etcd_client::ConnectOptions> =
-        match (etcd_username, etcd_password) {
-            (Ok(username), Ok(password)) => {
-                Some(etcd_client::ConnectOptions::new().with_user(username, password))
-            }
-            _ => None,
-        };
-
-    let arc_etcd_connection_options = Arc::new(etcd_connection_options.clone());
-
-    let mut etcd =
-        etcd_client::Client::connect(&etcd_urls, etcd_connection_options.clone()).await?;
-
-    println!("Connected to etcd");
-
     let etcd_lease_id: i64 = rand::thread_rng().random_range(0..i64::MAX);
 
-    let make_lease = etcd
-        .lease_grant(
-            //30 seconds
-            30,
-            Some(etcd_client::LeaseGrantOptions::new().with_id(etcd_lease_id)),
-        )
-        .await?;
+    //30 seconds
+    coordinator.grant_lease(etcd_lease_id, 30).await?;
 
     println!("etcd registered lease {}", etcd_lease_id);
 
@@ -198,62 +188,55 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
         if is_online {
             //renew the etcd lease
 
-            let _ = etcd.lease_keep_alive(etcd_lease_id).await?;
+            coordinator.keep_alive(etcd_lease_id).await?;
 
             // create this worker as an ephemeral node
 
-            let etcd_this_worker_assignment = etcd
+            coordinator
                 .put(
                     format!("/alpenrose_workers/{}", this_worker_id).as_str(),
                     bincode::serialize(&etcd_lease_id).unwrap(),
-                    Some(etcd_client::PutOptions::new().with_lease(etcd_lease_id)),
+                    Some(etcd_lease_id),
                 )
                 .await?;
 
             //each feed id ephemeral id contains the last time updated, with none meaning the data has not been assigned to the node yet
 
-            let mut election_client = etcd.election_client();
-
-            let current_leader_election = election_client.leader("/alpenrose_leader").await;
+            let current_leader_election = coordinator.leader("/alpenrose_leader").await;
 
             match current_leader_election {
-                Ok(current_leader_election) => {
-                    let leader_kv = current_leader_election.kv();
-
-                    match leader_kv {
-                        None => {
-                            let attempt_to_become_leader = election_client
-                                .campaign(
-                                    "/alpenrose_leader",
-                                    bincode::serialize(this_worker_id.as_ref()).unwrap(),
-                                    etcd_lease_id,
-                                )
-                                .await;
-
-                            println!("attempt_to_become_leader: {:#?}", attempt_to_become_leader);
-                        }
-                        Some(leader_kv) => {
-                            let leader_id: String =
-                                bincode::deserialize(leader_kv.value()).unwrap();
-
-                            if &leader_id == this_worker_id.as_ref() {
-                                // I AM THE LEADER!!!
-
-                                println!("I AM THE LEADER!!!");
-
-                                leader_job::perform_leader_job(
-                                    &mut etcd,
-                                    Arc::clone(&arc_conn_pool),
-                                    &mut last_set_of_active_nodes_hash,
-                                    &mut last_updated_feeds_hash,
-                                )
-                                .await?;
-                            }
+                Ok(leader_value) => match leader_value {
+                    None => {
+                        let attempt_to_become_leader = coordinator
+                            .campaign(
+                                "/alpenrose_leader",
+                                bincode::serialize(this_worker_id.as_ref()).unwrap(),
+                                etcd_lease_id,
+                            )
+                            .await;
+
+                        println!("attempt_to_become_leader: {:#?}", attempt_to_become_leader);
+                    }
+                    Some(leader_value) => {
+                        let leader_id: String = bincode::deserialize(&leader_value).unwrap();
+
+                        if &leader_id == this_worker_id.as_ref() {
+                            // I AM THE LEADER!!!
+
+                            println!("I AM THE LEADER!!!");
+
+                            leader_job::perform_leader_job(
+                                &coordinator,
+                                Arc::clone(&arc_conn_pool),
+                                &mut last_set_of_active_nodes_hash,
+                                &mut last_updated_feeds_hash,
+                            )
+                            .await?;
                         }
                     }
-                }
+                },
                 Err(leader_election_err) => {
-                    let attempt_to_become_leader = election_client
+                    let attempt_to_become_leader = coordinator
                         .campaign(
                             "/alpenrose_leader",
                             bincode::serialize(this_worker_id.as_ref()).unwrap(),
@@ -267,26 +250,23 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
                     //fetch again and see if leader
 
-                    let current_leader_election = election_client.leader("/alpenrose_leader").await;
+                    let current_leader_election = coordinator.leader("/alpenrose_leader").await;
 
-                    if let Ok(current_leader_resp) = current_leader_election {
-                        if let Some(leader_kv) = current_leader_resp.kv() {
-                            let leader_id: String =
-                                bincode::deserialize(leader_kv.value()).unwrap();
+                    if let Ok(Some(leader_value)) = current_leader_election {
+                        let leader_id: String = bincode::deserialize(&leader_value).unwrap();
 
-                            if &leader_id == this_worker_id.as_ref() {
-                                // I AM THE LEADER!!!
+                        if &leader_id == this_worker_id.as_ref() {
+                            // I AM THE LEADER!!!
 
-                                println!("I AM THE LEADER on first try!!!");
+                            println!("I AM THE LEADER on first try!!!");
 
-                                leader_job::perform_leader_job(
-                                    &mut etcd,
-                                    Arc::clone(&arc_conn_pool),
-                                    &mut last_set_of_active_nodes_hash,
-                                    &mut last_updated_feeds_hash,
-                                )
-                                .await?;
-                            }
+                            leader_job::perform_leader_job(
+                                &coordinator,
+                                Arc::clone(&arc_conn_pool),
+                                &mut last_set_of_active_nodes_hash,
+                                &mut last_updated_feeds_hash,
+                            )
+                            .await?;
                         }
                     }
                 }
@@ -294,19 +274,13 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
             //read from etcd to get the current assignments for this node
 
-            let fetch_last_updated_assignments_for_this_worker_resp = etcd
-                .get(
-                    format!("/alpenrose_assignments_last_updated/{}", this_worker_id),
-                    None,
-                )
+            let last_updated_worker_time = coordinator
+                .get(format!("/alpenrose_assignments_last_updated/{}", this_worker_id).as_str())
                 .await?;
 
-            let last_updated_worker_time_kv =
-                fetch_last_updated_assignments_for_this_worker_resp.kvs();
-
-            if let Some(last_updated_worker_time) = last_updated_worker_time_kv.first() {
+            if let Some(last_updated_worker_time) = last_updated_worker_time {
                 let last_updated_worker_time_value =
-                    bincode::deserialize::<u64>(last_updated_worker_time.value()).unwrap();
+                    bincode::deserialize::<u64>(&last_updated_worker_time).unwrap();
 
                 if Some(last_updated_worker_time_value)
                     != previously_known_updated_ms_for_this_worker
@@ -321,22 +295,14 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
                     let prefix_search = format!("/alpenrose_assignments/{}/", this_worker_id);
 
-                    let assignments = etcd
-                        .get(
-                            prefix_search.clone(),
-                            Some(etcd_client::GetOptions::new().with_prefix()),
-                        )
+                    let assignments = coordinator
+                        .get_prefix(prefix_search.as_str())
                         .await?
-                        .take_kvs()
                         .into_iter()
-                        .map(|each_kv| {
+                        .map(|(key, value)| {
                             (
-                                each_kv
-                                    .key_str()
-                                    .unwrap()
-                                    .to_string()
-                                    .replace(&prefix_search, ""),
-                                bincode::deserialize::<RealtimeFeedFetch>(each_kv.value()).unwrap(),
+                                key.replace(&prefix_search, ""),
+                                bincode::deserialize::<RealtimeFeedFetch>(&value).unwrap(),
                             )
                         })
                         .collect::<HashMap<String, RealtimeFeedFetch>>();
@@ -346,7 +312,11 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
             }
 
             //renew the lease
-            let _ = etcd.lease_keep_alive(etcd_lease_id).await?;
+            coordinator.keep_alive(etcd_lease_id).await?;
+
+            if let Err(e) = fan_out.refresh_if_stale().await {
+                eprintln!("Could not refresh realtime fan out rules: {}", e);
+            }
 
             //get the feed data from the feeds assigned to this worker
 
@@ -356,14 +326,14 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
                 Arc::clone(&last_fetch_per_feed),
                 Arc::clone(&amtrak_gtfs),
                 Arc::clone(&chicago_trips_str),
-                &etcd_urls,
-                &etcd_connection_options,
+                coordinator.clone(),
+                Arc::clone(&fan_out),
             )
             .await?;
         } else {
             //revoke the lease
 
-            let _ = etcd.lease_revoke(etcd_lease_id).await?;
+            coordinator.revoke_lease(etcd_lease_id).await?;
 
             //end the program
 
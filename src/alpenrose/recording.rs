@@ -0,0 +1,41 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+use catenary::aspen::lib::AspenRpcClient;
+use catenary::rt_recording::{send_to_aspen, FetchRecorder, RecordedFetch, RecordedResponse};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref RECORDER: Option<FetchRecorder> = FetchRecorder::from_env();
+}
+
+pub fn is_recording() -> bool {
+    RECORDER.is_some()
+}
+
+// Sends a fetch to aspen, saving it to the recording session first if one is enabled
+pub async fn send_and_record(
+    aspen_client: &AspenRpcClient,
+    fetch: RecordedFetch,
+) -> Result<bool, tarpc::client::RpcError> {
+    if let Some(recorder) = RECORDER.as_ref() {
+        if let Err(e) = recorder.record(&fetch).await {
+            eprintln!("{}: could not record fetch: {}", fetch.realtime_feed_id, e);
+        }
+    }
+
+    send_to_aspen(aspen_client, fetch).await
+}
+
+// Saves a response as it was received, whether or not it is sent on to aspen
+pub async fn record_response(response: RecordedResponse) {
+    if let Some(recorder) = RECORDER.as_ref() {
+        if let Err(e) = recorder.record_response(&response).await {
+            eprintln!(
+                "{}: could not record {} response: {}",
+                response.realtime_feed_id, response.url_type, e
+            );
+        }
+    }
+}
@@ -1,8 +1,14 @@
-use crate::KeyFormat;
-use crate::RealtimeFeedFetch;
+use super::RealtimeFeedFetch;
+use catenary::agency_secret::keyring::master_keyring;
+use catenary::agency_secret::{apply_password, open_passwords};
 use catenary::ahash_fast_hash;
+use catenary::coordination::Coordinator;
 use catenary::duration_since_unix_epoch;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::metrics::{
+    REALTIME_FETCH_DURATION, REALTIME_FETCH_RESPONSES, REALTIME_PAYLOAD_BYTES,
+};
+use catenary::rt_recording::{RecordedFetch, RecordedResponse};
 use dashmap::DashMap;
 use futures::StreamExt;
 use lazy_static::lazy_static;
@@ -16,7 +22,8 @@ use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::RwLock;
 
-use crate::custom_rt_feeds;
+use super::custom_rt_feeds;
+use super::fan_out::{decode_feed, send_fanned_out_data, FanOutContext};
 
 lazy_static! {
     static ref CUSTOM_FEEDS: HashSet<&'static str> = HashSet::from_iter([
@@ -32,49 +39,123 @@ lazy_static! {
     ]);
 }
 
-async fn cleanup_response(
-    response: Response,
-    urltype: UrlType,
+// a response with its body read, so it can be recorded whether or not it is sent on to aspen
+struct FetchedPayload {
+    http_status: Option<u16>,
+    body: Option<Vec<u8>>,
+    error: Option<String>,
+}
+
+async fn read_response(
     feed_id: &str,
-    hashes_of_data: Arc<SccHashMap<(String, UrlType), u64>>,
-) -> Option<Vec<u8>> {
-    match response.bytes().await {
-        Ok(bytes_pre) => {
-            let bytes = bytes_pre.as_ref().to_vec();
-
-            let hash = ahash_fast_hash(&bytes);
-
-            match hashes_of_data.get(&(feed_id.to_string(), urltype.clone())) {
-                Some(old_hash) => {
-                    let old_hash = old_hash.get();
-
-                    //if the data has not changed, don't send it
-                    match hash == *old_hash {
-                        true => None,
-                        false => {
-                            hashes_of_data
-                                .entry((feed_id.to_string(), urltype.clone()))
-                                .and_modify(|value| *value = hash)
-                                .or_insert(hash);
-                            Some(bytes)
-                        }
-                    }
+    urltype: UrlType,
+    response: Option<Result<Response, Box<dyn std::error::Error + Sync + Send>>>,
+) -> Option<FetchedPayload> {
+    match response? {
+        Ok(response) => {
+            let http_status = Some(response.status().as_u16());
+
+            match response.bytes().await {
+                Ok(bytes) => {
+                    REALTIME_PAYLOAD_BYTES
+                        .with_label_values(&[feed_id, urltype.as_str()])
+                        .observe(bytes.len() as f64);
+
+                    Some(FetchedPayload {
+                        http_status,
+                        body: Some(bytes.to_vec()),
+                        error: None,
+                    })
                 }
-                None => Some(bytes),
+                Err(e) => Some(FetchedPayload {
+                    http_status,
+                    body: None,
+                    error: Some(e.to_string()),
+                }),
             }
         }
-        Err(_) => None,
+        Err(e) => Some(FetchedPayload {
+            http_status: None,
+            body: None,
+            error: Some(e.to_string()),
+        }),
+    }
+}
+
+fn is_unchanged(
+    payload: &FetchedPayload,
+    urltype: &UrlType,
+    feed_id: &str,
+    hashes_of_data: &SccHashMap<(String, UrlType), u64>,
+) -> bool {
+    match (
+        &payload.body,
+        hashes_of_data.get(&(feed_id.to_string(), urltype.clone())),
+    ) {
+        (Some(body), Some(old_hash)) => ahash_fast_hash(body) == *old_hash.get(),
+        _ => false,
     }
 }
 
+fn changed_body(
+    payload: Option<FetchedPayload>,
+    urltype: UrlType,
+    feed_id: &str,
+    hashes_of_data: &SccHashMap<(String, UrlType), u64>,
+) -> Option<Vec<u8>> {
+    let payload = payload?;
+
+    //if the data has not changed, don't send it
+    if is_unchanged(&payload, &urltype, feed_id, hashes_of_data) {
+        return None;
+    }
+
+    let bytes = payload.body?;
+    let hash = ahash_fast_hash(&bytes);
+
+    hashes_of_data
+        .entry((feed_id.to_string(), urltype))
+        .and_modify(|value| *value = hash)
+        .or_insert(hash);
+
+    Some(bytes)
+}
+
+async fn record_payload(
+    feed_id: &str,
+    urltype: UrlType,
+    payload: &Option<FetchedPayload>,
+    hashes_of_data: &SccHashMap<(String, UrlType), u64>,
+    fetched_at_ms: u64,
+) {
+    if !super::recording::is_recording() {
+        return;
+    }
+
+    let Some(payload) = payload else {
+        return;
+    };
+
+    super::recording::record_response(RecordedResponse {
+        realtime_feed_id: feed_id.to_string(),
+        url_type: urltype.as_str().to_string(),
+        http_status: payload.http_status,
+        error: payload.error.clone(),
+        body: payload.body.clone(),
+        unchanged: is_unchanged(payload, &urltype, feed_id, hashes_of_data),
+        fetched_at_ms,
+    })
+    .await;
+}
+
 pub async fn single_fetch_time(
     client: reqwest::Client,
     assignments: Arc<RwLock<HashMap<String, RealtimeFeedFetch>>>,
     last_fetch_per_feed: Arc<DashMap<String, Instant>>,
     amtrak_gtfs: Arc<gtfs_structures::Gtfs>, //   etcd_client_addresses: Arc<RwLock<Vec<String>>>
     chicago_text_str: Arc<Option<String>>,
-    etcd_urls: &Vec<&str>,
-    etcd_connection_options: &Option<etcd_client::ConnectOptions>,
+    coordinator: Coordinator,
+    fan_out: Arc<FanOutContext>,
 ) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
     let start = Instant::now();
 
@@ -88,15 +169,12 @@ pub async fn single_fetch_time(
         let last_fetch_per_feed = last_fetch_per_feed.clone();
         let amtrak_gtfs = Arc::clone(&amtrak_gtfs);
         let chicago_text_str = chicago_text_str.clone();
-        let etcd_urls = etcd_urls.clone();
+        let coordinator = coordinator.clone();
+        let fan_out = Arc::clone(&fan_out);
 
         async move {
             let start = Instant::now();
 
-            let mut etcd = etcd_client::Client::connect(etcd_urls, etcd_connection_options.clone())
-                .await
-                .unwrap();
-
             let fetch_interval_ms = assignment.fetch_interval_ms.unwrap_or(1_000);
 
             if let Some(last_fetch) = last_fetch_per_feed.get(&feed_id.clone()) {
@@ -128,23 +206,57 @@ pub async fn single_fetch_time(
             let (vehicle_positions_data, trip_updates_data, alerts_data) =
                 futures::join!(vehicle_positions_future, trip_updates_future, alerts_future,);
 
+            let fetched_at_ms = duration_since_unix_epoch().as_millis() as u64;
+
+            let vehicle_positions_data =
+                read_response(feed_id, UrlType::VehiclePositions, vehicle_positions_data).await;
+            let trip_updates_data =
+                read_response(feed_id, UrlType::TripUpdates, trip_updates_data).await;
+            let alerts_data = read_response(feed_id, UrlType::Alerts, alerts_data).await;
+
+            record_response_status(feed_id, UrlType::VehiclePositions, &vehicle_positions_data);
+            record_response_status(feed_id, UrlType::TripUpdates, &trip_updates_data);
+            record_response_status(feed_id, UrlType::Alerts, &alerts_data);
+
+            //every response is recorded, including the ones that are rate limited or unchanged
+            record_payload(
+                feed_id,
+                UrlType::VehiclePositions,
+                &vehicle_positions_data,
+                &hashes_of_data,
+                fetched_at_ms,
+            )
+            .await;
+            record_payload(
+                feed_id,
+                UrlType::TripUpdates,
+                &trip_updates_data,
+                &hashes_of_data,
+                fetched_at_ms,
+            )
+            .await;
+            record_payload(
+                feed_id,
+                UrlType::Alerts,
+                &alerts_data,
+                &hashes_of_data,
+                fetched_at_ms,
+            )
+            .await;
+
             //send the data to aspen via tarpc
 
             if !CUSTOM_FEEDS.contains(feed_id.as_str()) {
-                let vehicle_positions_http_status = match &vehicle_positions_data {
-                    Some(Ok(response)) => Some(response.status().as_u16()),
-                    _ => None,
-                };
+                let vehicle_positions_http_status = vehicle_positions_data
+                    .as_ref()
+                    .and_then(|payload| payload.http_status);
 
-                let trip_updates_http_status = match &trip_updates_data {
-                    Some(Ok(response)) => Some(response.status().as_u16()),
-                    _ => None,
-                };
+                let trip_updates_http_status = trip_updates_data
+                    .as_ref()
+                    .and_then(|payload| payload.http_status);
 
-                let alerts_http_status = match &alerts_data {
-                    Some(Ok(response)) => Some(response.status().as_u16()),
-                    _ => None,
-                };
+                let alerts_http_status =
+                    alerts_data.as_ref().and_then(|payload| payload.http_status);
 
                 if (vehicle_positions_http_status == Some(429))
                     || (trip_updates_http_status == Some(429))
@@ -154,9 +266,30 @@ pub async fn single_fetch_time(
                     return;
                 }
 
+                if fan_out.has_rules(feed_id).await {
+                    let vehicles = fan_out_body(feed_id, vehicle_positions_data);
+                    let trips = fan_out_body(feed_id, trip_updates_data);
+                    let alerts = fan_out_body(feed_id, alerts_data);
+
+                    if let Err(e) = send_fanned_out_data(
+                        &coordinator,
+                        &fan_out,
+                        feed_id,
+                        vehicles,
+                        trips,
+                        alerts,
+                    )
+                    .await
+                    {
+                        eprintln!("{}: Error fanning out data: {}", feed_id, e);
+                    }
+
+                    return;
+                }
+
                 //lookup currently assigned realtime dataset in zookeeper
                 let fetch_assigned_node_meta =
-                    get_node_for_realtime_feed_id(&mut etcd, feed_id).await;
+                    get_node_for_realtime_feed_id(&coordinator, feed_id).await;
 
                 match fetch_assigned_node_meta {
                     Some(data) => {
@@ -177,56 +310,43 @@ pub async fn single_fetch_time(
                                     || trip_updates_http_status == Some(200)
                                     || alerts_http_status == Some(200)
                                 {
-                                    let tarpc_send_to_aspen = aspen_client
-                                        .from_alpenrose(
-                                            tarpc::context::current(),
-                                            data.chateau_id.clone(),
-                                            feed_id.clone(),
-                                            match vehicle_positions_data {
-                                                Some(Ok(response)) => {
-                                                    cleanup_response(
-                                                        response,
-                                                        UrlType::VehiclePositions,
-                                                        feed_id,
-                                                        Arc::clone(&hashes_of_data),
-                                                    )
-                                                    .await
-                                                }
-                                                _ => None,
-                                            },
-                                            match trip_updates_data {
-                                                Some(Ok(response)) => {
-                                                    cleanup_response(
-                                                        response,
-                                                        UrlType::TripUpdates,
-                                                        feed_id,
-                                                        Arc::clone(&hashes_of_data),
-                                                    )
-                                                    .await
-                                                }
-                                                _ => None,
-                                            },
-                                            match alerts_data {
-                                                Some(Ok(response)) => {
-                                                    cleanup_response(
-                                                        response,
-                                                        UrlType::Alerts,
-                                                        feed_id,
-                                                        Arc::clone(&hashes_of_data),
-                                                    )
-                                                    .await
-                                                }
-                                                _ => None,
-                                            },
-                                            assignment.realtime_vehicle_positions.is_some(),
-                                            assignment.realtime_trip_updates.is_some(),
-                                            assignment.realtime_alerts.is_some(),
-                                            vehicle_positions_http_status,
-                                            trip_updates_http_status,
-                                            alerts_http_status,
-                                            duration_since_unix_epoch().as_millis() as u64,
-                                        )
-                                        .await;
+                                    let tarpc_send_to_aspen = super::recording::send_and_record(
+                                        &aspen_client,
+                                        RecordedFetch {
+                                            chateau_id: data.chateau_id.clone(),
+                                            realtime_feed_id: feed_id.clone(),
+                                            vehicles: changed_body(
+                                                vehicle_positions_data,
+                                                UrlType::VehiclePositions,
+                                                feed_id,
+                                                &hashes_of_data,
+                                            ),
+                                            trips: changed_body(
+                                                trip_updates_data,
+                                                UrlType::TripUpdates,
+                                                feed_id,
+                                                &hashes_of_data,
+                                            ),
+                                            alerts: changed_body(
+                                                alerts_data,
+                                                UrlType::Alerts,
+                                                feed_id,
+                                                &hashes_of_data,
+                                            ),
+                                            has_vehicles: assignment
+                                                .realtime_vehicle_positions
+                                                .is_some(),
+                                            has_trips: assignment.realtime_trip_updates.is_some(),
+                                            has_alerts: assignment.realtime_alerts.is_some(),
+                                            vehicles_response_code: vehicle_positions_http_status,
+                                            trips_response_code: trip_updates_http_status,
+                                            alerts_response_code: alerts_http_status,
+                                            vehicle_consists: None,
+                                            supplementary: None,
+                                            fetched_at_ms,
+                                        },
+                                    )
+                                    .await;
 
                                     match tarpc_send_to_aspen {
                                         Ok(_) => {
@@ -259,7 +379,7 @@ pub async fn single_fetch_time(
                 match feed_id.as_str() {
                     "f-amtrak~rt" => {
                         custom_rt_feeds::amtrak::fetch_amtrak_data(
-                            &mut etcd,
+                            &coordinator,
                             feed_id,
                             &amtrak_gtfs,
                             &client,
@@ -267,29 +387,38 @@ pub async fn single_fetch_time(
                         .await;
                     }
                     "f-viarail~rt" => {
-                        custom_rt_feeds::viarail::fetch_via_data(&mut etcd, feed_id, &client).await;
+                        custom_rt_feeds::viarail::fetch_via_data(&coordinator, feed_id, &client)
+                            .await;
                     }
                     "f-mta~nyc~rt~lirr" => {
-                        custom_rt_feeds::mta::fetch_mta_lirr_data(&mut etcd, feed_id, &client)
+                        custom_rt_feeds::mta::fetch_mta_lirr_data(&coordinator, feed_id, &client)
                             .await;
                     }
                     "f-mta~nyc~rt~mnr" => {
                         custom_rt_feeds::mta::fetch_mta_metronorth_data(
-                            &mut etcd, feed_id, &client,
+                            &coordinator,
+                            feed_id,
+                            &client,
                         )
                         .await;
                     }
                     "f-metrolinktrains~extra~rt" => {
-                        custom_rt_feeds::metrolink_extra::fetch_data(&mut etcd, feed_id, &client)
+                        custom_rt_feeds::metrolink_extra::fetch_data(&coordinator, feed_id, &client)
                             .await
                     }
                     "f-bus~dft~gov~uk~rt" => {
-                        custom_rt_feeds::uk::fetch_dft_bus_data(&mut etcd, feed_id, &client).await;
+                        custom_rt_feeds::uk::fetch_dft_bus_data(
+                            &coordinator,
+                            feed_id,
+                            &client,
+                            &fan_out,
+                        )
+                        .await;
                     }
                     "f-dp3-cta~rt" => match chicago_text_str.as_ref() {
                         Some(chicago_text_str) => {
                             custom_rt_feeds::chicagotransit::fetch_chicago_data(
-                                &mut etcd,
+                                &coordinator,
                                 feed_id,
                                 &client,
                                 chicago_text_str.as_str(),
@@ -299,10 +428,11 @@ pub async fn single_fetch_time(
                         None => {}
                     },
                     "f-tlms~rt" => {
-                        custom_rt_feeds::tlms::fetch_tlms_data(&mut etcd, feed_id, &client).await;
+                        custom_rt_feeds::tlms::fetch_tlms_data(&coordinator, feed_id, &client)
+                            .await;
                     }
                     //    "f-uc~irvine~anteater~express~rt" => {
-                    //       custom_rt_feeds::uci::fetch_uci_data(&mut etcd, feed_id).await;
+                    //       custom_rt_feeds::uci::fetch_uci_data(&coordinator, feed_id).await;
                     //   }
                     _ => {}
                 }
@@ -311,6 +441,10 @@ pub async fn single_fetch_time(
             let duration = start.elapsed();
             let duration = duration.as_secs_f64();
             println!("{}: {:.2?}", feed_id, duration);
+
+            REALTIME_FETCH_DURATION
+                .with_label_values(&[feed_id])
+                .observe(duration);
         }
     }))
     .buffer_unordered(20)
@@ -320,6 +454,36 @@ pub async fn single_fetch_time(
     Ok(())
 }
 
+// aggregate feeds are always decoded in full, aspen discards unchanged partitions itself
+fn fan_out_body(
+    feed_id: &str,
+    payload: Option<FetchedPayload>,
+) -> Option<gtfs_realtime::FeedMessage> {
+    match payload {
+        Some(FetchedPayload {
+            http_status: Some(200),
+            body: Some(body),
+            ..
+        }) => decode_feed(feed_id, &body),
+        _ => None,
+    }
+}
+
+fn record_response_status(feed_id: &str, urltype: UrlType, payload: &Option<FetchedPayload>) {
+    let status = match payload {
+        Some(FetchedPayload {
+            http_status: Some(http_status),
+            ..
+        }) => http_status.to_string(),
+        Some(_) => String::from("error"),
+        None => return,
+    };
+
+    REALTIME_FETCH_RESPONSES
+        .with_label_values(&[feed_id, urltype.as_str(), status.as_str()])
+        .inc();
+}
+
 async fn run_optional_req(
     request: Option<reqwest::Request>,
     client: reqwest::Client,
@@ -337,6 +501,16 @@ pub enum UrlType {
     Alerts,
 }
 
+impl UrlType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UrlType::VehiclePositions => "vehicles",
+            UrlType::TripUpdates => "trips",
+            UrlType::Alerts => "alerts",
+        }
+    }
+}
+
 pub fn make_reqwest_for_url(
     url_type: UrlType,
     assignment: &RealtimeFeedFetch,
@@ -365,39 +539,51 @@ pub fn make_reqwest_for_url(
 
             let mut request = client.get(url);
 
-            if let Some(passwords) = &assignment.passwords {
-                //choose random account to use
-                if !passwords.is_empty() {
-                    let password_info = passwords.choose(&mut rand::rng());
-
-                    if let Some(password_info) = password_info {
-                        if password_info.password.len() == assignment.key_formats.len() {
-                            let mut url_parameter_seq: Vec<(String, String)> = vec![];
-                            for (key_index, key_format) in assignment.key_formats.iter().enumerate()
-                            {
-                                match key_format {
-                                    KeyFormat::Header(header) => {
-                                        request = request
-                                            .header(header, &password_info.password[key_index]);
-                                    }
-                                    KeyFormat::UrlQuery(query) => {
-                                        url_parameter_seq.push((
-                                            query.to_string(),
-                                            password_info.password[key_index].to_string(),
-                                        ));
-                                    }
-                                }
-                            }
+            let passwords = match (
+                &assignment.sealed_passwords,
+                &assignment.plaintext_passwords,
+            ) {
+                (Some(sealed_passwords), _) => {
+                    let keyring = match master_keyring() {
+                        Some(keyring) => keyring,
+                        None => {
+                            println!(
+                                "No master key to open credentials for feed_id: {}",
+                                assignment.feed_id
+                            );
+                            return None;
+                        }
+                    };
 
-                            request = request.query(&url_parameter_seq);
-                        } else {
+                    match open_passwords(sealed_passwords, keyring, &assignment.feed_id) {
+                        Ok(passwords) => Some(passwords),
+                        Err(e) => {
+                            println!(
+                                "Could not open credentials for feed_id: {}, {}",
+                                assignment.feed_id, e
+                            );
+                            return None;
+                        }
+                    }
+                }
+                (None, Some(plaintext_passwords)) => Some(plaintext_passwords.clone()),
+                (None, None) => None,
+            };
+
+            if let Some(passwords) = passwords {
+                //choose random account to use
+                if let Some(password_info) = passwords.choose(&mut rand::rng()) {
+                    request = match apply_password(request, &assignment.key_formats, password_info)
+                    {
+                        Some(request) => request,
+                        None => {
                             println!(
                                 "Password length does not match key format length for feed_id: {}",
                                 assignment.feed_id
                             );
                             return None;
                         }
-                    }
+                    };
                 }
             }
 
@@ -2,6 +2,7 @@ use catenary::aspen::lib::AspenWorkerMetadataEtcd;
 use catenary::aspen::lib::ChateauMetadataEtcd;
 use catenary::aspen::lib::ChateausLeaderHashMap;
 use catenary::aspen::lib::RealtimeFeedMetadataEtcd;
+use catenary::coordination::{Coordination, Coordinator};
 use catenary::postgres_tools::CatenaryPostgresPool;
 use catenary::ChateauDataNoGeometry;
 use diesel::query_dsl::select_dsl::SelectDsl;
@@ -13,7 +14,7 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 
 pub async fn assign_chateaus(
-    etcd: &mut etcd_client::Client,
+    coordinator: &Coordinator,
     arc_conn_pool: Arc<CatenaryPostgresPool>,
     workers_nodes: Arc<Mutex<Vec<String>>>,
     feeds_list: Arc<Mutex<Option<ChateausLeaderHashMap>>>,
@@ -80,20 +81,10 @@ pub async fn assign_chateaus(
         //make a hashmap of workers and their tailscale ips
         let mut workers_map = BTreeMap::new();
 
-        let mut fetch_workers_from_etcd_req = etcd
-            .get(
-                "/aspen_workers",
-                Some(etcd_client::GetOptions::new().with_prefix()),
-            )
-            .await?;
+        let fetch_workers_from_etcd = coordinator.get_prefix("/aspen_workers").await?;
 
-        let fetch_workers_from_etcd = fetch_workers_from_etcd_req.take_kvs();
-
-        let fetch_workers_revision_number =
-            fetch_workers_from_etcd_req.header().unwrap().revision();
-
-        for kv in fetch_workers_from_etcd {
-            let decoded_metadata = bincode::deserialize::<AspenWorkerMetadataEtcd>(kv.value());
+        for (_, value) in fetch_workers_from_etcd {
+            let decoded_metadata = bincode::deserialize::<AspenWorkerMetadataEtcd>(&value);
 
             if let Ok(decoded_metadata) = decoded_metadata {
                 workers_map.insert(decoded_metadata.worker_id.clone(), decoded_metadata.clone());
@@ -134,14 +125,11 @@ pub async fn assign_chateaus(
                         socket: worker_metadata.socket,
                     };
 
-                    let save_to_etcd = etcd
+                    coordinator
                         .put(
                             format!("/aspen_assigned_chateaus/{}", chateau_id).as_str(),
                             bincode::serialize(&assigned_chateau_data).unwrap(),
-                            Some(
-                                etcd_client::PutOptions::new()
-                                    .with_lease(worker_metadata.etcd_lease_id),
-                            ),
+                            Some(worker_metadata.etcd_lease_id),
                         )
                         .await?;
 
@@ -152,14 +140,12 @@ pub async fn assign_chateaus(
                             chateau_id: chateau_id.clone(),
                         };
 
-                        let save_to_etcd_realtime_data = etcd
+                        coordinator
                             .put(
-                                format!("/aspen_assigned_realtime_feed_ids/{}", realtime_feed_id),
+                                format!("/aspen_assigned_realtime_feed_ids/{}", realtime_feed_id)
+                                    .as_str(),
                                 bincode::serialize(&assigned_realtime_feed_data).unwrap(),
-                                Some(
-                                    etcd_client::PutOptions::new()
-                                        .with_lease(worker_metadata.etcd_lease_id),
-                                ),
+                                Some(worker_metadata.etcd_lease_id),
                             )
                             .await?;
                     }
@@ -174,7 +160,7 @@ pub async fn assign_chateaus(
 
             //compact history
 
-            etcd.compact(fetch_workers_revision_number, None).await?;
+            coordinator.compact_history().await?;
         }
     }
 
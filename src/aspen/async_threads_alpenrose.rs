@@ -1,9 +1,12 @@
 use catenary::aspen::lib::*;
 use catenary::aspen_dataset::GtfsRtType;
+use catenary::aspen_dataset::{SupplementaryRecord, VehicleConsistSupplement};
+use catenary::metrics::ASPEN_ALPENROSE_QUEUE_DEPTH;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use crossbeam::deque::{Injector, Steal};
 use gtfs_realtime::FeedMessage;
 use scc::HashMap as SccHashMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::sync::Arc;
@@ -11,12 +14,20 @@ use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio::task::JoinSet;
 
-use crate::import_alpenrose::new_rt_data;
+use super::crowding_observations::CrowdingObservationWriter;
+use super::import_alpenrose::new_rt_data;
+use super::platform_stops::StopCodeCache;
+use super::vehicle_history::VehicleHistoryWriter;
 
 pub async fn alpenrose_process_threads(
     alpenrose_to_process_queue: Arc<Injector<ProcessAlpenroseData>>,
     authoritative_gtfs_rt_store: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
     authoritative_data_store: Arc<SccHashMap<String, catenary::aspen_dataset::AspenisedData>>,
+    vehicle_consist_store: Arc<SccHashMap<String, HashMap<String, VehicleConsistSupplement>>>,
+    supplementary_data_store: Arc<SccHashMap<String, Vec<SupplementaryRecord>>>,
+    vehicle_history: Arc<VehicleHistoryWriter>,
+    crowding_observations: Arc<CrowdingObservationWriter>,
+    stop_code_cache: Arc<StopCodeCache>,
     conn_pool: Arc<CatenaryPostgresPool>,
     alpenrosethreadcount: usize,
     chateau_queue_list: Arc<Mutex<HashSet<String>>>,
@@ -27,6 +38,11 @@ pub async fn alpenrose_process_threads(
             let alpenrose_to_process_queue = Arc::clone(&alpenrose_to_process_queue);
             let authoritative_gtfs_rt_store = Arc::clone(&authoritative_gtfs_rt_store);
             let authoritative_data_store = Arc::clone(&authoritative_data_store);
+            let vehicle_consist_store = Arc::clone(&vehicle_consist_store);
+            let supplementary_data_store = Arc::clone(&supplementary_data_store);
+            let vehicle_history = Arc::clone(&vehicle_history);
+            let crowding_observations = Arc::clone(&crowding_observations);
+            let stop_code_cache = Arc::clone(&stop_code_cache);
             let conn_pool = Arc::clone(&conn_pool);
             let chateau_queue_list = Arc::clone(&chateau_queue_list);
             async move {
@@ -34,6 +50,11 @@ pub async fn alpenrose_process_threads(
                     alpenrose_to_process_queue,
                     authoritative_gtfs_rt_store,
                     authoritative_data_store,
+                    vehicle_consist_store,
+                    supplementary_data_store,
+                    vehicle_history,
+                    crowding_observations,
+                    stop_code_cache,
                     conn_pool,
                     chateau_queue_list,
                 )
@@ -53,12 +74,19 @@ pub async fn alpenrose_loop_process_thread(
     alpenrose_to_process_queue: Arc<Injector<ProcessAlpenroseData>>,
     authoritative_gtfs_rt_store: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
     authoritative_data_store: Arc<SccHashMap<String, catenary::aspen_dataset::AspenisedData>>,
+    vehicle_consist_store: Arc<SccHashMap<String, HashMap<String, VehicleConsistSupplement>>>,
+    supplementary_data_store: Arc<SccHashMap<String, Vec<SupplementaryRecord>>>,
+    vehicle_history: Arc<VehicleHistoryWriter>,
+    crowding_observations: Arc<CrowdingObservationWriter>,
+    stop_code_cache: Arc<StopCodeCache>,
     conn_pool: Arc<CatenaryPostgresPool>,
     chateau_queue_list: Arc<Mutex<HashSet<String>>>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     loop {
         // println!("From-Alpenrose process thread");
         if let Steal::Success(new_ingest_task) = alpenrose_to_process_queue.steal() {
+            ASPEN_ALPENROSE_QUEUE_DEPTH.set(alpenrose_to_process_queue.len() as i64);
+
             let feed_id = new_ingest_task.realtime_feed_id.clone();
 
             let mut chateau_queue_list = chateau_queue_list.lock().await;
@@ -70,6 +98,11 @@ pub async fn alpenrose_loop_process_thread(
             let rt_processed_status = new_rt_data(
                 Arc::clone(&authoritative_data_store),
                 Arc::clone(&authoritative_gtfs_rt_store),
+                Arc::clone(&vehicle_consist_store),
+                Arc::clone(&supplementary_data_store),
+                Arc::clone(&vehicle_history),
+                Arc::clone(&crowding_observations),
+                Arc::clone(&stop_code_cache),
                 new_ingest_task.chateau_id,
                 new_ingest_task.realtime_feed_id,
                 new_ingest_task.has_vehicles,
@@ -0,0 +1,406 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// When a vehicle is running late on one trip, the next trip on the same block can't leave until
+// the vehicle gets there. Feeds usually only publish updates for the trip in progress, so we
+// predict the start delay of the following trips ourselves, minus whatever layover is scheduled.
+
+use ahash::{AHashMap, AHashSet};
+use catenary::aspen_dataset::*;
+use catenary::models::{CompressedTrip, ItineraryPatternMeta};
+use compact_str::CompactString;
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use std::str::FromStr;
+
+// don't bother predicting anything for vehicles that are barely late
+const MIN_DELAY_TO_CARRY_SECONDS: i32 = 60;
+
+// scheduled offsets for one itinerary pattern, in stop order
+struct PatternSchedule {
+    // (gtfs_stop_sequence, stop_id, arrival offset, departure offset)
+    stops: Vec<(u32, CompactString, Option<i32>, Option<i32>)>,
+}
+
+impl PatternSchedule {
+    fn first_departure_offset(&self) -> i32 {
+        self.stops
+            .iter()
+            .find_map(|(_, _, arrival, departure)| departure.or(*arrival))
+            .unwrap_or(0)
+    }
+
+    fn last_arrival_offset(&self) -> i32 {
+        self.stops
+            .iter()
+            .rev()
+            .find_map(|(_, _, arrival, departure)| arrival.or(*departure))
+            .unwrap_or(0)
+    }
+}
+
+// midnight of the service date, per GTFS this is noon minus 12 hours
+fn service_date_reference(service_date: chrono::NaiveDate, timezone: chrono_tz::Tz) -> Option<i64> {
+    use chrono::TimeZone;
+
+    let noon = timezone
+        .from_local_datetime(&service_date.and_hms_opt(12, 0, 0)?)
+        .single()?;
+
+    Some(noon.timestamp() - 43200)
+}
+
+// the delay the vehicle is carrying at the furthest stop the feed tells us about
+fn latest_known_delay(
+    trip_update: &AspenisedTripUpdate,
+    schedule: &PatternSchedule,
+    trip_start_reference: i64,
+) -> Option<i32> {
+    for stu in trip_update.stop_time_update.iter().rev() {
+        let scheduled_stop = schedule
+            .stops
+            .iter()
+            .find(
+                |(gtfs_stop_sequence, stop_id, _, _)| match stu.stop_sequence {
+                    Some(stop_sequence) => stop_sequence == *gtfs_stop_sequence,
+                    None => stu.stop_id.as_ref() == Some(stop_id),
+                },
+            );
+
+        for (event, scheduled_offset) in [
+            (
+                &stu.arrival,
+                scheduled_stop.and_then(|(_, _, arrival, departure)| arrival.or(*departure)),
+            ),
+            (
+                &stu.departure,
+                scheduled_stop.and_then(|(_, _, arrival, departure)| departure.or(*arrival)),
+            ),
+        ] {
+            if let Some(event) = event {
+                if let Some(delay) = event.delay {
+                    return Some(delay);
+                }
+
+                if let (Some(time), Some(scheduled_offset)) = (event.time, scheduled_offset) {
+                    return Some((time - (trip_start_reference + scheduled_offset as i64)) as i32);
+                }
+            }
+        }
+    }
+
+    trip_update.delay
+}
+
+/// Makes trip updates for trips later on the same block as a late running trip.
+/// Trips that already have their own realtime data are left alone, and the chain stops there.
+pub async fn predict_block_delays(
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    chateau_id: &str,
+    trip_updates: &AHashMap<CompactString, AspenisedTripUpdate>,
+    trip_id_to_trip: &AHashMap<String, CompressedTrip>,
+    itinerary_pattern_metas: &AHashMap<String, ItineraryPatternMeta>,
+) -> Result<Vec<(CompactString, AspenisedTripUpdate)>, Box<dyn std::error::Error + Send + Sync>> {
+    // trip_id -> start dates that already have realtime data (None if the feed doesn't say)
+    let mut trips_with_realtime: AHashMap<String, AHashSet<Option<String>>> = AHashMap::new();
+
+    let mut block_ids_to_lookup: AHashSet<String> = AHashSet::new();
+
+    for trip_update in trip_updates
+        .values()
+        .filter(|trip_update| trip_update.block_delay_prediction.is_none())
+    {
+        if let Some(trip_id) = &trip_update.trip.trip_id {
+            trips_with_realtime
+                .entry(trip_id.clone())
+                .or_default()
+                .insert(trip_update.trip.start_date.clone());
+
+            if let Some(block_id) = trip_id_to_trip
+                .get(trip_id)
+                .and_then(|trip| trip.block_id.as_ref())
+            {
+                block_ids_to_lookup.insert(block_id.clone());
+            }
+        }
+    }
+
+    if block_ids_to_lookup.is_empty() {
+        return Ok(vec![]);
+    }
+
+    use catenary::schema::gtfs::trips_compressed as trips_compressed_pg_schema;
+
+    let block_trips = trips_compressed_pg_schema::dsl::trips_compressed
+        .filter(trips_compressed_pg_schema::dsl::chateau.eq(chateau_id))
+        .filter(trips_compressed_pg_schema::dsl::block_id.eq_any(&block_ids_to_lookup))
+        .filter(trips_compressed_pg_schema::dsl::has_frequencies.eq(false))
+        .select(CompressedTrip::as_select())
+        .load::<CompressedTrip>(conn)
+        .await?;
+
+    let mut block_id_to_trips: AHashMap<String, Vec<CompressedTrip>> = AHashMap::new();
+
+    for trip in block_trips {
+        if let Some(block_id) = &trip.block_id {
+            block_id_to_trips
+                .entry(block_id.clone())
+                .or_default()
+                .push(trip);
+        }
+    }
+
+    let itinerary_patterns_to_lookup = block_id_to_trips
+        .values()
+        .flatten()
+        .map(|trip| trip.itinerary_pattern_id.clone())
+        .collect::<AHashSet<String>>();
+
+    use catenary::schema::gtfs::itinerary_pattern as itinerary_pattern_pg_schema;
+
+    let itinerary_rows = itinerary_pattern_pg_schema::dsl::itinerary_pattern
+        .filter(itinerary_pattern_pg_schema::dsl::chateau.eq(chateau_id))
+        .filter(
+            itinerary_pattern_pg_schema::dsl::itinerary_pattern_id
+                .eq_any(&itinerary_patterns_to_lookup),
+        )
+        .order(itinerary_pattern_pg_schema::dsl::stop_sequence.asc())
+        .select(catenary::models::ItineraryPatternRow::as_select())
+        .load::<catenary::models::ItineraryPatternRow>(conn)
+        .await?;
+
+    let mut pattern_schedules: AHashMap<String, PatternSchedule> = AHashMap::new();
+
+    for row in itinerary_rows {
+        pattern_schedules
+            .entry(row.itinerary_pattern_id.clone())
+            .or_insert_with(|| PatternSchedule { stops: vec![] })
+            .stops
+            .push((
+                row.gtfs_stop_sequence,
+                row.stop_id.clone(),
+                row.arrival_time_since_start,
+                row.departure_time_since_start,
+            ));
+    }
+
+    let service_ids_to_lookup = block_id_to_trips
+        .values()
+        .flatten()
+        .map(|trip| trip.service_id.to_string())
+        .collect::<AHashSet<String>>();
+
+    let calendars = catenary::schema::gtfs::calendar::dsl::calendar
+        .filter(catenary::schema::gtfs::calendar::dsl::chateau.eq(chateau_id))
+        .filter(catenary::schema::gtfs::calendar::dsl::service_id.eq_any(&service_ids_to_lookup))
+        .select(catenary::models::Calendar::as_select())
+        .load::<catenary::models::Calendar>(conn)
+        .await?;
+
+    let calendar_dates = catenary::schema::gtfs::calendar_dates::dsl::calendar_dates
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::chateau.eq(chateau_id))
+        .filter(
+            catenary::schema::gtfs::calendar_dates::dsl::service_id.eq_any(&service_ids_to_lookup),
+        )
+        .select(catenary::models::CalendarDate::as_select())
+        .load::<catenary::models::CalendarDate>(conn)
+        .await?;
+
+    let calendar_structure =
+        catenary::make_calendar_structure_from_pg_single_chateau(calendars, calendar_dates);
+
+    let mut predictions: Vec<(CompactString, AspenisedTripUpdate)> = vec![];
+    let mut already_predicted: AHashSet<(String, chrono::NaiveDate)> = AHashSet::new();
+
+    for trip_update in trip_updates
+        .values()
+        .filter(|trip_update| trip_update.block_delay_prediction.is_none())
+    {
+        // cancelled trips don't hand a vehicle to anyone
+        if trip_update.trip.schedule_relationship == Some(3) {
+            continue;
+        }
+
+        let trip_id = match &trip_update.trip.trip_id {
+            Some(trip_id) => trip_id,
+            None => continue,
+        };
+
+        let source_trip = match trip_id_to_trip.get(trip_id) {
+            Some(source_trip) => source_trip,
+            None => continue,
+        };
+
+        let block_trips = match source_trip
+            .block_id
+            .as_ref()
+            .and_then(|block_id| block_id_to_trips.get(block_id))
+        {
+            Some(block_trips) => block_trips,
+            None => continue,
+        };
+
+        let timezone = match itinerary_pattern_metas
+            .get(&source_trip.itinerary_pattern_id)
+            .and_then(|meta| chrono_tz::Tz::from_str(&meta.timezone).ok())
+        {
+            Some(timezone) => timezone,
+            None => continue,
+        };
+
+        let service_date = match &trip_update.trip.start_date {
+            Some(start_date) => match chrono::NaiveDate::parse_from_str(start_date, "%Y%m%d") {
+                Ok(service_date) => service_date,
+                Err(_) => continue,
+            },
+            // trips after midnight belong to yesterday's service, but without a start date there
+            // is no telling, so only handle the common case
+            None => chrono::Utc::now().with_timezone(&timezone).date_naive(),
+        };
+
+        let reference = match service_date_reference(service_date, timezone) {
+            Some(reference) => reference,
+            None => continue,
+        };
+
+        let source_schedule = match pattern_schedules.get(&source_trip.itinerary_pattern_id) {
+            Some(source_schedule) => source_schedule,
+            None => continue,
+        };
+
+        let source_delay = match latest_known_delay(
+            trip_update,
+            source_schedule,
+            reference + source_trip.start_time as i64,
+        ) {
+            Some(source_delay) => source_delay,
+            None => continue,
+        };
+
+        if source_delay < MIN_DELAY_TO_CARRY_SECONDS {
+            continue;
+        }
+
+        let source_end = source_trip.start_time as i32 + source_schedule.last_arrival_offset();
+
+        let mut following_trips = block_trips
+            .iter()
+            .filter(|trip| trip.trip_id != source_trip.trip_id)
+            .filter(|trip| {
+                calendar_structure
+                    .get(trip.service_id.as_str())
+                    .map(|service| catenary::datetime_in_service(service, service_date))
+                    .unwrap_or(false)
+            })
+            .filter_map(|trip| {
+                let schedule = pattern_schedules.get(&trip.itinerary_pattern_id)?;
+
+                let start = trip.start_time as i32 + schedule.first_departure_offset();
+                let end = trip.start_time as i32 + schedule.last_arrival_offset();
+
+                match start >= source_end {
+                    true => Some((trip, schedule, start, end)),
+                    false => None,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        following_trips.sort_by_key(|(_, _, start, _)| *start);
+
+        let mut carried_delay = source_delay;
+        let mut previous_end = source_end;
+
+        for (trip, schedule, start, end) in following_trips {
+            let layover_seconds = start - previous_end;
+            let predicted_delay = carried_delay - layover_seconds;
+
+            if predicted_delay < MIN_DELAY_TO_CARRY_SECONDS {
+                break;
+            }
+
+            // real data for the next trip always wins over our guess
+            let has_own_realtime = trips_with_realtime
+                .get(&trip.trip_id)
+                .map(|start_dates| {
+                    start_dates.contains(&None)
+                        || start_dates.contains(&Some(service_date.format("%Y%m%d").to_string()))
+                })
+                .unwrap_or(false);
+
+            if has_own_realtime {
+                break;
+            }
+
+            if !already_predicted.insert((trip.trip_id.clone(), service_date)) {
+                break;
+            }
+
+            let trip_reference = reference + trip.start_time as i64;
+
+            let predicted_event = |offset: Option<i32>| {
+                offset.map(|offset| AspenStopTimeEvent {
+                    delay: Some(predicted_delay),
+                    time: Some(trip_reference + offset as i64 + predicted_delay as i64),
+                    uncertainty: None,
+                })
+            };
+
+            let predicted_update = AspenisedTripUpdate {
+                trip: AspenRawTripInfo {
+                    trip_id: Some(trip.trip_id.clone()),
+                    route_id: Some(trip.route_id.clone()),
+                    direction_id: trip.direction_id.map(|direction_id| direction_id as u32),
+                    start_time: None,
+                    start_date: Some(service_date.format("%Y%m%d").to_string()),
+                    schedule_relationship: None,
+                    modified_trip: None,
+                },
+                vehicle: trip_update.vehicle.clone(),
+                timestamp: trip_update.timestamp,
+                delay: Some(predicted_delay),
+                stop_time_update: schedule
+                    .stops
+                    .iter()
+                    .map(|(gtfs_stop_sequence, stop_id, arrival, departure)| {
+                        AspenisedStopTimeUpdate {
+                            stop_sequence: Some(*gtfs_stop_sequence),
+                            stop_id: Some(stop_id.clone()),
+                            arrival: predicted_event(*arrival),
+                            departure: predicted_event(*departure),
+                            departure_occupancy_status: None,
+                            schedule_relationship: None,
+                            stop_time_properties: None,
+                            platform_string: None,
+                            platform_assignment: None,
+                        }
+                    })
+                    .collect(),
+                trip_properties: None,
+                trip_headsign: None,
+                block_delay_prediction: Some(BlockDelayPrediction {
+                    from_trip_id: source_trip.trip_id.clone(),
+                    from_trip_delay: source_delay,
+                    layover_seconds,
+                }),
+            };
+
+            predictions.push((
+                CompactString::from(format!(
+                    "block_prediction_{}_{}",
+                    trip.trip_id,
+                    service_date.format("%Y%m%d")
+                )),
+                predicted_update,
+            ));
+
+            // assume the vehicle doesn't make up any time on the trip itself
+            carried_delay = predicted_delay;
+            previous_end = end;
+        }
+    }
+
+    Ok(predictions)
+}
@@ -0,0 +1,335 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Adds the occupancy reported by vehicles to the crowding profiles in postgres.
+// A vehicle is counted once per stop, the first time it is seen after departing it,
+// so vehicles sitting at a terminus do not outweigh everything else.
+// Rows are written through a QueuedWriter.
+
+use ahash::AHashMap;
+use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::crowding::{
+    direction_key, time_of_week_bucket, valid_occupancy_percentage, valid_occupancy_status,
+    CrowdingProfileKey,
+};
+use catenary::models::{CompressedTrip, CrowdingProfileRow, ItineraryPatternMeta};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::crowding_profiles;
+use compact_str::CompactString;
+use diesel::dsl::sql;
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use diesel_async::RunQueryDsl;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::queued_writer::QueuedWriter;
+
+fn observations_to_record(
+    chateau_id: &str,
+    vehicle_positions: &AHashMap<String, AspenisedVehiclePosition>,
+    trip_id_to_trip: &AHashMap<String, CompressedTrip>,
+    itinerary_pattern_metas: &AHashMap<String, ItineraryPatternMeta>,
+    last_counted_stop: &mut AHashMap<String, (String, CompactString)>,
+    now_ms: i64,
+) -> Vec<CrowdingProfileRow> {
+    // several vehicles can leave the same stop in one batch, postgres refuses to upsert a row twice
+    let mut rows: AHashMap<CrowdingProfileKey, CrowdingProfileRow> = AHashMap::new();
+    let mut still_present: AHashMap<String, (String, CompactString)> = AHashMap::new();
+
+    for (entity_id, vehicle_position) in vehicle_positions {
+        let occupancy_status = valid_occupancy_status(vehicle_position.occupancy_status);
+        let occupancy_percentage = valid_occupancy_percentage(
+            vehicle_position
+                .occupancy_percentage
+                .and_then(|percentage| i32::try_from(percentage).ok()),
+        );
+
+        if occupancy_status.is_none() && occupancy_percentage.is_none() {
+            continue;
+        }
+
+        let Some(stop_id) = vehicle_position
+            .trip_progress
+            .as_ref()
+            .and_then(|trip_progress| trip_progress.previous_stop_id.clone())
+        else {
+            continue;
+        };
+
+        let Some(trip) = vehicle_position
+            .trip
+            .as_ref()
+            .and_then(|trip| trip.trip_id.as_ref())
+            .and_then(|trip_id| trip_id_to_trip.get(trip_id))
+        else {
+            continue;
+        };
+
+        let Some(timezone) = itinerary_pattern_metas
+            .get(&trip.itinerary_pattern_id)
+            .and_then(|meta| chrono_tz::Tz::from_str(&meta.timezone).ok())
+        else {
+            continue;
+        };
+
+        let vehicle_id = vehicle_position
+            .vehicle
+            .as_ref()
+            .and_then(|vehicle| vehicle.id.clone())
+            .unwrap_or_else(|| entity_id.clone());
+
+        let counted = (trip.trip_id.clone(), stop_id.clone());
+
+        if last_counted_stop.get(&vehicle_id) == Some(&counted) {
+            still_present.insert(vehicle_id, counted);
+            continue;
+        }
+
+        still_present.insert(vehicle_id, counted);
+
+        let observed_ms = vehicle_position
+            .timestamp
+            .map(|timestamp| timestamp as i64 * 1000)
+            .unwrap_or(now_ms);
+
+        let Some(local_time) = chrono::DateTime::from_timestamp_millis(observed_ms)
+            .map(|time| time.with_timezone(&timezone))
+        else {
+            continue;
+        };
+
+        let key = (
+            trip.route_id.clone(),
+            direction_key(trip.direction_id),
+            stop_id.to_string(),
+            time_of_week_bucket(&local_time),
+        );
+
+        let row = rows
+            .entry(key)
+            .or_insert_with_key(|key| CrowdingProfileRow {
+                chateau: chateau_id.to_string(),
+                route_id: key.0.clone(),
+                direction_id: key.1,
+                stop_id: key.2.clone(),
+                time_of_week_bucket: key.3,
+                occupancy_status_sum: 0,
+                occupancy_status_count: 0,
+                occupancy_percentage_sum: 0,
+                occupancy_percentage_count: 0,
+                last_observed_ms: observed_ms,
+            });
+
+        if let Some(occupancy_status) = occupancy_status {
+            row.occupancy_status_sum += occupancy_status as i64;
+            row.occupancy_status_count += 1;
+        }
+
+        if let Some(occupancy_percentage) = occupancy_percentage {
+            row.occupancy_percentage_sum += occupancy_percentage as i64;
+            row.occupancy_percentage_count += 1;
+        }
+
+        row.last_observed_ms = row.last_observed_ms.max(observed_ms);
+    }
+
+    *last_counted_stop = still_present;
+
+    rows.into_values().collect()
+}
+
+pub struct CrowdingObservationWriter {
+    // vehicle id -> (trip_id, stop_id) last counted
+    writer: QueuedWriter<(String, CompactString), CrowdingProfileRow>,
+}
+
+impl CrowdingObservationWriter {
+    // starts the task that adds queued observations to the profiles in postgres
+    pub fn spawn(pool: Arc<CatenaryPostgresPool>) -> Arc<CrowdingObservationWriter> {
+        Arc::new(CrowdingObservationWriter {
+            writer: QueuedWriter::spawn("crowding observations", pool, write_rows),
+        })
+    }
+
+    pub fn record(
+        &self,
+        chateau_id: &str,
+        vehicle_positions: &AHashMap<String, AspenisedVehiclePosition>,
+        trip_id_to_trip: &AHashMap<String, CompressedTrip>,
+        itinerary_pattern_metas: &AHashMap<String, ItineraryPatternMeta>,
+    ) {
+        let now_ms = catenary::duration_since_unix_epoch().as_millis() as i64;
+
+        self.writer.record(chateau_id, |last_counted_stop| {
+            observations_to_record(
+                chateau_id,
+                vehicle_positions,
+                trip_id_to_trip,
+                itinerary_pattern_metas,
+                last_counted_stop,
+                now_ms,
+            )
+        });
+    }
+}
+
+async fn write_rows(
+    pool: Arc<CatenaryPostgresPool>,
+    rows: Vec<CrowdingProfileRow>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn = &mut pool.get().await?;
+
+    for chunk in rows.chunks(5000) {
+        diesel::insert_into(crowding_profiles::dsl::crowding_profiles)
+            .values(chunk)
+            .on_conflict((
+                crowding_profiles::dsl::chateau,
+                crowding_profiles::dsl::route_id,
+                crowding_profiles::dsl::direction_id,
+                crowding_profiles::dsl::stop_id,
+                crowding_profiles::dsl::time_of_week_bucket,
+            ))
+            .do_update()
+            .set((
+                crowding_profiles::dsl::occupancy_status_sum
+                    .eq(crowding_profiles::dsl::occupancy_status_sum
+                        + excluded(crowding_profiles::dsl::occupancy_status_sum)),
+                crowding_profiles::dsl::occupancy_status_count
+                    .eq(crowding_profiles::dsl::occupancy_status_count
+                        + excluded(crowding_profiles::dsl::occupancy_status_count)),
+                crowding_profiles::dsl::occupancy_percentage_sum
+                    .eq(crowding_profiles::dsl::occupancy_percentage_sum
+                        + excluded(crowding_profiles::dsl::occupancy_percentage_sum)),
+                crowding_profiles::dsl::occupancy_percentage_count
+                    .eq(crowding_profiles::dsl::occupancy_percentage_count
+                        + excluded(crowding_profiles::dsl::occupancy_percentage_count)),
+                // vehicle timestamps can be older than what an earlier batch already saw
+                crowding_profiles::dsl::last_observed_ms.eq(sql::<BigInt>(
+                    "GREATEST(crowding_profiles.last_observed_ms, excluded.last_observed_ms)",
+                )),
+            ))
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_vehicles;
+    use catenary::aspen_dataset::{AspenisedTripProgress, AspenisedVehicleTripInfo};
+
+    fn trip() -> CompressedTrip {
+        CompressedTrip {
+            onestop_feed_id: String::from("f-9q5-metro~losangeles"),
+            trip_id: String::from("720-1"),
+            attempt_id: String::from("attempt"),
+            service_id: CompactString::from("weekday"),
+            trip_short_name: None,
+            direction_id: Some(false),
+            block_id: None,
+            wheelchair_accessible: 0,
+            bikes_allowed: 0,
+            chateau: String::from("metro~losangeles"),
+            frequencies: None,
+            has_frequencies: false,
+            itinerary_pattern_id: String::from("pattern"),
+            route_id: String::from("720"),
+            start_time: 0,
+        }
+    }
+
+    fn meta() -> ItineraryPatternMeta {
+        ItineraryPatternMeta {
+            onestop_feed_id: String::from("f-9q5-metro~losangeles"),
+            attempt_id: String::from("attempt"),
+            trip_ids: vec![],
+            itinerary_pattern_id: String::from("pattern"),
+            chateau: String::from("metro~losangeles"),
+            trip_headsign: None,
+            trip_headsign_translations: None,
+            shape_id: None,
+            timezone: String::from("America/Los_Angeles"),
+            route_id: CompactString::from("720"),
+            direction_pattern_id: None,
+        }
+    }
+
+    fn vehicle(
+        id: &str,
+        previous_stop_id: &str,
+        occupancy_status: i32,
+    ) -> AspenisedVehiclePosition {
+        AspenisedVehiclePosition {
+            trip: Some(AspenisedVehicleTripInfo {
+                trip_id: Some(String::from("720-1")),
+                trip_headsign: None,
+                route_id: Some(String::from("720")),
+                trip_short_name: None,
+                direction_id: None,
+                start_time: None,
+                start_date: None,
+                schedule_relationship: None,
+            }),
+            // Monday 2025-04-14 08:30 in Los Angeles
+            timestamp: Some(1744644600),
+            occupancy_status: Some(occupancy_status),
+            trip_progress: Some(AspenisedTripProgress {
+                shape_id: String::from("shape"),
+                distance_along_shape_m: 0.,
+                snapped_latitude: 0.,
+                snapped_longitude: 0.,
+                distance_from_shape_m: 0.,
+                inferred_bearing: None,
+                previous_stop_id: Some(CompactString::from(previous_stop_id)),
+                next_stop_id: None,
+                fraction_between_stops: None,
+            }),
+            ..test_vehicles::vehicle(id)
+        }
+    }
+
+    #[test]
+    fn vehicles_are_counted_once_per_stop() {
+        let trips = AHashMap::from_iter([(String::from("720-1"), trip())]);
+        let metas = AHashMap::from_iter([(String::from("pattern"), meta())]);
+        let mut last_counted_stop = AHashMap::new();
+
+        let positions = AHashMap::from_iter([
+            (String::from("a"), vehicle("a", "3094", 2)),
+            (String::from("b"), vehicle("b", "3094", 4)),
+        ]);
+
+        let rows = observations_to_record(
+            "metro~losangeles",
+            &positions,
+            &trips,
+            &metas,
+            &mut last_counted_stop,
+            0,
+        );
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].occupancy_status_sum, 6);
+        assert_eq!(rows[0].occupancy_status_count, 2);
+        assert_eq!(rows[0].direction_id, 0);
+        assert_eq!(rows[0].time_of_week_bucket, 8);
+
+        let rows = observations_to_record(
+            "metro~losangeles",
+            &positions,
+            &trips,
+            &metas,
+            &mut last_counted_stop,
+            0,
+        );
+
+        assert!(rows.is_empty());
+    }
+}
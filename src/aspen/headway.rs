@@ -0,0 +1,362 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// On frequent routes riders care about how far apart vehicles are, not whether each one is on time.
+// Vehicles on the same direction pattern and shape are ordered by distance along the shape,
+// and the headway between neighbours is the scheduled running time between their positions.
+
+use ahash::AHashMap;
+use catenary::aspen_dataset::*;
+use catenary::gtfs_schedule_protobuf::protobuf_to_frequencies;
+use catenary::models::{CompressedTrip, ItineraryPatternMeta};
+
+// below this share of the scheduled headway, the follower has caught up to the leader
+const BUNCHED_RATIO: f64 = 0.5;
+// above this share, riders in between wait noticeably longer than promised
+const GAP_RATIO: f64 = 1.5;
+// routes scheduled less often than this are judged on punctuality instead
+const MAX_FREQUENT_HEADWAY_SECS: u32 = 15 * 60;
+
+lazy_static::lazy_static! {
+    static ref HEADWAY_ALERTS_ENABLED: bool = std::env::var("ASPEN_HEADWAY_ALERTS")
+        .map(|x| x == "true")
+        .unwrap_or(false);
+}
+
+struct PlacedVehicle<'a> {
+    vehicle_id: &'a str,
+    trip: &'a CompressedTrip,
+    // realtime start time, frequency based trips share one trip_id
+    start_time_secs: Option<u32>,
+    distance_along_shape_m: f64,
+}
+
+pub fn headway_status(headway_seconds: u32, scheduled_headway_seconds: u32) -> HeadwayStatus {
+    let ratio = headway_seconds as f64 / scheduled_headway_seconds as f64;
+
+    if ratio < BUNCHED_RATIO {
+        HeadwayStatus::Bunched
+    } else if ratio > GAP_RATIO {
+        HeadwayStatus::Gap
+    } else {
+        HeadwayStatus::Normal
+    }
+}
+
+/// Scheduled seconds after the trip start at this distance, interpolated between stops
+fn scheduled_time_at_distance(
+    stops: &[ShapeProgressStop],
+    distance_along_shape_m: f64,
+) -> Option<f64> {
+    let timed_stops = stops
+        .iter()
+        .filter_map(
+            |stop| match (stop.distance_along_shape_m, stop.time_since_start) {
+                (Some(distance), Some(time)) => Some((distance, time as f64)),
+                _ => None,
+            },
+        )
+        .collect::<Vec<(f64, f64)>>();
+
+    let first = timed_stops.first()?;
+    let last = timed_stops.last()?;
+
+    if distance_along_shape_m <= first.0 {
+        return Some(first.1);
+    }
+
+    if distance_along_shape_m >= last.0 {
+        return Some(last.1);
+    }
+
+    timed_stops.windows(2).find_map(|pair| {
+        let (start, end) = (pair[0], pair[1]);
+
+        if distance_along_shape_m < start.0 || distance_along_shape_m > end.0 {
+            return None;
+        }
+
+        match end.0 - start.0 {
+            span if span > 0. => {
+                Some(start.1 + (end.1 - start.1) * (distance_along_shape_m - start.0) / span)
+            }
+            _ => Some(start.1),
+        }
+    })
+}
+
+fn parse_start_time(start_time: &str) -> Option<u32> {
+    let mut parts = start_time.split(':').map(|part| part.parse::<u32>().ok());
+
+    let hours = parts.next()??;
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+fn frequency_headway(trip: &CompressedTrip, start_time_secs: Option<u32>) -> Option<u32> {
+    let frequencies = trip
+        .frequencies
+        .as_ref()
+        .and_then(|data| {
+            <catenary::gtfs_schedule_protobuf::GtfsFrequenciesProto as prost::Message>::decode(
+                data.as_ref(),
+            )
+            .ok()
+        })
+        .map(|x| protobuf_to_frequencies(&x))?;
+
+    let covering = start_time_secs.and_then(|start_time_secs| {
+        frequencies.iter().find(|frequency| {
+            frequency.start_time <= start_time_secs && start_time_secs < frequency.end_time
+        })
+    });
+
+    covering
+        .or(frequencies.first())
+        .map(|frequency| frequency.headway_secs)
+}
+
+fn scheduled_headway(leader: &PlacedVehicle, follower: &PlacedVehicle) -> Option<u32> {
+    if follower.trip.has_frequencies {
+        return frequency_headway(follower.trip, follower.start_time_secs);
+    }
+
+    let leader_start = leader.start_time_secs.unwrap_or(leader.trip.start_time);
+    let follower_start = follower.start_time_secs.unwrap_or(follower.trip.start_time);
+
+    match follower_start > leader_start {
+        true => Some(follower_start - leader_start),
+        false => None,
+    }
+}
+
+/// Headways between every pair of neighbouring vehicles, grouped by route
+pub fn headway_health_for_routes(
+    vehicle_positions: &AHashMap<String, AspenisedVehiclePosition>,
+    trip_id_to_trip: &AHashMap<String, CompressedTrip>,
+    itinerary_pattern_metas: &AHashMap<String, ItineraryPatternMeta>,
+    cache: &ShapeProgressInternalCache,
+) -> AHashMap<String, AspenisedRouteHeadwayHealth> {
+    // (route_id, direction_pattern_id, shape_id) -> vehicles
+    let mut groups: AHashMap<(&str, Option<&str>, &str), Vec<PlacedVehicle>> = AHashMap::new();
+
+    for (vehicle_id, vehicle) in vehicle_positions {
+        let Some(trip_progress) = &vehicle.trip_progress else {
+            continue;
+        };
+
+        let Some(trip) = vehicle
+            .trip
+            .as_ref()
+            .and_then(|trip| trip.trip_id.as_ref())
+            .and_then(|trip_id| trip_id_to_trip.get(trip_id))
+        else {
+            continue;
+        };
+
+        let direction_pattern_id = itinerary_pattern_metas
+            .get(&trip.itinerary_pattern_id)
+            .and_then(|meta| meta.direction_pattern_id.as_deref());
+
+        groups
+            .entry((
+                trip.route_id.as_str(),
+                direction_pattern_id,
+                trip_progress.shape_id.as_str(),
+            ))
+            .or_default()
+            .push(PlacedVehicle {
+                vehicle_id: vehicle_id.as_str(),
+                trip,
+                start_time_secs: vehicle
+                    .trip
+                    .as_ref()
+                    .and_then(|trip| trip.start_time.as_deref())
+                    .and_then(parse_start_time),
+                distance_along_shape_m: trip_progress.distance_along_shape_m,
+            });
+    }
+
+    let mut health: AHashMap<String, AspenisedRouteHeadwayHealth> = AHashMap::new();
+
+    for ((route_id, direction_pattern_id, shape_id), mut vehicles) in groups {
+        vehicles.sort_by(|a, b| {
+            b.distance_along_shape_m
+                .total_cmp(&a.distance_along_shape_m)
+        });
+
+        let headways = vehicles
+            .windows(2)
+            .filter_map(|pair| {
+                let (leader, follower) = (&pair[0], &pair[1]);
+
+                // the same trip reported by two feeds, or two vehicles coupled together
+                if leader.trip.trip_id == follower.trip.trip_id
+                    && leader.start_time_secs == follower.start_time_secs
+                {
+                    return None;
+                }
+
+                let scheduled_headway_seconds = scheduled_headway(leader, follower)?;
+
+                if scheduled_headway_seconds == 0
+                    || scheduled_headway_seconds > MAX_FREQUENT_HEADWAY_SECS
+                {
+                    return None;
+                }
+
+                let stops = cache
+                    .itinerary_stop_distances
+                    .get(&follower.trip.itinerary_pattern_id)?;
+
+                let headway_seconds =
+                    (scheduled_time_at_distance(stops, leader.distance_along_shape_m)?
+                        - scheduled_time_at_distance(stops, follower.distance_along_shape_m)?)
+                    .max(0.)
+                    .round() as u32;
+
+                Some(AspenisedHeadway {
+                    leading_vehicle_id: leader.vehicle_id.to_string(),
+                    following_vehicle_id: follower.vehicle_id.to_string(),
+                    leading_trip_id: leader.trip.trip_id.clone(),
+                    following_trip_id: follower.trip.trip_id.clone(),
+                    headway_seconds,
+                    scheduled_headway_seconds,
+                    status: headway_status(headway_seconds, scheduled_headway_seconds),
+                })
+            })
+            .collect::<Vec<AspenisedHeadway>>();
+
+        if headways.is_empty() {
+            continue;
+        }
+
+        let route_health =
+            health
+                .entry(route_id.to_string())
+                .or_insert_with(|| AspenisedRouteHeadwayHealth {
+                    route_id: route_id.to_string(),
+                    bunched_count: 0,
+                    gap_count: 0,
+                    directions: vec![],
+                });
+
+        route_health.bunched_count += headways
+            .iter()
+            .filter(|headway| headway.status == HeadwayStatus::Bunched)
+            .count() as u32;
+        route_health.gap_count += headways
+            .iter()
+            .filter(|headway| headway.status == HeadwayStatus::Gap)
+            .count() as u32;
+
+        route_health.directions.push(AspenisedDirectionHeadways {
+            direction_pattern_id: direction_pattern_id.map(|x| x.to_string()),
+            shape_id: shape_id.to_string(),
+            headways,
+        });
+    }
+
+    health
+}
+
+fn english_text(text: String) -> AspenTranslatedString {
+    AspenTranslatedString {
+        translation: vec![AspenTranslation {
+            text,
+            language: Some(String::from("en")),
+        }],
+    }
+}
+
+/// Alerts for routes with gaps or bunching, only made when ASPEN_HEADWAY_ALERTS is true
+pub fn headway_alerts(
+    health: &AHashMap<String, AspenisedRouteHeadwayHealth>,
+) -> Vec<(String, AspenisedAlert)> {
+    if !*HEADWAY_ALERTS_ENABLED {
+        return vec![];
+    }
+
+    health
+        .values()
+        .filter(|route_health| route_health.gap_count > 0 || route_health.bunched_count > 0)
+        .map(|route_health| {
+            let (effect, header) = match route_health.gap_count > 0 {
+                true => (
+                    gtfs_realtime::alert::Effect::SignificantDelays,
+                    "Longer waits than scheduled",
+                ),
+                false => (
+                    gtfs_realtime::alert::Effect::OtherEffect,
+                    "Irregular service",
+                ),
+            };
+
+            let alert = AspenisedAlert {
+                active_period: vec![],
+                informed_entity: vec![AspenEntitySelector {
+                    agency_id: None,
+                    route_id: Some(route_health.route_id.clone()),
+                    route_type: None,
+                    trip: None,
+                    stop_id: None,
+                    direction_id: None,
+                }],
+                cause: None,
+                effect: Some(effect as i32),
+                url: None,
+                header_text: Some(english_text(String::from(header))),
+                description_text: Some(english_text(format!(
+                    "Vehicles are not evenly spaced: {} gaps and {} bunched vehicles right now.",
+                    route_health.gap_count, route_health.bunched_count
+                ))),
+                tts_header_text: None,
+                tts_description_text: None,
+                severity_level: Some(gtfs_realtime::alert::SeverityLevel::Info as i32),
+                image: None,
+                image_alternative_text: None,
+                cause_detail: None,
+                effect_detail: None,
+            };
+
+            (
+                format!("{}{}", HEADWAY_ALERT_ID_PREFIX, route_health.route_id),
+                alert,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(distance: f64, time: i32) -> ShapeProgressStop {
+        ShapeProgressStop {
+            stop_id: "stop".into(),
+            gtfs_stop_sequence: 0,
+            distance_along_shape_m: Some(distance),
+            time_since_start: Some(time),
+        }
+    }
+
+    #[test]
+    fn headway_is_running_time_between_positions() {
+        let stops = vec![stop(0., 0), stop(1000., 120), stop(3000., 360)];
+
+        assert_eq!(scheduled_time_at_distance(&stops, 500.), Some(60.));
+        assert_eq!(scheduled_time_at_distance(&stops, 2000.), Some(240.));
+        assert_eq!(scheduled_time_at_distance(&stops, 5000.), Some(360.));
+        assert_eq!(scheduled_time_at_distance(&[], 5.), None);
+
+        assert_eq!(headway_status(100, 300), HeadwayStatus::Bunched);
+        assert_eq!(headway_status(300, 300), HeadwayStatus::Normal);
+        assert_eq!(headway_status(600, 300), HeadwayStatus::Gap);
+
+        assert_eq!(parse_start_time("25:04:10"), Some(90250));
+        assert_eq!(parse_start_time("7:30"), None);
+    }
+}
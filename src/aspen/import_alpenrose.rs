@@ -3,132 +3,36 @@
 // Attribution cannot be removed
 
 extern crate catenary;
-use crate::metrolink_california_additions::vehicle_pos_supplement;
+use super::block_delay_carryover::predict_block_delays;
+use super::crowding_observations::CrowdingObservationWriter;
+use super::headway::{headway_alerts, headway_health_for_routes};
+use super::platform_stops::{stop_codes_for_chateau, StopCodeCache, StopCodes};
+use super::supplementary_merge::{SupplementaryMerge, SupplementaryMergeRule};
+use super::vehicle_history::VehicleHistoryWriter;
+use super::vehicle_shape_progress::{refresh_shape_progress_cache, trip_progress_for_vehicle};
 use ahash::{AHashMap, AHashSet};
 use catenary::aspen_dataset::*;
+use catenary::metrics::ASPEN_INGEST_DURATION;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use compact_str::CompactString;
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use gtfs_realtime::FeedMessage;
 use scc::HashMap as SccHashMap;
-use serde::Deserialize;
-use serde::Serialize;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct MetrolinkTrackData {
-    #[serde(rename = "TrainDesignation")]
-    train_designation: String,
-    #[serde(rename = "RouteCode")]
-    route_code: String,
-    #[serde(rename = "PlatformName")]
-    platform_name: String,
-    #[serde(rename = "EventType")]
-    event_type: String,
-    #[serde(rename = "FormattedTrackDesignation")]
-    formatted_track_designation: String,
-    #[serde(rename = "TrainMovementTime")]
-    train_movement_time: String,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct MetrolinkTrackDataCleaned {
-    track_movement_time_arrival: Option<u64>,
-    track_movement_time_departure: Option<u64>,
-    stop_id: String,
-    formatted_track_designation: String,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-enum MetrolinkEventType {
-    Arrival,
-    Departure,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct MetrolinkOutputTrackData {
-    //cleaned 3 digit trip number -> stop_id -> MetrolinkTrackDataCleaned
-    track_lookup: HashMap<String, HashMap<String, MetrolinkTrackDataCleaned>>,
-}
-
-#[derive(Clone, Debug)]
-pub enum TrackData {
-    //output Option<MetrolinkOutputTrackData> instead
-    Metrolink(Option<MetrolinkOutputTrackData>),
-    None,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct MetrolinkPosRaw {
-    pub symbol: CompactString,
-    pub direction: CompactString,
-    pub lat: CompactString,
-    pub long: CompactString,
-    pub speed: CompactString,
-    pub line: CompactString,
-    pub ptc_time: CompactString,
-    pub ptc_status: CompactString,
-    pub delay_status: CompactString,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct MetrolinkPos {
-    pub lat: f32,
-    pub lon: f32,
-    pub speed: f32,
-    pub symbol: CompactString,
-}
 
 const realtime_feeds_to_use_vehicle_ids: [&str; 1] = ["f-ezzx-tbc~rt"];
 
-fn mph_to_mps(mph: &CompactString) -> Option<f32> {
-    let mph: f32 = match mph.parse() {
-        Ok(mph) => mph,
-        Err(_) => return None,
-    };
-
-    Some(mph * 0.44704)
-}
-
-fn metrlink_coord_to_f32(coord: &CompactString) -> Option<f32> {
-    //Split into 3 parts based on :
-    let parts: Vec<&str> = coord.split(':').collect();
-
-    if parts.len() != 3 {
-        return None;
-    }
-
-    let degrees: f32 = match parts[0].parse() {
-        Ok(degrees) => degrees,
-        Err(_) => return None,
-    };
-
-    let sign = if degrees < 0. { -1.0 } else { 1.0 };
-
-    let degrees = degrees.abs();
-
-    let minutes: f32 = match parts[1].parse() {
-        Ok(minutes) => minutes,
-        Err(_) => return None,
-    };
-
-    let seconds: f32 = match parts[2].parse() {
-        Ok(seconds) => seconds,
-        Err(_) => return None,
-    };
-
-    let mut decimal = degrees + minutes / 60.0 + seconds / 3600.0;
-
-    decimal = decimal * sign;
-
-    Some(decimal)
-}
-
 pub async fn new_rt_data(
     authoritative_data_store: Arc<SccHashMap<String, catenary::aspen_dataset::AspenisedData>>,
     authoritative_gtfs_rt: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
+    vehicle_consist_store: Arc<SccHashMap<String, HashMap<String, VehicleConsistSupplement>>>,
+    supplementary_data_store: Arc<SccHashMap<String, Vec<SupplementaryRecord>>>,
+    vehicle_history: Arc<VehicleHistoryWriter>,
+    crowding_observations: Arc<CrowdingObservationWriter>,
+    stop_code_cache: Arc<StopCodeCache>,
     chateau_id: String,
     realtime_feed_id: String,
     has_vehicles: bool,
@@ -149,63 +53,15 @@ pub async fn new_rt_data(
             None => CompressedTripInternalCache::new(),
         };
 
-    let fetch_supplemental_data_positions_metrolink: Option<AHashMap<CompactString, MetrolinkPos>> =
-        match realtime_feed_id.as_str() {
-            "f-metrolinktrains~rt" => {
-                let raw_data_req =
-                    reqwest::get("https://rtt.metrolinktrains.com/trainlist.json").await;
-
-                match raw_data_req {
-                    Ok(metrolink_data) => {
-                        let metrolink_data = metrolink_data.json::<Vec<MetrolinkPosRaw>>().await;
-
-                        match metrolink_data {
-                            Ok(metrolink_data) => {
-                                let mut metrolink_positions: AHashMap<CompactString, MetrolinkPos> =
-                                    AHashMap::new();
-
-                                for pos in metrolink_data {
-                                    let lat = metrlink_coord_to_f32(&pos.lat);
-                                    let lon = metrlink_coord_to_f32(&pos.long);
-                                    let speed = mph_to_mps(&pos.speed);
-
-                                    if let (Some(lat), Some(lon), Some(speed)) = (lat, lon, speed) {
-                                        metrolink_positions.insert(
-                                            pos.symbol.clone(),
-                                            MetrolinkPos {
-                                                lat,
-                                                lon,
-                                                speed,
-                                                symbol: pos.symbol.clone(),
-                                            },
-                                        );
-                                    }
-                                }
-
-                                println!("Got {} metrolink positions", metrolink_positions.len());
-
-                                Some(metrolink_positions)
-                            }
-                            Err(e) => {
-                                println!("Error fetching metrolink data: {}", e);
-                                None
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("Error fetching metrolink data, could not connect: {}", e);
-                        None
-                    }
-                }
-            }
-            _ => None,
+    let mut shape_progress_internal_cache: ShapeProgressInternalCache =
+        match authoritative_data_store.get(&chateau_id) {
+            Some(data) => data.shape_progress_internal_cache.clone(),
+            None => ShapeProgressInternalCache::new(),
         };
 
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
 
-    let fetched_track_data: TrackData = fetch_track_data(&chateau_id).await;
-
     //println!("Forming pg connection");
     let conn = &mut conn_pre?;
     //println!("Connected to postges");
@@ -229,6 +85,7 @@ pub async fn new_rt_data(
     let impacted_stop_id_to_alert_ids: AHashMap<String, Vec<String>> = AHashMap::new();
     let mut impact_trip_id_to_alert_ids: AHashMap<String, Vec<String>> = AHashMap::new();
     let general_alerts: AHashMap<String, Vec<String>> = AHashMap::new();
+    let mut route_headway_health: AHashMap<String, AspenisedRouteHeadwayHealth> = AHashMap::new();
 
     use catenary::schema::gtfs::chateaus as chateaus_pg_schema;
     use catenary::schema::gtfs::routes as routes_pg_schema;
@@ -241,6 +98,66 @@ pub async fn new_rt_data(
         .await?;
     let chateau_elapsed = start_chateau_query.elapsed();
 
+    //aggregate feeds split across chateaus arrive under one partition feed id per chateau
+    use catenary::schema::gtfs::realtime_fan_out_rules as fan_out_pg_schema;
+
+    let fan_out_aggregate_feed_ids: Vec<String> = fan_out_pg_schema::dsl::realtime_fan_out_rules
+        .filter(fan_out_pg_schema::dsl::chateau.eq(&chateau_id))
+        .select(fan_out_pg_schema::dsl::aggregate_feed_id)
+        .distinct()
+        .load::<String>(conn)
+        .await?;
+
+    let mut realtime_feed_ids_for_chateau: Vec<String> = this_chateau
+        .realtime_feeds
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+    for aggregate_feed_id in fan_out_aggregate_feed_ids.iter() {
+        realtime_feed_ids_for_chateau.push(catenary::realtime_fan_out::partition_feed_id(
+            aggregate_feed_id,
+            &chateau_id,
+        ));
+    }
+
+    use catenary::schema::gtfs::supplementary_merge_rules as merge_rules_pg_schema;
+
+    let merge_rule_rows = merge_rules_pg_schema::dsl::supplementary_merge_rules
+        .filter(merge_rules_pg_schema::dsl::chateau.eq(&chateau_id))
+        .order(merge_rules_pg_schema::dsl::rule_index.asc())
+        .select(catenary::models::SupplementaryMergeRuleRow::as_select())
+        .load::<catenary::models::SupplementaryMergeRuleRow>(conn)
+        .await?;
+
+    let merge_rules = merge_rule_rows
+        .iter()
+        .filter_map(|row| {
+            let rule = SupplementaryMergeRule::from_row(row);
+
+            if rule.is_none() {
+                eprintln!(
+                    "Ignoring invalid supplementary merge rule {} #{} ({} {})",
+                    row.chateau, row.rule_index, row.field, row.mode
+                );
+            }
+
+            rule
+        })
+        .collect::<Vec<SupplementaryMergeRule>>();
+
+    let supplementary_merge = SupplementaryMerge::new(
+        merge_rules,
+        &realtime_feed_ids_for_chateau,
+        supplementary_data_store.as_ref(),
+    );
+
+    let stop_codes = match supplementary_merge.has_platforms() {
+        true => stop_codes_for_chateau(&stop_code_cache, conn_pool, &chateau_id).await,
+        false => Arc::new(StopCodes::new()),
+    };
+
     //get all routes inside chateau from postgres db
     //: Vec<catenary::models::Route>
 
@@ -277,7 +194,7 @@ pub async fn new_rt_data(
 
     let mut trip_ids_to_lookup: AHashSet<String> = AHashSet::new();
 
-    for realtime_feed_id in this_chateau.realtime_feeds.iter().flatten() {
+    for realtime_feed_id in realtime_feed_ids_for_chateau.iter() {
         if let Some(vehicle_gtfs_rt_for_feed_id) =
             authoritative_gtfs_rt.get(&(realtime_feed_id.clone(), GtfsRtType::VehiclePositions))
         {
@@ -419,14 +336,34 @@ pub async fn new_rt_data(
         let itinerary_pattern_id_to_itinerary_pattern_meta =
             itinerary_pattern_id_to_itinerary_pattern_meta;
 
+        let shape_lookup_start = std::time::Instant::now();
+
+        if let Err(e) = refresh_shape_progress_cache(
+            conn,
+            &chateau_id,
+            &mut shape_progress_internal_cache,
+            &itinerary_pattern_id_to_itinerary_pattern_meta,
+        )
+        .await
+        {
+            eprintln!("Could not load shapes for chateau {}: {}", chateau_id, e);
+        }
+
+        let shape_lookup_duration = shape_lookup_start.elapsed();
+
         let mut route_ids_to_insert = AHashSet::new();
 
-        for realtime_feed_id in this_chateau.realtime_feeds.iter().flatten() {
+        for realtime_feed_id in realtime_feed_ids_for_chateau.iter() {
             if let Some(vehicle_gtfs_rt_for_feed_id) =
                 authoritative_gtfs_rt.get(&(realtime_feed_id.clone(), GtfsRtType::VehiclePositions))
             {
                 let vehicle_gtfs_rt_for_feed_id = vehicle_gtfs_rt_for_feed_id.get();
 
+                let vehicle_consists_for_feed_id = vehicle_consist_store.get(realtime_feed_id);
+                let vehicle_consists_for_feed_id = vehicle_consists_for_feed_id
+                    .as_ref()
+                    .map(|vehicle_consists| vehicle_consists.get());
+
                 for vehicle_entity in vehicle_gtfs_rt_for_feed_id.entity.iter() {
                     if let Some(vehicle_pos) = &vehicle_entity.vehicle {
                         let recalculate_route_id: Option<String> = match &vehicle_pos.trip {
@@ -530,15 +467,27 @@ pub async fn new_rt_data(
                             current_stop_sequence: vehicle_pos.current_stop_sequence,
                             occupancy_status: vehicle_pos.occupancy_status,
                             occupancy_percentage: vehicle_pos.occupancy_percentage,
-                            congestion_level: vehicle_pos.congestion_level
+                            congestion_level: vehicle_pos.congestion_level,
+                            trip_progress: None,
+                            multi_carriage_details: vehicle_pos
+                                .multi_carriage_details
+                                .iter()
+                                .cloned()
+                                .map(AspenisedCarriageDetails::from)
+                                .collect(),
+                            consist_length: None,
+                            scheduled_consist_length: None,
                         };
 
-                        let pos_aspenised = vehicle_pos_supplement(
+                        let pos_aspenised = apply_consist_supplement(
                             pos_aspenised,
-                            &fetch_supplemental_data_positions_metrolink,
-                            &chateau_id,
+                            vehicle_consists_for_feed_id.and_then(|vehicle_consists| {
+                                vehicle_consists.get(&vehicle_entity.id)
+                            }),
                         );
 
+                        let pos_aspenised = supplementary_merge.merge_vehicle(pos_aspenised);
+
                         let pos_aspenised = match realtime_feeds_to_use_vehicle_ids
                             .contains(&realtime_feed_id.as_str())
                         {
@@ -546,6 +495,15 @@ pub async fn new_rt_data(
                             false => pos_aspenised,
                         };
 
+                        let mut pos_aspenised = pos_aspenised;
+
+                        pos_aspenised.trip_progress = trip_progress_for_vehicle(
+                            &pos_aspenised,
+                            &trip_id_to_trip,
+                            &itinerary_pattern_id_to_itinerary_pattern_meta,
+                            &shape_progress_internal_cache,
+                        );
+
                         aspenised_vehicle_positions
                             .insert(vehicle_entity.id.clone(), pos_aspenised);
 
@@ -584,6 +542,32 @@ pub async fn new_rt_data(
                             None => None,
                         };
 
+                        let mut platform_join_keys = vec![];
+
+                        if let Some(trip_id) = &trip_id {
+                            platform_join_keys.push(SupplementaryJoinKey::TripId(trip_id.clone()));
+                        }
+
+                        if let Some(trip_short_name) = compressed_trip
+                            .and_then(|compressed_trip| compressed_trip.trip_short_name.as_deref())
+                        {
+                            platform_join_keys.push(SupplementaryJoinKey::TripShortName(
+                                trip_short_name.to_string(),
+                            ));
+                        }
+
+                        if let Some(vehicle) = &trip_update.vehicle {
+                            if let Some(id) = &vehicle.id {
+                                platform_join_keys
+                                    .push(SupplementaryJoinKey::VehicleId(id.clone()));
+                            }
+
+                            if let Some(label) = &vehicle.label {
+                                platform_join_keys
+                                    .push(SupplementaryJoinKey::VehicleLabel(label.clone()));
+                            }
+                        }
+
                         let trip_update = AspenisedTripUpdate {
                             trip: trip_update.trip.clone().into(),
                             vehicle: trip_update.vehicle.clone().map(|x| x.into()),
@@ -591,74 +575,53 @@ pub async fn new_rt_data(
                             stop_time_update: trip_update
                                 .stop_time_update
                                 .iter()
-                                .map(|stu| AspenisedStopTimeUpdate {
-                                    stop_sequence: stu.stop_sequence,
-                                    stop_id: stu.stop_id.as_ref().map(|x| x.into()),
-                                    arrival: stu.arrival.clone().map(|arrival| {
-                                        AspenStopTimeEvent {
-                                            delay: None,
-                                            time: arrival.time,
-                                            uncertainty: arrival.uncertainty,
-                                        }
-                                    }),
-                                    departure: stu.departure.clone().map(|departure| {
-                                        AspenStopTimeEvent {
-                                            delay: None,
-                                            time: departure.time,
-                                            uncertainty: departure.uncertainty,
-                                        }
-                                    }),
-                                    platform_string: match chateau_id.as_str() {
-                                        "metrolinktrains" => {
-                                            let mut track_resp = None;
-
-                                            if let TrackData::Metrolink(Some(track_data_scax)) =
-                                                &fetched_track_data
-                                            {
-                                                let mut metrolink_code = String::from("M");
-
-                                                if let Some(compressed_trip) = compressed_trip {
-                                                    if let Some(trip_short_name) =
-                                                        compressed_trip.trip_short_name.as_ref()
-                                                    {
-                                                        metrolink_code.push_str(trip_short_name);
-                                                    }
-                                                }
-
-                                                if let Some(train_data) = track_data_scax
-                                                    .track_lookup
-                                                    .get(&metrolink_code)
-                                                {
-                                                    if let Some(stop_id) = &stu.stop_id {
-                                                        if let Some(train_and_stop_scax) =
-                                                            train_data.get(stop_id)
-                                                        {
-                                                            track_resp = Some(
-                                                                train_and_stop_scax
-                                                                    .formatted_track_designation
-                                                                    .clone()
-                                                                    .replace("Platform ", ""),
-                                                            );
-                                                        }
-                                                    }
-                                                }
+                                .map(|stu| {
+                                    let platform_assignment = match &stu.stop_id {
+                                        Some(stop_id) => supplementary_merge.merge_platform(
+                                            realtime_feed_id,
+                                            &platform_join_keys,
+                                            stop_id,
+                                            stop_codes.get(stop_id).map(|x| x.as_str()),
+                                        ),
+                                        None => None,
+                                    };
+
+                                    AspenisedStopTimeUpdate {
+                                        stop_sequence: stu.stop_sequence,
+                                        stop_id: stu.stop_id.as_ref().map(|x| x.into()),
+                                        arrival: stu.arrival.clone().map(|arrival| {
+                                            AspenStopTimeEvent {
+                                                delay: None,
+                                                time: arrival.time,
+                                                uncertainty: arrival.uncertainty,
                                             }
-
-                                            track_resp
-                                        }
-                                        _ => None,
-                                    },
-                                    schedule_relationship: stu.schedule_relationship,
-                                    departure_occupancy_status: stu.departure_occupancy_status,
-                                    stop_time_properties: stu
-                                        .stop_time_properties
-                                        .clone()
-                                        .map(|x| x.into()),
+                                        }),
+                                        departure: stu.departure.clone().map(|departure| {
+                                            AspenStopTimeEvent {
+                                                delay: None,
+                                                time: departure.time,
+                                                uncertainty: departure.uncertainty,
+                                            }
+                                        }),
+                                        platform_string: platform_assignment.as_ref().map(
+                                            |platform_assignment| {
+                                                platform_assignment.platform.clone()
+                                            },
+                                        ),
+                                        platform_assignment,
+                                        schedule_relationship: stu.schedule_relationship,
+                                        departure_occupancy_status: stu.departure_occupancy_status,
+                                        stop_time_properties: stu
+                                            .stop_time_properties
+                                            .clone()
+                                            .map(|x| x.into()),
+                                    }
                                 })
                                 .collect(),
                             timestamp: trip_update.timestamp,
                             delay: trip_update.delay,
                             trip_properties: trip_update.trip_properties.clone().map(|x| x.into()),
+                            block_delay_prediction: None,
                         };
 
                         let trip_update = match realtime_feeds_to_use_vehicle_ids
@@ -731,6 +694,66 @@ pub async fn new_rt_data(
             }
         }
 
+        let block_prediction_start = std::time::Instant::now();
+
+        match predict_block_delays(
+            conn,
+            &chateau_id,
+            &trip_updates,
+            &trip_id_to_trip,
+            &itinerary_pattern_id_to_itinerary_pattern_meta,
+        )
+        .await
+        {
+            Ok(predictions) => {
+                for (prediction_id, predicted_trip_update) in predictions {
+                    if let Some(trip_id) = &predicted_trip_update.trip.trip_id {
+                        trip_updates_lookup_by_trip_id_to_trip_update_ids
+                            .entry(trip_id.into())
+                            .or_default()
+                            .push(prediction_id.clone());
+                    }
+
+                    trip_updates.insert(prediction_id, predicted_trip_update);
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Could not predict block delays for chateau {}: {}",
+                    chateau_id, e
+                );
+            }
+        }
+
+        let block_prediction_duration = block_prediction_start.elapsed();
+
+        route_headway_health = headway_health_for_routes(
+            &aspenised_vehicle_positions,
+            &trip_id_to_trip,
+            &itinerary_pattern_id_to_itinerary_pattern_meta,
+            &shape_progress_internal_cache,
+        );
+
+        crowding_observations.record(
+            &chateau_id,
+            &aspenised_vehicle_positions,
+            &trip_id_to_trip,
+            &itinerary_pattern_id_to_itinerary_pattern_meta,
+        );
+
+        for (alert_id, alert) in headway_alerts(&route_headway_health) {
+            for informed_entity in alert.informed_entity.iter() {
+                if let Some(route_id) = &informed_entity.route_id {
+                    impacted_route_id_to_alert_ids
+                        .entry(route_id.clone())
+                        .or_default()
+                        .push(alert_id.clone());
+                }
+            }
+
+            alerts.insert(alert_id, alert);
+        }
+
         //insert the route cache
 
         for route_id in route_ids_to_insert.iter() {
@@ -760,9 +783,11 @@ pub async fn new_rt_data(
             }
         }
 
-        println!("Finished processing {} chateau took {:?} for route lookup, {:?} for trips, {:?} for itins", chateau_id,routes_query_elapsed, trip_duration, itin_lookup_duration);
+        println!("Finished processing {} chateau took {:?} for route lookup, {:?} for trips, {:?} for itins, {:?} for shapes, {:?} for block predictions", chateau_id,routes_query_elapsed, trip_duration, itin_lookup_duration, shape_lookup_duration, block_prediction_duration);
     }
 
+    vehicle_history.record(&chateau_id, &aspenised_vehicle_positions);
+
     //Insert data back into process-wide authoritative_data_store
 
     match authoritative_data_store.entry(chateau_id.clone()) {
@@ -781,6 +806,8 @@ pub async fn new_rt_data(
                 impacted_trips_alerts: impact_trip_id_to_alert_ids,
                 compressed_trip_internal_cache,
                 itinerary_pattern_internal_cache: ItineraryPatternInternalCache::new(),
+                shape_progress_internal_cache,
+                route_headway_health,
                 last_updated_time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
             }
         }
@@ -798,6 +825,8 @@ pub async fn new_rt_data(
                 impacted_trips_alerts: impact_trip_id_to_alert_ids,
                 compressed_trip_internal_cache,
                 itinerary_pattern_internal_cache: ItineraryPatternInternalCache::new(),
+                shape_progress_internal_cache,
+                route_headway_health,
                 last_updated_time_ms: catenary::duration_since_unix_epoch().as_millis() as u64,
             });
         }
@@ -805,107 +834,92 @@ pub async fn new_rt_data(
 
     println!("Updated Chateau {}", chateau_id);
 
+    ASPEN_INGEST_DURATION
+        .with_label_values(&[chateau_id.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
     Ok(true)
 }
 
-pub async fn fetch_track_data(chateau_id: &str) -> TrackData {
-    match chateau_id {
-        "metrolinktrains" => {
-            let url = "https://rtt.metrolinktrains.com/StationScheduleList.json";
-
-            match reqwest::get(url).await {
-                Ok(r) => {
-                    let response = r.json::<Vec<MetrolinkTrackData>>().await;
-
-                    println!("{:?}", response);
+// Adapters know more about a consist than GTFS-RT can carry, so their cars replace the feed's
+fn apply_consist_supplement(
+    pos_aspenised: AspenisedVehiclePosition,
+    supplement: Option<&VehicleConsistSupplement>,
+) -> AspenisedVehiclePosition {
+    let mut pos_aspenised = pos_aspenised;
 
-                    match response {
-                        Ok(response) => {
-                            let mut track_lookup: HashMap<
-                                String,
-                                HashMap<String, MetrolinkTrackDataCleaned>,
-                            > = HashMap::new();
-
-                            for t in response {
-                                if !track_lookup.contains_key(&t.train_designation) {
-                                    track_lookup
-                                        .insert(t.train_designation.clone(), HashMap::new());
-                                }
-
-                                let mut train_lookup_entry =
-                                    track_lookup.get_mut(&t.train_designation).unwrap();
-
-                                let stop_id_find =
-                                    catenary::metrolink_ptc_to_stop_id::METROLINK_STOP_LIST
-                                        .iter()
-                                        .find(|x| x.1 == &t.platform_name);
-
-                                if let Some((stop_id, _)) = stop_id_find {
-                                    if !train_lookup_entry.contains_key(*stop_id) {
-                                        train_lookup_entry.insert(
-                                            stop_id.to_string(),
-                                            MetrolinkTrackDataCleaned {
-                                                track_movement_time_arrival: None,
-                                                track_movement_time_departure: None,
-                                                stop_id: stop_id.to_string(),
-                                                formatted_track_designation: t
-                                                    .formatted_track_designation
-                                                    .clone(),
-                                            },
-                                        );
-                                    }
-
-                                    let train_and_stop_entry =
-                                        train_lookup_entry.get_mut(&stop_id.to_string()).unwrap();
+    if let Some(supplement) = supplement {
+        if !supplement.carriages.is_empty() {
+            pos_aspenised.multi_carriage_details = supplement.carriages.clone();
+        }
 
-                                    match t.event_type.as_str() {
-                                        "Arrival" => {
-                                            train_and_stop_entry.track_movement_time_arrival =
-                                                Some(catenary::metrolink_unix_fix(
-                                                    &t.train_movement_time,
-                                                ));
-                                        }
-                                        "Departure" => {
-                                            train_and_stop_entry.track_movement_time_departure =
-                                                Some(catenary::metrolink_unix_fix(
-                                                    &t.train_movement_time,
-                                                ));
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
+        pos_aspenised.consist_length = supplement.consist_length;
+        pos_aspenised.scheduled_consist_length = supplement.scheduled_consist_length;
+    }
 
-                            TrackData::Metrolink(Some(MetrolinkOutputTrackData {
-                                track_lookup: track_lookup,
-                            }))
-                        }
-                        Err(e) => {
-                            println!("Error decoding Metrolink data: {}", e);
-                            TrackData::Metrolink(None)
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!("Error fetching Metrolink data: {}", e);
-                    TrackData::Metrolink(None)
-                }
-            }
-        }
-        _ => TrackData::None,
+    if pos_aspenised.consist_length.is_none() && !pos_aspenised.multi_carriage_details.is_empty() {
+        pos_aspenised.consist_length = Some(pos_aspenised.multi_carriage_details.len() as u32);
     }
+
+    pos_aspenised
 }
 
 #[cfg(test)]
 mod tests {
-    #[tokio::test]
-    async fn test_fetch_track_data() {
-        let track_data = super::fetch_track_data("metrolinktrains").await;
-        match track_data {
-            super::TrackData::Metrolink(m_data) => {
-                assert!(m_data.is_some());
-            }
-            _ => panic!("Expected Metrolink data"),
+    use super::*;
+
+    fn carriage(sequence: u32, car_type: Option<&str>) -> AspenisedCarriageDetails {
+        AspenisedCarriageDetails {
+            id: Some(format!("{}", 7000 + sequence)),
+            label: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            carriage_sequence: Some(sequence),
+            car_type: car_type.map(|car_type| car_type.to_string()),
+            restroom: None,
+            bike_capacity: None,
+            locomotive: None,
+            revenue: None,
+        }
+    }
+
+    fn position(multi_carriage_details: Vec<AspenisedCarriageDetails>) -> AspenisedVehiclePosition {
+        AspenisedVehiclePosition {
+            trip: None,
+            vehicle: None,
+            position: None,
+            timestamp: None,
+            route_type: 2,
+            current_stop_sequence: None,
+            current_status: None,
+            congestion_level: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            trip_progress: None,
+            multi_carriage_details,
+            consist_length: None,
+            scheduled_consist_length: None,
         }
     }
+
+    #[test]
+    fn consist_supplement_replaces_feed_carriages() {
+        let from_feed = apply_consist_supplement(position(vec![carriage(1, None)]), None);
+
+        assert_eq!(from_feed.consist_length, Some(1));
+        assert_eq!(from_feed.scheduled_consist_length, None);
+
+        let supplement = VehicleConsistSupplement {
+            consist_length: Some(2),
+            scheduled_consist_length: Some(4),
+            carriages: vec![carriage(1, Some("M7")), carriage(2, Some("M7"))],
+        };
+
+        let supplemented =
+            apply_consist_supplement(position(vec![carriage(1, None)]), Some(&supplement));
+
+        assert_eq!(supplemented.multi_carriage_details, supplement.carriages);
+        assert_eq!(supplemented.consist_length, Some(2));
+        assert_eq!(supplemented.scheduled_consist_length, Some(4));
+    }
 }
@@ -1,8 +1,10 @@
 use catenary::aspen::lib::ChateausLeaderHashMap;
+use catenary::coordination::{Coordination, Coordinator};
 use catenary::postgres_tools::CatenaryPostgresPool;
 use std::error::Error;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 pub async fn aspen_leader_thread(
@@ -10,45 +12,34 @@ pub async fn aspen_leader_thread(
     feeds_list: Arc<Mutex<Option<ChateausLeaderHashMap>>>,
     this_worker_id: Arc<String>,
     arc_conn_pool: Arc<CatenaryPostgresPool>,
-    etcd_addresses: Arc<Vec<String>>,
-    arc_etcd_connection_options: Arc<Option<etcd_client::ConnectOptions>>,
+    coordinator: Coordinator,
     lease_id_for_this_worker: i64,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     println!("starting leader thread");
 
-    let mut etcd = etcd_client::Client::connect(
-        etcd_addresses.as_slice(),
-        arc_etcd_connection_options.as_ref().to_owned(),
-    )
-    .await?;
-
-    println!("Connected to etcd!");
-
     let worker_nodes: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
     let feeds_list: Arc<Mutex<Option<ChateausLeaderHashMap>>> = Arc::new(Mutex::new(None));
 
+    let mut last_history_purge: Option<Instant> = None;
+
     loop {
         //attempt to become leader
 
-        let make_lease = etcd
-            .lease_grant(
+        let make_lease = coordinator
+            .grant_lease(
+                lease_id_for_this_worker,
                 //10 seconds
                 10,
-                Some(etcd_client::LeaseGrantOptions::new().with_id(lease_id_for_this_worker)),
             )
             .await;
 
-        let mut election_client = etcd.election_client();
-
-        let current_leader_election = election_client.leader("/aspen_leader").await;
+        let current_leader_election = coordinator.leader("/aspen_leader").await;
 
         match current_leader_election {
             Ok(current_leader_election) => {
-                let leader_kv = current_leader_election.kv();
-
-                match leader_kv {
+                match current_leader_election {
                     None => {
-                        let attempt_to_become_leader = election_client
+                        let attempt_to_become_leader = coordinator
                             .campaign(
                                 "/aspen_leader",
                                 bincode::serialize(this_worker_id.as_ref()).unwrap(),
@@ -58,8 +49,8 @@ pub async fn aspen_leader_thread(
 
                         println!("attempt_to_become_leader: {:#?}", attempt_to_become_leader);
                     }
-                    Some(leader_kv) => {
-                        let leader_id: String = bincode::deserialize(leader_kv.value()).unwrap();
+                    Some(leader_value) => {
+                        let leader_id: String = bincode::deserialize(&leader_value).unwrap();
 
                         if &leader_id == this_worker_id.as_ref() {
                             // I AM THE LEADER!!!
@@ -69,16 +60,35 @@ pub async fn aspen_leader_thread(
                             //if the current is the current worker id, do leader tasks
                             // Read the DMFR dataset, divide it into chunks, and assign it to workers
 
-                            crate::aspen_assignment::assign_chateaus(
-                                &mut etcd,
+                            super::aspen_assignment::assign_chateaus(
+                                &coordinator,
                                 Arc::clone(&arc_conn_pool),
                                 Arc::clone(&workers_nodes),
                                 Arc::clone(&feeds_list),
                             )
                             .await?;
 
-                            //renew the etcd lease
-                            let _ = etcd.lease_keep_alive(lease_id_for_this_worker).await?;
+                            //drop expired vehicle history, at most once an hour
+                            if last_history_purge
+                                .map(|x| x.elapsed() > Duration::from_secs(3600))
+                                .unwrap_or(true)
+                            {
+                                match super::vehicle_history::purge_expired_positions(
+                                    arc_conn_pool.as_ref(),
+                                )
+                                .await
+                                {
+                                    Ok(deleted) => {
+                                        println!("Purged {} expired vehicle positions", deleted)
+                                    }
+                                    Err(e) => eprintln!("Could not purge vehicle history: {:?}", e),
+                                }
+
+                                last_history_purge = Some(Instant::now());
+                            }
+
+                            //renew the lease
+                            coordinator.keep_alive(lease_id_for_this_worker).await?;
 
                             tokio::time::sleep(std::time::Duration::from_secs(10)).await;
                         }
@@ -86,7 +96,7 @@ pub async fn aspen_leader_thread(
                 }
             }
             Err(leader_election_err) => {
-                let attempt_to_become_leader = election_client
+                let attempt_to_become_leader = coordinator
                     .campaign(
                         "/aspen_leader",
                         bincode::serialize(this_worker_id.as_ref()).unwrap(),
@@ -100,8 +110,8 @@ pub async fn aspen_leader_thread(
             }
         }
 
-        //renew the etcd lease
-        let _ = etcd.lease_keep_alive(lease_id_for_this_worker).await?;
+        //renew the lease
+        coordinator.keep_alive(lease_id_for_this_worker).await?;
 
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
@@ -5,6 +5,7 @@
 /// This is the service definition. It looks a lot like a trait definition.
 /// It defines one RPC, hello, which takes one arg, name, and returns a String.
 use crate::aspen_dataset::*;
+use crate::crowding::ExpectedCrowding;
 use crate::ChateauDataNoGeometry;
 use ahash::AHashMap;
 use ahash::AHashSet;
@@ -19,6 +20,8 @@ use tarpc::{client, tokio_serde::formats::Bincode};
 pub struct TripsSelectionResponse {
     pub trip_updates: AHashMap<String, AspenisedTripUpdate>,
     pub trip_id_to_trip_update_ids: AHashMap<String, Vec<String>>,
+    // occupancy reported right now by the vehicle running the trip
+    pub trip_id_to_live_crowding: AHashMap<String, ExpectedCrowding>,
 }
 
 #[tarpc::service]
@@ -44,6 +47,8 @@ pub trait AspenRpc {
         vehicles_response_code: Option<u16>,
         trips_response_code: Option<u16>,
         alerts_response_code: Option<u16>,
+        vehicle_consists: Option<HashMap<String, VehicleConsistSupplement>>,
+        supplementary: Option<Vec<SupplementaryRecord>>,
         time_of_submission_ms: u64,
     ) -> bool;
 
@@ -57,6 +62,11 @@ pub trait AspenRpc {
         vehicle_label: String,
     ) -> Option<AspenisedVehiclePosition>;
 
+    async fn get_vehicle_locations_from_trip_id(
+        chateau_id: String,
+        trip_id: String,
+    ) -> Option<Vec<AspenisedVehiclePosition>>;
+
     async fn get_vehicle_locations(
         chateau_id: String,
         existing_fasthash_of_routes: Option<u64>,
@@ -67,6 +77,12 @@ pub trait AspenRpc {
         feed_type: crate::aspen_dataset::GtfsRtType,
     ) -> Option<Vec<u8>>;
 
+    // the chateau's merged and cleaned realtime data, encoded as a GTFS Realtime full dataset
+    async fn get_chateau_gtfs_rt(
+        chateau_id: String,
+        feed_type: crate::aspen_dataset::GtfsRtType,
+    ) -> Option<Vec<u8>>;
+
     async fn get_trip_updates_from_trip_id(
         chateau_id: String,
         trip_id: String,
@@ -93,6 +109,12 @@ pub trait AspenRpc {
     ) -> Option<AlertsforManyStops>;
 
     async fn get_all_alerts(chateau_id: String) -> Option<HashMap<String, AspenisedAlert>>;
+
+    // None when the route has no frequent service with vehicles on it right now
+    async fn get_route_headways(
+        chateau_id: String,
+        route_id: String,
+    ) -> Option<AspenisedRouteHeadwayHealth>;
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -25,6 +25,8 @@
     clippy::iter_cloned_collect
 )]
 use ahash::AHashSet;
+use catenary::coordination::{Coordination, Coordinator};
+use catenary::crowding::{live_crowding, ExpectedCrowding};
 use catenary::postgres_tools::make_async_pool;
 use catenary::{aspen::lib::*, id_cleanup};
 use clap::Parser;
@@ -57,14 +59,24 @@ use gtfs_realtime::FeedMessage;
 use scc::HashMap as SccHashMap;
 use std::error::Error;
 mod async_threads_alpenrose;
-mod metrolink_california_additions;
-use crate::id_cleanup::gtfs_rt_correct_route_id_string;
+mod crowding_observations;
+mod headway;
+mod platform_stops;
+mod queued_writer;
+mod supplementary_merge;
+#[cfg(test)]
+mod test_vehicles;
+mod vehicle_history;
+mod vehicle_shape_progress;
+use catenary::id_cleanup::gtfs_rt_correct_route_id_string;
 use catenary::parse_gtfs_rt_message;
 use rand::Rng;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 mod alerts_responder;
 mod aspen_assignment;
+mod block_delay_carryover;
 use catenary::rt_recent_history::RtCacheEntry;
 use catenary::rt_recent_history::RtKey;
 use prost::Message;
@@ -77,6 +89,7 @@ pub struct GtfsRealtimeHashStore {
     vehicles: Option<u64>,
     trips: Option<u64>,
     alerts: Option<u64>,
+    supplementary: Option<u64>,
 }
 
 // This is the type that implements the generated World trait. It is the business logic
@@ -99,10 +112,14 @@ pub struct AspenServer {
     pub backup_gtfs_rt_store: Arc<SccHashMap<(String, GtfsRtType), FeedMessage>>,
     pub backup_trip_updates_by_gtfs_feed_history:
         Arc<SccHashMap<CompactString, AHashMap<RtKey, RtCacheEntry>>>,
-    pub etcd_addresses: Arc<Vec<String>>,
-    pub etcd_connect_options: Arc<Option<etcd_client::ConnectOptions>>,
+    pub coordinator: Coordinator,
     pub worker_etcd_lease_id: i64,
     pub timestamps_of_gtfs_rt: Arc<SccHashMap<(String, GtfsRtType), u64>>,
+    // consist data sent alongside vehicle positions, by realtime feed id then vehicle entity id
+    pub vehicle_consist_store: Arc<SccHashMap<String, HashMap<String, VehicleConsistSupplement>>>,
+    // records from secondary sources, by the realtime feed id alpenrose sent them under
+    pub supplementary_data_store: Arc<SccHashMap<String, Vec<SupplementaryRecord>>>,
+    pub platform_stop_cache: Arc<platform_stops::PlatformStopCache>,
 }
 
 impl AspenRpc for AspenServer {
@@ -132,6 +149,29 @@ impl AspenRpc for AspenServer {
                 let mut trip_id_to_trip_update_ids: AHashMap<String, Vec<String>> = AHashMap::new();
                 let mut trip_updates: AHashMap<String, AspenisedTripUpdate> = AHashMap::new();
 
+                let mut trip_id_to_live_crowding: AHashMap<String, ExpectedCrowding> =
+                    AHashMap::new();
+
+                for vehicle_position in authoritative_data.vehicle_positions.values() {
+                    let Some(trip_id) = vehicle_position
+                        .trip
+                        .as_ref()
+                        .and_then(|trip| trip.trip_id.as_ref())
+                        .filter(|trip_id| trip_id_list.contains(trip_id.as_str()))
+                    else {
+                        continue;
+                    };
+
+                    if let Some(crowding) = live_crowding(
+                        vehicle_position.occupancy_status,
+                        vehicle_position
+                            .occupancy_percentage
+                            .and_then(|percentage| i32::try_from(percentage).ok()),
+                    ) {
+                        trip_id_to_live_crowding.insert(trip_id.clone(), crowding);
+                    }
+                }
+
                 for trip_id in trip_id_list {
                     if let Some(trip_update_id_list) = authoritative_data
                         .trip_updates_lookup_by_trip_id_to_trip_update_ids
@@ -159,6 +199,7 @@ impl AspenRpc for AspenServer {
                 Some(TripsSelectionResponse {
                     trip_updates,
                     trip_id_to_trip_update_ids,
+                    trip_id_to_live_crowding,
                 })
             }
         }
@@ -184,6 +225,37 @@ impl AspenRpc for AspenServer {
         }
     }
 
+    async fn get_chateau_gtfs_rt(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        feed_type: catenary::aspen_dataset::GtfsRtType,
+    ) -> Option<Vec<u8>> {
+        // only trip updates carry platforms
+        let platform_stops = match feed_type {
+            GtfsRtType::TripUpdates => {
+                platform_stops::platform_stops_for_chateau(
+                    &self.platform_stop_cache,
+                    &self.conn_pool,
+                    &chateau_id,
+                )
+                .await
+            }
+            _ => Arc::new(catenary::gtfs_rt_export::PlatformStops::new()),
+        };
+
+        self.authoritative_data_store
+            .get(&chateau_id)
+            .map(|aspenised_data| {
+                catenary::gtfs_rt_export::chateau_feed_message(
+                    aspenised_data.get(),
+                    feed_type,
+                    &platform_stops,
+                )
+                .encode_to_vec()
+            })
+    }
+
     async fn get_alerts_from_route_id(
         self,
         _: context::Context,
@@ -250,6 +322,8 @@ impl AspenRpc for AspenServer {
         vehicles_response_code: Option<u16>,
         trips_response_code: Option<u16>,
         alerts_response_code: Option<u16>,
+        vehicle_consists: Option<HashMap<String, VehicleConsistSupplement>>,
+        supplementary: Option<Vec<SupplementaryRecord>>,
         time_of_submission_ms: u64,
     ) -> bool {
         let v_purehash = vehicles
@@ -261,6 +335,32 @@ impl AspenRpc for AspenServer {
         let a_purehash = alerts
             .as_ref()
             .map(|data| catenary::ahash_fast_hash(&data.as_slice()));
+        //platforms are in an AHashMap and records can arrive in any order, so both are sorted
+        //before hashing, otherwise unchanged records would look new
+        let s_purehash = supplementary.as_ref().map(|records| {
+            let mut serialized_records = records
+                .iter()
+                .map(|record| {
+                    bincode::serialize(&(
+                        &record.join_key,
+                        &record.position,
+                        record.speed,
+                        record.occupancy_status,
+                        record.occupancy_percentage,
+                        record.platforms.iter().collect::<BTreeMap<_, _>>(),
+                    ))
+                    .unwrap_or_default()
+                })
+                .collect::<Vec<Vec<u8>>>();
+
+            serialized_records.sort();
+
+            catenary::ahash_fast_hash(&serialized_records.as_slice())
+        });
+
+        //partitions of aggregate feeds are cleaned up the same way as the feed they came from
+        let source_feed_id =
+            catenary::realtime_fan_out::aggregate_feed_id(&realtime_feed_id).to_string();
 
         let existing_hashes = self.hash_of_raw_gtfs_rt_protobuf.get(&realtime_feed_id);
 
@@ -268,6 +368,7 @@ impl AspenRpc for AspenServer {
             vehicles: v_purehash,
             trips: t_purehash,
             alerts: a_purehash,
+            supplementary: s_purehash,
         };
 
         let new_data_status_from_pure_hash = match existing_hashes {
@@ -286,7 +387,7 @@ impl AspenRpc for AspenServer {
                     Some(v) => match parse_gtfs_rt_message(v.as_slice()) {
                         Ok(v) => Some(gtfs_rt_correct_route_id_string(
                             id_cleanup::gtfs_rt_cleanup(v),
-                            realtime_feed_id.as_str(),
+                            source_feed_id.as_str(),
                         )),
                         Err(e) => {
                             println!("Error decoding vehicles: {}", e);
@@ -299,7 +400,7 @@ impl AspenRpc for AspenServer {
             };
 
             let vehicles_gtfs_rt =
-                vehicles_gtfs_rt.map(|gtfs_rt_feed| match realtime_feed_id.as_str() {
+                vehicles_gtfs_rt.map(|gtfs_rt_feed| match source_feed_id.as_str() {
                     "f-amtrak~rt" => amtrak_gtfs_rt::filter_capital_corridor(gtfs_rt_feed),
                     _ => gtfs_rt_feed,
                 });
@@ -309,7 +410,7 @@ impl AspenRpc for AspenServer {
                     Some(t) => match parse_gtfs_rt_message(t.as_slice()) {
                         Ok(t) => Some(gtfs_rt_correct_route_id_string(
                             id_cleanup::gtfs_rt_cleanup(t),
-                            realtime_feed_id.as_str(),
+                            source_feed_id.as_str(),
                         )),
                         Err(e) => {
                             println!("Error decoding trips: {}", e);
@@ -321,7 +422,7 @@ impl AspenRpc for AspenServer {
                 _ => None,
             };
 
-            let trips_gtfs_rt = trips_gtfs_rt.map(|gtfs_rt_feed| match realtime_feed_id.as_str() {
+            let trips_gtfs_rt = trips_gtfs_rt.map(|gtfs_rt_feed| match source_feed_id.as_str() {
                 "f-amtrak~rt" => amtrak_gtfs_rt::filter_capital_corridor(gtfs_rt_feed),
                 _ => gtfs_rt_feed,
             });
@@ -410,12 +511,56 @@ impl AspenRpc for AspenServer {
                     save_timestamps(&self, &realtime_feed_id, GtfsRtType::Alerts, alerts_gtfs_rt);
             }
 
+            if let Some(supplementary) = supplementary {
+                let supplementary_changed =
+                    match self.supplementary_data_store.get(&realtime_feed_id) {
+                        Some(existing) => *existing.get() != supplementary,
+                        None => true,
+                    };
+
+                if supplementary_changed {
+                    self.supplementary_data_store
+                        .entry(realtime_feed_id.clone())
+                        .and_modify(|records| *records = supplementary.clone())
+                        .or_insert(supplementary);
+
+                    // the primary feeds may be unchanged, but the merged result is not
+                    new_data = true;
+                }
+            }
+
             if new_data || chateau_id == "uc~irvine~anteater~express" {
                 if let Some(vehicles_gtfs_rt) = vehicles_gtfs_rt {
                     self.authoritative_gtfs_rt_store
                         .entry((realtime_feed_id.clone(), GtfsRtType::VehiclePositions))
                         .and_modify(|gtfs_data| *gtfs_data = vehicles_gtfs_rt.clone())
                         .or_insert(vehicles_gtfs_rt.clone());
+
+                    match vehicle_consists {
+                        Some(vehicle_consists) => {
+                            // entity ids were cleaned up while parsing, so the keys have to match
+                            let vehicle_consists = vehicle_consists
+                                .into_iter()
+                                .map(|(entity_id, consist)| {
+                                    (
+                                        id_cleanup::gtfs_rt_id_cleanup(
+                                            vehicles_gtfs_rt.header.timestamp,
+                                            entity_id,
+                                        ),
+                                        consist,
+                                    )
+                                })
+                                .collect::<HashMap<String, VehicleConsistSupplement>>();
+
+                            self.vehicle_consist_store
+                                .entry(realtime_feed_id.clone())
+                                .and_modify(|consists| *consists = vehicle_consists.clone())
+                                .or_insert(vehicle_consists);
+                        }
+                        None => {
+                            self.vehicle_consist_store.remove(&realtime_feed_id);
+                        }
+                    }
                 }
 
                 if let Some(trip_gtfs_rt) = trips_gtfs_rt {
@@ -461,6 +606,9 @@ impl AspenRpc for AspenServer {
                         alerts_response_code,
                         time_of_submission_ms,
                     });
+
+                    catenary::metrics::ASPEN_ALPENROSE_QUEUE_DEPTH
+                        .set(self.alpenrose_to_process_queue.len() as i64);
                 }
             }
         }
@@ -528,6 +676,35 @@ impl AspenRpc for AspenServer {
         }
     }
 
+    async fn get_vehicle_locations_from_trip_id(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        trip_id: String,
+    ) -> Option<Vec<AspenisedVehiclePosition>> {
+        match self.authoritative_data_store.get(&chateau_id) {
+            Some(aspenised_data) => {
+                let aspenised_data = aspenised_data.get();
+
+                Some(
+                    aspenised_data
+                        .vehicle_positions
+                        .values()
+                        .filter(|vehicle_position| {
+                            vehicle_position
+                                .trip
+                                .as_ref()
+                                .and_then(|trip| trip.trip_id.as_ref())
+                                == Some(&trip_id)
+                        })
+                        .cloned()
+                        .collect(),
+                )
+            }
+            None => None,
+        }
+    }
+
     async fn get_single_vehicle_location_from_vehicle_label(
         self,
         _: context::Context,
@@ -559,6 +736,23 @@ impl AspenRpc for AspenServer {
         }
     }
 
+    async fn get_route_headways(
+        self,
+        _: context::Context,
+        chateau_id: String,
+        route_id: String,
+    ) -> Option<AspenisedRouteHeadwayHealth> {
+        self.authoritative_data_store
+            .get(&chateau_id)
+            .and_then(|aspenised_data| {
+                aspenised_data
+                    .get()
+                    .route_headway_health
+                    .get(&route_id)
+                    .cloned()
+            })
+    }
+
     async fn get_trip_updates_from_trip_id(
         self,
         _: context::Context,
@@ -634,31 +828,16 @@ async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Worker Id for this instance of Aspen
-    let this_worker_id = Arc::new(Uuid::new_v4().to_string());
-
-    let etcd_urls_original =
-        std::env::var("ETCD_URLS").unwrap_or_else(|_| "localhost:2379".to_string());
-    let etcd_urls = etcd_urls_original
-        .split(',')
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>();
-
-    let etcd_addresses = Arc::new(etcd_urls);
-
-    let etcd_username = std::env::var("ETCD_USERNAME");
-
-    let etcd_password = std::env::var("ETCD_PASSWORD");
+    let coordinator = Coordinator::from_env()
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
 
-    let etcd_connect_options: Option<etcd_client::ConnectOptions> =
-        match (etcd_username, etcd_password) {
-            (Ok(username), Ok(password)) => {
-                Some(etcd_client::ConnectOptions::new().with_user(username, password))
-            }
-            _ => None,
-        };
+    run(coordinator).await
+}
 
-    let arc_etcd_connect_options = Arc::new(etcd_connect_options.clone());
+pub async fn run(coordinator: Coordinator) -> anyhow::Result<()> {
+    // Worker Id for this instance of Aspen
+    let this_worker_id = Arc::new(Uuid::new_v4().to_string());
 
     let channel_count = std::env::var("CHANNELS")
         .expect("channels not set")
@@ -677,6 +856,13 @@ async fn main() -> anyhow::Result<()> {
     let arc_conn_pool: Arc<CatenaryPostgresPool> = Arc::new(conn_pool);
     println!("Connected to postgres");
 
+    tokio::spawn(catenary::metrics::serve_metrics(
+        catenary::metrics::metrics_addr_from_env(9102),
+    ));
+    tokio::spawn(catenary::metrics::keep_pool_metrics_fresh(Arc::clone(
+        &arc_conn_pool,
+    )));
+
     //let tailscale_ip = catenary::tailscale::interface().expect("no tailscale interface found");
 
     let server_addr = (IpAddr::V6(Ipv6Addr::LOCALHOST), 40427);
@@ -686,25 +872,16 @@ async fn main() -> anyhow::Result<()> {
     //tracing::info!("Listening on port {}", listener.local_addr().port());
     listener.config_mut().max_frame_length(usize::MAX);
 
-    //connect to etcd
-
-    let mut etcd = etcd_client::Client::connect(
-        etcd_addresses.as_slice(),
-        arc_etcd_connect_options.as_ref().to_owned(),
-    )
-    .await
-    .expect("Failed to connect to etcd");
-
     //register etcd_lease_id
 
-    let make_lease = etcd
-        .lease_grant(
-            //10 seconds
+    coordinator
+        .grant_lease(
+            etcd_lease_id_for_this_worker,
+            //5 seconds
             5,
-            Some(etcd_client::LeaseGrantOptions::new().with_id(etcd_lease_id_for_this_worker)),
         )
         .await
-        .expect("Failed to make lease with etcd");
+        .expect("Failed to make lease");
 
     //register that the worker exists
 
@@ -714,13 +891,14 @@ async fn main() -> anyhow::Result<()> {
         worker_id: this_worker_id.to_string(),
     };
 
-    let etcd_this_worker_assignment = etcd
+    coordinator
         .put(
             format!("/aspen_workers/{}", this_worker_id).as_str(),
             bincode::serialize(&worker_metadata).unwrap(),
-            Some(etcd_client::PutOptions::new().with_lease(etcd_lease_id_for_this_worker)),
+            Some(etcd_lease_id_for_this_worker),
         )
-        .await?;
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
 
     let workers_nodes: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
     let chateau_list: Arc<Mutex<Option<ChateausLeaderHashMap>>> = Arc::new(Mutex::new(None));
@@ -737,6 +915,12 @@ async fn main() -> anyhow::Result<()> {
         Arc::new(SccHashMap::new());
     let timestamps_of_gtfs_rt: Arc<SccHashMap<(String, GtfsRtType), u64>> =
         Arc::new(SccHashMap::new());
+    let vehicle_consist_store: Arc<SccHashMap<String, HashMap<String, VehicleConsistSupplement>>> =
+        Arc::new(SccHashMap::new());
+    let supplementary_data_store: Arc<SccHashMap<String, Vec<SupplementaryRecord>>> =
+        Arc::new(SccHashMap::new());
+    let platform_stop_cache: Arc<platform_stops::PlatformStopCache> = Arc::new(SccHashMap::new());
+    let stop_code_cache: Arc<platform_stops::StopCodeCache> = Arc::new(SccHashMap::new());
     //run both the leader and the listener simultaniously
 
     let workers_nodes_for_leader_thread = Arc::clone(&workers_nodes);
@@ -744,16 +928,13 @@ async fn main() -> anyhow::Result<()> {
     let this_worker_id_for_leader_thread = Arc::clone(&this_worker_id);
     let arc_conn_pool_for_leader_thread = Arc::clone(&arc_conn_pool);
 
-    let arc_etcd_connect_options_for_lt = Arc::clone(&arc_etcd_connect_options);
-
     let leader_thread_handler: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
         tokio::task::spawn(aspen_leader_thread(
             workers_nodes_for_leader_thread,
             chateau_list_for_leader_thread,
             this_worker_id_for_leader_thread,
             arc_conn_pool_for_leader_thread,
-            Arc::clone(&etcd_addresses),
-            arc_etcd_connect_options_for_lt,
+            coordinator.clone(),
             etcd_lease_id_for_this_worker,
         ));
 
@@ -769,6 +950,11 @@ async fn main() -> anyhow::Result<()> {
         b_alpenrose_to_process_queue,
         b_authoritative_gtfs_rt_store,
         b_authoritative_data_store,
+        Arc::clone(&vehicle_consist_store),
+        Arc::clone(&supplementary_data_store),
+        vehicle_history::VehicleHistoryWriter::spawn(Arc::clone(&arc_conn_pool)),
+        crowding_observations::CrowdingObservationWriter::spawn(Arc::clone(&arc_conn_pool)),
+        Arc::clone(&stop_code_cache),
         b_conn_pool,
         b_thread_count,
         Arc::clone(&alpenrose_to_process_queue_chateaus),
@@ -777,17 +963,13 @@ async fn main() -> anyhow::Result<()> {
 
     let etcd_lease_renewer: tokio::task::JoinHandle<Result<(), Box<dyn Error + Sync + Send>>> =
         tokio::task::spawn({
-            let etcd_addresses = etcd_addresses.clone();
-            let arc_etcd_connect_options = arc_etcd_connect_options.clone();
+            let coordinator = coordinator.clone();
 
             async move {
                 loop {
-                    let mut etcd = etcd_client::Client::connect(
-                        etcd_addresses.clone().as_slice(),
-                        arc_etcd_connect_options.as_ref().to_owned(),
-                    )
-                    .await?;
-                    let _ = etcd.lease_keep_alive(etcd_lease_id_for_this_worker).await?;
+                    coordinator
+                        .keep_alive(etcd_lease_id_for_this_worker)
+                        .await?;
 
                     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
                 }
@@ -820,9 +1002,11 @@ async fn main() -> anyhow::Result<()> {
                             rough_hash_of_gtfs_rt: Arc::clone(&rough_hash_of_gtfs_rt),
                             hash_of_raw_gtfs_rt_protobuf: Arc::clone(&hash_of_raw_gtfs_rt_protobuf),
                             worker_etcd_lease_id: etcd_lease_id_for_this_worker,
-                            etcd_addresses: Arc::clone(&etcd_addresses),
-                            etcd_connect_options: Arc::clone(&arc_etcd_connect_options),
+                            coordinator: coordinator.clone(),
                             timestamps_of_gtfs_rt: Arc::clone(&timestamps_of_gtfs_rt),
+                            vehicle_consist_store: Arc::clone(&vehicle_consist_store),
+                            supplementary_data_store: Arc::clone(&supplementary_data_store),
+                            platform_stop_cache: Arc::clone(&platform_stop_cache),
                             authoritative_trip_updates_by_gtfs_feed_history: Arc::new(
                                 SccHashMap::new(),
                             ),
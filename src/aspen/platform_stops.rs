@@ -0,0 +1,140 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Static stop lookups for realtime platforms, kept per chateau.
+// Platform stops of every station let the GTFS Realtime export carry a track from a platform
+// provider as the assigned_stop_id of the matching child stop. Stop codes let platforms from
+// operators that only know their station codes be matched to GTFS stop ids.
+// Static data rarely changes, so each chateau is only looked up again after an hour.
+
+use ahash::AHashMap;
+use catenary::gtfs_rt_export::PlatformStops;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::stops as stops_pg_schema;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use scc::HashMap as SccHashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PLATFORM_STOPS_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub type PlatformStopCache = SccHashMap<String, (Instant, Arc<PlatformStops>)>;
+
+/// gtfs stop id -> stop_code
+pub type StopCodes = AHashMap<String, String>;
+
+pub type StopCodeCache = SccHashMap<String, (Instant, Arc<StopCodes>)>;
+
+async fn load_platform_stops(
+    conn_pool: &CatenaryPostgresPool,
+    chateau_id: &str,
+) -> Result<PlatformStops, Box<dyn Error + Sync + Send>> {
+    let conn = &mut conn_pool.get().await?;
+
+    let rows = stops_pg_schema::dsl::stops
+        .filter(stops_pg_schema::dsl::chateau.eq(chateau_id))
+        .filter(stops_pg_schema::dsl::parent_station.is_not_null())
+        .filter(stops_pg_schema::dsl::platform_code.is_not_null())
+        .select((
+            stops_pg_schema::dsl::gtfs_id,
+            stops_pg_schema::dsl::parent_station,
+            stops_pg_schema::dsl::platform_code,
+        ))
+        .load::<(String, Option<String>, Option<String>)>(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(gtfs_id, parent_station, platform_code)| {
+            Some(((parent_station?, platform_code?), gtfs_id))
+        })
+        .collect())
+}
+
+async fn load_stop_codes(
+    conn_pool: &CatenaryPostgresPool,
+    chateau_id: &str,
+) -> Result<StopCodes, Box<dyn Error + Sync + Send>> {
+    let conn = &mut conn_pool.get().await?;
+
+    let rows = stops_pg_schema::dsl::stops
+        .filter(stops_pg_schema::dsl::chateau.eq(chateau_id))
+        .filter(stops_pg_schema::dsl::code.is_not_null())
+        .select((stops_pg_schema::dsl::gtfs_id, stops_pg_schema::dsl::code))
+        .load::<(String, Option<String>)>(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(gtfs_id, code)| Some((gtfs_id, code?)))
+        .collect())
+}
+
+async fn cached_for_chateau<T, F>(
+    cache: &SccHashMap<String, (Instant, Arc<T>)>,
+    chateau_id: &str,
+    what: &str,
+    load: F,
+) -> Arc<T>
+where
+    T: Default,
+    F: Future<Output = Result<T, Box<dyn Error + Sync + Send>>>,
+{
+    if let Some(entry) = cache.get(chateau_id) {
+        let (loaded_at, value) = entry.get();
+
+        if loaded_at.elapsed() < PLATFORM_STOPS_TTL {
+            return Arc::clone(value);
+        }
+    }
+
+    match load.await {
+        Ok(value) => {
+            let value = Arc::new(value);
+
+            cache
+                .entry(chateau_id.to_string())
+                .and_modify(|entry| *entry = (Instant::now(), Arc::clone(&value)))
+                .or_insert((Instant::now(), Arc::clone(&value)));
+
+            value
+        }
+        Err(e) => {
+            eprintln!("{}: could not load {}: {}", chateau_id, what, e);
+
+            Arc::new(T::default())
+        }
+    }
+}
+
+pub async fn platform_stops_for_chateau(
+    cache: &PlatformStopCache,
+    conn_pool: &CatenaryPostgresPool,
+    chateau_id: &str,
+) -> Arc<PlatformStops> {
+    cached_for_chateau(
+        cache,
+        chateau_id,
+        "platform stops",
+        load_platform_stops(conn_pool, chateau_id),
+    )
+    .await
+}
+
+pub async fn stop_codes_for_chateau(
+    cache: &StopCodeCache,
+    conn_pool: &CatenaryPostgresPool,
+    chateau_id: &str,
+) -> Arc<StopCodes> {
+    cached_for_chateau(
+        cache,
+        chateau_id,
+        "stop codes",
+        load_stop_codes(conn_pool, chateau_id),
+    )
+    .await
+}
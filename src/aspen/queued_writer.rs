@@ -0,0 +1,86 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Rows computed during an ingest are queued and written by a task of their own,
+// so postgres never holds up an ingest.
+// The writer also keeps whatever its caller needs to remember per chateau and vehicle between ingests.
+
+use ahash::AHashMap;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use scc::HashMap as SccHashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// batches waiting to be written, past this they are dropped rather than slowing down ingest
+const QUEUE_CAPACITY: usize = 256;
+
+pub struct QueuedWriter<VehicleState, Row> {
+    // what is written, for the log messages
+    name: &'static str,
+    // chateau -> vehicle id -> state
+    vehicle_state: SccHashMap<String, AHashMap<String, VehicleState>>,
+    queue: mpsc::Sender<Vec<Row>>,
+}
+
+impl<VehicleState, Row> QueuedWriter<VehicleState, Row>
+where
+    VehicleState: Send + Sync + 'static,
+    Row: Send + 'static,
+{
+    // starts the task that hands queued rows to write_rows
+    pub fn spawn<W, F>(
+        name: &'static str,
+        pool: Arc<CatenaryPostgresPool>,
+        write_rows: W,
+    ) -> QueuedWriter<VehicleState, Row>
+    where
+        W: Fn(Arc<CatenaryPostgresPool>, Vec<Row>) -> F + Send + 'static,
+        F: Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send,
+    {
+        let (queue, mut receiver) = mpsc::channel::<Vec<Row>>(QUEUE_CAPACITY);
+
+        tokio::spawn(async move {
+            while let Some(rows) = receiver.recv().await {
+                if let Err(e) = write_rows(Arc::clone(&pool), rows).await {
+                    eprintln!("Could not record {}: {:?}", name, e);
+                }
+            }
+        });
+
+        QueuedWriter {
+            name,
+            vehicle_state: SccHashMap::new(),
+            queue,
+        }
+    }
+
+    // rows_to_record gets the state of this chateau's vehicles, and should drop vehicles that are gone
+    pub fn record(
+        &self,
+        chateau_id: &str,
+        rows_to_record: impl FnOnce(&mut AHashMap<String, VehicleState>) -> Vec<Row>,
+    ) {
+        let rows = {
+            let mut state_for_chateau = self
+                .vehicle_state
+                .entry(chateau_id.to_string())
+                .or_default();
+
+            rows_to_record(state_for_chateau.get_mut())
+        };
+
+        if rows.is_empty() {
+            return;
+        }
+
+        if self.queue.try_send(rows).is_err() {
+            eprintln!(
+                "{}: {} queue is full, dropping {}",
+                chateau_id, self.name, self.name
+            );
+        }
+    }
+}
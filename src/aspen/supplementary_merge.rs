@@ -0,0 +1,372 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Merges records from secondary sources onto the vehicles and trips of a chateau's primary feeds.
+// Alpenrose fetches the secondary sources and sends them under their own realtime feed id.
+// Records a primary feed sends about itself, such as the platforms from an operator's train list,
+// apply to that feed without a rule and are then subject to the rules like the feed's own data.
+
+use ahash::AHashMap;
+use catenary::aspen_dataset::*;
+use scc::HashMap as SccHashMap;
+
+/// Rules come from the supplementary_merge_rules table
+#[derive(Clone, Debug, PartialEq)]
+pub struct SupplementaryMergeRule {
+    pub source_feed_id: String,
+    pub field: SupplementaryField,
+    pub mode: SupplementaryMergeMode,
+}
+
+impl SupplementaryMergeRule {
+    pub fn from_row(row: &catenary::models::SupplementaryMergeRuleRow) -> Option<Self> {
+        let field = match row.field.as_str() {
+            "position" => SupplementaryField::Position,
+            "speed" => SupplementaryField::Speed,
+            "occupancy" => SupplementaryField::Occupancy,
+            "platform" => SupplementaryField::Platform,
+            _ => return None,
+        };
+
+        let mode = match row.mode.as_str() {
+            "fill_missing" => SupplementaryMergeMode::FillMissing,
+            "override" => SupplementaryMergeMode::Override,
+            _ => return None,
+        };
+
+        Some(SupplementaryMergeRule {
+            source_feed_id: row.source_feed_id.clone(),
+            field,
+            mode,
+        })
+    }
+}
+
+pub struct SupplementaryMerge {
+    rules: Vec<SupplementaryMergeRule>,
+    records_by_source: AHashMap<String, AHashMap<SupplementaryJoinKey, SupplementaryRecord>>,
+}
+
+fn record_platform<'a>(
+    record: &'a SupplementaryRecord,
+    stop_id: &str,
+    stop_code: Option<&str>,
+) -> Option<&'a PlatformAssignment> {
+    record
+        .platforms
+        .get(stop_id)
+        .or_else(|| record.platforms.get(stop_code?))
+}
+
+impl SupplementaryMerge {
+    /// `rules` are the chateau's rules in rule_index order.
+    /// `realtime_feed_ids` are the chateau's primary feeds, whose own records are loaded too
+    pub fn new(
+        rules: Vec<SupplementaryMergeRule>,
+        realtime_feed_ids: &[String],
+        supplementary_data_store: &SccHashMap<String, Vec<SupplementaryRecord>>,
+    ) -> SupplementaryMerge {
+        let mut records_by_source = AHashMap::new();
+
+        let source_feed_ids = rules
+            .iter()
+            .map(|rule| rule.source_feed_id.as_str())
+            .chain(realtime_feed_ids.iter().map(|x| x.as_str()));
+
+        for source_feed_id in source_feed_ids {
+            if records_by_source.contains_key(source_feed_id) {
+                continue;
+            }
+
+            if let Some(records) = supplementary_data_store.get(source_feed_id) {
+                let records = records
+                    .get()
+                    .iter()
+                    .map(|record| (record.join_key.clone(), record.clone()))
+                    .collect::<AHashMap<SupplementaryJoinKey, SupplementaryRecord>>();
+
+                records_by_source.insert(source_feed_id.to_string(), records);
+            }
+        }
+
+        SupplementaryMerge {
+            rules,
+            records_by_source,
+        }
+    }
+
+    fn find(
+        &self,
+        source_feed_id: &str,
+        join_keys: &[SupplementaryJoinKey],
+    ) -> Option<&SupplementaryRecord> {
+        let records = self.records_by_source.get(source_feed_id)?;
+
+        join_keys.iter().find_map(|join_key| records.get(join_key))
+    }
+
+    pub fn merge_vehicle(
+        &self,
+        pos_aspenised: AspenisedVehiclePosition,
+    ) -> AspenisedVehiclePosition {
+        let mut pos_aspenised = pos_aspenised;
+
+        let join_keys = vehicle_join_keys(&pos_aspenised);
+
+        for rule in self.rules.iter() {
+            let record = match self.find(&rule.source_feed_id, &join_keys) {
+                Some(record) => record,
+                None => continue,
+            };
+
+            let fill_only = rule.mode == SupplementaryMergeMode::FillMissing;
+
+            match rule.field {
+                SupplementaryField::Position => {
+                    if let Some(position) = &record.position {
+                        match pos_aspenised.position.as_mut() {
+                            Some(existing) => {
+                                if !fill_only {
+                                    existing.latitude = position.latitude;
+                                    existing.longitude = position.longitude;
+                                    existing.bearing = position.bearing.or(existing.bearing);
+                                }
+                            }
+                            None => {
+                                pos_aspenised.position = Some(CatenaryRtVehiclePosition {
+                                    latitude: position.latitude,
+                                    longitude: position.longitude,
+                                    bearing: position.bearing,
+                                    odometer: None,
+                                    speed: None,
+                                });
+                            }
+                        }
+                    }
+                }
+                SupplementaryField::Speed => {
+                    if let (Some(speed), Some(existing)) =
+                        (record.speed, pos_aspenised.position.as_mut())
+                    {
+                        if !fill_only || existing.speed.is_none() {
+                            existing.speed = Some(speed);
+                        }
+                    }
+                }
+                SupplementaryField::Occupancy => {
+                    if record.occupancy_status.is_some() || record.occupancy_percentage.is_some() {
+                        if !fill_only
+                            || (pos_aspenised.occupancy_status.is_none()
+                                && pos_aspenised.occupancy_percentage.is_none())
+                        {
+                            pos_aspenised.occupancy_status = record.occupancy_status;
+                            pos_aspenised.occupancy_percentage = record.occupancy_percentage;
+                        }
+                    }
+                }
+                // platforms belong to stop time updates, see merge_platform
+                SupplementaryField::Platform => {}
+            }
+        }
+
+        pos_aspenised
+    }
+
+    /// Whether any loaded record has platforms, so stop codes are only looked up when needed
+    pub fn has_platforms(&self) -> bool {
+        self.records_by_source
+            .values()
+            .flat_map(|records| records.values())
+            .any(|record| !record.platforms.is_empty())
+    }
+
+    /// Platforms are keyed by gtfs stop id, or by stop_code for sources that only know station codes
+    pub fn merge_platform(
+        &self,
+        realtime_feed_id: &str,
+        join_keys: &[SupplementaryJoinKey],
+        stop_id: &str,
+        stop_code: Option<&str>,
+    ) -> Option<PlatformAssignment> {
+        let mut platform_assignment = self
+            .find(realtime_feed_id, join_keys)
+            .and_then(|record| record_platform(record, stop_id, stop_code))
+            .cloned();
+
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.field == SupplementaryField::Platform)
+        {
+            let record_platform = self
+                .find(&rule.source_feed_id, join_keys)
+                .and_then(|record| record_platform(record, stop_id, stop_code));
+
+            if let Some(record_platform) = record_platform {
+                if rule.mode == SupplementaryMergeMode::Override || platform_assignment.is_none() {
+                    platform_assignment = Some(record_platform.clone());
+                }
+            }
+        }
+
+        platform_assignment
+    }
+}
+
+pub fn vehicle_join_keys(pos_aspenised: &AspenisedVehiclePosition) -> Vec<SupplementaryJoinKey> {
+    let mut join_keys = vec![];
+
+    if let Some(vehicle) = &pos_aspenised.vehicle {
+        if let Some(id) = &vehicle.id {
+            join_keys.push(SupplementaryJoinKey::VehicleId(id.clone()));
+        }
+
+        if let Some(label) = &vehicle.label {
+            join_keys.push(SupplementaryJoinKey::VehicleLabel(label.clone()));
+        }
+    }
+
+    if let Some(trip) = &pos_aspenised.trip {
+        if let Some(trip_id) = &trip.trip_id {
+            join_keys.push(SupplementaryJoinKey::TripId(trip_id.clone()));
+        }
+
+        if let Some(trip_short_name) = &trip.trip_short_name {
+            join_keys.push(SupplementaryJoinKey::TripShortName(trip_short_name.clone()));
+        }
+    }
+
+    join_keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_vehicles;
+
+    fn vehicle(id: &str, position: Option<CatenaryRtVehiclePosition>) -> AspenisedVehiclePosition {
+        AspenisedVehiclePosition {
+            position,
+            route_type: 2,
+            ..test_vehicles::vehicle(id)
+        }
+    }
+
+    fn position(latitude: f32, speed: Option<f32>) -> CatenaryRtVehiclePosition {
+        CatenaryRtVehiclePosition {
+            latitude,
+            longitude: -118.25,
+            bearing: None,
+            odometer: None,
+            speed,
+        }
+    }
+
+    #[test]
+    fn metrolink_train_list_only_fills_missing_positions() {
+        let store = SccHashMap::new();
+
+        let _ = store.insert(
+            "f-metrolinktrains~extra~rt".to_string(),
+            vec![SupplementaryRecord {
+                join_key: SupplementaryJoinKey::VehicleId("M123".to_string()),
+                position: Some(position(34.1, None)),
+                speed: Some(20.0),
+                occupancy_status: None,
+                occupancy_percentage: None,
+                platforms: AHashMap::new(),
+            }],
+        );
+
+        let rules = vec![
+            SupplementaryMergeRule {
+                source_feed_id: "f-metrolinktrains~extra~rt".to_string(),
+                field: SupplementaryField::Position,
+                mode: SupplementaryMergeMode::FillMissing,
+            },
+            SupplementaryMergeRule {
+                source_feed_id: "f-metrolinktrains~extra~rt".to_string(),
+                field: SupplementaryField::Speed,
+                mode: SupplementaryMergeMode::FillMissing,
+            },
+        ];
+
+        let merge = SupplementaryMerge::new(rules, &[], &store);
+
+        let filled = merge.merge_vehicle(vehicle("M123", None));
+        assert_eq!(filled.position, Some(position(34.1, Some(20.0))));
+
+        let untouched = merge.merge_vehicle(vehicle("M123", Some(position(34.0, Some(5.0)))));
+        assert_eq!(untouched.position, Some(position(34.0, Some(5.0))));
+
+        let unmatched = merge.merge_vehicle(vehicle("M456", None));
+        assert_eq!(unmatched.position, None);
+    }
+
+    #[test]
+    fn rules_are_read_from_rows() {
+        let row = |field: &str, mode: &str| catenary::models::SupplementaryMergeRuleRow {
+            chateau: "metrolinktrains".to_string(),
+            rule_index: 0,
+            source_feed_id: "f-metrolinktrains~extra~rt".to_string(),
+            field: field.to_string(),
+            mode: mode.to_string(),
+        };
+
+        assert_eq!(
+            SupplementaryMergeRule::from_row(&row("platform", "override")),
+            Some(SupplementaryMergeRule {
+                source_feed_id: "f-metrolinktrains~extra~rt".to_string(),
+                field: SupplementaryField::Platform,
+                mode: SupplementaryMergeMode::Override,
+            })
+        );
+        assert_eq!(
+            SupplementaryMergeRule::from_row(&row("platform", "sometimes")),
+            None
+        );
+        assert_eq!(
+            SupplementaryMergeRule::from_row(&row("colour", "override")),
+            None
+        );
+    }
+
+    #[test]
+    fn feeds_use_their_own_platforms_by_stop_code() {
+        let store = SccHashMap::new();
+
+        let platform = PlatformAssignment {
+            platform: "19".to_string(),
+            confidence: PlatformConfidence::Posted,
+            changed_from_scheduled: true,
+        };
+
+        let _ = store.insert(
+            "f-mta~nyc~rt~lirr".to_string(),
+            vec![SupplementaryRecord {
+                join_key: SupplementaryJoinKey::TripShortName("1234".to_string()),
+                position: None,
+                speed: None,
+                occupancy_status: None,
+                occupancy_percentage: None,
+                platforms: AHashMap::from_iter([("NYK".to_string(), platform.clone())]),
+            }],
+        );
+
+        let merge = SupplementaryMerge::new(vec![], &["f-mta~nyc~rt~lirr".to_string()], &store);
+
+        assert!(merge.has_platforms());
+
+        let join_keys = vec![SupplementaryJoinKey::TripShortName("1234".to_string())];
+
+        assert_eq!(
+            merge.merge_platform("f-mta~nyc~rt~lirr", &join_keys, "237", Some("NYK")),
+            Some(platform)
+        );
+        assert_eq!(
+            merge.merge_platform("f-mta~nyc~rt~lirr", &join_keys, "237", None),
+            None
+        );
+    }
+}
@@ -0,0 +1,32 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Vehicle positions for the tests of the modules that look at aspenised vehicles
+
+use catenary::aspen_dataset::{AspenisedVehicleDescriptor, AspenisedVehiclePosition};
+
+// a bus with only a vehicle id, tests set the fields they need with ..vehicle(id)
+pub fn vehicle(id: &str) -> AspenisedVehiclePosition {
+    AspenisedVehiclePosition {
+        trip: None,
+        vehicle: Some(AspenisedVehicleDescriptor {
+            id: Some(id.to_string()),
+            label: None,
+            license_plate: None,
+            wheelchair_accessible: None,
+        }),
+        position: None,
+        timestamp: None,
+        route_type: 3,
+        current_stop_sequence: None,
+        current_status: None,
+        congestion_level: None,
+        occupancy_status: None,
+        occupancy_percentage: None,
+        trip_progress: None,
+        multi_carriage_details: vec![],
+        consist_length: None,
+        scheduled_consist_length: None,
+    }
+}
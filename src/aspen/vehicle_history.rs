@@ -0,0 +1,221 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Keeps a downsampled history of vehicle positions in postgres, so incidents can be looked at
+// after the live positions have been overwritten.
+// VEHICLE_HISTORY_INTERVAL_SECONDS (default 30) is the minimum time between two saved positions of
+// one vehicle, VEHICLE_HISTORY_RETENTION_DAYS (default 7) how long they are kept.
+// Rows are written through a QueuedWriter.
+
+use ahash::AHashMap;
+use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::models::VehiclePositionHistoryRow;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::vehicle_position_history;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use lazy_static::lazy_static;
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::queued_writer::QueuedWriter;
+
+lazy_static! {
+    static ref INTERVAL_MS: i64 = std::env::var("VEHICLE_HISTORY_INTERVAL_SECONDS")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(30)
+        * 1000;
+    static ref RETENTION_DAYS: i64 = std::env::var("VEHICLE_HISTORY_RETENTION_DAYS")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(7);
+}
+
+fn rows_to_record(
+    chateau_id: &str,
+    vehicle_positions: &AHashMap<String, AspenisedVehiclePosition>,
+    last_recorded_ms: &mut AHashMap<String, i64>,
+    now_ms: i64,
+    interval_ms: i64,
+) -> Vec<VehiclePositionHistoryRow> {
+    let mut rows = vec![];
+    let mut still_present: AHashMap<String, i64> = AHashMap::new();
+
+    for (entity_id, vehicle_position) in vehicle_positions {
+        let position = match &vehicle_position.position {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let vehicle_id = vehicle_position
+            .vehicle
+            .as_ref()
+            .and_then(|vehicle| vehicle.id.clone())
+            .unwrap_or_else(|| entity_id.clone());
+
+        let recorded_at_ms = vehicle_position
+            .timestamp
+            .map(|timestamp| timestamp as i64 * 1000)
+            .unwrap_or(now_ms);
+
+        let previous = last_recorded_ms.get(&vehicle_id).copied();
+
+        let due = match previous {
+            Some(previous) => recorded_at_ms - previous >= interval_ms,
+            None => true,
+        };
+
+        if !due {
+            still_present.insert(vehicle_id, previous.unwrap());
+            continue;
+        }
+
+        still_present.insert(vehicle_id.clone(), recorded_at_ms);
+
+        rows.push(VehiclePositionHistoryRow {
+            chateau: chateau_id.to_string(),
+            vehicle_id,
+            recorded_at_ms,
+            vehicle_label: vehicle_position
+                .vehicle
+                .as_ref()
+                .and_then(|vehicle| vehicle.label.clone()),
+            trip_id: vehicle_position
+                .trip
+                .as_ref()
+                .and_then(|trip| trip.trip_id.clone()),
+            route_id: vehicle_position
+                .trip
+                .as_ref()
+                .and_then(|trip| trip.route_id.clone()),
+            latitude: position.latitude,
+            longitude: position.longitude,
+            bearing: position.bearing,
+            speed: position.speed,
+        });
+    }
+
+    // forget vehicles that are gone so the map does not grow forever
+    *last_recorded_ms = still_present;
+
+    rows
+}
+
+pub struct VehicleHistoryWriter {
+    // vehicle id -> time of the last saved position
+    writer: QueuedWriter<i64, VehiclePositionHistoryRow>,
+}
+
+impl VehicleHistoryWriter {
+    // starts the task that writes queued positions to postgres
+    pub fn spawn(pool: Arc<CatenaryPostgresPool>) -> Arc<VehicleHistoryWriter> {
+        Arc::new(VehicleHistoryWriter {
+            writer: QueuedWriter::spawn("vehicle history", pool, write_rows),
+        })
+    }
+
+    pub fn record(
+        &self,
+        chateau_id: &str,
+        vehicle_positions: &AHashMap<String, AspenisedVehiclePosition>,
+    ) {
+        let now_ms = catenary::duration_since_unix_epoch().as_millis() as i64;
+
+        self.writer.record(chateau_id, |last_recorded_ms| {
+            rows_to_record(
+                chateau_id,
+                vehicle_positions,
+                last_recorded_ms,
+                now_ms,
+                *INTERVAL_MS,
+            )
+        });
+    }
+}
+
+async fn write_rows(
+    pool: Arc<CatenaryPostgresPool>,
+    rows: Vec<VehiclePositionHistoryRow>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn = &mut pool.get().await?;
+
+    for chunk in rows.chunks(5000) {
+        diesel::insert_into(vehicle_position_history::dsl::vehicle_position_history)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn purge_expired_positions(
+    pool: &CatenaryPostgresPool,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let cutoff_ms = catenary::duration_since_unix_epoch().as_millis() as i64
+        - *RETENTION_DAYS * 24 * 60 * 60 * 1000;
+
+    let conn = &mut pool.get().await?;
+
+    let deleted = diesel::delete(
+        vehicle_position_history::dsl::vehicle_position_history
+            .filter(vehicle_position_history::dsl::recorded_at_ms.lt(cutoff_ms)),
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_vehicles;
+    use catenary::aspen_dataset::CatenaryRtVehiclePosition;
+
+    fn vehicle(id: &str, timestamp: u64) -> AspenisedVehiclePosition {
+        AspenisedVehiclePosition {
+            position: Some(CatenaryRtVehiclePosition {
+                latitude: 34.05,
+                longitude: -118.25,
+                bearing: None,
+                odometer: None,
+                speed: None,
+            }),
+            timestamp: Some(timestamp),
+            ..test_vehicles::vehicle(id)
+        }
+    }
+
+    #[test]
+    fn positions_are_downsampled_per_vehicle() {
+        let mut last_recorded_ms = AHashMap::new();
+
+        let mut positions = AHashMap::new();
+        positions.insert("1".to_string(), vehicle("bus-1", 1_000));
+        positions.insert("2".to_string(), vehicle("bus-2", 1_000));
+
+        let first = rows_to_record("c", &positions, &mut last_recorded_ms, 0, 30_000);
+        assert_eq!(first.len(), 2);
+
+        positions.insert("1".to_string(), vehicle("bus-1", 1_010));
+        positions.insert("2".to_string(), vehicle("bus-2", 1_040));
+
+        let second = rows_to_record("c", &positions, &mut last_recorded_ms, 0, 30_000);
+        assert_eq!(
+            second
+                .iter()
+                .map(|row| row.vehicle_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bus-2"]
+        );
+
+        positions.remove("2");
+
+        rows_to_record("c", &positions, &mut last_recorded_ms, 0, 30_000);
+        assert!(!last_recorded_ms.contains_key("bus-2"));
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+use ahash::{AHashMap, AHashSet};
+use catenary::aspen_dataset::*;
+use catenary::shape_progress::{progress_between_stops, ProjectedShape};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+
+// vehicles further than this from their shape are probably detoured or on the wrong trip
+const MAX_DISTANCE_FROM_SHAPE_M: f64 = 300.0;
+
+/// Loads shapes and stop distances for any itinerary patterns not already in the cache,
+/// and drops the ones no longer referenced by a running trip.
+pub async fn refresh_shape_progress_cache(
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    chateau_id: &str,
+    cache: &mut ShapeProgressInternalCache,
+    itinerary_pattern_metas: &AHashMap<String, catenary::models::ItineraryPatternMeta>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let shape_ids_in_use = itinerary_pattern_metas
+        .values()
+        .filter_map(|meta| meta.shape_id.clone())
+        .collect::<AHashSet<String>>();
+
+    cache
+        .shapes
+        .retain(|shape_id, _| shape_ids_in_use.contains(shape_id));
+    cache
+        .itinerary_stop_distances
+        .retain(|itinerary_pattern_id, _| {
+            itinerary_pattern_metas.contains_key(itinerary_pattern_id)
+        });
+
+    let shape_ids_to_lookup = shape_ids_in_use
+        .iter()
+        .filter(|shape_id| !cache.shapes.contains_key(shape_id.as_str()))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    if !shape_ids_to_lookup.is_empty() {
+        use catenary::schema::gtfs::shapes as shapes_pg_schema;
+
+        let shapes = shapes_pg_schema::dsl::shapes
+            .filter(shapes_pg_schema::dsl::chateau.eq(chateau_id))
+            .filter(shapes_pg_schema::dsl::shape_id.eq_any(&shape_ids_to_lookup))
+            .select((
+                shapes_pg_schema::dsl::shape_id,
+                shapes_pg_schema::dsl::linestring,
+            ))
+            .load::<(
+                String,
+                postgis_diesel::types::LineString<postgis_diesel::types::Point>,
+            )>(conn)
+            .await?;
+
+        for (shape_id, linestring) in shapes {
+            if let Some(projected_shape) = ProjectedShape::from_linestring(&linestring) {
+                cache.shapes.insert(shape_id, projected_shape);
+            }
+        }
+    }
+
+    let itinerary_patterns_to_lookup = itinerary_pattern_metas
+        .values()
+        .filter(|meta| meta.shape_id.is_some())
+        .filter(|meta| {
+            !cache
+                .itinerary_stop_distances
+                .contains_key(&meta.itinerary_pattern_id)
+        })
+        .map(|meta| meta.itinerary_pattern_id.clone())
+        .collect::<Vec<String>>();
+
+    if itinerary_patterns_to_lookup.is_empty() {
+        return Ok(());
+    }
+
+    use catenary::schema::gtfs::itinerary_pattern as itinerary_pattern_pg_schema;
+
+    let itinerary_rows = itinerary_pattern_pg_schema::dsl::itinerary_pattern
+        .filter(itinerary_pattern_pg_schema::dsl::chateau.eq(chateau_id))
+        .filter(
+            itinerary_pattern_pg_schema::dsl::itinerary_pattern_id
+                .eq_any(&itinerary_patterns_to_lookup),
+        )
+        .order(itinerary_pattern_pg_schema::dsl::stop_sequence.asc())
+        .select(catenary::models::ItineraryPatternRow::as_select())
+        .load::<catenary::models::ItineraryPatternRow>(conn)
+        .await?;
+
+    let stop_ids_to_lookup = itinerary_rows
+        .iter()
+        .map(|row| row.stop_id.to_string())
+        .collect::<AHashSet<String>>()
+        .into_iter()
+        .collect::<Vec<String>>();
+
+    use catenary::schema::gtfs::stops as stops_pg_schema;
+
+    let stops = stops_pg_schema::dsl::stops
+        .filter(stops_pg_schema::dsl::chateau.eq(chateau_id))
+        .filter(stops_pg_schema::dsl::gtfs_id.eq_any(&stop_ids_to_lookup))
+        .select((
+            stops_pg_schema::dsl::onestop_feed_id,
+            stops_pg_schema::dsl::gtfs_id,
+            stops_pg_schema::dsl::point,
+        ))
+        .load::<(String, String, Option<postgis_diesel::types::Point>)>(conn)
+        .await?;
+
+    // (onestop_feed_id, stop_id) -> (lon, lat)
+    let stop_points: AHashMap<(String, String), (f64, f64)> = stops
+        .into_iter()
+        .filter_map(|(feed_id, stop_id, point)| {
+            point.map(|point| ((feed_id, stop_id), (point.x, point.y)))
+        })
+        .collect();
+
+    let mut rows_by_itinerary: AHashMap<String, Vec<catenary::models::ItineraryPatternRow>> =
+        AHashMap::new();
+
+    for row in itinerary_rows {
+        rows_by_itinerary
+            .entry(row.itinerary_pattern_id.clone())
+            .or_default()
+            .push(row);
+    }
+
+    for (itinerary_pattern_id, rows) in rows_by_itinerary {
+        let shape = itinerary_pattern_metas
+            .get(&itinerary_pattern_id)
+            .and_then(|meta| meta.shape_id.as_ref())
+            .and_then(|shape_id| cache.shapes.get(shape_id));
+
+        if let Some(shape) = shape {
+            let stop_coords = rows
+                .iter()
+                .map(|row| {
+                    stop_points
+                        .get(&(row.onestop_feed_id.clone(), row.stop_id.to_string()))
+                        .copied()
+                })
+                .collect::<Vec<Option<(f64, f64)>>>();
+
+            let distances = shape.stop_distances_along_shape(&stop_coords);
+
+            let stops = rows
+                .iter()
+                .zip(distances)
+                .map(|(row, distance_along_shape_m)| ShapeProgressStop {
+                    stop_id: row.stop_id.clone(),
+                    gtfs_stop_sequence: row.gtfs_stop_sequence,
+                    distance_along_shape_m,
+                    time_since_start: row
+                        .arrival_time_since_start
+                        .or(row.departure_time_since_start)
+                        .or(row.interpolated_time_since_start),
+                })
+                .collect::<Vec<ShapeProgressStop>>();
+
+            cache
+                .itinerary_stop_distances
+                .insert(itinerary_pattern_id, stops);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn trip_progress_for_vehicle(
+    vehicle: &AspenisedVehiclePosition,
+    trip_id_to_trip: &AHashMap<String, catenary::models::CompressedTrip>,
+    itinerary_pattern_metas: &AHashMap<String, catenary::models::ItineraryPatternMeta>,
+    cache: &ShapeProgressInternalCache,
+) -> Option<AspenisedTripProgress> {
+    let position = vehicle.position.as_ref()?;
+    let trip_id = vehicle.trip.as_ref()?.trip_id.as_ref()?;
+    let compressed_trip = trip_id_to_trip.get(trip_id)?;
+    let itinerary_pattern_meta =
+        itinerary_pattern_metas.get(&compressed_trip.itinerary_pattern_id)?;
+    let shape_id = itinerary_pattern_meta.shape_id.as_ref()?;
+    let shape = cache.shapes.get(shape_id)?;
+
+    let stops = cache
+        .itinerary_stop_distances
+        .get(&compressed_trip.itinerary_pattern_id);
+
+    // on loop routes, the stop the vehicle is heading to tells us which pass of the shape it is on
+    let min_distance_along_m = match (stops, vehicle.current_stop_sequence) {
+        (Some(stops), Some(current_stop_sequence)) => stops
+            .iter()
+            .take_while(|stop| stop.gtfs_stop_sequence < current_stop_sequence)
+            .filter_map(|stop| stop.distance_along_shape_m)
+            .last(),
+        _ => None,
+    };
+
+    let projection = shape.project(
+        position.longitude as f64,
+        position.latitude as f64,
+        min_distance_along_m,
+    )?;
+
+    if projection.distance_from_shape_m > MAX_DISTANCE_FROM_SHAPE_M {
+        return None;
+    }
+
+    let (previous_stop_id, next_stop_id, fraction_between_stops) = match stops {
+        Some(stops) => {
+            let stop_distances = stops
+                .iter()
+                .map(|stop| stop.distance_along_shape_m)
+                .collect::<Vec<Option<f64>>>();
+
+            let progress =
+                progress_between_stops(&stop_distances, projection.distance_along_shape_m);
+
+            (
+                progress
+                    .previous_stop_index
+                    .map(|index| stops[index].stop_id.clone()),
+                progress
+                    .next_stop_index
+                    .map(|index| stops[index].stop_id.clone()),
+                progress.fraction_between_stops,
+            )
+        }
+        None => (None, None, None),
+    };
+
+    Some(AspenisedTripProgress {
+        shape_id: shape_id.clone(),
+        distance_along_shape_m: projection.distance_along_shape_m,
+        snapped_latitude: projection.snapped_lat as f32,
+        snapped_longitude: projection.snapped_lon as f32,
+        distance_from_shape_m: projection.distance_from_shape_m,
+        inferred_bearing: match position.bearing {
+            Some(_) => None,
+            None => Some(projection.bearing),
+        },
+        previous_stop_id,
+        next_stop_id,
+        fraction_between_stops,
+    })
+}
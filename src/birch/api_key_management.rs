@@ -1,17 +1,20 @@
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use argon2::{
-    password_hash::{PasswordHasher, SaltString},
-    Argon2,
-};
+use catenary::admin_auth;
+use catenary::admin_auth::{AdminAuditAction, AdminRole, AuthenticatedAdmin};
+use catenary::agency_secret::keyring::master_keyring;
+use catenary::agency_secret::StoredPasswordFormat;
 use catenary::postgres_tools::CatenaryPostgresPool;
 use diesel::ExpressionMethods;
+use diesel::OptionalExtension;
 use diesel::QueryDsl;
 use diesel::SelectableHelper;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
 use diesel_async::RunQueryDsl;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::error::Error;
+use std::str::FromStr;
 use std::sync::Arc;
 
 #[derive(Serialize, Clone, Deserialize, Debug)]
@@ -25,41 +28,145 @@ struct KeyResponse {
     passwords: HashMap<String, EachPasswordRow>,
 }
 
-// Admin Credential login
-pub async fn login(
-    pool: Arc<CatenaryPostgresPool>,
-    email: &str,
-    password: &str,
-) -> Result<bool, Box<dyn Error + Send + Sync>> {
+#[derive(Deserialize)]
+struct LoginReq {
+    email: String,
+    password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LoginResponse {
+    token: String,
+    expires_ms: i64,
+    role: AdminRole,
+}
+
+fn session_token_from_request(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim())
+}
+
+// Resolves the session on the request and checks that the admin holds at least `required_role`
+pub async fn authorise_request(
+    pool: &Arc<CatenaryPostgresPool>,
+    req: &HttpRequest,
+    required_role: AdminRole,
+) -> Result<AuthenticatedAdmin, HttpResponse> {
+    let token = match session_token_from_request(req) {
+        Some(token) => token,
+        None => return Err(HttpResponse::Unauthorized().finish()),
+    };
+
+    let conn_pre = pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    match admin_auth::admin_from_session_token(conn, token).await {
+        Ok(Some(admin)) => match admin.role.permits(required_role) {
+            true => Ok(admin),
+            false => {
+                println!(
+                    "{} has role {} but {} is required",
+                    admin.email,
+                    admin.role.as_str(),
+                    required_role.as_str()
+                );
+                Err(HttpResponse::Forbidden().finish())
+            }
+        },
+        Ok(None) => Err(HttpResponse::Unauthorized().finish()),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[actix_web::post("/admin/login")]
+pub async fn admin_login(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    web::Form(LoginReq { email, password }): web::Form<LoginReq>,
+) -> impl Responder {
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
-    let conn = &mut conn_pre.unwrap();
-
-    use catenary::schema::gtfs::admin_credentials as admin_credentials_table;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
 
-    let admin_credentials = admin_credentials_table::table
-        .filter(admin_credentials_table::email.eq(email))
-        .select(admin_credentials_table::all_columns)
-        .load::<catenary::models::AdminCredentials>(conn)
-        .await?;
+    let admin_credentials = match admin_auth::verify_admin_password(conn, &email, &password).await {
+        Ok(Some(admin_credentials)) => admin_credentials,
+        Ok(None) => {
+            println!("Failed login attempt for {}", email);
+            return HttpResponse::Unauthorized().finish();
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
 
-    match admin_credentials.len() {
-        0 => Ok(false),
-        _ => {
-            let admin_credentials = admin_credentials[0].clone();
+    let role = match AdminRole::from_str(&admin_credentials.role) {
+        Ok(role) => role,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::Forbidden().finish();
+        }
+    };
 
-            let db_hash = admin_credentials.hash.clone();
-            let db_salt = SaltString::from_b64(admin_credentials.salt.as_str()).unwrap();
+    match admin_auth::create_session(conn, &admin_credentials.email).await {
+        Ok((token, session)) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .json(LoginResponse {
+                token,
+                expires_ms: session.expires_ms,
+                role,
+            }),
+        Err(e) => {
+            eprintln!("could not create session\n{:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
 
-            let argon2 = Argon2::default();
-            let user_submitted_hash = argon2
-                .hash_password(password.as_bytes(), &db_salt)
-                .unwrap()
-                .to_string();
+#[actix_web::post("/admin/logout")]
+pub async fn admin_logout(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+) -> impl Responder {
+    let token = match session_token_from_request(&req) {
+        Some(token) => token,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
 
-            let is_authorised = user_submitted_hash == db_hash;
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
 
-            Ok(is_authorised)
+    match admin_auth::delete_session(conn, token).await {
+        Ok(()) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .finish(),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
         }
     }
 }
@@ -73,37 +180,114 @@ pub async fn set_realtime_key(
 ) -> impl Responder {
     let feed_id = feed_id.into_inner();
 
-    let email = req.headers().get("email").unwrap().to_str().unwrap();
-    let password = req.headers().get("password").unwrap().to_str().unwrap();
-
-    let is_authorised = login(pool.as_ref().clone(), email, password).await.unwrap();
-
-    if !is_authorised {
-        println!("User not authenticated! {}", email);
-        return HttpResponse::Unauthorized().finish();
-    }
+    let admin = match authorise_request(pool.as_ref(), &req, AdminRole::KeyEditor).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
 
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
-    let conn = &mut conn_pre.unwrap();
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
 
     let time = catenary::duration_since_unix_epoch().as_millis() as i64;
 
-    let data = ron::from_str::<EachPasswordRow>(&input_data);
+    let data = match ron::from_str::<EachPasswordRow>(&input_data) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("could not deserialise realtime credentials\n{}", e);
+            return HttpResponse::InternalServerError().body("Deserialise password failed");
+        }
+    };
 
-    if data.is_err() {
-        return HttpResponse::InternalServerError().body("Deserialise password failed");
-    }
+    use catenary::schema::gtfs::realtime_feeds as realtime_feeds_table;
+    use catenary::schema::gtfs::realtime_passwords as realtime_passwords_table;
 
-    let data = data.unwrap();
+    //look up the previous values for the audit log
+    let previous_password_row = realtime_passwords_table::table
+        .filter(realtime_passwords_table::onestop_feed_id.eq(&feed_id))
+        .select(catenary::models::RealtimePasswordRow::as_select())
+        .first::<catenary::models::RealtimePasswordRow>(conn)
+        .await
+        .optional();
+
+    let previous_fetch_interval_ms = realtime_feeds_table::table
+        .filter(realtime_feeds_table::onestop_feed_id.eq(&feed_id))
+        .select(realtime_feeds_table::fetch_interval_ms)
+        .first::<Option<i32>>(conn)
+        .await
+        .optional();
+
+    let (previous_password_row, previous_fetch_interval_ms) =
+        match (previous_password_row, previous_fetch_interval_ms) {
+            (Ok(previous_password_row), Ok(previous_fetch_interval_ms)) => {
+                (previous_password_row, previous_fetch_interval_ms.flatten())
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("could not read previous feed settings\n{}", e);
+                return HttpResponse::InternalServerError().body("read previous settings failed");
+            }
+        };
+
+    let previous_passwords = previous_password_row
+        .and_then(|row| row.passwords)
+        .and_then(|value| StoredPasswordFormat::from_value(value).ok());
+
+    //only the sealed form is stored, birch never opens it again
+    let passwords_to_store = match data.passwords {
+        None => None,
+        // the redacted keys from /getrealtimekeys sent back, maybe with other settings changed
+        Some(passwords) if passwords.contains_redacted() => {
+            match previous_passwords
+                .as_ref()
+                .and_then(|previous| previous.keep_passwords_with_settings_of(&passwords))
+            {
+                Some(kept) => Some(kept),
+                None => {
+                    return HttpResponse::BadRequest().body(
+                        "Redacted keys can only be sent back for the same accounts, send every key to change them",
+                    )
+                }
+            }
+        }
+        Some(passwords) => {
+            let keyring = match master_keyring() {
+                Some(keyring) => keyring,
+                None => {
+                    return HttpResponse::InternalServerError()
+                        .body("No master key to seal credentials with")
+                }
+            };
+
+            match passwords.seal(keyring, &feed_id) {
+                Ok(sealed) => Some(StoredPasswordFormat::Sealed(sealed)),
+                Err(e) => {
+                    eprintln!("could not seal realtime credentials\n{}", e);
+                    return HttpResponse::InternalServerError().body("Sealing credentials failed");
+                }
+            }
+        }
+    };
+
+    let credentials_changed = previous_passwords != passwords_to_store;
 
     //convert password format to js value
-    let password_for_postgres = data
-        .passwords
+    let password_for_postgres = match passwords_to_store
         .as_ref()
-        .map(|x| serde_json::to_value(x).unwrap());
-
-    use catenary::schema::gtfs::realtime_passwords as realtime_passwords_table;
+        .map(serde_json::to_value)
+        .transpose()
+    {
+        Ok(password_for_postgres) => password_for_postgres,
+        Err(e) => {
+            eprintln!("could not serialise realtime credentials\n{}", e);
+            return HttpResponse::InternalServerError().body("Serialising credentials failed");
+        }
+    };
 
     //insert or update the password
     use catenary::models::RealtimePasswordRow;
@@ -114,46 +298,66 @@ pub async fn set_realtime_key(
         last_updated_ms: time,
     };
 
-    let insert_result = diesel::insert_into(realtime_passwords_table::table)
-        .values(password_row)
-        .on_conflict(realtime_passwords_table::onestop_feed_id)
-        .do_update()
-        .set((
-            realtime_passwords_table::passwords.eq(password_for_postgres),
-            realtime_passwords_table::last_updated_ms.eq(time),
-        ))
-        .execute(conn)
-        .await;
-
-    if let Err(insert_result) = &insert_result {
-        eprintln!(
-            "could not insert / update realtime passwords\n{}",
-            insert_result
-        );
-
-        return HttpResponse::InternalServerError().body("insert into realtime passwords failed");
-    }
-
-    //upload the fetch interval
+    // the credentials, the fetch interval and the audit log change together or not at all
+    let save_result = conn
+        .transaction::<_, Box<dyn std::error::Error + Send + Sync>, _>(|conn| {
+            async move {
+                diesel::insert_into(realtime_passwords_table::table)
+                    .values(password_row)
+                    .on_conflict(realtime_passwords_table::onestop_feed_id)
+                    .do_update()
+                    .set((
+                        realtime_passwords_table::passwords.eq(password_for_postgres),
+                        realtime_passwords_table::last_updated_ms.eq(time),
+                    ))
+                    .execute(conn)
+                    .await?;
+
+                diesel::update(
+                    realtime_feeds_table::table
+                        .filter(realtime_feeds_table::onestop_feed_id.eq(&feed_id)),
+                )
+                .set(realtime_feeds_table::fetch_interval_ms.eq(data.fetch_interval_ms))
+                .execute(conn)
+                .await?;
+
+                //record who changed what, the credentials themselves are never written to the audit log
+                if credentials_changed {
+                    admin_auth::insert_audit_log(
+                        conn,
+                        &admin.email,
+                        &feed_id,
+                        AdminAuditAction::SetRealtimeCredentials,
+                        None,
+                        None,
+                    )
+                    .await?;
+                }
 
-    use catenary::schema::gtfs::realtime_feeds as realtime_feeds_table;
+                if previous_fetch_interval_ms != data.fetch_interval_ms {
+                    admin_auth::insert_audit_log(
+                        conn,
+                        &admin.email,
+                        &feed_id,
+                        AdminAuditAction::SetFetchInterval,
+                        previous_fetch_interval_ms.map(|x| x.to_string()),
+                        data.fetch_interval_ms.map(|x| x.to_string()),
+                    )
+                    .await?;
+                }
 
-    let update_result = diesel::update(
-        realtime_feeds_table::table.filter(realtime_feeds_table::onestop_feed_id.eq(&feed_id)),
-    )
-    .set(realtime_feeds_table::fetch_interval_ms.eq(data.fetch_interval_ms))
-    .execute(conn)
-    .await;
+                Ok(())
+            }
+            .scope_boxed()
+        })
+        .await;
 
-    if let Err(update_result) = &update_result {
-        eprintln!(
-            "could not insert / update realtime update interval\n{}",
-            update_result
-        );
+    if let Err(e) = save_result {
+        eprintln!("could not save realtime credentials\n{}", e);
 
         return HttpResponse::InternalServerError()
             .append_header(("Cache-Control", "no-cache"))
-            .body("insert update interval fail");
+            .body("Saving realtime credentials failed");
     }
 
     HttpResponse::Ok()
@@ -161,87 +365,132 @@ pub async fn set_realtime_key(
         .finish()
 }
 
-#[actix_web::get("/exportrealtimekeys/")]
-pub async fn export_realtime_keys(
-    pool: web::Data<Arc<CatenaryPostgresPool>>,
-    req: HttpRequest,
-) -> impl Responder {
-    let email = req.headers().get("email");
-    let password = req.headers().get("password");
+fn realtime_keys_csv(
+    realtime_passwords: Vec<catenary::models::RealtimePasswordRow>,
+    can_export_secrets: bool,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    use csv::WriterBuilder;
 
-    if email.is_none() || password.is_none() {
-        return HttpResponse::Unauthorized().finish();
-    }
+    let mut wtr = WriterBuilder::new().from_writer(vec![]);
 
-    let email = email.unwrap().to_str().unwrap();
-    let password = password.unwrap().to_str().unwrap();
+    wtr.serialize(("onestop_feed_id", "passwords", "last_updated_ms"))?;
+
+    for row in realtime_passwords {
+        let stored = row
+            .passwords
+            .map(StoredPasswordFormat::from_value)
+            .transpose()?;
+
+        let exported = match stored {
+            Some(stored) => Some(match (can_export_secrets, master_keyring()) {
+                // rows from before encryption are sealed on the way out
+                (true, Some(keyring)) => {
+                    match stored.clone().into_sealed(keyring, &row.onestop_feed_id) {
+                        Ok(sealed) => serde_json::to_string(&StoredPasswordFormat::Sealed(sealed))?,
+                        Err(e) => {
+                            eprintln!("could not seal {} for export\n{}", row.onestop_feed_id, e);
+                            serde_json::to_string(&stored.redacted())?
+                        }
+                    }
+                }
+                _ => serde_json::to_string(&stored.redacted())?,
+            }),
+            None => None,
+        };
 
-    println!("email: {}, password: {}", email, password);
+        wtr.serialize((row.onestop_feed_id, exported, row.last_updated_ms))?;
+    }
 
-    let is_authorised = login(pool.as_ref().clone(), email, password).await.unwrap();
+    Ok(String::from_utf8(wtr.into_inner()?)?)
+}
 
-    if !is_authorised {
-        return HttpResponse::Unauthorized().finish();
-    }
+/// Redacted unless the admin was granted `can_export_secrets`, which exports the sealed form.
+/// Plaintext is never exported, opening credentials only happens in alpenrose.
+#[actix_web::get("/exportrealtimekeys/")]
+pub async fn export_realtime_keys(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match authorise_request(pool.as_ref(), &req, AdminRole::KeyViewer).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
 
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
-    let conn = &mut conn_pre.unwrap();
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
 
     use catenary::schema::gtfs::realtime_passwords as realtime_passwords_table;
 
-    let realtime_passwords = realtime_passwords_table::table
+    let realtime_passwords = match realtime_passwords_table::table
         .select(catenary::models::RealtimePasswordRow::as_select())
         .load::<catenary::models::RealtimePasswordRow>(conn)
-        .await;
-
-    //convert to csv
-    use csv::WriterBuilder;
-
-    let mut wtr = WriterBuilder::new().from_writer(vec![]);
+        .await
+    {
+        Ok(realtime_passwords) => realtime_passwords,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError()
+                .append_header(("Cache-Control", "no-cache"))
+                .finish();
+        }
+    };
 
-    wtr.serialize(("onestop_feed_id", "passwords", "last_updated_ms"))
-        .unwrap();
+    let data_str = match realtime_keys_csv(realtime_passwords, admin.can_export_secrets) {
+        Ok(data_str) => data_str,
+        Err(e) => {
+            eprintln!("could not write realtime keys csv\n{}", e);
+            return HttpResponse::InternalServerError()
+                .append_header(("Cache-Control", "no-cache"))
+                .finish();
+        }
+    };
 
-    for row in realtime_passwords.unwrap() {
-        wtr.serialize((row.onestop_feed_id, row.passwords, row.last_updated_ms))
-            .unwrap();
+    if admin.can_export_secrets {
+        if let Err(e) = admin_auth::insert_audit_log(
+            conn,
+            &admin.email,
+            "*",
+            AdminAuditAction::ExportRealtimeCredentials,
+            None,
+            None,
+        )
+        .await
+        {
+            eprintln!("could not write audit log\n{:?}", e);
+        }
     }
 
-    let data_str = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
-
     HttpResponse::Ok()
         .append_header(("Cache-Control", "no-cache"))
         .body(data_str)
 }
 
-#[derive(Deserialize)]
-struct RetrieveReq {
-    email: String,
-    password: String,
-}
-
 #[actix_web::post("/getrealtimekeys")]
 pub async fn get_realtime_keys(
     pool: web::Data<Arc<CatenaryPostgresPool>>,
     req: HttpRequest,
-    web::Form(RetrieveReq { email, password }): web::Form<RetrieveReq>,
 ) -> impl Responder {
     //check if the user is authorised
-
-    println!("login attempt: email: {}, password: {}", email, password);
-
-    let is_authorised = login(pool.as_ref().clone(), &email, &password)
-        .await
-        .unwrap();
-
-    if !is_authorised {
-        return HttpResponse::Unauthorized().finish();
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::KeyViewer).await {
+        return response;
     }
 
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
-    let conn = &mut conn_pre.unwrap();
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
 
     use catenary::schema::gtfs::realtime_passwords as realtime_passwords_table;
 
@@ -265,12 +514,23 @@ pub async fn get_realtime_keys(
                         Option<catenary::agency_secret::PasswordFormat>,
                     > = HashMap::new();
                     for password in passwords {
-                        let password_formatted = password.passwords.as_ref().map(|value| {
-                            serde_json::from_value::<catenary::agency_secret::PasswordFormat>(
-                                value.clone(),
-                            )
-                            .unwrap()
-                        });
+                        let password_formatted = match password
+                            .passwords
+                            .clone()
+                            .map(StoredPasswordFormat::from_value)
+                            .transpose()
+                        {
+                            Ok(stored) => stored.map(|stored| stored.redacted()),
+                            Err(e) => {
+                                eprintln!(
+                                    "Credentials of {} could not be read: {:?}",
+                                    password.onestop_feed_id, e
+                                );
+                                return HttpResponse::InternalServerError()
+                                    .append_header(("Cache-Control", "no-cache"))
+                                    .finish();
+                            }
+                        };
 
                         raw_password_data
                             .insert(password.onestop_feed_id.clone(), password_formatted);
@@ -316,3 +576,60 @@ pub async fn get_realtime_keys(
         }
     }
 }
+
+#[derive(Deserialize)]
+struct AuditLogQuery {
+    onestop_feed_id: Option<String>,
+    limit: Option<i64>,
+}
+
+#[actix_web::get("/admin/audit_log")]
+pub async fn get_audit_log(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+    query: web::Query<AuditLogQuery>,
+) -> impl Responder {
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::FeedAdmin).await {
+        return response;
+    }
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    use catenary::schema::gtfs::admin_audit_log as admin_audit_log_table;
+
+    let limit = query.limit.unwrap_or(500).clamp(1, 5000);
+
+    let mut audit_query = admin_audit_log_table::table
+        .select(catenary::models::AdminAuditLogRow::as_select())
+        .order(admin_audit_log_table::changed_ms.desc())
+        .limit(limit)
+        .into_boxed();
+
+    if let Some(onestop_feed_id) = &query.onestop_feed_id {
+        audit_query =
+            audit_query.filter(admin_audit_log_table::onestop_feed_id.eq(onestop_feed_id));
+    }
+
+    match audit_query
+        .load::<catenary::models::AdminAuditLogRow>(conn)
+        .await
+    {
+        Ok(rows) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .json(rows),
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            HttpResponse::InternalServerError()
+                .append_header(("Cache-Control", "no-cache"))
+                .finish()
+        }
+    }
+}
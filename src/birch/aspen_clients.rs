@@ -0,0 +1,39 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// tarpc clients to aspen workers, kept open between requests instead of connecting for every one
+
+use catenary::aspen::lib::AspenRpcClient;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct AspenClients {
+    clients: Mutex<HashMap<SocketAddr, AspenRpcClient>>,
+}
+
+impl AspenClients {
+    pub async fn client_for(
+        &self,
+        socket: &SocketAddr,
+    ) -> Result<AspenRpcClient, Box<dyn std::error::Error + Sync + Send>> {
+        if let Some(client) = self.clients.lock().unwrap().get(socket) {
+            return Ok(client.clone());
+        }
+
+        let client = catenary::aspen::lib::spawn_aspen_client_from_ip(socket).await?;
+
+        self.clients.lock().unwrap().insert(*socket, client.clone());
+
+        Ok(client)
+    }
+
+    // after a failed call, so the next one reconnects
+    pub fn forget(&self, socket: &SocketAddr) {
+        self.clients.lock().unwrap().remove(socket);
+    }
+}
@@ -4,11 +4,10 @@ use catenary::aspen::lib::ChateauMetadataEtcd;
 use catenary::aspen::lib::GetVehicleLocationsResponse;
 use catenary::aspen_dataset::AspenisedVehiclePosition;
 use catenary::aspen_dataset::AspenisedVehicleRouteCache;
-use catenary::EtcdConnectionIps;
+use catenary::coordination::{Coordination, Coordinator};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
-use std::sync::Arc;
 use tarpc::context;
 
 #[derive(Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
@@ -82,22 +81,9 @@ pub struct BulkFetchResponse {
 #[actix_web::post("/bulk_realtime_fetch_v1")]
 pub async fn bulk_realtime_fetch_v1(
     req: HttpRequest,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
+    coordinator: web::Data<Coordinator>,
     params: web::Json<BulkFetchParams>,
 ) -> impl Responder {
-    let etcd =
-        etcd_client::Client::connect(etcd_connection_ips.ip_addresses.as_slice(), None).await;
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
     let mut bulk_fetch_response = BulkFetchResponse {
         chateaus: BTreeMap::new(),
     };
@@ -118,15 +104,12 @@ pub async fn bulk_realtime_fetch_v1(
     let etcd_data_list: Vec<(
         &String,
         &ChateauAskParams,
-        Result<etcd_client::GetResponse, etcd_client::Error>,
+        catenary::coordination::CoordinationResult<Option<Vec<u8>>>,
     )> = futures::stream::iter(params.chateaus.iter().map(|(chateau_id, chateau_params)| {
-        let mut etcd = etcd.clone();
+        let coordinator = coordinator.clone();
         async move {
-            let fetch_assigned_node_for_this_realtime_feed = etcd
-                .get(
-                    format!("/aspen_assigned_chateaus/{}", chateau_id).as_str(),
-                    None,
-                )
+            let fetch_assigned_node_for_this_realtime_feed = coordinator
+                .get(format!("/aspen_assigned_chateaus/{}", chateau_id).as_str())
                 .await;
 
             (
@@ -148,16 +131,15 @@ pub async fn bulk_realtime_fetch_v1(
                 (chateau_id, chateau_params, etcd_data.as_ref().unwrap())
             })
             .map(|(chateau_id, chateau_params, etcd_data_list)| async move {
-                if etcd_data_list.kvs().is_empty() {
-                    return (chateau_id, None, chateau_params);
-                }
+                let etcd_data_list = match etcd_data_list {
+                    Some(etcd_data_list) => etcd_data_list,
+                    None => return (chateau_id, None, chateau_params),
+                };
 
                 //deserialise into ChateauMetadataZookeeper
 
-                let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-                    etcd_data_list.kvs().first().unwrap().value(),
-                )
-                .unwrap();
+                let assigned_chateau_data =
+                    bincode::deserialize::<ChateauMetadataEtcd>(etcd_data_list).unwrap();
 
                 //then connect to the node via tarpc
 
@@ -288,25 +270,12 @@ pub async fn bulk_realtime_fetch_v1(
 #[actix_web::get("/get_realtime_locations/{chateau_id}/{category}/{last_updated_time_ms}/{existing_fasthash_of_routes}")]
 pub async fn get_realtime_locations(
     req: HttpRequest,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
+    coordinator: web::Data<Coordinator>,
     path: web::Path<(String, String, u64, u64)>,
 ) -> impl Responder {
     let (chateau_id, category, client_last_updated_time_ms, existing_fasthash_of_routes) =
         path.into_inner();
 
-    let etcd =
-        etcd_client::Client::connect(etcd_connection_ips.ip_addresses.as_slice(), None).await;
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
     let category_requested = match category.as_str() {
         "metro" => CategoryOfRealtimeVehicleData::Metro,
         "bus" => CategoryOfRealtimeVehicleData::Bus,
@@ -322,11 +291,8 @@ pub async fn get_realtime_locations(
 
     //first identify which node to connect to
 
-    let fetch_assigned_node_for_this_realtime_feed = etcd
-        .get(
-            format!("/aspen_assigned_chateaus/{}", chateau_id).as_str(),
-            None,
-        )
+    let fetch_assigned_node_for_this_realtime_feed = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", chateau_id).as_str())
         .await;
 
     if let Err(err_fetch) = &fetch_assigned_node_for_this_realtime_feed {
@@ -342,22 +308,23 @@ pub async fn get_realtime_locations(
     let fetch_assigned_node_for_this_realtime_feed =
         fetch_assigned_node_for_this_realtime_feed.unwrap();
 
-    if fetch_assigned_node_for_this_realtime_feed.kvs().is_empty() {
-        return HttpResponse::Ok()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("No assigned node found for this chateau");
-    }
+    let fetch_assigned_node_for_this_realtime_feed =
+        match fetch_assigned_node_for_this_realtime_feed {
+            Some(fetch_assigned_node_for_this_realtime_feed) => {
+                fetch_assigned_node_for_this_realtime_feed
+            }
+            None => {
+                return HttpResponse::Ok()
+                    .append_header(("Cache-Control", "no-cache"))
+                    .body("No assigned node found for this chateau");
+            }
+        };
 
     //deserialise into ChateauMetadataZookeeper
 
-    let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-        fetch_assigned_node_for_this_realtime_feed
-            .kvs()
-            .first()
-            .unwrap()
-            .value(),
-    )
-    .unwrap();
+    let assigned_chateau_data =
+        bincode::deserialize::<ChateauMetadataEtcd>(&fetch_assigned_node_for_this_realtime_feed)
+            .unwrap();
 
     //then connect to the node via tarpc
 
@@ -0,0 +1,386 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Which chateaus cover a place, so the map only fetches the chateaus it can see.
+// Point and bbox lookups return feeds and agencies without geometry,
+// hulls are simplified to the requested zoom, and the boundaries are also served as vector tiles.
+
+use actix_web::{web, HttpResponse, Responder};
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::sync::Arc;
+use tilejson::TileJSON;
+
+// square degrees, past this the client should use the vector tiles instead
+const MAX_HULL_BBOX_AREA: f64 = 2500.;
+const MAX_ZOOM: u8 = 22;
+
+#[derive(Serialize, Clone, Debug)]
+struct CoverageAgency {
+    static_onestop_id: String,
+    agency_id: String,
+    agency_name: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ChateauCoverage {
+    chateau: String,
+    realtime_feeds: Vec<String>,
+    schedule_feeds: Vec<String>,
+    agencies: Vec<CoverageAgency>,
+}
+
+#[derive(Deserialize)]
+struct PointQuery {
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Deserialize)]
+struct BboxQuery {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+}
+
+#[derive(Deserialize)]
+struct HullQuery {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+    zoom: u8,
+}
+
+fn valid_bbox(min_lon: f64, min_lat: f64, max_lon: f64, max_lat: f64) -> bool {
+    min_lon < max_lon
+        && min_lat < max_lat
+        && (-180.0..=180.0).contains(&min_lon)
+        && (-180.0..=180.0).contains(&max_lon)
+        && (-90.0..=90.0).contains(&min_lat)
+        && (-90.0..=90.0).contains(&max_lat)
+}
+
+/// Half a pixel of a 256px tile at this zoom, in degrees.
+/// Anything smaller than that can't be seen, so it isn't sent.
+fn simplify_tolerance_degrees(zoom: u8) -> f64 {
+    360. / (256. * 2f64.powi(zoom.min(MAX_ZOOM) as i32)) / 2.
+}
+
+/// Half a unit of the 4096 tile grid at this zoom, in web mercator metres
+fn simplify_tolerance_metres(zoom: u8) -> f64 {
+    40_075_016.686 / (4096. * 2f64.powi(zoom.min(MAX_ZOOM) as i32)) / 2.
+}
+
+fn feeds_from_row(row: &sqlx::postgres::PgRow, column: &str) -> Vec<String> {
+    row.get::<Vec<Option<String>>, _>(column)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Attaches agencies to the chateaus returned by `where_clause`, a condition on gtfs.chateaus
+async fn coverage_where(
+    sqlx_pool: &sqlx::Pool<sqlx::Postgres>,
+    where_clause: &str,
+    binds: &[f64],
+) -> Result<Vec<ChateauCoverage>, sqlx::Error> {
+    let query_str = format!(
+        "SELECT chateau, static_feeds, realtime_feeds FROM gtfs.chateaus WHERE {} ORDER BY chateau",
+        where_clause
+    );
+
+    let mut query = sqlx::query(query_str.as_str());
+
+    for bind in binds {
+        query = query.bind(*bind);
+    }
+
+    let chateau_rows = query.fetch_all(sqlx_pool).await?;
+
+    let chateau_ids = chateau_rows
+        .iter()
+        .map(|row| row.get::<String, _>("chateau"))
+        .collect::<Vec<String>>();
+
+    let agency_rows = sqlx::query(
+        "SELECT DISTINCT chateau, static_onestop_id, agency_id, agency_name FROM gtfs.agencies WHERE chateau = ANY($1) ORDER BY chateau, agency_name",
+    )
+    .bind(&chateau_ids)
+    .fetch_all(sqlx_pool)
+    .await?;
+
+    let mut agencies_by_chateau: AHashMap<String, Vec<CoverageAgency>> = AHashMap::new();
+
+    for row in agency_rows {
+        agencies_by_chateau
+            .entry(row.get::<String, _>("chateau"))
+            .or_default()
+            .push(CoverageAgency {
+                static_onestop_id: row.get("static_onestop_id"),
+                agency_id: row.get("agency_id"),
+                agency_name: row.get("agency_name"),
+            });
+    }
+
+    Ok(chateau_rows
+        .iter()
+        .map(|row| {
+            let chateau: String = row.get("chateau");
+
+            ChateauCoverage {
+                agencies: agencies_by_chateau.remove(&chateau).unwrap_or_default(),
+                realtime_feeds: feeds_from_row(row, "realtime_feeds"),
+                schedule_feeds: feeds_from_row(row, "static_feeds"),
+                chateau,
+            }
+        })
+        .collect())
+}
+
+fn coverage_response(coverage: Result<Vec<ChateauCoverage>, sqlx::Error>) -> HttpResponse {
+    match coverage {
+        Ok(coverage) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "max-age=3600, public"))
+            .json(coverage),
+        Err(err) => {
+            eprintln!("{:?}", err);
+            HttpResponse::InternalServerError().body("Failed to fetch from postgres!")
+        }
+    }
+}
+
+#[actix_web::get("/chateau_coverage/point")]
+pub async fn chateaus_at_point(
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    query: web::Query<PointQuery>,
+) -> impl Responder {
+    if !(-180.0..=180.0).contains(&query.lon) || !(-90.0..=90.0).contains(&query.lat) {
+        return HttpResponse::BadRequest().body("Invalid point");
+    }
+
+    let coverage = coverage_where(
+        sqlx_pool.as_ref().as_ref(),
+        "ST_Intersects(hull, ST_SetSRID(ST_MakePoint($1, $2), 4326))",
+        &[query.lon, query.lat],
+    )
+    .await;
+
+    coverage_response(coverage)
+}
+
+#[actix_web::get("/chateau_coverage/bbox")]
+pub async fn chateaus_in_bbox(
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    query: web::Query<BboxQuery>,
+) -> impl Responder {
+    if !valid_bbox(query.min_lon, query.min_lat, query.max_lon, query.max_lat) {
+        return HttpResponse::BadRequest().body("Invalid bbox");
+    }
+
+    let coverage = coverage_where(
+        sqlx_pool.as_ref().as_ref(),
+        "ST_Intersects(hull, ST_MakeEnvelope($1, $2, $3, $4, 4326))",
+        &[query.min_lon, query.min_lat, query.max_lon, query.max_lat],
+    )
+    .await;
+
+    coverage_response(coverage)
+}
+
+#[actix_web::get("/chateau_coverage/hulls")]
+pub async fn chateau_hulls(
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    query: web::Query<HullQuery>,
+) -> impl Responder {
+    if !valid_bbox(query.min_lon, query.min_lat, query.max_lon, query.max_lat) {
+        return HttpResponse::BadRequest().body("Invalid bbox");
+    }
+
+    if (query.max_lon - query.min_lon) * (query.max_lat - query.min_lat) > MAX_HULL_BBOX_AREA {
+        return HttpResponse::BadRequest().body("Bbox too large");
+    }
+
+    let rows = sqlx::query(
+        "SELECT chateau, static_feeds, realtime_feeds, ST_AsGeoJSON(ST_SimplifyPreserveTopology(hull, $5)) AS hull FROM gtfs.chateaus WHERE hull && ST_MakeEnvelope($1, $2, $3, $4, 4326) ORDER BY chateau",
+    )
+    .bind(query.min_lon)
+    .bind(query.min_lat)
+    .bind(query.max_lon)
+    .bind(query.max_lat)
+    .bind(simplify_tolerance_degrees(query.zoom))
+    .fetch_all(sqlx_pool.as_ref().as_ref())
+    .await;
+
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("{:?}", err);
+            return HttpResponse::InternalServerError().body("Failed to fetch from postgres!");
+        }
+    };
+
+    let features = rows
+        .iter()
+        .filter_map(|row| {
+            let chateau: String = row.get("chateau");
+
+            let geometry = row
+                .get::<Option<String>, _>("hull")
+                .and_then(|hull| serde_json::from_str::<geojson::Geometry>(&hull).ok())?;
+
+            let mut properties = serde_json::Map::new();
+
+            properties.insert(
+                String::from("chateau"),
+                serde_json::Value::String(chateau.clone()),
+            );
+            properties.insert(
+                String::from("realtime_feeds"),
+                serde_json::json!(feeds_from_row(row, "realtime_feeds")),
+            );
+            properties.insert(
+                String::from("schedule_feeds"),
+                serde_json::json!(feeds_from_row(row, "static_feeds")),
+            );
+
+            Some(geojson::Feature {
+                bbox: None,
+                geometry: Some(geometry),
+                id: Some(geojson::feature::Id::String(chateau)),
+                properties: Some(properties),
+                foreign_members: None,
+            })
+        })
+        .collect::<Vec<geojson::Feature>>();
+
+    let feature_collection = geojson::FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/geo+json"))
+        .insert_header(("Cache-Control", "max-age=3600, public"))
+        .body(geojson::GeoJson::from(feature_collection).to_string())
+}
+
+#[actix_web::get("/chateau_boundaries")]
+pub async fn chateau_boundaries_meta() -> impl Responder {
+    let mut fields = std::collections::BTreeMap::new();
+    fields.insert(String::from("chateau"), String::from("text"));
+    fields.insert(String::from("realtime_feeds"), String::from("text[]"));
+    fields.insert(String::from("static_feeds"), String::from("text[]"));
+
+    let fields = tilejson::VectorLayer::new(String::from("data"), fields);
+
+    let tile_json = TileJSON {
+        vector_layers: Some(vec![fields]),
+        tilejson: String::from("3.0.0"),
+        bounds: None,
+        center: None,
+        data: None,
+        description: None,
+        fillzoom: None,
+        grids: None,
+        legend: None,
+        maxzoom: Some(15),
+        minzoom: None,
+        name: Some(String::from("chateau_boundaries")),
+        scheme: None,
+        template: None,
+        version: None,
+        other: std::collections::BTreeMap::new(),
+        tiles: vec![String::from(
+            "https://birch.catenarymaps.org/chateau_boundaries/{z}/{x}/{y}.pbf",
+        )],
+        attribution: None,
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/json"))
+        .insert_header(("Cache-Control", "max-age=1000, public"))
+        .body(serde_json::to_string(&tile_json).unwrap())
+}
+
+#[actix_web::get("/chateau_boundaries/{z}/{x}/{y}.pbf")]
+pub async fn chateau_boundaries_tile(
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    path: web::Path<(u8, u32, u32)>,
+) -> impl Responder {
+    let (z, x, y) = path.into_inner();
+
+    if z > 30 || x >= 1 << z || y >= 1 << z {
+        return HttpResponse::BadRequest().body("Invalid tile");
+    }
+
+    let query_str = format!(
+        "
+    SELECT
+    ST_AsMVT(q, 'data', 4096, 'geom')
+FROM (
+    SELECT
+        chateau,
+        realtime_feeds,
+        static_feeds,
+        ST_AsMVTGeom(ST_SimplifyPreserveTopology(ST_Transform(hull, 3857), {tolerance}),
+        ST_TileEnvelope({z}, {x}, {y}), 4096, 64, true) AS geom
+    FROM
+        gtfs.chateaus
+    WHERE
+        hull && ST_Transform(ST_TileEnvelope({z}, {x}, {y}), 4326)
+) q",
+        z = z,
+        x = x,
+        y = y,
+        tolerance = simplify_tolerance_metres(z)
+    );
+
+    match sqlx::query(query_str.as_str())
+        .fetch_one(sqlx_pool.as_ref().as_ref())
+        .await
+    {
+        Ok(mvt_result) => {
+            let mvt_bytes: Vec<u8> = mvt_result.get(0);
+
+            HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/x-protobuf"))
+                .insert_header(("Cache-Control", "max-age=3600, public"))
+                .body(mvt_bytes)
+        }
+        Err(err) => {
+            eprintln!("{:?}", err);
+            HttpResponse::InternalServerError().body("Failed to fetch from postgres!")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerance_halves_with_each_zoom_level() {
+        assert!((simplify_tolerance_degrees(0) - 360. / 512.).abs() < 1e-12);
+        assert!((simplify_tolerance_degrees(5) * 2. - simplify_tolerance_degrees(4)).abs() < 1e-12);
+        assert_eq!(
+            simplify_tolerance_degrees(40),
+            simplify_tolerance_degrees(MAX_ZOOM)
+        );
+        assert!(simplify_tolerance_metres(14) < 1.);
+    }
+
+    #[test]
+    fn rejects_inverted_and_out_of_range_bboxes() {
+        assert!(valid_bbox(-118.5, 33.7, -117.9, 34.3));
+        assert!(!valid_bbox(-117.9, 33.7, -118.5, 34.3));
+        assert!(!valid_bbox(-181., 33.7, -117.9, 34.3));
+        assert!(!valid_bbox(-118.5, 33.7, -117.9, 91.));
+    }
+}
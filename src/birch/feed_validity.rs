@@ -0,0 +1,284 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Warns about chateaus whose production schedule runs out soon or already has.
+// feed_expiration_date from feed_info is used when present,
+// otherwise the last date calendar or calendar_dates has service.
+
+use actix_web::{web, HttpResponse, Responder};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::prelude::*;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const DEFAULT_WARNING_DAYS: i64 = 14;
+
+pub type FeedValidityState = RwLock<Vec<FeedValidity>>;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedValidityStatus {
+    Valid,
+    ExpiringSoon,
+    Expired,
+    // no feed_info and no calendar, nothing to go by
+    Unknown,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FeedValidity {
+    chateau: Option<String>,
+    onestop_feed_id: String,
+    attempt_id: String,
+    last_service_date: Option<chrono::NaiveDate>,
+    days_remaining: Option<i64>,
+    status: FeedValidityStatus,
+}
+
+#[derive(Deserialize)]
+struct FeedValidityQuery {
+    /// flag feeds ending within this many days, FEED_EXPIRY_WARNING_DAYS or 14 if missing
+    within_days: Option<i64>,
+    /// also list feeds that are not flagged
+    all: Option<bool>,
+}
+
+fn warning_days_from_env() -> i64 {
+    std::env::var("FEED_EXPIRY_WARNING_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_WARNING_DAYS)
+}
+
+fn classify(
+    today: chrono::NaiveDate,
+    last_service_date: Option<chrono::NaiveDate>,
+    warning_days: i64,
+) -> (Option<i64>, FeedValidityStatus) {
+    match last_service_date {
+        None => (None, FeedValidityStatus::Unknown),
+        Some(last_service_date) => {
+            let days_remaining = (last_service_date - today).num_days();
+
+            let status = if days_remaining < 0 {
+                FeedValidityStatus::Expired
+            } else if days_remaining <= warning_days {
+                FeedValidityStatus::ExpiringSoon
+            } else {
+                FeedValidityStatus::Valid
+            };
+
+            (Some(days_remaining), status)
+        }
+    }
+}
+
+async fn check_feed_validity(
+    arc_conn_pool: &Arc<CatenaryPostgresPool>,
+    warning_days: i64,
+) -> Result<Vec<FeedValidity>, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let production_attempts = catenary::schema::gtfs::ingested_static::dsl::ingested_static
+        .filter(catenary::schema::gtfs::ingested_static::dsl::production.eq(true))
+        .filter(catenary::schema::gtfs::ingested_static::dsl::deleted.eq(false))
+        .select(catenary::models::IngestedStatic::as_select())
+        .load::<catenary::models::IngestedStatic>(conn)
+        .await?;
+
+    let attempt_ids = production_attempts
+        .iter()
+        .map(|attempt| attempt.attempt_id.clone())
+        .collect::<Vec<String>>();
+
+    let calendar_ends = {
+        use catenary::schema::gtfs::calendar::dsl::*;
+
+        calendar
+            .filter(attempt_id.eq_any(&attempt_ids))
+            .group_by((onestop_feed_id, attempt_id))
+            .select((onestop_feed_id, attempt_id, diesel::dsl::max(gtfs_end_date)))
+            .load::<(String, String, Option<chrono::NaiveDate>)>(conn)
+            .await?
+    };
+
+    let added_date_ends = {
+        use catenary::schema::gtfs::calendar_dates::dsl::*;
+
+        calendar_dates
+            .filter(attempt_id.eq_any(&attempt_ids))
+            .filter(exception_type.eq(1))
+            .group_by((onestop_feed_id, attempt_id))
+            .select((onestop_feed_id, attempt_id, diesel::dsl::max(gtfs_date)))
+            .load::<(String, String, Option<chrono::NaiveDate>)>(conn)
+            .await?
+    };
+
+    let mut service_ends: HashMap<(String, String), chrono::NaiveDate> = HashMap::new();
+
+    for (feed_id, attempt, end_date) in calendar_ends.into_iter().chain(added_date_ends) {
+        if let Some(end_date) = end_date {
+            let existing = service_ends.entry((feed_id, attempt)).or_insert(end_date);
+            *existing = (*existing).max(end_date);
+        }
+    }
+
+    let chateaus = catenary::schema::gtfs::chateaus::dsl::chateaus
+        .select((
+            catenary::schema::gtfs::chateaus::dsl::chateau,
+            catenary::schema::gtfs::chateaus::dsl::static_feeds,
+        ))
+        .load::<(String, Vec<Option<String>>)>(conn)
+        .await?;
+
+    let chateau_of_feed: HashMap<String, String> = chateaus
+        .into_iter()
+        .flat_map(|(chateau, static_feeds)| {
+            static_feeds
+                .into_iter()
+                .flatten()
+                .map(move |feed_id| (feed_id, chateau.clone()))
+        })
+        .collect();
+
+    let today = chrono::Utc::now().date_naive();
+
+    let mut feeds = production_attempts
+        .into_iter()
+        .map(|attempt| {
+            let last_service_date = attempt.feed_expiration_date.or_else(|| {
+                service_ends
+                    .get(&(attempt.onestop_feed_id.clone(), attempt.attempt_id.clone()))
+                    .copied()
+            });
+
+            let (days_remaining, status) = classify(today, last_service_date, warning_days);
+
+            FeedValidity {
+                chateau: chateau_of_feed.get(&attempt.onestop_feed_id).cloned(),
+                onestop_feed_id: attempt.onestop_feed_id,
+                attempt_id: attempt.attempt_id,
+                last_service_date,
+                days_remaining,
+                status,
+            }
+        })
+        .collect::<Vec<FeedValidity>>();
+
+    feeds.sort_by_key(|feed| {
+        (
+            feed.days_remaining.unwrap_or(i64::MAX),
+            feed.onestop_feed_id.clone(),
+        )
+    });
+
+    Ok(feeds)
+}
+
+/// Rechecks every production feed hourly and logs the ones running out
+pub async fn feed_validity_check_loop(
+    state: Arc<FeedValidityState>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) {
+    let warning_days = warning_days_from_env();
+
+    loop {
+        match check_feed_validity(&arc_conn_pool, warning_days).await {
+            Ok(feeds) => {
+                for feed in &feeds {
+                    match feed.status {
+                        FeedValidityStatus::Expired => eprintln!(
+                            "Feed {} of chateau {:?} expired on {:?}",
+                            feed.onestop_feed_id, feed.chateau, feed.last_service_date
+                        ),
+                        FeedValidityStatus::ExpiringSoon => eprintln!(
+                            "Feed {} of chateau {:?} expires in {:?} days",
+                            feed.onestop_feed_id, feed.chateau, feed.days_remaining
+                        ),
+                        _ => {}
+                    }
+                }
+
+                *state.write().unwrap() = feeds;
+            }
+            Err(e) => {
+                eprintln!("Could not check feed validity: {}", e);
+            }
+        }
+
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+#[actix_web::get("/feed_validity")]
+pub async fn feed_validity(
+    state: web::Data<Arc<FeedValidityState>>,
+    query: web::Query<FeedValidityQuery>,
+) -> impl Responder {
+    let warning_days = query.within_days.unwrap_or_else(warning_days_from_env);
+    let include_all = query.all.unwrap_or(false);
+
+    let today = chrono::Utc::now().date_naive();
+
+    let feeds = state
+        .read()
+        .unwrap()
+        .iter()
+        .map(|feed| {
+            let (days_remaining, status) = classify(today, feed.last_service_date, warning_days);
+
+            FeedValidity {
+                days_remaining,
+                status,
+                ..feed.clone()
+            }
+        })
+        .filter(|feed| include_all || feed.status != FeedValidityStatus::Valid)
+        .collect::<Vec<FeedValidity>>();
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-cache"))
+        .json(feeds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_feeds_by_days_left() {
+        let today = chrono::NaiveDate::from_ymd_opt(2025, 3, 1).unwrap();
+
+        assert_eq!(
+            classify(today, chrono::NaiveDate::from_ymd_opt(2025, 2, 28), 14),
+            (Some(-1), FeedValidityStatus::Expired)
+        );
+        assert_eq!(
+            classify(today, Some(today), 14),
+            (Some(0), FeedValidityStatus::ExpiringSoon)
+        );
+        assert_eq!(
+            classify(today, chrono::NaiveDate::from_ymd_opt(2025, 3, 15), 14),
+            (Some(14), FeedValidityStatus::ExpiringSoon)
+        );
+        assert_eq!(
+            classify(today, chrono::NaiveDate::from_ymd_opt(2025, 3, 16), 14),
+            (Some(15), FeedValidityStatus::Valid)
+        );
+        assert_eq!(
+            classify(today, None, 14),
+            (None, FeedValidityStatus::Unknown)
+        );
+    }
+}
@@ -3,15 +3,18 @@ use actix_web::{web, HttpResponse, Responder};
 use catenary::aspen::lib::ChateauMetadataEtcd;
 use catenary::aspen_dataset::AspenStopTimeEvent;
 use catenary::aspen_dataset::AspenisedAlert;
+use catenary::aspen_dataset::AspenisedTripProgress;
 use catenary::aspen_dataset::AspenisedVehicleDescriptor;
 use catenary::aspen_dataset::AspenisedVehiclePosition;
+use catenary::aspen_dataset::BlockDelayPrediction;
+use catenary::aspen_dataset::PlatformConfidence;
+use catenary::coordination::{Coordination, Coordinator};
 use catenary::postgres_tools::CatenaryPostgresPool;
 use catenary::schema::gtfs::itinerary_pattern as itinerary_pattern_pg_schema;
 use catenary::schema::gtfs::itinerary_pattern_meta as itinerary_pattern_meta_pg_schema;
 use catenary::schema::gtfs::routes as routes_pg_schema;
 use catenary::schema::gtfs::stops as stops_pg_schema;
 use catenary::schema::gtfs::trips_compressed as trips_compressed_pg_schema;
-use catenary::EtcdConnectionIps;
 use chrono::TimeZone;
 use chrono_tz::Tz;
 use compact_str::CompactString;
@@ -36,43 +39,22 @@ pub async fn get_vehicle_metadata(path: web::Path<(String, String)>) -> impl Res
 #[actix_web::get("/get_vehicle_information_from_label/{chateau}/{vehicle_label}")]
 pub async fn get_vehicle_information_from_label(
     path: web::Path<(String, String)>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    coordinator: web::Data<Coordinator>,
 ) -> impl Responder {
     let (chateau, vehicle_label) = path.into_inner();
 
-    let etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await;
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
-    let fetch_assigned_node_for_this_chateau = etcd
-        .get(
-            format!("/aspen_assigned_chateaus/{}", chateau).as_str(),
-            None,
-        )
+    let fetch_assigned_node_for_this_chateau = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", chateau).as_str())
         .await;
 
     if let Ok(fetch_assigned_node_for_this_chateau) = fetch_assigned_node_for_this_chateau {
-        let fetch_assigned_node_for_this_chateau_kv_first =
-            fetch_assigned_node_for_this_chateau.kvs().first();
+        let fetch_assigned_node_for_this_chateau_kv_first = fetch_assigned_node_for_this_chateau;
 
         if let Some(fetch_assigned_node_for_this_chateau_data) =
             fetch_assigned_node_for_this_chateau_kv_first
         {
             let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-                fetch_assigned_node_for_this_chateau_data.value(),
+                &fetch_assigned_node_for_this_chateau_data,
             )
             .unwrap();
 
@@ -122,43 +104,22 @@ pub async fn get_vehicle_information_from_label(
 #[actix_web::get("/get_vehicle_information/{chateau}/{gtfs_rt_id}")]
 pub async fn get_vehicle_information(
     path: web::Path<(String, String)>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    coordinator: web::Data<Coordinator>,
 ) -> impl Responder {
     let (chateau, gtfs_id) = path.into_inner();
 
-    let etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await;
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
-    let fetch_assigned_node_for_this_chateau = etcd
-        .get(
-            format!("/aspen_assigned_chateaus/{}", chateau).as_str(),
-            None,
-        )
+    let fetch_assigned_node_for_this_chateau = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", chateau).as_str())
         .await;
 
     if let Ok(fetch_assigned_node_for_this_chateau) = fetch_assigned_node_for_this_chateau {
-        let fetch_assigned_node_for_this_chateau_kv_first =
-            fetch_assigned_node_for_this_chateau.kvs().first();
+        let fetch_assigned_node_for_this_chateau_kv_first = fetch_assigned_node_for_this_chateau;
 
         if let Some(fetch_assigned_node_for_this_chateau_data) =
             fetch_assigned_node_for_this_chateau_kv_first
         {
             let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-                fetch_assigned_node_for_this_chateau_data.value(),
+                &fetch_assigned_node_for_this_chateau_data,
             )
             .unwrap();
 
@@ -220,6 +181,8 @@ struct ResponseForGtfsRtRefresh {
 #[derive(Deserialize, Serialize, Clone, Debug)]
 struct GtfsRtRefreshData {
     stoptimes: Vec<StopTimeRefresh>,
+    // set when the times are predicted from a late vehicle on an earlier trip of the block
+    block_delay_prediction: Option<BlockDelayPrediction>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -230,6 +193,8 @@ struct StopTimeRefresh {
     pub schedule_relationship: Option<i32>,
     pub gtfs_stop_sequence: Option<u16>,
     pub rt_platform_string: Option<String>,
+    pub rt_platform_confidence: Option<PlatformConfidence>,
+    pub rt_platform_changed_from_scheduled: Option<bool>,
     pub departure_occupancy_status: Option<i32>,
 }
 
@@ -256,6 +221,8 @@ struct TripIntroductionInformation {
     pub alert_ids_for_this_trip: Vec<String>,
     pub shape_polyline: Option<String>,
     pub trip_id_found_in_db: bool,
+    pub trip_progress: Option<AspenisedTripProgress>,
+    pub block_delay_prediction: Option<BlockDelayPrediction>,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
 struct StopTimeIntroduction {
@@ -264,6 +231,8 @@ struct StopTimeIntroduction {
     pub translations: Option<BTreeMap<String, String>>,
     pub platform_code: Option<String>,
     pub rt_platform_string: Option<String>,
+    pub rt_platform_confidence: Option<PlatformConfidence>,
+    pub rt_platform_changed_from_scheduled: Option<bool>,
     pub timezone: Option<Tz>,
     pub code: Option<String>,
     pub longitude: Option<f64>,
@@ -289,45 +258,24 @@ struct QueryTripInformationParams {
 pub async fn get_trip_rt_update(
     path: web::Path<String>,
     query: web::Query<QueryTripInformationParams>, // pool: web::Data<Arc<CatenaryPostgresPool>>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    coordinator: web::Data<Coordinator>,
 ) -> impl Responder {
     let chateau = path.into_inner();
 
     let query = query.into_inner();
 
-    let etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await;
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
-    let fetch_assigned_node_for_this_chateau = etcd
-        .get(
-            format!("/aspen_assigned_chateaus/{}", chateau).as_str(),
-            None,
-        )
+    let fetch_assigned_node_for_this_chateau = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", chateau).as_str())
         .await;
 
     if let Ok(fetch_assigned_node_for_this_chateau) = fetch_assigned_node_for_this_chateau {
-        let fetch_assigned_node_for_this_chateau_kv_first =
-            fetch_assigned_node_for_this_chateau.kvs().first();
+        let fetch_assigned_node_for_this_chateau_kv_first = fetch_assigned_node_for_this_chateau;
 
         if let Some(fetch_assigned_node_for_this_chateau_data) =
             fetch_assigned_node_for_this_chateau_kv_first
         {
             let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-                fetch_assigned_node_for_this_chateau_data.value(),
+                &fetch_assigned_node_for_this_chateau_data,
             )
             .unwrap();
 
@@ -392,6 +340,16 @@ pub async fn get_trip_rt_update(
                                     .stop_sequence
                                     .map(|x| x as u16),
                                 rt_platform_string: stop_time_update.platform_string.clone(),
+                                rt_platform_confidence: stop_time_update
+                                    .platform_assignment
+                                    .as_ref()
+                                    .map(|platform_assignment| platform_assignment.confidence),
+                                rt_platform_changed_from_scheduled: stop_time_update
+                                    .platform_assignment
+                                    .as_ref()
+                                    .map(|platform_assignment| {
+                                        platform_assignment.changed_from_scheduled
+                                    }),
                                 departure_occupancy_status: stop_time_update
                                     .departure_occupancy_status,
                             })
@@ -401,6 +359,9 @@ pub async fn get_trip_rt_update(
                             found_data: true,
                             data: Some(GtfsRtRefreshData {
                                 stoptimes: stop_data,
+                                block_delay_prediction: rt_trip_update
+                                    .block_delay_prediction
+                                    .clone(),
                             }),
                         })
                     } else {
@@ -440,8 +401,7 @@ pub async fn get_trip_init_v2(
     query: web::Query<QueryTripInformationParams>,
     // sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
     pool: web::Data<Arc<CatenaryPostgresPool>>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    coordinator: web::Data<Coordinator>,
 ) -> impl Responder {
     let mut timer = simple_server_timing_header::Timer::new();
     let chateau = path.into_inner();
@@ -499,14 +459,38 @@ pub async fn get_trip_init_v2(
     HttpResponse::Ok().body("Not Implemented Yet")
 }
 
+// only a vehicle on this run of the trip, attaching another run's progress would be wrong
+// many feeds leave out start_date and start_time, a field the vehicle does not send is not checked
+fn vehicle_is_on_run(
+    vehicle_position: &AspenisedVehiclePosition,
+    start_date: &str,
+    start_time: Option<&str>,
+) -> bool {
+    match &vehicle_position.trip {
+        Some(trip) => {
+            let same_date = match &trip.start_date {
+                Some(vehicle_start_date) => vehicle_start_date == start_date,
+                None => true,
+            };
+
+            let same_time = match (&trip.start_time, start_time) {
+                (Some(vehicle_start_time), Some(start_time)) => vehicle_start_time == start_time,
+                _ => true,
+            };
+
+            same_date && same_time
+        }
+        None => false,
+    }
+}
+
 #[actix_web::get("/get_trip_information/{chateau}/")]
 pub async fn get_trip_init(
     path: web::Path<String>,
     query: web::Query<QueryTripInformationParams>,
     // sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
     pool: web::Data<Arc<CatenaryPostgresPool>>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    coordinator: web::Data<Coordinator>,
 ) -> impl Responder {
     let mut timer = simple_server_timing_header::Timer::new();
     let chateau = path.into_inner();
@@ -854,6 +838,8 @@ pub async fn get_trip_init(
             rt_departure: None,
             schedule_relationship: None,
             rt_platform_string: None,
+            rt_platform_confidence: None,
+            rt_platform_changed_from_scheduled: None,
         };
 
         stop_times_for_this_trip.push(stop_time);
@@ -861,44 +847,24 @@ pub async fn get_trip_init(
 
     timer.add("stop_time_calculation");
 
-    let etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await;
-
-    timer.add("connect_to_etcd");
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
-    let fetch_assigned_node_for_this_chateau = etcd
-        .get(
-            format!("/aspen_assigned_chateaus/{}", chateau).as_str(),
-            None,
-        )
+    let fetch_assigned_node_for_this_chateau = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", chateau).as_str())
         .await;
 
     timer.add("fetch_assigned_aspen_chateau_data_from_etcd");
 
     let mut vehicle = None;
+    let mut trip_progress = None;
+    let mut block_delay_prediction = None;
 
     if let Ok(fetch_assigned_node_for_this_chateau) = fetch_assigned_node_for_this_chateau {
-        let fetch_assigned_node_for_this_chateau_kv_first =
-            fetch_assigned_node_for_this_chateau.kvs().first();
+        let fetch_assigned_node_for_this_chateau_kv_first = fetch_assigned_node_for_this_chateau;
 
         if let Some(fetch_assigned_node_for_this_chateau_data) =
             fetch_assigned_node_for_this_chateau_kv_first
         {
             let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-                fetch_assigned_node_for_this_chateau_data.value(),
+                &fetch_assigned_node_for_this_chateau_data,
             )
             .unwrap();
 
@@ -957,6 +923,7 @@ pub async fn get_trip_init(
                             };
 
                             vehicle = rt_trip_update.vehicle.clone();
+                            block_delay_prediction = rt_trip_update.block_delay_prediction.clone();
 
                             println!(
                                 "rt data contains {} stop updates",
@@ -1005,6 +972,15 @@ pub async fn get_trip_init(
                                     {
                                         stop_time.rt_platform_string = Some(rt_platform_string);
                                     }
+
+                                    if let Some(platform_assignment) =
+                                        &stop_time_update.platform_assignment
+                                    {
+                                        stop_time.rt_platform_confidence =
+                                            Some(platform_assignment.confidence);
+                                        stop_time.rt_platform_changed_from_scheduled =
+                                            Some(platform_assignment.changed_from_scheduled);
+                                    }
                                 }
                             }
                         }
@@ -1013,6 +989,45 @@ pub async fn get_trip_init(
                     }
                 }
 
+                let vehicles_for_trip = aspen_client
+                    .get_vehicle_locations_from_trip_id(
+                        context::current(),
+                        chateau.clone(),
+                        query.trip_id.clone(),
+                    )
+                    .await;
+
+                timer.add("get_vehicles_for_trip_from_aspen");
+
+                if let Ok(Some(vehicles_for_trip)) = vehicles_for_trip {
+                    let queried_start_date = start_naive_date.format("%Y%m%d").to_string();
+
+                    let on_this_run = |vehicle_position: &&AspenisedVehiclePosition| {
+                        vehicle_is_on_run(
+                            vehicle_position,
+                            &queried_start_date,
+                            query.start_time.as_deref(),
+                        )
+                    };
+
+                    // prefer the vehicle the trip update names
+                    let vehicle_for_trip = vehicles_for_trip
+                        .iter()
+                        .filter(on_this_run)
+                        .find(
+                            |vehicle_position| match (&vehicle, &vehicle_position.vehicle) {
+                                (Some(vehicle), Some(vehicle_position)) => {
+                                    vehicle.id.is_some() && vehicle.id == vehicle_position.id
+                                }
+                                _ => false,
+                            },
+                        )
+                        .or_else(|| vehicles_for_trip.iter().find(on_this_run));
+
+                    trip_progress = vehicle_for_trip
+                        .and_then(|vehicle_position| vehicle_position.trip_progress.clone());
+                }
+
                 // GET ALERTS
 
                 let alerts_for_route = aspen_client
@@ -1137,6 +1152,8 @@ pub async fn get_trip_init(
         alert_id_to_alert,
         shape_polyline,
         trip_id_found_in_db: true,
+        trip_progress,
+        block_delay_prediction,
     };
 
     let text = serde_json::to_string(&response).unwrap();
@@ -1150,3 +1167,58 @@ pub async fn get_trip_init(
         ))
         .body(text)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catenary::aspen_dataset::AspenisedVehicleTripInfo;
+
+    fn vehicle_on(start_date: Option<&str>, start_time: Option<&str>) -> AspenisedVehiclePosition {
+        AspenisedVehiclePosition {
+            trip: Some(AspenisedVehicleTripInfo {
+                trip_id: Some("trip".to_string()),
+                trip_headsign: None,
+                route_id: None,
+                trip_short_name: None,
+                direction_id: None,
+                start_time: start_time.map(|start_time| start_time.to_string()),
+                start_date: start_date.map(|start_date| start_date.to_string()),
+                schedule_relationship: None,
+            }),
+            vehicle: None,
+            position: None,
+            timestamp: None,
+            route_type: 3,
+            current_stop_sequence: None,
+            current_status: None,
+            congestion_level: None,
+            occupancy_status: None,
+            occupancy_percentage: None,
+            trip_progress: None,
+            multi_carriage_details: vec![],
+            consist_length: None,
+            scheduled_consist_length: None,
+        }
+    }
+
+    #[test]
+    fn missing_start_fields_match_any_run() {
+        let vehicle = vehicle_on(None, None);
+
+        assert!(vehicle_is_on_run(&vehicle, "20250301", Some("08:00:00")));
+        assert!(vehicle_is_on_run(&vehicle, "20250301", None));
+    }
+
+    #[test]
+    fn present_start_fields_must_match() {
+        let vehicle = vehicle_on(Some("20250301"), Some("08:00:00"));
+
+        assert!(vehicle_is_on_run(&vehicle, "20250301", Some("08:00:00")));
+        assert!(vehicle_is_on_run(&vehicle, "20250301", None));
+        assert!(!vehicle_is_on_run(&vehicle, "20250302", Some("08:00:00")));
+        assert!(!vehicle_is_on_run(&vehicle, "20250301", Some("09:00:00")));
+
+        let no_date = vehicle_on(None, Some("08:00:00"));
+        assert!(!vehicle_is_on_run(&no_date, "20250301", Some("09:00:00")));
+    }
+}
@@ -1,10 +1,17 @@
-use crate::EtcdConnectionIps;
+use crate::aspen_clients::AspenClients;
 use actix_web::{web, HttpResponse, Responder};
 use catenary::aspen_dataset::GtfsRtType;
+use catenary::coordination::Coordinator;
+use catenary::get_node_for_chateau;
 use catenary::get_node_for_realtime_feed_id;
+use catenary::gtfs_rt_export::DifferentialFeed;
+use gtfs_realtime::FeedMessage;
 use prost::Message;
 use serde::Deserialize;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
 
 #[derive(Deserialize, Clone)]
 struct BirchGtfsRtOptions {
@@ -19,31 +26,51 @@ enum ConvertedFormat {
     Ron,
 }
 
-#[actix_web::get("/gtfs_rt")]
-async fn gtfs_rt(
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
-    query: web::Query<BirchGtfsRtOptions>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
-) -> impl Responder {
-    let query = query.into_inner();
-
-    let etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await;
+fn parse_feed_type(feed_type: &str) -> Option<GtfsRtType> {
+    match feed_type {
+        "vehicle" => Some(GtfsRtType::VehiclePositions),
+        "trip" => Some(GtfsRtType::TripUpdates),
+        "alert" => Some(GtfsRtType::Alerts),
+        _ => None,
+    }
+}
 
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
+fn parse_format(format: &Option<String>) -> ConvertedFormat {
+    match format {
+        Some(format_gtfs_req) => match format_gtfs_req.as_str() {
+            "pb" => ConvertedFormat::Protobuf,
+            "json" => ConvertedFormat::Json,
+            "ron" => ConvertedFormat::Ron,
+            _ => ConvertedFormat::Protobuf,
+        },
+        _ => ConvertedFormat::Protobuf,
+    }
+}
 
-        return HttpResponse::InternalServerError()
+fn feed_message_response(data: FeedMessage, format: ConvertedFormat) -> HttpResponse {
+    match format {
+        ConvertedFormat::Protobuf => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .body(data.encode_to_vec()),
+        ConvertedFormat::Ron => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .body(ron::ser::to_string_pretty(&data, ron::ser::PrettyConfig::default()).unwrap()),
+        ConvertedFormat::Json => HttpResponse::Ok()
             .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
+            .append_header(actix_web::http::header::ContentType(mime::APPLICATION_JSON))
+            .body(serde_json::to_string_pretty(&data).unwrap()),
     }
+}
 
-    let mut etcd = etcd.unwrap();
+#[actix_web::get("/gtfs_rt")]
+async fn gtfs_rt(
+    coordinator: web::Data<Coordinator>,
+    query: web::Query<BirchGtfsRtOptions>,
+) -> impl Responder {
+    let query = query.into_inner();
 
-    let fetch_assigned_node_meta = get_node_for_realtime_feed_id(&mut etcd, &query.feed_id).await;
+    let fetch_assigned_node_meta =
+        get_node_for_realtime_feed_id(&coordinator, &query.feed_id).await;
 
     match fetch_assigned_node_meta {
         Some(data) => {
@@ -53,22 +80,9 @@ async fn gtfs_rt(
                 .await
                 .unwrap();
 
-            let feed_type = match query.feed_type.as_str() {
-                "vehicle" => Some(GtfsRtType::VehiclePositions),
-                "trip" => Some(GtfsRtType::TripUpdates),
-                "alert" => Some(GtfsRtType::Alerts),
-                _ => None,
-            };
+            let feed_type = parse_feed_type(query.feed_type.as_str());
 
-            let format = match &query.format {
-                Some(format_gtfs_req) => match format_gtfs_req.as_str() {
-                    "pb" => ConvertedFormat::Protobuf,
-                    "json" => ConvertedFormat::Json,
-                    "ron" => ConvertedFormat::Ron,
-                    _ => ConvertedFormat::Protobuf,
-                },
-                _ => ConvertedFormat::Protobuf,
-            };
+            let format = parse_format(&query.format);
 
             match feed_type {
                 Some(feed_type) => {
@@ -82,26 +96,7 @@ async fn gtfs_rt(
                             .body("Node crashed during request"),
                         Ok(Some(protobuf)) => {
                             match catenary::parse_gtfs_rt_message(protobuf.as_slice()) {
-                                Ok(data) => match format {
-                                    ConvertedFormat::Protobuf => HttpResponse::Ok()
-                                        .append_header(("Cache-Control", "no-cache"))
-                                        .body(data.encode_to_vec()),
-                                    ConvertedFormat::Ron => HttpResponse::Ok()
-                                        .append_header(("Cache-Control", "no-cache"))
-                                        .body(
-                                            ron::ser::to_string_pretty(
-                                                &data,
-                                                ron::ser::PrettyConfig::default(),
-                                            )
-                                            .unwrap(),
-                                        ),
-                                    ConvertedFormat::Json => HttpResponse::Ok()
-                                        .append_header(("Cache-Control", "no-cache"))
-                                        .append_header(actix_web::http::header::ContentType(
-                                            mime::APPLICATION_JSON,
-                                        ))
-                                        .body(serde_json::to_string_pretty(&data).unwrap()),
-                                },
+                                Ok(data) => feed_message_response(data, format),
                                 Err(_) => HttpResponse::InternalServerError()
                                     .append_header(("Cache-Control", "no-cache"))
                                     .body("Failed to decode protobuf data"),
@@ -124,3 +119,212 @@ async fn gtfs_rt(
         }
     }
 }
+
+#[derive(Deserialize, Clone)]
+struct BirchChateauGtfsRtOptions {
+    feed_type: String,
+    format: Option<String>,
+}
+
+// Aspen's merged state for a whole chateau, with cleaned route ids, enriched vehicle positions
+// and the alerts of every feed in the chateau
+#[actix_web::get("/gtfs_rt_chateau/{chateau_id}")]
+async fn gtfs_rt_chateau(
+    coordinator: web::Data<Coordinator>,
+    producers: web::Data<Arc<ChateauFeedProducers>>,
+    path: web::Path<String>,
+    query: web::Query<BirchChateauGtfsRtOptions>,
+) -> impl Responder {
+    let chateau_id = path.into_inner();
+    let query = query.into_inner();
+
+    let feed_type = match parse_feed_type(query.feed_type.as_str()) {
+        Some(feed_type) => feed_type,
+        None => {
+            return HttpResponse::NotFound()
+                .append_header(("Cache-Control", "no-cache"))
+                .body("Bad Feed Type, either vehicle trip or alert accepted")
+        }
+    };
+
+    match producers
+        .fetch_chateau_feed(&coordinator, &chateau_id, feed_type)
+        .await
+    {
+        Ok(Some(data)) => feed_message_response(data, parse_format(&query.format)),
+        Ok(None) => HttpResponse::InternalServerError()
+            .append_header(("Cache-Control", "no-cache"))
+            .body("Data doesn't exist on node. try again in a few minutes?"),
+        Err(e) => {
+            eprintln!("{}: {}", chateau_id, e);
+            HttpResponse::InternalServerError()
+                .append_header(("Cache-Control", "no-cache"))
+                .body("Could not fetch data from assigned node")
+        }
+    }
+}
+
+// How often a producer asks aspen for the latest state of its chateau
+const PRODUCER_INTERVAL: Duration = Duration::from_millis(500);
+
+type LatestFeed = watch::Receiver<Option<Arc<FeedMessage>>>;
+
+// One producer per chateau and feed type polls aspen, every stream client of it reads the result.
+// A producer stops once its last client has disconnected.
+#[derive(Default)]
+pub struct ChateauFeedProducers {
+    // each entry holds a receiver of its own, so a producer has clients while it has more than one
+    producers: Mutex<HashMap<(String, GtfsRtType), LatestFeed>>,
+    aspen_clients: AspenClients,
+}
+
+impl ChateauFeedProducers {
+    fn subscribe(
+        self: &Arc<Self>,
+        coordinator: Arc<Coordinator>,
+        chateau_id: &str,
+        feed_type: GtfsRtType,
+    ) -> LatestFeed {
+        let mut producers = self.producers.lock().unwrap();
+
+        let key = (chateau_id.to_string(), feed_type);
+
+        if let Some(latest) = producers.get(&key) {
+            return latest.clone();
+        }
+
+        let (sender, latest) = watch::channel(None);
+
+        producers.insert(key.clone(), latest.clone());
+
+        actix_web::rt::spawn(Arc::clone(self).produce(coordinator, key, sender));
+
+        latest
+    }
+
+    async fn produce(
+        self: Arc<Self>,
+        coordinator: Arc<Coordinator>,
+        key: (String, GtfsRtType),
+        sender: watch::Sender<Option<Arc<FeedMessage>>>,
+    ) {
+        let (chateau_id, feed_type) = &key;
+
+        loop {
+            {
+                let mut producers = self.producers.lock().unwrap();
+
+                if sender.receiver_count() <= 1 {
+                    producers.remove(&key);
+                    return;
+                }
+            }
+
+            match self
+                .fetch_chateau_feed(&coordinator, chateau_id, *feed_type)
+                .await
+            {
+                Ok(Some(full_dataset)) => {
+                    sender.send_replace(Some(Arc::new(full_dataset)));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("{}: stream fetch failed: {}", chateau_id, e);
+                }
+            }
+
+            tokio::time::sleep(PRODUCER_INTERVAL).await;
+        }
+    }
+
+    async fn fetch_chateau_feed(
+        &self,
+        coordinator: &Coordinator,
+        chateau_id: &str,
+        feed_type: GtfsRtType,
+    ) -> Result<Option<FeedMessage>, Box<dyn std::error::Error + Sync + Send>> {
+        let assigned_chateau_data = get_node_for_chateau(coordinator, chateau_id)
+            .await
+            .ok_or("Could not find Assigned Node")?;
+
+        let socket = assigned_chateau_data.socket;
+
+        let aspen_client = self.aspen_clients.client_for(&socket).await?;
+
+        let protobuf = match aspen_client
+            .get_chateau_gtfs_rt(tarpc::context::current(), chateau_id.to_string(), feed_type)
+            .await
+        {
+            Ok(protobuf) => protobuf,
+            Err(e) => {
+                self.aspen_clients.forget(&socket);
+                return Err(e.into());
+            }
+        };
+
+        match protobuf {
+            Some(protobuf) => Ok(Some(FeedMessage::decode(protobuf.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct BirchChateauGtfsRtStreamOptions {
+    feed_type: String,
+    interval_ms: Option<u64>,
+}
+
+// A long lived response of length delimited GTFS Realtime messages.
+// The first message is a full dataset, the following ones are DIFFERENTIAL updates.
+#[actix_web::get("/gtfs_rt_chateau_stream/{chateau_id}")]
+async fn gtfs_rt_chateau_stream(
+    coordinator: web::Data<Coordinator>,
+    producers: web::Data<Arc<ChateauFeedProducers>>,
+    path: web::Path<String>,
+    query: web::Query<BirchChateauGtfsRtStreamOptions>,
+) -> impl Responder {
+    let chateau_id = path.into_inner();
+    let query = query.into_inner();
+
+    let feed_type = match parse_feed_type(query.feed_type.as_str()) {
+        Some(feed_type) => feed_type,
+        None => {
+            return HttpResponse::NotFound()
+                .append_header(("Cache-Control", "no-cache"))
+                .body("Bad Feed Type, either vehicle trip or alert accepted")
+        }
+    };
+
+    let interval = Duration::from_millis(query.interval_ms.unwrap_or(2_000).max(500));
+
+    let latest = producers.subscribe(coordinator.into_inner(), &chateau_id, feed_type);
+
+    let stream = futures::stream::unfold(
+        (DifferentialFeed::new(), latest, true),
+        move |(mut differential, mut latest, first)| async move {
+            if !first {
+                tokio::time::sleep(interval).await;
+            }
+
+            let full_dataset = match latest.wait_for(|full_dataset| full_dataset.is_some()).await {
+                Ok(full_dataset) => full_dataset.clone()?,
+                Err(_) => return None,
+            };
+
+            let message = differential.next_message(&full_dataset);
+
+            Some((
+                Ok::<web::Bytes, actix_web::Error>(web::Bytes::from(
+                    message.encode_length_delimited_to_vec(),
+                )),
+                (differential, latest, false),
+            ))
+        },
+    );
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "no-cache"))
+        .append_header(("Content-Type", "application/x-protobuf"))
+        .streaming(stream)
+}
@@ -0,0 +1,226 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use actix_web::{web, HttpResponse, Responder};
+use catenary::models::{GtfsValidationCount, GtfsValidationFinding, GtfsValidationReport};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::gtfs_errors;
+use catenary::schema::gtfs::gtfs_validation_counts;
+use catenary::schema::gtfs::gtfs_validation_reports;
+use diesel::prelude::*;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct GtfsErrorsQuery {
+    attempt_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GtfsErrorsResponse {
+    onestop_feed_id: String,
+    attempt_id: Option<String>,
+    validated_ms: Option<i64>,
+    count_by_severity: BTreeMap<String, i64>,
+    count_by_code: BTreeMap<String, i64>,
+    findings: Vec<GtfsValidationFinding>,
+}
+
+#[derive(Serialize)]
+struct GtfsErrorsSummary {
+    attempt_id: String,
+    validated_ms: i64,
+    count_by_severity: BTreeMap<String, i64>,
+}
+
+// counts are stored per code when the feed is validated, so they cover findings that were not kept
+fn count_by_severity(counts: &[GtfsValidationCount]) -> BTreeMap<String, i64> {
+    let mut count_by_severity: BTreeMap<String, i64> = BTreeMap::new();
+
+    for count in counts {
+        *count_by_severity.entry(count.severity.clone()).or_insert(0) += count.finding_count;
+    }
+
+    count_by_severity
+}
+
+/// Validation report for one feed, the latest attempt unless `attempt_id` is given
+#[actix_web::get("/gtfs_errors/{onestop_feed_id}")]
+pub async fn gtfs_errors_for_feed(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    path: web::Path<String>,
+    query: web::Query<GtfsErrorsQuery>,
+) -> impl Responder {
+    let onestop_feed_id = path.into_inner();
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    // every attempt writes a report row, so a clean attempt replaces the findings of the last one
+    let report = match &query.attempt_id {
+        Some(attempt_id) => gtfs_validation_reports::dsl::gtfs_validation_reports
+            .filter(gtfs_validation_reports::dsl::onestop_feed_id.eq(&onestop_feed_id))
+            .filter(gtfs_validation_reports::dsl::attempt_id.eq(attempt_id))
+            .select(GtfsValidationReport::as_select())
+            .first::<GtfsValidationReport>(conn)
+            .await
+            .optional(),
+        None => gtfs_validation_reports::dsl::gtfs_validation_reports
+            .filter(gtfs_validation_reports::dsl::onestop_feed_id.eq(&onestop_feed_id))
+            .order(gtfs_validation_reports::dsl::validated_ms.desc())
+            .select(GtfsValidationReport::as_select())
+            .first::<GtfsValidationReport>(conn)
+            .await
+            .optional(),
+    };
+
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch validation report");
+        }
+    };
+
+    let (counts, findings) = match &report {
+        Some(report) => {
+            let counts = gtfs_validation_counts::dsl::gtfs_validation_counts
+                .filter(gtfs_validation_counts::dsl::onestop_feed_id.eq(&onestop_feed_id))
+                .filter(gtfs_validation_counts::dsl::attempt_id.eq(&report.attempt_id))
+                .select(GtfsValidationCount::as_select())
+                .load::<GtfsValidationCount>(conn)
+                .await;
+
+            let findings = gtfs_errors::dsl::gtfs_errors
+                .filter(gtfs_errors::dsl::onestop_feed_id.eq(&onestop_feed_id))
+                .filter(gtfs_errors::dsl::attempt_id.eq(&report.attempt_id))
+                .order(gtfs_errors::dsl::error_index.asc())
+                .select(GtfsValidationFinding::as_select())
+                .load::<GtfsValidationFinding>(conn)
+                .await;
+
+            match (counts, findings) {
+                (Ok(counts), Ok(findings)) => (counts, findings),
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("{}", e);
+                    return HttpResponse::InternalServerError()
+                        .body("Could not fetch validation report");
+                }
+            }
+        }
+        None => (vec![], vec![]),
+    };
+
+    let count_by_code = counts
+        .iter()
+        .map(|count| (count.code.clone(), count.finding_count))
+        .collect::<BTreeMap<String, i64>>();
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "max-age=60"))
+        .json(GtfsErrorsResponse {
+            onestop_feed_id,
+            attempt_id: report.as_ref().map(|report| report.attempt_id.clone()),
+            validated_ms: report.as_ref().map(|report| report.validated_ms),
+            count_by_severity: count_by_severity(&counts),
+            count_by_code,
+            findings,
+        })
+}
+
+/// Finding counts for the latest attempt of every feed
+#[actix_web::get("/gtfs_errors_summary")]
+pub async fn gtfs_errors_summary(pool: web::Data<Arc<CatenaryPostgresPool>>) -> impl Responder {
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    let reports = gtfs_validation_reports::dsl::gtfs_validation_reports
+        .distinct_on(gtfs_validation_reports::dsl::onestop_feed_id)
+        .order((
+            gtfs_validation_reports::dsl::onestop_feed_id,
+            gtfs_validation_reports::dsl::validated_ms.desc(),
+        ))
+        .select(GtfsValidationReport::as_select())
+        .load::<GtfsValidationReport>(conn)
+        .await;
+
+    let reports = match reports {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch validation reports");
+        }
+    };
+
+    let attempt_ids = reports
+        .iter()
+        .map(|report| report.attempt_id.clone())
+        .collect::<Vec<String>>();
+
+    let counts = gtfs_validation_counts::dsl::gtfs_validation_counts
+        .filter(gtfs_validation_counts::dsl::attempt_id.eq_any(&attempt_ids))
+        .select(GtfsValidationCount::as_select())
+        .load::<GtfsValidationCount>(conn)
+        .await;
+
+    let counts = match counts {
+        Ok(counts) => counts,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch validation reports");
+        }
+    };
+
+    let mut counts_by_attempt: BTreeMap<(String, String), Vec<GtfsValidationCount>> =
+        BTreeMap::new();
+
+    for count in counts {
+        counts_by_attempt
+            .entry((count.onestop_feed_id.clone(), count.attempt_id.clone()))
+            .or_default()
+            .push(count);
+    }
+
+    // feeds whose latest attempt was clean are listed with no counts
+    let summary = reports
+        .into_iter()
+        .map(|report| {
+            let counts = counts_by_attempt
+                .remove(&(report.onestop_feed_id.clone(), report.attempt_id.clone()))
+                .unwrap_or_default();
+
+            (
+                report.onestop_feed_id,
+                GtfsErrorsSummary {
+                    attempt_id: report.attempt_id,
+                    validated_ms: report.validated_ms,
+                    count_by_severity: count_by_severity(&counts),
+                },
+            )
+        })
+        .collect::<BTreeMap<String, GtfsErrorsSummary>>();
+
+    HttpResponse::Ok()
+        .append_header(("Cache-Control", "max-age=60"))
+        .json(summary)
+}
@@ -0,0 +1,38 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use catenary::metrics::HTTP_REQUEST_DURATION;
+use std::time::Instant;
+
+pub async fn http_metrics_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+
+    // the route pattern rather than the path, so ids in urls do not each become a new series
+    let endpoint = req
+        .match_pattern()
+        .unwrap_or_else(|| String::from("unmatched"));
+
+    let res = next.call(req).await;
+
+    // errors become responses further out, they are recorded with the status they will get
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[method.as_str(), endpoint.as_str(), status.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    res
+}
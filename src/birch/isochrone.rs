@@ -0,0 +1,732 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Reachable area from a point within a time budget.
+// Walks to the stops around the origin, then alternates between riding scheduled trips and
+// walking to nearby stops, for up to `max_transfers` + 1 rides. Whatever time is left at each
+// reached stop becomes a walking circle, and the union of the circles is the answer.
+// Answers are cached for a few minutes by rounded origin and departure minute, since the same
+// isochrone is usually asked for again and again while a map is open.
+
+use super::nearby_departures::make_degree_length_as_distance_from_point;
+use super::route_timetable::{service_day_start, trip_instances};
+use super::service_calendar::calendar_structure_by_feed;
+use actix_web::{web, HttpResponse, Responder};
+use ahash::{AHashMap, AHashSet};
+use catenary::models::{CompressedTrip, ItineraryPatternMeta, ItineraryPatternRow};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::timetable_graph::TimetableGraph;
+use compact_str::CompactString;
+use dashmap::DashMap;
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use diesel::SelectableHelper;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use geo::BooleanOps;
+use geo::HaversineDestination;
+use geo::HaversineDistance;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WALKING_SPEED_MPS: f64 = 1.25;
+const MAX_MINUTES: u32 = 120;
+const MAX_TRANSFERS: u8 = 4;
+const MAX_WALK_M: f64 = 2000.;
+// how many points go into one spatial query for transfers
+const TRANSFER_QUERY_CHUNK: usize = 200;
+// departure times further than this from now are refused
+const MAX_DEPARTURE_OFFSET_SECS: i64 = 366 * 24 * 60 * 60;
+const CACHED_ISOCHRONE_TTL: Duration = Duration::from_secs(300);
+const MAX_CACHED_ISOCHRONES: usize = 2000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct IsochroneKey {
+    // thousandths of a degree, about 100 metres
+    lat: i64,
+    lon: i64,
+    departure_minute: i64,
+    max_minutes: u32,
+    max_transfers: u8,
+    max_walk_m: u32,
+}
+
+#[derive(Default)]
+pub struct IsochroneCache {
+    results: DashMap<IsochroneKey, (Instant, Arc<String>)>,
+}
+
+impl IsochroneCache {
+    fn get(&self, key: &IsochroneKey) -> Option<Arc<String>> {
+        self.results
+            .get(key)
+            .filter(|entry| entry.0.elapsed() < CACHED_ISOCHRONE_TTL)
+            .map(|entry| Arc::clone(&entry.1))
+    }
+
+    fn insert(&self, key: IsochroneKey, body: Arc<String>) {
+        if self.results.len() >= MAX_CACHED_ISOCHRONES {
+            self.results
+                .retain(|_, (created, _)| created.elapsed() < CACHED_ISOCHRONE_TTL);
+        }
+
+        if self.results.len() < MAX_CACHED_ISOCHRONES {
+            self.results.insert(key, (Instant::now(), body));
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IsochroneQuery {
+    lat: f64,
+    lon: f64,
+    /// unix seconds, now if missing
+    departure_time: Option<u64>,
+    max_minutes: Option<u32>,
+    max_transfers: Option<u8>,
+    /// longest walk to, from and between stops
+    max_walk_m: Option<f64>,
+}
+
+fn walking_seconds(distance_m: f64) -> i64 {
+    (distance_m / WALKING_SPEED_MPS).ceil() as i64
+}
+
+fn walking_circle(center: &geo::Point, radius_m: f64) -> geo::MultiPolygon {
+    let mut ring = (0..32)
+        .map(|i| {
+            let point = center.haversine_destination(i as f64 * 360. / 32., radius_m);
+
+            geo::coord! { x: point.x(), y: point.y() }
+        })
+        .collect::<Vec<geo::Coord>>();
+
+    ring.push(ring[0]);
+
+    geo::MultiPolygon::new(vec![geo::Polygon::new(geo::LineString::new(ring), vec![])])
+}
+
+/// Unions in pairs, which keeps every union small
+fn union_all(mut areas: Vec<geo::MultiPolygon>) -> geo::MultiPolygon {
+    while areas.len() > 1 {
+        areas = areas
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => a.union(b),
+                [a] => a.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+
+    areas
+        .pop()
+        .unwrap_or_else(|| geo::MultiPolygon::new(vec![]))
+}
+
+/// Stops allowed in spatial queries within `radius_m` of any of the points
+async fn stops_near_points(
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    points: &[geo::Point],
+    radius_m: f64,
+) -> QueryResult<Vec<catenary::models::Stop>> {
+    let degrees = points
+        .iter()
+        .map(|point| make_degree_length_as_distance_from_point(point, radius_m))
+        .fold(0., f64::max);
+
+    let geometry = match points {
+        [point] => format!("POINT({} {})", point.x(), point.y()),
+        _ => format!(
+            "MULTIPOINT({})",
+            points
+                .iter()
+                .map(|point| format!("({} {})", point.x(), point.y()))
+                .collect::<Vec<String>>()
+                .join(",")
+        ),
+    };
+
+    let where_query_for_stops = format!(
+        "ST_DWithin(gtfs.stops.point, 'SRID=4326;{}', {}) AND allowed_spatial_query = TRUE",
+        geometry, degrees
+    );
+
+    catenary::schema::gtfs::stops::dsl::stops
+        .filter(sql::<Bool>(&where_query_for_stops))
+        .select(catenary::models::Stop::as_select())
+        .load::<catenary::models::Stop>(conn)
+        .await
+}
+
+/// Adds every itinerary serving the stops to the graph, with the runs between `window_start`
+/// and `window_end`
+async fn load_patterns_at_stops(
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    graph: &mut TimetableGraph,
+    chateau: &str,
+    stop_ids: &[CompactString],
+    window_start: i64,
+    window_end: i64,
+) -> QueryResult<()> {
+    // (onestop_feed_id, itinerary_pattern_id), ids are only unique within a feed
+    let itinerary_keys = catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern
+        .filter(catenary::schema::gtfs::itinerary_pattern::dsl::chateau.eq(chateau))
+        .filter(catenary::schema::gtfs::itinerary_pattern::dsl::stop_id.eq_any(stop_ids))
+        .select((
+            catenary::schema::gtfs::itinerary_pattern::dsl::onestop_feed_id,
+            catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern_id,
+        ))
+        .distinct()
+        .load::<(String, String)>(conn)
+        .await?
+        .into_iter()
+        .filter(|(onestop_feed_id, itinerary_pattern_id)| {
+            !graph.has_pattern(chateau, onestop_feed_id, itinerary_pattern_id)
+        })
+        .collect::<AHashSet<(String, String)>>();
+
+    if itinerary_keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut itinerary_pattern_ids = itinerary_keys
+        .iter()
+        .map(|(_, itinerary_pattern_id)| itinerary_pattern_id.clone())
+        .collect::<Vec<String>>();
+
+    itinerary_pattern_ids.sort();
+    itinerary_pattern_ids.dedup();
+
+    // the queries below are by id alone and can return the same id of other feeds
+    let is_wanted = |onestop_feed_id: &str, itinerary_pattern_id: &str| {
+        itinerary_keys.contains(&(
+            onestop_feed_id.to_string(),
+            itinerary_pattern_id.to_string(),
+        ))
+    };
+
+    let itinerary_rows = catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern
+        .filter(catenary::schema::gtfs::itinerary_pattern::dsl::chateau.eq(chateau))
+        .filter(
+            catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern_id
+                .eq_any(&itinerary_pattern_ids),
+        )
+        .select(ItineraryPatternRow::as_select())
+        .load::<ItineraryPatternRow>(conn)
+        .await?
+        .into_iter()
+        .filter(|row| is_wanted(&row.onestop_feed_id, &row.itinerary_pattern_id))
+        .collect::<Vec<ItineraryPatternRow>>();
+
+    let itinerary_metas =
+        catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta
+            .filter(catenary::schema::gtfs::itinerary_pattern_meta::dsl::chateau.eq(chateau))
+            .filter(
+                catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_id
+                    .eq_any(&itinerary_pattern_ids),
+            )
+            .select(ItineraryPatternMeta::as_select())
+            .load::<ItineraryPatternMeta>(conn)
+            .await?
+            .into_iter()
+            .filter(|meta| is_wanted(&meta.onestop_feed_id, &meta.itinerary_pattern_id))
+            .collect::<Vec<ItineraryPatternMeta>>();
+
+    let trips = catenary::schema::gtfs::trips_compressed::dsl::trips_compressed
+        .filter(catenary::schema::gtfs::trips_compressed::dsl::chateau.eq(chateau))
+        .filter(
+            catenary::schema::gtfs::trips_compressed::dsl::itinerary_pattern_id
+                .eq_any(&itinerary_pattern_ids),
+        )
+        .select(CompressedTrip::as_select())
+        .load::<CompressedTrip>(conn)
+        .await?
+        .into_iter()
+        .filter(|trip| is_wanted(&trip.onestop_feed_id, &trip.itinerary_pattern_id))
+        .collect::<Vec<CompressedTrip>>();
+
+    let mut service_ids = trips
+        .iter()
+        .map(|trip| trip.service_id.to_string())
+        .collect::<Vec<String>>();
+
+    service_ids.sort();
+    service_ids.dedup();
+
+    let calendars = catenary::schema::gtfs::calendar::dsl::calendar
+        .filter(catenary::schema::gtfs::calendar::dsl::chateau.eq(chateau))
+        .filter(catenary::schema::gtfs::calendar::dsl::service_id.eq_any(&service_ids))
+        .select(catenary::models::Calendar::as_select())
+        .load::<catenary::models::Calendar>(conn)
+        .await?;
+
+    let calendar_dates = catenary::schema::gtfs::calendar_dates::dsl::calendar_dates
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::chateau.eq(chateau))
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::service_id.eq_any(&service_ids))
+        .select(catenary::models::CalendarDate::as_select())
+        .load::<catenary::models::CalendarDate>(conn)
+        .await?;
+
+    let calendar_structure = calendar_structure_by_feed(calendars, calendar_dates);
+
+    let mut rows_by_itinerary: AHashMap<(String, String), Vec<ItineraryPatternRow>> =
+        AHashMap::new();
+
+    for row in itinerary_rows {
+        rows_by_itinerary
+            .entry((
+                row.onestop_feed_id.clone(),
+                row.itinerary_pattern_id.clone(),
+            ))
+            .or_default()
+            .push(row);
+    }
+
+    let mut trips_by_itinerary: AHashMap<(String, String), Vec<CompressedTrip>> = AHashMap::new();
+
+    for trip in trips {
+        trips_by_itinerary
+            .entry((
+                trip.onestop_feed_id.clone(),
+                trip.itinerary_pattern_id.clone(),
+            ))
+            .or_default()
+            .push(trip);
+    }
+
+    let timezones: AHashMap<(String, String), chrono_tz::Tz> = itinerary_metas
+        .iter()
+        .filter_map(|meta| {
+            chrono_tz::Tz::from_str(&meta.timezone)
+                .ok()
+                .map(|timezone| {
+                    (
+                        (
+                            meta.onestop_feed_id.clone(),
+                            meta.itinerary_pattern_id.clone(),
+                        ),
+                        timezone,
+                    )
+                })
+        })
+        .collect();
+
+    for itinerary_key in itinerary_keys {
+        let mut rows = rows_by_itinerary.remove(&itinerary_key).unwrap_or_default();
+
+        rows.sort_by_key(|row| row.stop_sequence);
+
+        let stops = rows
+            .into_iter()
+            .filter_map(|row| {
+                let arrival = row
+                    .arrival_time_since_start
+                    .or(row.interpolated_time_since_start)
+                    .or(row.departure_time_since_start)?;
+                let departure = row
+                    .departure_time_since_start
+                    .or(row.arrival_time_since_start)
+                    .or(row.interpolated_time_since_start)?;
+
+                Some((row.stop_id, arrival as i64, departure as i64))
+            })
+            .collect::<Vec<(CompactString, i64, i64)>>();
+
+        let last_offset = stops.last().map(|x| x.1).unwrap_or(0);
+
+        let mut trip_starts: Vec<i64> = vec![];
+
+        if let Some(timezone) = timezones.get(&itinerary_key) {
+            // yesterday's service day can still be running after midnight
+            let first_date = chrono::DateTime::from_timestamp(window_start, 0)
+                .map(|x| x.with_timezone(timezone).date_naive())
+                .and_then(|x| x.pred_opt());
+            let last_date = chrono::DateTime::from_timestamp(window_end, 0)
+                .map(|x| x.with_timezone(timezone).date_naive());
+
+            let (Some(first_date), Some(last_date)) = (first_date, last_date) else {
+                continue;
+            };
+
+            for trip in trips_by_itinerary.get(&itinerary_key).into_iter().flatten() {
+                let service = match calendar_structure
+                    .get(&trip.onestop_feed_id)
+                    .and_then(|services| services.get(trip.service_id.as_str()))
+                {
+                    Some(service) => service,
+                    None => continue,
+                };
+
+                let instances = trip_instances(trip);
+
+                let mut date = first_date;
+
+                while date <= last_date {
+                    if catenary::datetime_in_service(service, date) {
+                        let day_start = service_day_start(timezone, date);
+
+                        for (start_time, _) in instances.iter() {
+                            let start = day_start + *start_time as i64;
+
+                            if start <= window_end && start + last_offset >= window_start {
+                                trip_starts.push(start);
+                            }
+                        }
+                    }
+
+                    date = match date.succ_opt() {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+            }
+        }
+
+        let (onestop_feed_id, itinerary_pattern_id) = itinerary_key;
+
+        graph.add_pattern(
+            chateau,
+            &onestop_feed_id,
+            &itinerary_pattern_id,
+            stops,
+            trip_starts,
+        );
+    }
+
+    Ok(())
+}
+
+/// GeoJSON multipolygon of everywhere reachable by walking and scheduled transit
+#[actix_web::get("/isochrone")]
+pub async fn isochrone(
+    query: web::Query<IsochroneQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    cache: web::Data<Arc<IsochroneCache>>,
+) -> impl Responder {
+    if !(-90.0..=90.0).contains(&query.lat) || !(-180.0..=180.0).contains(&query.lon) {
+        return HttpResponse::BadRequest().body("Invalid coordinates");
+    }
+
+    let now = catenary::duration_since_unix_epoch().as_secs() as i64;
+
+    let departure_time = match query.departure_time {
+        None => now,
+        Some(departure_time) => match i64::try_from(departure_time) {
+            Ok(departure_time) if (departure_time - now).abs() <= MAX_DEPARTURE_OFFSET_SECS => {
+                departure_time
+            }
+            _ => {
+                return HttpResponse::BadRequest()
+                    .body("departure_time must be within a year of now");
+            }
+        },
+    };
+    let max_minutes = query.max_minutes.unwrap_or(30).clamp(1, MAX_MINUTES);
+    let max_transfers = query.max_transfers.unwrap_or(2).min(MAX_TRANSFERS);
+    let max_walk_m = query
+        .max_walk_m
+        .unwrap_or(800.)
+        .clamp(0., MAX_WALK_M)
+        .round();
+
+    let key = IsochroneKey {
+        lat: (query.lat * 1000.).round() as i64,
+        lon: (query.lon * 1000.).round() as i64,
+        departure_minute: departure_time.div_euclid(60),
+        max_minutes,
+        max_transfers,
+        max_walk_m: max_walk_m as u32,
+    };
+
+    if let Some(body) = cache.get(&key) {
+        return isochrone_response(body.as_ref().clone());
+    }
+
+    // everything below uses the rounded values, so a cached answer is the same for every hit
+    let departure_time = key.departure_minute * 60;
+    let deadline = departure_time + max_minutes as i64 * 60;
+    let origin = geo::Point::new(key.lon as f64 / 1000., key.lat as f64 / 1000.);
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    let mut graph = TimetableGraph::new();
+    let mut best_arrival: AHashMap<usize, i64> = AHashMap::new();
+    let mut stop_points: AHashMap<usize, geo::Point> = AHashMap::new();
+    let mut marked: AHashSet<usize> = AHashSet::new();
+
+    // walk to the stops around the origin
+
+    let access_radius = max_walk_m.min(max_minutes as f64 * 60. * WALKING_SPEED_MPS);
+
+    if access_radius > 0. {
+        let access_stops = match stops_near_points(conn, &[origin], access_radius).await {
+            Ok(access_stops) => access_stops,
+            Err(e) => {
+                eprintln!("{}", e);
+                return HttpResponse::InternalServerError().body("Could not fetch stops");
+            }
+        };
+
+        for stop in access_stops {
+            if let Some(stop_point) = &stop.point {
+                let point = geo::Point::new(stop_point.x, stop_point.y);
+                let distance = origin.haversine_distance(&point);
+
+                if distance <= access_radius {
+                    let index = graph.stop_index(&stop.chateau, &stop.gtfs_id);
+
+                    stop_points.insert(index, point);
+                    best_arrival.insert(index, departure_time + walking_seconds(distance));
+                    marked.insert(index);
+                }
+            }
+        }
+    }
+
+    for _ in 0..=max_transfers {
+        if marked.is_empty() {
+            break;
+        }
+
+        // ride
+
+        let mut marked_by_chateau: BTreeMap<String, Vec<CompactString>> = BTreeMap::new();
+
+        for stop in marked.iter() {
+            let (chateau, stop_id) = graph.stop_key(*stop);
+
+            marked_by_chateau
+                .entry(chateau.clone())
+                .or_default()
+                .push(stop_id.clone());
+        }
+
+        for (chateau, stop_ids) in marked_by_chateau {
+            if let Err(e) = load_patterns_at_stops(
+                conn,
+                &mut graph,
+                &chateau,
+                &stop_ids,
+                departure_time,
+                deadline,
+            )
+            .await
+            {
+                eprintln!("{}", e);
+                return HttpResponse::InternalServerError().body("Could not fetch timetables");
+            }
+        }
+
+        let scanned = tokio::task::spawn_blocking(move || {
+            let improved = graph.transit_round(&marked, &best_arrival, deadline);
+
+            (graph, best_arrival, improved)
+        })
+        .await;
+
+        let improved = match scanned {
+            Ok((scanned_graph, scanned_best_arrival, improved)) => {
+                graph = scanned_graph;
+                best_arrival = scanned_best_arrival;
+
+                improved
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return HttpResponse::InternalServerError().body("Could not compute isochrone");
+            }
+        };
+
+        best_arrival.extend(improved.iter().map(|(stop, arrival)| (*stop, *arrival)));
+
+        // locate the stops we got off at
+
+        let mut missing_by_chateau: BTreeMap<String, Vec<CompactString>> = BTreeMap::new();
+
+        for stop in improved
+            .keys()
+            .filter(|stop| !stop_points.contains_key(stop))
+        {
+            let (chateau, stop_id) = graph.stop_key(*stop);
+
+            missing_by_chateau
+                .entry(chateau.clone())
+                .or_default()
+                .push(stop_id.clone());
+        }
+
+        for (chateau, stop_ids) in missing_by_chateau {
+            let stops = catenary::schema::gtfs::stops::dsl::stops
+                .filter(catenary::schema::gtfs::stops::dsl::chateau.eq(&chateau))
+                .filter(catenary::schema::gtfs::stops::dsl::gtfs_id.eq_any(&stop_ids))
+                .select(catenary::models::Stop::as_select())
+                .load::<catenary::models::Stop>(conn)
+                .await;
+
+            match stops {
+                Ok(stops) => {
+                    for stop in stops {
+                        if let Some(stop_point) = &stop.point {
+                            let index = graph.stop_index(&stop.chateau, &stop.gtfs_id);
+
+                            stop_points.insert(index, geo::Point::new(stop_point.x, stop_point.y));
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return HttpResponse::InternalServerError().body("Could not fetch stops");
+                }
+            }
+        }
+
+        // walk to nearby stops for the next ride
+
+        marked = improved.keys().copied().collect();
+
+        if max_walk_m <= 0. {
+            continue;
+        }
+
+        let sources = improved
+            .iter()
+            .filter_map(|(stop, arrival)| stop_points.get(stop).map(|point| (*point, *arrival)))
+            .collect::<Vec<(geo::Point, i64)>>();
+
+        for chunk in sources.chunks(TRANSFER_QUERY_CHUNK) {
+            let points = chunk.iter().map(|(point, _)| *point).collect::<Vec<_>>();
+
+            let candidates = match stops_near_points(conn, &points, max_walk_m).await {
+                Ok(candidates) => candidates,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return HttpResponse::InternalServerError().body("Could not fetch stops");
+                }
+            };
+
+            for candidate in candidates {
+                let candidate_point = match &candidate.point {
+                    Some(point) => geo::Point::new(point.x, point.y),
+                    None => continue,
+                };
+
+                let arrival = chunk
+                    .iter()
+                    .filter_map(|(point, arrival)| {
+                        let distance = point.haversine_distance(&candidate_point);
+
+                        (distance <= max_walk_m).then(|| arrival + walking_seconds(distance))
+                    })
+                    .min();
+
+                if let Some(arrival) = arrival {
+                    let index = graph.stop_index(&candidate.chateau, &candidate.gtfs_id);
+
+                    stop_points.insert(index, candidate_point);
+
+                    if arrival <= deadline
+                        && best_arrival
+                            .get(&index)
+                            .map(|best| arrival < *best)
+                            .unwrap_or(true)
+                    {
+                        best_arrival.insert(index, arrival);
+                        marked.insert(index);
+                    }
+                }
+            }
+        }
+    }
+
+    // whatever time is left at each stop is spent walking
+
+    let mut circles = vec![];
+
+    if access_radius > 0. {
+        circles.push(walking_circle(&origin, access_radius));
+    }
+
+    for (stop, arrival) in best_arrival.iter() {
+        if let Some(point) = stop_points.get(stop) {
+            let radius = ((deadline - arrival) as f64 * WALKING_SPEED_MPS).min(max_walk_m);
+
+            if radius >= 10. {
+                circles.push(walking_circle(point, radius));
+            }
+        }
+    }
+
+    let reached_stops = best_arrival.len();
+
+    // unioning thousands of circles takes a while, keep it off the actix workers
+    let body = tokio::task::spawn_blocking(move || {
+        let area = union_all(circles);
+
+        let mut properties: serde_json::map::Map<String, serde_json::Value> =
+            serde_json::map::Map::new();
+
+        properties.insert(
+            String::from("departure_time"),
+            serde_json::Value::from(departure_time),
+        );
+        properties.insert(
+            String::from("max_minutes"),
+            serde_json::Value::from(max_minutes),
+        );
+        properties.insert(
+            String::from("reached_stops"),
+            serde_json::Value::from(reached_stops),
+        );
+
+        let feature = geojson::Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry {
+                bbox: None,
+                value: geojson::Value::from(&area),
+                foreign_members: None,
+            }),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        };
+
+        geojson::GeoJson::from(feature).to_string()
+    })
+    .await;
+
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not compute isochrone");
+        }
+    };
+
+    cache.insert(key, Arc::new(body.clone()));
+
+    isochrone_response(body)
+}
+
+fn isochrone_response(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/geo+json"))
+        .append_header(("Cache-Control", "max-age=300"))
+        .body(body)
+}
@@ -12,11 +12,18 @@ use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::Responder;
 use ahash::AHashMap;
-use catenary::aspen::lib::ChateauMetadataEtcd;
 use catenary::aspen_dataset::AspenisedTripUpdate;
+use catenary::aspen_dataset::PlatformConfidence;
+use catenary::coordination::Coordinator;
+use catenary::crowding::{
+    direction_key, expected_crowding_from_profile, load_crowding_profiles, time_of_week_bucket,
+    time_of_week_buckets_between, ExpectedCrowding,
+};
+use catenary::get_node_for_chateau;
 use catenary::gtfs_schedule_protobuf::protobuf_to_frequencies;
 use catenary::make_weekdays;
 use catenary::maple_syrup::DirectionPattern;
+use catenary::metrics::NEARBY_DEPARTURES_STAGE_DURATION;
 use catenary::models::DirectionPatternRow;
 use catenary::models::ItineraryPatternMeta;
 use catenary::models::{CompressedTrip, ItineraryPatternRow};
@@ -24,7 +31,6 @@ use catenary::postgres_tools::CatenaryPostgresPool;
 use catenary::schema::gtfs::itinerary_pattern;
 use catenary::schema::gtfs::trips_compressed;
 use catenary::CalendarUnified;
-use catenary::EtcdConnectionIps;
 use chrono::TimeZone;
 use compact_str::CompactString;
 use diesel::dsl::sql;
@@ -110,6 +116,12 @@ pub struct DepartingTrip {
     pub is_interpolated: bool,
     pub cancelled: bool,
     pub platform: Option<String>,
+    pub platform_confidence: Option<PlatformConfidence>,
+    pub platform_changed_from_scheduled: bool,
+    // realtime time comes from a late vehicle on an earlier trip of the same block
+    pub delay_predicted_from_block: bool,
+    // live occupancy of the vehicle, otherwise the historical profile at this hour of the week
+    pub expected_crowding: Option<ExpectedCrowding>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -146,6 +158,7 @@ pub struct ValidTripSet {
     pub timezone: Option<chrono_tz::Tz>,
     pub trip_start_time: u32,
     pub trip_short_name: Option<CompactString>,
+    pub direction_id: Option<bool>,
 }
 
 // final datastructure ideas?
@@ -217,20 +230,12 @@ pub struct DepartingTripsDataAnswer {
 pub async fn nearby_from_coords(
     req: HttpRequest,
     query: Query<NearbyFromCoords>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
+    coordinator: web::Data<Coordinator>,
     sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
     pool: web::Data<Arc<CatenaryPostgresPool>>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
 ) -> impl Responder {
     let start = Instant::now();
 
-    let mut etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await
-    .unwrap();
-
     let conn_pool = pool.as_ref();
     let (conn_pre, conn2_pre, conn3_pre) =
         tokio::join!(conn_pool.get(), conn_pool.get(), conn_pool.get());
@@ -848,20 +853,8 @@ pub async fn nearby_from_coords(
     let mut chateau_metadata = HashMap::new();
 
     for chateau_id in chateaus {
-        let etcd_data = etcd
-            .get(
-                format!("/aspen_assigned_chateaus/{}", chateau_id.clone()).as_str(),
-                None,
-            )
-            .await;
-
-        if let Ok(etcd_data) = etcd_data {
-            if let Some(first_value) = etcd_data.kvs().first() {
-                let this_chateau_metadata =
-                    bincode::deserialize::<ChateauMetadataEtcd>(first_value.value()).unwrap();
-
-                chateau_metadata.insert(chateau_id.clone(), this_chateau_metadata);
-            }
+        if let Some(this_chateau_metadata) = get_node_for_chateau(&coordinator, &chateau_id).await {
+            chateau_metadata.insert(chateau_id.clone(), this_chateau_metadata);
         }
     }
 
@@ -950,6 +943,7 @@ pub async fn nearby_from_coords(
                                     route_id: itin_ref.route_id.clone(),
                                     trip_start_time: trip.start_time,
                                     trip_short_name: trip.trip_short_name.clone(),
+                                    direction_id: trip.direction_id,
                                 };
 
                                 match valid_trips.entry(trip.trip_id.clone()) {
@@ -996,6 +990,41 @@ pub async fn nearby_from_coords(
                 }
                 .flatten();
 
+                let crowding_profiles = {
+                    let stop_ids = valid_trips
+                        .values()
+                        .flatten()
+                        .map(|trip| trip.itinerary_options[0].stop_id.to_string())
+                        .collect::<BTreeSet<String>>()
+                        .into_iter()
+                        .collect::<Vec<String>>();
+
+                    let buckets = valid_trips
+                        .values()
+                        .flatten()
+                        .filter_map(|trip| trip.timezone)
+                        .collect::<HashSet<chrono_tz::Tz>>()
+                        .iter()
+                        .flat_map(|timezone| {
+                            time_of_week_buckets_between(
+                                departure_time_chrono - seek_back,
+                                departure_time_chrono + seek_forward,
+                                timezone,
+                            )
+                        })
+                        .collect::<BTreeSet<i16>>()
+                        .into_iter()
+                        .collect::<Vec<i16>>();
+
+                    match load_crowding_profiles(conn, chateau_id, &stop_ids, &buckets).await {
+                        Ok(crowding_profiles) => crowding_profiles,
+                        Err(e) => {
+                            eprintln!("Could not load crowding profiles: {}", e);
+                            AHashMap::new()
+                        }
+                    }
+                };
+
                 //sort through each time response
 
                 //  temp_answer.insert(chateau_id.clone(), valid_trips);
@@ -1056,6 +1085,9 @@ pub async fn nearby_from_coords(
 
                         let mut departure_time_rt: Option<u64> = None;
                         let mut platform: Option<String> = None;
+                        let mut platform_confidence: Option<PlatformConfidence> = None;
+                        let mut platform_changed_from_scheduled: bool = false;
+                        let mut delay_predicted_from_block: bool = false;
 
                         if let Some(gtfs_trip_aspenised) = gtfs_trips_aspenised.as_ref() {
                             if let Some(trip_update_ids) = gtfs_trip_aspenised
@@ -1095,6 +1127,9 @@ pub async fn nearby_from_coords(
                                         if trip_update.trip.schedule_relationship == Some(3) {
                                             is_cancelled = true;
                                         } else {
+                                            delay_predicted_from_block =
+                                                trip_update.block_delay_prediction.is_some();
+
                                             let relevant_stop_time_update =
                                                 trip_update.stop_time_update.iter().find(|x| {
                                                     x.stop_id
@@ -1127,6 +1162,15 @@ pub async fn nearby_from_coords(
                                                 {
                                                     platform = Some(platform_id.clone());
                                                 }
+
+                                                if let Some(platform_assignment) =
+                                                    &relevant_stop_time_update.platform_assignment
+                                                {
+                                                    platform_confidence =
+                                                        Some(platform_assignment.confidence);
+                                                    platform_changed_from_scheduled =
+                                                        platform_assignment.changed_from_scheduled;
+                                                }
                                             }
                                         }
                                     }
@@ -1134,20 +1178,8 @@ pub async fn nearby_from_coords(
                             }
                         }
 
-                        headsign_group.trips.push(DepartingTrip {
-                            trip_id: trip.trip_id.clone(),
-                            gtfs_schedule_start_day: trip.trip_service_date,
-                            departure_realtime: departure_time_rt,
-                            arrival_schedule: None,
-                            arrival_realtime: None,
-                            stop_id: trip.itinerary_options[0].stop_id.clone(),
-                            trip_short_name: trip.trip_short_name.clone(),
-                            tz: trip.timezone.as_ref().unwrap().name().to_string(),
-                            is_frequency: trip.frequencies.is_some(),
-                            platform: platform,
-                            departure_schedule: match trip.itinerary_options[0]
-                                .departure_time_since_start
-                            {
+                        let departure_schedule =
+                            match trip.itinerary_options[0].departure_time_since_start {
                                 Some(departure_time_since_start) => Some(
                                     trip.reference_start_of_service_date.timestamp() as u64
                                         + trip.trip_start_time as u64
@@ -1170,12 +1202,51 @@ pub async fn nearby_from_coords(
                                         None => None,
                                     },
                                 },
-                            },
+                            };
+
+                        let live_crowding = gtfs_trips_aspenised.as_ref().and_then(|x| {
+                            x.trip_id_to_live_crowding
+                                .get(trip.trip_id.as_str())
+                                .cloned()
+                        });
+
+                        let expected_crowding = live_crowding.or_else(|| {
+                            let departure = departure_time_rt.or(departure_schedule)?;
+                            let local_departure =
+                                chrono::DateTime::from_timestamp(departure as i64, 0)?
+                                    .with_timezone(trip.timezone.as_ref()?);
+
+                            crowding_profiles
+                                .get(&(
+                                    trip.route_id.to_string(),
+                                    direction_key(trip.direction_id),
+                                    trip.itinerary_options[0].stop_id.to_string(),
+                                    time_of_week_bucket(&local_departure),
+                                ))
+                                .and_then(expected_crowding_from_profile)
+                        });
+
+                        headsign_group.trips.push(DepartingTrip {
+                            trip_id: trip.trip_id.clone(),
+                            gtfs_schedule_start_day: trip.trip_service_date,
+                            departure_realtime: departure_time_rt,
+                            arrival_schedule: None,
+                            arrival_realtime: None,
+                            stop_id: trip.itinerary_options[0].stop_id.clone(),
+                            trip_short_name: trip.trip_short_name.clone(),
+                            tz: trip.timezone.as_ref().unwrap().name().to_string(),
+                            is_frequency: trip.frequencies.is_some(),
+                            platform,
+                            platform_confidence,
+                            platform_changed_from_scheduled,
+                            departure_schedule,
                             is_interpolated: trip.itinerary_options[0]
                                 .interpolated_time_since_start
                                 .is_some(),
                             gtfs_frequency_start_time: None,
                             cancelled: is_cancelled,
+                            delay_predicted_from_block,
+                            expected_crowding,
                         });
                     }
 
@@ -1231,6 +1302,20 @@ pub async fn nearby_from_coords(
 
             let total_elapsed_time = start.elapsed();
 
+            for (stage, duration) in [
+                ("stop_lookup", end_stops_duration),
+                ("directions", directions_lookup_duration),
+                ("itinerary_meta", itineraries_meta_duration),
+                ("itinerary_rows", itinerary_duration),
+                ("trips", trip_lookup_elapsed),
+                ("route_and_calendar", calendar_timer_finish),
+                ("total", total_elapsed_time),
+            ] {
+                NEARBY_DEPARTURES_STAGE_DURATION
+                    .with_label_values(&[stage])
+                    .observe(duration.as_secs_f64());
+            }
+
             HttpResponse::Ok().json(DepartingTripsDataAnswer {
                 number_of_stops_searched_through: stops.len(),
                 bus_limited_metres: bus_distance_limit as f64,
@@ -1251,58 +1336,6 @@ pub async fn nearby_from_coords(
     }
 }
 
-fn make_calendar_structure_from_pg_single_chateau(
-    services_calendar_lookup_queries_to_perform: Vec<catenary::models::Calendar>,
-    services_calendar_dates_lookup_queries_to_perform: Vec<catenary::models::CalendarDate>,
-) -> BTreeMap<String, catenary::CalendarUnified> {
-    let mut calendar_structures: BTreeMap<String, catenary::CalendarUnified> = BTreeMap::new();
-
-    for calendar in services_calendar_lookup_queries_to_perform {
-        calendar_structures.insert(
-            calendar.service_id.clone(),
-            catenary::CalendarUnified {
-                id: calendar.service_id.clone(),
-                general_calendar: Some(catenary::GeneralCalendar {
-                    days: make_weekdays(&calendar),
-                    start_date: calendar.gtfs_start_date,
-                    end_date: calendar.gtfs_end_date,
-                }),
-                exceptions: None,
-            },
-        );
-    }
-
-    for calendar_date in services_calendar_dates_lookup_queries_to_perform {
-        let exception_number = match calendar_date.exception_type {
-            1 => gtfs_structures::Exception::Added,
-            2 => gtfs_structures::Exception::Deleted,
-            _ => panic!("WHAT IS THIS!!!!!!"),
-        };
-
-        match calendar_structures.entry(calendar_date.service_id.clone()) {
-            btree_map::Entry::Occupied(mut oe) => {
-                let mut calendar_unified = oe.get_mut();
-
-                if let Some(entry) = &mut calendar_unified.exceptions {
-                    entry.insert(calendar_date.gtfs_date, exception_number);
-                } else {
-                    calendar_unified.exceptions = Some(BTreeMap::from_iter([(
-                        calendar_date.gtfs_date,
-                        exception_number,
-                    )]));
-                }
-            }
-            btree_map::Entry::Vacant(mut ve) => {
-                ve.insert(CalendarUnified::empty_exception_from_calendar_date(
-                    &calendar_date,
-                ));
-            }
-        }
-    }
-
-    calendar_structures
-}
-
 fn make_calendar_structure_from_pg(
     services_calendar_lookup_queries_to_perform: Vec<
         diesel::QueryResult<Vec<catenary::models::Calendar>>,
@@ -1371,10 +1404,9 @@ fn make_calendar_structure_from_pg(
             let pile_of_calendars = calendar_structures.get_mut(&chateau).unwrap();
 
             for calendar_date in calendar_date_group {
-                let exception_number = match calendar_date.exception_type {
-                    1 => gtfs_structures::Exception::Added,
-                    2 => gtfs_structures::Exception::Deleted,
-                    _ => panic!("WHAT IS THIS!!!!!!"),
+                let Some(exception_number) = catenary::exception_from_calendar_date(&calendar_date)
+                else {
+                    continue;
                 };
 
                 match pile_of_calendars.entry(calendar_date.service_id.clone()) {
@@ -1393,6 +1425,7 @@ fn make_calendar_structure_from_pg(
                     btree_map::Entry::Vacant(mut ve) => {
                         ve.insert(CalendarUnified::empty_exception_from_calendar_date(
                             &calendar_date,
+                            exception_number,
                         ));
                     }
                 }
@@ -1403,7 +1436,7 @@ fn make_calendar_structure_from_pg(
     Ok(calendar_structures)
 }
 
-fn make_degree_length_as_distance_from_point(point: &geo::Point, distance_metres: f64) -> f64 {
+pub fn make_degree_length_as_distance_from_point(point: &geo::Point, distance_metres: f64) -> f64 {
     let direction = match point.x() > 0. {
         true => 90.,
         false => -90.,
@@ -3,7 +3,6 @@ use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Res
 use catenary::models::IpToGeoAddr;
 use catenary::postgis_to_diesel::diesel_multi_polygon_to_geo;
 use catenary::postgres_tools::{make_async_pool, CatenaryPostgresPool};
-use catenary::EtcdConnectionIps;
 use geojson::{Feature, GeoJson, JsonValue};
 use ordered_float::Pow;
 use serde::Deserialize;
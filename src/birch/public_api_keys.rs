@@ -0,0 +1,264 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use super::api_key_management::authorise_request;
+use super::rate_limit::api_key_from_request;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use catenary::admin_auth::AdminRole;
+use catenary::models::{PublicApiKey, PublicApiKeyUsage};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use rand::distr::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const USAGE_REPORT_DAYS: i64 = 30;
+
+#[derive(Deserialize)]
+struct NewPublicApiKeyReq {
+    name: String,
+    owner_email: String,
+    daily_quota: Option<i64>,
+    tiles_per_minute: Option<i32>,
+    realtime_per_minute: Option<i32>,
+    departures_per_minute: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct PublicApiKeyWithUsage {
+    key: PublicApiKey,
+    // date -> endpoint class -> (requests, rejected)
+    usage: BTreeMap<chrono::NaiveDate, BTreeMap<String, (i64, i64)>>,
+}
+
+fn make_public_api_key() -> String {
+    let random_part: String = rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+
+    format!("cat_{}", random_part)
+}
+
+fn group_usage(
+    rows: Vec<PublicApiKeyUsage>,
+) -> BTreeMap<String, BTreeMap<chrono::NaiveDate, BTreeMap<String, (i64, i64)>>> {
+    let mut grouped: BTreeMap<String, BTreeMap<chrono::NaiveDate, BTreeMap<String, (i64, i64)>>> =
+        BTreeMap::new();
+
+    for row in rows {
+        grouped
+            .entry(row.api_key)
+            .or_default()
+            .entry(row.usage_date)
+            .or_default()
+            .insert(row.endpoint_class, (row.request_count, row.rejected_count));
+    }
+
+    grouped
+}
+
+async fn load_usage(
+    pool: &CatenaryPostgresPool,
+    api_key: Option<&str>,
+) -> Result<Vec<PublicApiKeyUsage>, Box<dyn std::error::Error + Send + Sync>> {
+    let conn = &mut pool.get().await?;
+
+    use catenary::schema::gtfs::public_api_key_usage as usage_table;
+
+    let since = chrono::Utc::now().date_naive() - chrono::Duration::days(USAGE_REPORT_DAYS);
+
+    let mut query = usage_table::table
+        .filter(usage_table::usage_date.ge(since))
+        .select(PublicApiKeyUsage::as_select())
+        .into_boxed();
+
+    if let Some(api_key) = api_key {
+        query = query.filter(usage_table::api_key.eq(api_key.to_string()));
+    }
+
+    Ok(query.load::<PublicApiKeyUsage>(conn).await?)
+}
+
+#[actix_web::post("/admin/public_api_keys")]
+pub async fn create_public_api_key(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+    input: web::Json<NewPublicApiKeyReq>,
+) -> impl Responder {
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::FeedAdmin).await {
+        return response;
+    }
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let input = input.into_inner();
+
+    let new_key = PublicApiKey {
+        api_key: make_public_api_key(),
+        name: input.name,
+        owner_email: input.owner_email,
+        daily_quota: input.daily_quota,
+        tiles_per_minute: input.tiles_per_minute,
+        realtime_per_minute: input.realtime_per_minute,
+        departures_per_minute: input.departures_per_minute,
+        disabled: false,
+        created_ms: catenary::duration_since_unix_epoch().as_millis() as i64,
+    };
+
+    let insert_result =
+        diesel::insert_into(catenary::schema::gtfs::public_api_keys::dsl::public_api_keys)
+            .values(&new_key)
+            .execute(conn)
+            .await;
+
+    match insert_result {
+        Ok(_) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .json(new_key),
+        Err(e) => {
+            eprintln!("could not insert public api key\n{}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[actix_web::post("/admin/public_api_keys/{api_key}/disable")]
+pub async fn disable_public_api_key(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+    api_key: web::Path<String>,
+) -> impl Responder {
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::FeedAdmin).await {
+        return response;
+    }
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    use catenary::schema::gtfs::public_api_keys as public_api_keys_table;
+
+    let update_result = diesel::update(
+        public_api_keys_table::table
+            .filter(public_api_keys_table::api_key.eq(api_key.into_inner())),
+    )
+    .set(public_api_keys_table::disabled.eq(true))
+    .execute(conn)
+    .await;
+
+    match update_result {
+        Ok(0) => HttpResponse::NotFound().finish(),
+        Ok(_) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "no-cache"))
+            .finish(),
+        Err(e) => {
+            eprintln!("could not disable public api key\n{}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[actix_web::get("/admin/public_api_keys")]
+pub async fn list_public_api_keys(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::FeedAdmin).await {
+        return response;
+    }
+
+    let keys = {
+        let conn_pool = pool.as_ref();
+        let conn_pre = conn_pool.get().await;
+        let conn = &mut match conn_pre {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+        catenary::schema::gtfs::public_api_keys::dsl::public_api_keys
+            .select(PublicApiKey::as_select())
+            .load::<PublicApiKey>(conn)
+            .await
+    };
+
+    let usage = load_usage(pool.as_ref(), None).await;
+
+    match (keys, usage) {
+        (Ok(keys), Ok(usage)) => {
+            let mut usage = group_usage(usage);
+
+            let response = keys
+                .into_iter()
+                .map(|key| PublicApiKeyWithUsage {
+                    usage: usage.remove(&key.api_key).unwrap_or_default(),
+                    key,
+                })
+                .collect::<Vec<PublicApiKeyWithUsage>>();
+
+            HttpResponse::Ok()
+                .append_header(("Cache-Control", "no-cache"))
+                .json(response)
+        }
+        (Err(e), _) => {
+            eprintln!("Error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+        (_, Err(e)) => {
+            eprintln!("Error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Lets a key holder see their own usage for the last 30 days
+#[actix_web::get("/api_key_usage")]
+pub async fn own_api_key_usage(
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    req: HttpRequest,
+) -> impl Responder {
+    let api_key = match api_key_from_request(&req) {
+        Some(api_key) => api_key,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    match load_usage(pool.as_ref(), Some(&api_key)).await {
+        Ok(usage) => {
+            let mut usage = group_usage(usage);
+
+            HttpResponse::Ok()
+                .append_header(("Cache-Control", "no-cache"))
+                .json(usage.remove(&api_key).unwrap_or_default())
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,641 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, HttpResponse};
+use ahash::AHashMap;
+use catenary::models::PublicApiKey;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use chrono::NaiveDate;
+use dashmap::DashMap;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub const API_KEY_HEADER: &str = "X-Catenary-Api-Key";
+pub const API_KEY_QUERY_PARAM: &str = "api_key";
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+const IDLE_BUCKET_EXPIRY: Duration = Duration::from_secs(600);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EndpointClass {
+    Tiles,
+    Realtime,
+    Departures,
+    /// Endpoints that do a lot of work per request, such as isochrones and timetables
+    Compute,
+    /// Everything not in another class
+    Other,
+}
+
+impl EndpointClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndpointClass::Tiles => "tiles",
+            EndpointClass::Realtime => "realtime",
+            EndpointClass::Departures => "departures",
+            EndpointClass::Compute => "compute",
+            EndpointClass::Other => "other",
+        }
+    }
+
+    /// Paths that are in no other class are metered as `Other`
+    pub fn from_path(path: &str) -> EndpointClass {
+        const TILE_PREFIXES: [&str; 9] = [
+            "/shapes_",
+            "/busstops",
+            "/railstops",
+            "/otherstops",
+            "/station_features",
+            "/terrain_tiles_proxy",
+            "/watchduty_tiles_proxy",
+            "/chateau_boundaries",
+            "/chateau_coverage/",
+        ];
+
+        const REALTIME_PREFIXES: [&str; 6] = [
+            "/get_realtime_locations",
+            "/bulk_realtime_fetch",
+            "/get_trip_information",
+            "/get_vehicle",
+            "/gtfs_rt",
+            "/realtime_vehicles",
+        ];
+
+        const DEPARTURES_PREFIXES: [&str; 2] =
+            ["/nearbydeparturesfromcoords", "/departures_at_stop"];
+
+        const COMPUTE_PREFIXES: [&str; 5] = [
+            "/isochrone",
+            "/route_timetable",
+            "/service_calendar",
+            "/vehicle_trajectory",
+            "/chateau_replay",
+        ];
+
+        let matches = |prefixes: &[&str]| prefixes.iter().any(|prefix| path.starts_with(prefix));
+
+        if matches(&TILE_PREFIXES) {
+            EndpointClass::Tiles
+        } else if matches(&REALTIME_PREFIXES) {
+            EndpointClass::Realtime
+        } else if matches(&DEPARTURES_PREFIXES) {
+            EndpointClass::Departures
+        } else if matches(&COMPUTE_PREFIXES) {
+            EndpointClass::Compute
+        } else {
+            EndpointClass::Other
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PerMinuteLimits {
+    pub tiles: u32,
+    pub realtime: u32,
+    pub departures: u32,
+    pub compute: u32,
+    pub other: u32,
+}
+
+impl PerMinuteLimits {
+    fn for_class(&self, endpoint_class: EndpointClass) -> u32 {
+        match endpoint_class {
+            EndpointClass::Tiles => self.tiles,
+            EndpointClass::Realtime => self.realtime,
+            EndpointClass::Departures => self.departures,
+            EndpointClass::Compute => self.compute,
+            EndpointClass::Other => self.other,
+        }
+    }
+
+    // compute and other have no per key columns, keys get the defaults for them
+    fn with_overrides(&self, key: &PublicApiKey) -> PerMinuteLimits {
+        PerMinuteLimits {
+            tiles: key
+                .tiles_per_minute
+                .map(|x| x.max(0) as u32)
+                .unwrap_or(self.tiles),
+            realtime: key
+                .realtime_per_minute
+                .map(|x| x.max(0) as u32)
+                .unwrap_or(self.realtime),
+            departures: key
+                .departures_per_minute
+                .map(|x| x.max(0) as u32)
+                .unwrap_or(self.departures),
+            ..*self
+        }
+    }
+}
+
+/// Requests without a key are limited per IP address
+pub const ANONYMOUS_LIMITS: PerMinuteLimits = PerMinuteLimits {
+    tiles: 600,
+    realtime: 120,
+    departures: 6,
+    compute: 6,
+    other: 300,
+};
+
+/// Keys without their own limits in `public_api_keys` get these
+pub const DEFAULT_KEY_LIMITS: PerMinuteLimits = PerMinuteLimits {
+    tiles: 3000,
+    realtime: 600,
+    departures: 60,
+    compute: 60,
+    other: 1200,
+};
+
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Refills at `per_minute / 60` tokens a second, up to `per_minute` tokens.
+    /// Returns the number of seconds until a token is available if the bucket is empty.
+    pub fn try_take(&mut self, per_minute: u32, now: Instant) -> Result<(), u64> {
+        let capacity = per_minute as f64;
+        let refill_per_second = capacity / 60.0;
+
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * refill_per_second).min(capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if refill_per_second <= 0.0 {
+            Err(60)
+        } else {
+            Err(((1.0 - self.tokens) / refill_per_second).ceil() as u64)
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug)]
+struct UsageCounter {
+    requests: u64,
+    rejected: u64,
+}
+
+pub enum RateLimitDecision {
+    Allow,
+    InvalidKey,
+    TooManyRequests { retry_after_secs: u64 },
+    DailyQuotaExceeded,
+}
+
+pub struct RateLimiterState {
+    keys: RwLock<AHashMap<String, PublicApiKey>>,
+    buckets: DashMap<(String, EndpointClass), TokenBucket>,
+    // usage since the last flush to postgres, by the UTC day the requests were made
+    pending_usage: DashMap<(String, NaiveDate, EndpointClass), UsageCounter>,
+    // requests per key for the current UTC day, used for quotas
+    daily_totals: DashMap<String, (NaiveDate, u64)>,
+    trusted_origins: Vec<String>,
+    // only these peers may tell us the client address through X-Forwarded-For or Forwarded
+    trusted_proxies: Vec<IpAddr>,
+}
+
+fn comma_separated_env(name: &str, default: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<String>>()
+}
+
+impl RateLimiterState {
+    pub fn new(trusted_origins: Vec<String>, trusted_proxies: Vec<IpAddr>) -> Self {
+        RateLimiterState {
+            keys: RwLock::new(AHashMap::new()),
+            buckets: DashMap::new(),
+            pending_usage: DashMap::new(),
+            daily_totals: DashMap::new(),
+            trusted_origins,
+            trusted_proxies,
+        }
+    }
+
+    /// Reads `BIRCH_TRUSTED_ORIGINS`, a comma separated list of first party origins that are not rate limited,
+    /// and `BIRCH_TRUSTED_PROXIES`, a comma separated list of proxy IP addresses
+    pub fn from_env() -> Self {
+        let trusted_origins = comma_separated_env(
+            "BIRCH_TRUSTED_ORIGINS",
+            "https://maps.catenarymaps.org,https://catenarymaps.org",
+        );
+
+        let trusted_proxies = comma_separated_env("BIRCH_TRUSTED_PROXIES", "")
+            .into_iter()
+            .filter_map(|ip| match IpAddr::from_str(&ip) {
+                Ok(ip) => Some(ip),
+                Err(_) => {
+                    eprintln!("Ignoring trusted proxy {}, not an IP address", ip);
+                    None
+                }
+            })
+            .collect::<Vec<IpAddr>>();
+
+        RateLimiterState::new(trusted_origins, trusted_proxies)
+    }
+
+    /// The address anonymous limits are keyed on.
+    /// Forwarded headers are only believed when the connection comes from a trusted proxy.
+    fn client_ip(&self, req: &ServiceRequest) -> String {
+        let peer_ip = req.peer_addr().map(|addr| addr.ip());
+
+        match peer_ip {
+            Some(peer_ip) if self.trusted_proxies.contains(&peer_ip) => req
+                .connection_info()
+                .realip_remote_addr()
+                .unwrap_or("unknown")
+                .to_string(),
+            Some(peer_ip) => peer_ip.to_string(),
+            None => "unknown".to_string(),
+        }
+    }
+
+    fn is_trusted_origin(&self, req: &ServiceRequest) -> bool {
+        let headers = req.headers();
+
+        ["Origin", "Referer"].iter().any(|header_name| {
+            headers
+                .get(*header_name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| {
+                    self.trusted_origins
+                        .iter()
+                        .any(|origin| value == origin || value.starts_with(&format!("{}/", origin)))
+                })
+                .unwrap_or(false)
+        })
+    }
+
+    pub fn check(&self, req: &ServiceRequest, endpoint_class: EndpointClass) -> RateLimitDecision {
+        let now = Instant::now();
+
+        match api_key_from_request(req.request()) {
+            Some(api_key) => {
+                let key_row = {
+                    let keys = self.keys.read().unwrap();
+                    keys.get(&api_key).cloned()
+                };
+
+                let key_row = match key_row {
+                    Some(key_row) if !key_row.disabled => key_row,
+                    _ => return RateLimitDecision::InvalidKey,
+                };
+
+                let today = chrono::Utc::now().date_naive();
+
+                if let Some(daily_quota) = key_row.daily_quota {
+                    let used_today = match self.daily_totals.get(&api_key) {
+                        Some(entry) if entry.0 == today => entry.1,
+                        _ => 0,
+                    };
+
+                    if used_today >= daily_quota.max(0) as u64 {
+                        self.record_usage(&api_key, today, endpoint_class, false);
+                        return RateLimitDecision::DailyQuotaExceeded;
+                    }
+                }
+
+                let limits = DEFAULT_KEY_LIMITS.with_overrides(&key_row);
+
+                let decision = self.take_token(
+                    api_key.clone(),
+                    endpoint_class,
+                    limits.for_class(endpoint_class),
+                    now,
+                );
+
+                let allowed = matches!(decision, RateLimitDecision::Allow);
+
+                self.record_usage(&api_key, today, endpoint_class, allowed);
+
+                if allowed {
+                    self.daily_totals
+                        .entry(api_key)
+                        .and_modify(|(date, count)| {
+                            if *date != today {
+                                *date = today;
+                                *count = 0;
+                            }
+                            *count += 1;
+                        })
+                        .or_insert((today, 1));
+                }
+
+                decision
+            }
+            // the Catenary frontend does not send a key
+            None if self.is_trusted_origin(req) => RateLimitDecision::Allow,
+            None => self.take_token(
+                format!("ip:{}", self.client_ip(req)),
+                endpoint_class,
+                ANONYMOUS_LIMITS.for_class(endpoint_class),
+                now,
+            ),
+        }
+    }
+
+    fn take_token(
+        &self,
+        bucket_key: String,
+        endpoint_class: EndpointClass,
+        per_minute: u32,
+        now: Instant,
+    ) -> RateLimitDecision {
+        let mut bucket = self
+            .buckets
+            .entry((bucket_key, endpoint_class))
+            .or_insert_with(|| TokenBucket::new(per_minute, now));
+
+        match bucket.try_take(per_minute, now) {
+            Ok(()) => RateLimitDecision::Allow,
+            Err(retry_after_secs) => RateLimitDecision::TooManyRequests { retry_after_secs },
+        }
+    }
+
+    fn record_usage(
+        &self,
+        api_key: &str,
+        date: NaiveDate,
+        endpoint_class: EndpointClass,
+        allowed: bool,
+    ) {
+        let mut counter = self
+            .pending_usage
+            .entry((api_key.to_string(), date, endpoint_class))
+            .or_default();
+
+        match allowed {
+            true => counter.requests += 1,
+            false => counter.rejected += 1,
+        }
+    }
+
+    pub async fn reload_keys(
+        &self,
+        pool: &CatenaryPostgresPool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = &mut pool.get().await?;
+
+        use catenary::schema::gtfs::public_api_keys as public_api_keys_table;
+
+        let keys = public_api_keys_table::table
+            .select(PublicApiKey::as_select())
+            .load::<PublicApiKey>(conn)
+            .await?;
+
+        let keys = keys
+            .into_iter()
+            .map(|key| (key.api_key.clone(), key))
+            .collect::<AHashMap<String, PublicApiKey>>();
+
+        *self.keys.write().unwrap() = keys;
+
+        Ok(())
+    }
+
+    /// Seeds the daily totals from postgres so quotas survive a restart
+    pub async fn load_daily_totals(
+        &self,
+        pool: &CatenaryPostgresPool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let conn = &mut pool.get().await?;
+
+        use catenary::schema::gtfs::public_api_key_usage as usage_table;
+
+        let today = chrono::Utc::now().date_naive();
+
+        let rows = usage_table::table
+            .filter(usage_table::usage_date.eq(today))
+            .select(catenary::models::PublicApiKeyUsage::as_select())
+            .load::<catenary::models::PublicApiKeyUsage>(conn)
+            .await?;
+
+        for row in rows {
+            self.daily_totals
+                .entry(row.api_key)
+                .and_modify(|(_, count)| *count += row.request_count.max(0) as u64)
+                .or_insert((today, row.request_count.max(0) as u64));
+        }
+
+        Ok(())
+    }
+
+    pub async fn flush_usage(
+        &self,
+        pool: &CatenaryPostgresPool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let keys_to_flush = self
+            .pending_usage
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<(String, NaiveDate, EndpointClass)>>();
+
+        let rows = keys_to_flush
+            .into_iter()
+            .filter_map(|key| self.pending_usage.remove(&key))
+            .map(|((api_key, usage_date, endpoint_class), counter)| {
+                catenary::models::PublicApiKeyUsage {
+                    api_key,
+                    usage_date,
+                    endpoint_class: endpoint_class.as_str().to_string(),
+                    request_count: counter.requests as i64,
+                    rejected_count: counter.rejected as i64,
+                }
+            })
+            .collect::<Vec<catenary::models::PublicApiKeyUsage>>();
+
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let conn = &mut pool.get().await?;
+
+        use catenary::schema::gtfs::public_api_key_usage as usage_table;
+        use diesel::upsert::excluded;
+
+        for chunk in rows.chunks(100) {
+            diesel::insert_into(usage_table::table)
+                .values(chunk)
+                .on_conflict((
+                    usage_table::api_key,
+                    usage_table::usage_date,
+                    usage_table::endpoint_class,
+                ))
+                .do_update()
+                .set((
+                    usage_table::request_count
+                        .eq(usage_table::request_count + excluded(usage_table::request_count)),
+                    usage_table::rejected_count
+                        .eq(usage_table::rejected_count + excluded(usage_table::rejected_count)),
+                ))
+                .execute(conn)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn prune_idle_buckets(&self) {
+        let now = Instant::now();
+
+        self.buckets.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.last_refill) < IDLE_BUCKET_EXPIRY
+        });
+    }
+}
+
+/// The key from the `X-Catenary-Api-Key` header, or the `api_key` query parameter
+pub fn api_key_from_request(req: &HttpRequest) -> Option<String> {
+    if let Some(header_value) = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        return Some(header_value.trim().to_string());
+    }
+
+    qstring::QString::from(req.query_string())
+        .get(API_KEY_QUERY_PARAM)
+        .map(|x| x.to_string())
+}
+
+/// Reloads keys and flushes usage counts to postgres every minute
+pub async fn rate_limit_sync_loop(state: Arc<RateLimiterState>, pool: Arc<CatenaryPostgresPool>) {
+    if let Err(e) = state.load_daily_totals(pool.as_ref()).await {
+        eprintln!("Could not load api key usage totals: {:?}", e);
+    }
+
+    loop {
+        if let Err(e) = state.reload_keys(pool.as_ref()).await {
+            eprintln!("Could not reload public api keys: {:?}", e);
+        }
+
+        if let Err(e) = state.flush_usage(pool.as_ref()).await {
+            eprintln!("Could not flush api key usage: {:?}", e);
+        }
+
+        state.prune_idle_buckets();
+
+        tokio::time::sleep(SYNC_INTERVAL).await;
+    }
+}
+
+pub async fn rate_limit_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let endpoint_class = EndpointClass::from_path(req.path());
+
+    let state = match req.app_data::<web::Data<Arc<RateLimiterState>>>() {
+        Some(state) => Arc::clone(state.get_ref()),
+        None => return next.call(req).await.map(|res| res.map_into_left_body()),
+    };
+
+    let rejection = match state.check(&req, endpoint_class) {
+        RateLimitDecision::Allow => None,
+        RateLimitDecision::InvalidKey => Some(
+            HttpResponse::Forbidden()
+                .insert_header(("Cache-Control", "no-cache"))
+                .body("Invalid or disabled API key"),
+        ),
+        RateLimitDecision::TooManyRequests { retry_after_secs } => Some(
+            HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after_secs.max(1).to_string()))
+                .insert_header(("Cache-Control", "no-cache"))
+                .body(format!(
+                    "Rate limit exceeded for {} endpoints",
+                    endpoint_class.as_str()
+                )),
+        ),
+        RateLimitDecision::DailyQuotaExceeded => Some(
+            HttpResponse::TooManyRequests()
+                .insert_header(("Cache-Control", "no-cache"))
+                .body("Daily quota exceeded for this API key"),
+        ),
+    };
+
+    match rejection {
+        None => next.call(req).await.map(|res| res.map_into_left_body()),
+        Some(rejection) => Ok(req.into_response(rejection).map_into_right_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+
+        assert!(bucket.try_take(2, start).is_ok());
+        assert!(bucket.try_take(2, start).is_ok());
+        assert!(bucket.try_take(2, start).is_err());
+
+        //2 per minute refills one token every 30 seconds
+        assert!(bucket.try_take(2, start + Duration::from_secs(31)).is_ok());
+    }
+
+    #[test]
+    fn test_endpoint_classes() {
+        assert_eq!(
+            EndpointClass::from_path("/shapes_bus/10/100/200"),
+            EndpointClass::Tiles
+        );
+        assert_eq!(
+            EndpointClass::from_path("/nearbydeparturesfromcoords"),
+            EndpointClass::Departures
+        );
+        assert_eq!(
+            EndpointClass::from_path("/get_realtime_locations/a/b/0/0"),
+            EndpointClass::Realtime
+        );
+        assert_eq!(
+            EndpointClass::from_path("/realtime_vehicles/10/100/200.pbf"),
+            EndpointClass::Realtime
+        );
+        assert_eq!(
+            EndpointClass::from_path("/isochrone"),
+            EndpointClass::Compute
+        );
+        assert_eq!(
+            EndpointClass::from_path("/chateau_coverage/point"),
+            EndpointClass::Tiles
+        );
+        assert_eq!(
+            EndpointClass::from_path("/getchateaus"),
+            EndpointClass::Other
+        );
+    }
+}
@@ -0,0 +1,510 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Live vehicles for ordinary map libraries, as vector tiles and as GeoJSON.
+// Vehicles come from every Aspen worker whose chateau hull touches the requested area.
+// Each chateau's vehicles are kept for a few seconds, so neighbouring tiles share one fetch.
+// Tiles asking for a chateau while it is being fetched wait for that fetch instead of starting another.
+
+use crate::aspen_clients::AspenClients;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use catenary::coordination::Coordinator;
+use catenary::get_node_for_chateau;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tarpc::context;
+use tilejson::TileJSON;
+
+// below this zoom a tile covers too many chateaus to ask every worker
+const MIN_ZOOM: u8 = 6;
+// square degrees
+const MAX_BBOX_AREA: f64 = 25.;
+// tiles are served with max-age=5 as well
+const CHATEAU_VEHICLES_TTL: Duration = Duration::from_secs(3);
+// ST_AsMVTGeom keeps geometry this far outside the tile, out of an extent of 4096,
+// so icons on the edge of a tile are drawn by both tiles
+const TILE_BUFFER: f64 = 64. / 4096.;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Bbox {
+    west: f64,
+    south: f64,
+    east: f64,
+    north: f64,
+}
+
+impl Bbox {
+    fn contains(&self, lon: f64, lat: f64) -> bool {
+        lon >= self.west && lon <= self.east && lat >= self.south && lat <= self.north
+    }
+}
+
+/// Web mercator tile to longitude and latitude bounds, grown by `buffer` tiles on every side
+fn tile_bbox(z: u8, x: u32, y: u32, buffer: f64) -> Bbox {
+    let n = 2f64.powi(z as i32);
+    let (x, y) = (x as f64, y as f64);
+
+    let lat_of = |y: f64| {
+        (std::f64::consts::PI * (1. - 2. * y / n))
+            .sinh()
+            .atan()
+            .to_degrees()
+    };
+
+    Bbox {
+        west: (x - buffer) / n * 360. - 180.,
+        east: (x + 1. + buffer) / n * 360. - 180.,
+        north: lat_of(y - buffer),
+        south: lat_of(y + 1. + buffer),
+    }
+}
+
+/// One vehicle, flattened with the display data of its route.
+/// The field names are the column names in the tile.
+#[derive(Serialize, Clone, Debug)]
+struct VehicleFeature {
+    chateau: String,
+    id: String,
+    lat: f64,
+    lon: f64,
+    bearing: Option<f32>,
+    label: Option<String>,
+    route_id: Option<String>,
+    trip_id: Option<String>,
+    route_type: i16,
+    route_short_name: Option<String>,
+    route_long_name: Option<String>,
+    color: Option<String>,
+    text_color: Option<String>,
+    timestamp: Option<u64>,
+}
+
+type VehicleFetch = Shared<BoxFuture<'static, Option<Arc<Vec<VehicleFeature>>>>>;
+
+#[derive(Default)]
+pub struct ChateauVehicleCache {
+    aspen_clients: AspenClients,
+    chateaus: DashMap<String, (Instant, Arc<Vec<VehicleFeature>>)>,
+    // fetches that have not finished yet, by chateau
+    in_flight: DashMap<String, VehicleFetch>,
+}
+
+impl ChateauVehicleCache {
+    /// Every vehicle of the chateau with a position, fetched from Aspen at most once per TTL
+    async fn chateau_vehicles(
+        self: &Arc<Self>,
+        coordinator: &Coordinator,
+        chateau_id: &str,
+    ) -> Option<Arc<Vec<VehicleFeature>>> {
+        if let Some(entry) = self.chateaus.get(chateau_id) {
+            if entry.0.elapsed() < CHATEAU_VEHICLES_TTL {
+                return Some(Arc::clone(&entry.1));
+            }
+        }
+
+        let fetch = match self.in_flight.entry(chateau_id.to_string()) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                let cache = Arc::clone(self);
+                let coordinator = coordinator.clone();
+                let chateau_id = chateau_id.to_string();
+
+                let fetch = async move {
+                    let vehicles = cache
+                        .fetch_chateau_vehicles(&coordinator, &chateau_id)
+                        .await;
+
+                    cache.in_flight.remove(&chateau_id);
+
+                    vehicles
+                }
+                .boxed()
+                .shared();
+
+                entry.insert(fetch.clone());
+
+                fetch
+            }
+        };
+
+        fetch.await
+    }
+
+    async fn fetch_chateau_vehicles(
+        &self,
+        coordinator: &Coordinator,
+        chateau_id: &str,
+    ) -> Option<Arc<Vec<VehicleFeature>>> {
+        let chateau_metadata = get_node_for_chateau(coordinator, chateau_id).await?;
+
+        let aspen_client = self
+            .aspen_clients
+            .client_for(&chateau_metadata.socket)
+            .await
+            .ok()?;
+
+        let response = match aspen_client
+            .get_vehicle_locations(context::current(), chateau_id.to_string(), None)
+            .await
+        {
+            Ok(response) => response?,
+            Err(e) => {
+                eprintln!("{}: could not fetch vehicles: {}", chateau_id, e);
+                self.aspen_clients.forget(&chateau_metadata.socket);
+                return None;
+            }
+        };
+
+        let route_cache = response.vehicle_route_cache.unwrap_or_default();
+
+        let features = response
+            .vehicle_positions
+            .into_iter()
+            .filter_map(|(id, vehicle)| {
+                let position = vehicle.position.as_ref()?;
+
+                let route_id = vehicle.trip.as_ref().and_then(|trip| trip.route_id.clone());
+                let route = route_id.as_ref().and_then(|x| route_cache.get(x));
+
+                Some(VehicleFeature {
+                    chateau: chateau_id.to_string(),
+                    label: vehicle
+                        .vehicle
+                        .as_ref()
+                        .and_then(|x| x.label.clone().or(x.id.clone())),
+                    id,
+                    lat: position.latitude as f64,
+                    lon: position.longitude as f64,
+                    bearing: position.bearing,
+                    trip_id: vehicle.trip.as_ref().and_then(|trip| trip.trip_id.clone()),
+                    route_type: vehicle.route_type,
+                    route_short_name: route.and_then(|x| x.route_short_name.clone()),
+                    route_long_name: route.and_then(|x| x.route_long_name.clone()),
+                    color: route.and_then(|x| x.route_colour.clone()),
+                    text_color: route.and_then(|x| x.route_text_colour.clone()),
+                    timestamp: vehicle.timestamp,
+                    route_id,
+                })
+            })
+            .collect::<Vec<VehicleFeature>>();
+
+        let features = Arc::new(features);
+
+        self.chateaus.insert(
+            chateau_id.to_string(),
+            (Instant::now(), Arc::clone(&features)),
+        );
+
+        Some(features)
+    }
+}
+
+async fn vehicles_in_bbox(
+    coordinator: &Coordinator,
+    sqlx_pool: &sqlx::Pool<sqlx::Postgres>,
+    vehicle_cache: &Arc<ChateauVehicleCache>,
+    bbox: Bbox,
+) -> Result<Vec<VehicleFeature>, sqlx::Error> {
+    let chateaus: Vec<String> = sqlx::query(
+        "SELECT chateau FROM gtfs.chateaus WHERE hull && ST_MakeEnvelope($1, $2, $3, $4, 4326)",
+    )
+    .bind(bbox.west)
+    .bind(bbox.south)
+    .bind(bbox.east)
+    .bind(bbox.north)
+    .fetch_all(sqlx_pool)
+    .await?
+    .iter()
+    .map(|row| row.get::<String, _>(0))
+    .collect();
+
+    let vehicles_per_chateau =
+        futures::stream::iter(chateaus.into_iter().map(|chateau_id| async move {
+            let vehicles = vehicle_cache
+                .chateau_vehicles(coordinator, &chateau_id)
+                .await?;
+
+            Some(
+                vehicles
+                    .iter()
+                    .filter(|vehicle| bbox.contains(vehicle.lon, vehicle.lat))
+                    .cloned()
+                    .collect::<Vec<VehicleFeature>>(),
+            )
+        }))
+        .buffer_unordered(32)
+        .collect::<Vec<Option<Vec<VehicleFeature>>>>()
+        .await;
+
+    Ok(vehicles_per_chateau
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect())
+}
+
+#[actix_web::get("/realtime_vehicles")]
+pub async fn realtime_vehicles_meta(req: HttpRequest) -> impl Responder {
+    let mut fields = std::collections::BTreeMap::new();
+
+    fields.insert(String::from("chateau"), String::from("text"));
+    fields.insert(String::from("id"), String::from("text"));
+    fields.insert(String::from("bearing"), String::from("real"));
+    fields.insert(String::from("label"), String::from("text"));
+    fields.insert(String::from("route_id"), String::from("text"));
+    fields.insert(String::from("trip_id"), String::from("text"));
+    fields.insert(String::from("route_type"), String::from("smallint"));
+    fields.insert(String::from("route_short_name"), String::from("text"));
+    fields.insert(String::from("route_long_name"), String::from("text"));
+    fields.insert(String::from("color"), String::from("text"));
+    fields.insert(String::from("text_color"), String::from("text"));
+    fields.insert(String::from("timestamp"), String::from("bigint"));
+
+    let fields = tilejson::VectorLayer::new(String::from("data"), fields);
+
+    let tile_json = TileJSON {
+        vector_layers: Some(vec![fields]),
+        tilejson: String::from("3.0.0"),
+        bounds: None,
+        center: None,
+        data: None,
+        description: None,
+        fillzoom: None,
+        grids: None,
+        legend: None,
+        maxzoom: Some(22),
+        minzoom: Some(MIN_ZOOM),
+        name: Some(String::from("realtime_vehicles")),
+        scheme: None,
+        template: None,
+        version: None,
+        other: std::collections::BTreeMap::new(),
+        tiles: vec![String::from(
+            "https://birch.catenarymaps.org/realtime_vehicles/{z}/{x}/{y}.pbf",
+        )],
+        attribution: None,
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/json"))
+        .insert_header(("Cache-Control", "max-age=1000, public"))
+        .body(serde_json::to_string(&tile_json).unwrap())
+}
+
+#[actix_web::get("/realtime_vehicles/{z}/{x}/{y}.pbf")]
+pub async fn realtime_vehicles_tile(
+    coordinator: web::Data<Coordinator>,
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    vehicle_cache: web::Data<Arc<ChateauVehicleCache>>,
+    path: web::Path<(u8, u32, u32)>,
+) -> impl Responder {
+    let (z, x, y) = path.into_inner();
+
+    if z > 30 || x >= 1 << z || y >= 1 << z {
+        return HttpResponse::BadRequest().body("Invalid tile");
+    }
+
+    if z < MIN_ZOOM {
+        return HttpResponse::Ok()
+            .insert_header(("Content-Type", "application/x-protobuf"))
+            .insert_header(("Cache-Control", "max-age=3600, public"))
+            .body(Vec::<u8>::new());
+    }
+
+    let sqlx_pool_ref = sqlx_pool.as_ref().as_ref();
+
+    let vehicles = match vehicles_in_bbox(
+        &coordinator,
+        sqlx_pool_ref,
+        vehicle_cache.get_ref(),
+        tile_bbox(z, x, y, TILE_BUFFER),
+    )
+    .await
+    {
+        Ok(vehicles) => vehicles,
+        Err(err) => {
+            eprintln!("{:?}", err);
+            return HttpResponse::InternalServerError().body("Failed to fetch from postgres!");
+        }
+    };
+
+    // postgis already encodes every other tile layer, so it encodes this one too
+    let query_str = format!(
+        "
+    SELECT
+    ST_AsMVT(q, 'data', 4096, 'geom')
+FROM (
+    SELECT
+        chateau,
+        id,
+        bearing,
+        label,
+        route_id,
+        trip_id,
+        route_type,
+        route_short_name,
+        route_long_name,
+        color,
+        text_color,
+        timestamp,
+        ST_AsMVTGeom(ST_Transform(ST_SetSRID(ST_MakePoint(lon, lat), 4326), 3857),
+        ST_TileEnvelope({z}, {x}, {y}), 4096, 64, true) AS geom
+    FROM
+        json_to_recordset($1::json) AS v(
+            chateau text,
+            id text,
+            lat double precision,
+            lon double precision,
+            bearing real,
+            label text,
+            route_id text,
+            trip_id text,
+            route_type smallint,
+            route_short_name text,
+            route_long_name text,
+            color text,
+            text_color text,
+            timestamp bigint
+        )
+) q",
+        z = z,
+        x = x,
+        y = y
+    );
+
+    match sqlx::query(query_str.as_str())
+        .bind(serde_json::to_string(&vehicles).unwrap())
+        .fetch_one(sqlx_pool_ref)
+        .await
+    {
+        Ok(mvt_result) => {
+            let mvt_bytes: Vec<u8> = mvt_result.get(0);
+
+            HttpResponse::Ok()
+                .insert_header(("Content-Type", "application/x-protobuf"))
+                .insert_header(("Cache-Control", "max-age=5, public"))
+                .body(mvt_bytes)
+        }
+        Err(err) => {
+            eprintln!("{:?}", err);
+            HttpResponse::InternalServerError().body("Failed to fetch from postgres!")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BboxQuery {
+    min_lon: f64,
+    min_lat: f64,
+    max_lon: f64,
+    max_lat: f64,
+}
+
+#[actix_web::get("/realtime_vehicles_geojson")]
+pub async fn realtime_vehicles_geojson(
+    coordinator: web::Data<Coordinator>,
+    sqlx_pool: web::Data<Arc<sqlx::Pool<sqlx::Postgres>>>,
+    vehicle_cache: web::Data<Arc<ChateauVehicleCache>>,
+    query: web::Query<BboxQuery>,
+) -> impl Responder {
+    let bbox = Bbox {
+        west: query.min_lon,
+        south: query.min_lat,
+        east: query.max_lon,
+        north: query.max_lat,
+    };
+
+    if !(bbox.west < bbox.east && bbox.south < bbox.north) {
+        return HttpResponse::BadRequest().body("Invalid bbox");
+    }
+
+    if (bbox.east - bbox.west) * (bbox.north - bbox.south) > MAX_BBOX_AREA {
+        return HttpResponse::BadRequest().body("Bbox too large");
+    }
+
+    let sqlx_pool_ref = sqlx_pool.as_ref().as_ref();
+
+    let vehicles =
+        match vehicles_in_bbox(&coordinator, sqlx_pool_ref, vehicle_cache.get_ref(), bbox).await {
+            Ok(vehicles) => vehicles,
+            Err(err) => {
+                eprintln!("{:?}", err);
+                return HttpResponse::InternalServerError().body("Failed to fetch from postgres!");
+            }
+        };
+
+    let features = vehicles
+        .into_iter()
+        .map(|vehicle| {
+            let geometry =
+                geojson::Geometry::new(geojson::Value::Point(vec![vehicle.lon, vehicle.lat]));
+
+            let properties = match serde_json::to_value(&vehicle) {
+                Ok(serde_json::Value::Object(mut properties)) => {
+                    properties.remove("lat");
+                    properties.remove("lon");
+                    Some(properties)
+                }
+                _ => None,
+            };
+
+            geojson::Feature {
+                bbox: None,
+                geometry: Some(geometry),
+                id: Some(geojson::feature::Id::String(format!(
+                    "{}/{}",
+                    vehicle.chateau, vehicle.id
+                ))),
+                properties,
+                foreign_members: None,
+            }
+        })
+        .collect::<Vec<geojson::Feature>>();
+
+    let feature_collection = geojson::FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/geo+json"))
+        .insert_header(("Cache-Control", "max-age=5, public"))
+        .body(geojson::GeoJson::from(feature_collection).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_bounds() {
+        let world = tile_bbox(0, 0, 0, 0.);
+
+        assert_eq!(world.west, -180.);
+        assert_eq!(world.east, 180.);
+        assert!((world.north - 85.0511).abs() < 0.001);
+        assert!((world.south + 85.0511).abs() < 0.001);
+
+        let north_east = tile_bbox(1, 1, 0, 0.);
+
+        assert_eq!(north_east.west, 0.);
+        assert_eq!(north_east.south, 0.);
+        assert!(north_east.contains(10., 10.));
+        assert!(!north_east.contains(-10., 10.));
+
+        let buffered = tile_bbox(1, 1, 0, TILE_BUFFER);
+
+        assert!(buffered.contains(-1., 10.));
+        assert!(!buffered.contains(-10., 10.));
+    }
+}
@@ -1,12 +1,13 @@
-use crate::CatenaryPostgresPool;
 use actix_web::web;
 use actix_web::HttpResponse;
 use actix_web::Responder;
 use catenary::aspen::lib::ChateauMetadataEtcd;
 use catenary::aspen_dataset::AspenisedAlert;
+use catenary::aspen_dataset::AspenisedRouteHeadwayHealth;
+use catenary::coordination::{Coordination, Coordinator};
 use catenary::models::DirectionPatternMeta;
 use catenary::models::DirectionPatternRow;
-use catenary::EtcdConnectionIps;
+use catenary::postgres_tools::CatenaryPostgresPool;
 use compact_str::CompactString;
 use diesel::prelude::*;
 use diesel_async::AsyncConnection;
@@ -39,6 +40,8 @@ pub struct RouteInfoResponse {
     pub alert_ids_for_this_route: Vec<String>,
     pub alert_id_to_alert: BTreeMap<String, AspenisedAlert>,
     pub stop_id_to_alert_ids: BTreeMap<String, Vec<String>>,
+    // live spacing between vehicles, only for frequent routes with vehicles running
+    pub headway_health: Option<AspenisedRouteHeadwayHealth>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -56,30 +59,11 @@ struct QueryRouteInfo {
 #[actix_web::get("/route_info")]
 pub async fn route_info(
     query: web::Query<QueryRouteInfo>,
-    etcd_connection_options: web::Data<Arc<Option<etcd_client::ConnectOptions>>>,
+    coordinator: web::Data<Coordinator>,
     pool: web::Data<Arc<CatenaryPostgresPool>>,
-    etcd_connection_ips: web::Data<Arc<EtcdConnectionIps>>,
 ) -> impl Responder {
     let query = query.into_inner();
 
-    //connect to etcd
-
-    let etcd = etcd_client::Client::connect(
-        etcd_connection_ips.ip_addresses.as_slice(),
-        etcd_connection_options.as_ref().as_ref().to_owned(),
-    )
-    .await;
-
-    if let Err(etcd_err) = &etcd {
-        eprintln!("{:#?}", etcd_err);
-
-        return HttpResponse::InternalServerError()
-            .append_header(("Cache-Control", "no-cache"))
-            .body("Could not connect to etcd");
-    }
-
-    let mut etcd = etcd.unwrap();
-
     //connect to postgres
     let conn_pool = pool.as_ref();
     let conn_pre = conn_pool.get().await;
@@ -309,26 +293,23 @@ pub async fn route_info(
 
     //query realtime data pool for alerts
 
-    let fetch_assigned_node_for_this_chateau = etcd
-        .get(
-            format!("/aspen_assigned_chateaus/{}", &query.chateau).as_str(),
-            None,
-        )
+    let fetch_assigned_node_for_this_chateau = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", &query.chateau).as_str())
         .await;
 
     let mut alerts_for_route_send: BTreeMap<String, AspenisedAlert> = BTreeMap::new();
     let mut stop_id_to_alert_ids: BTreeMap<String, Vec<String>> = BTreeMap::new();
     let mut alert_ids = vec![];
+    let mut headway_health: Option<AspenisedRouteHeadwayHealth> = None;
 
     if let Ok(fetch_assigned_node_for_this_chateau) = fetch_assigned_node_for_this_chateau {
-        let fetch_assigned_node_for_this_chateau_kv_first =
-            fetch_assigned_node_for_this_chateau.kvs().first();
+        let fetch_assigned_node_for_this_chateau_kv_first = fetch_assigned_node_for_this_chateau;
 
         if let Some(fetch_assigned_node_for_this_chateau_data) =
             fetch_assigned_node_for_this_chateau_kv_first
         {
             let assigned_chateau_data = bincode::deserialize::<ChateauMetadataEtcd>(
-                fetch_assigned_node_for_this_chateau_data.value(),
+                &fetch_assigned_node_for_this_chateau_data,
             )
             .unwrap();
 
@@ -360,6 +341,17 @@ pub async fn route_info(
                     )
                     .await;
 
+                if let Ok(route_headways) = aspen_client
+                    .get_route_headways(
+                        context::current(),
+                        query.chateau.clone(),
+                        route.route_id.clone(),
+                    )
+                    .await
+                {
+                    headway_health = route_headways;
+                }
+
                 if let Ok(Some(alerts_for_stops)) = alerts_for_stops {
                     for (alert_id, alert) in alerts_for_stops.alerts {
                         alert_ids.push(alert_id.clone());
@@ -421,6 +413,7 @@ pub async fn route_info(
         alert_ids_for_this_route: alert_ids,
         alert_id_to_alert: alerts_for_route_send,
         stop_id_to_alert_ids,
+        headway_health,
     };
 
     HttpResponse::Ok().json(response)
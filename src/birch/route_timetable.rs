@@ -0,0 +1,735 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use super::service_calendar::calendar_structure_by_feed;
+use actix_web::{web, HttpResponse, Responder};
+use catenary::aspen_dataset::AspenisedTripUpdate;
+use catenary::coordination::Coordinator;
+use catenary::get_node_for_chateau;
+use catenary::gtfs_schedule_protobuf::protobuf_to_frequencies;
+use catenary::models::{CompressedTrip, ItineraryPatternMeta, ItineraryPatternRow};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use chrono::TimeZone;
+use compact_str::CompactString;
+use diesel::prelude::*;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct RouteTimetableQuery {
+    chateau: String,
+    route_id: String,
+    /// only the route of this feed, for chateaus where several feeds use the same route_id
+    onestop_feed_id: Option<String>,
+    /// YYYYMMDD or YYYY-MM-DD, today in the route's timezone if missing
+    date: Option<String>,
+    /// GTFS direction_id, 0 or 1, 0 if missing
+    direction: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct RouteTimetableResponse {
+    chateau: String,
+    route_id: String,
+    date: chrono::NaiveDate,
+    direction: u8,
+    timezone: String,
+    /// unix seconds of the service day start (noon minus 12 hours), all times are relative to it
+    service_day_start: i64,
+    realtime: bool,
+    stops: Vec<TimetableStop>,
+    trips: Vec<TimetableTrip>,
+}
+
+#[derive(Serialize)]
+struct TimetableStop {
+    stop_id: CompactString,
+    name: Option<String>,
+    code: Option<String>,
+    platform_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TimetableTrip {
+    trip_id: String,
+    trip_short_name: Option<CompactString>,
+    headsign: Option<String>,
+    start_time: u32,
+    /// generated from a frequency without exact times, the column is an approximation
+    frequency_based: bool,
+    cancelled: bool,
+    /// one entry per row of `stops`
+    times: Vec<Option<TimetableCell>>,
+}
+
+#[derive(Serialize, Clone)]
+struct TimetableCell {
+    arrival: Option<u32>,
+    departure: Option<u32>,
+    interpolated: bool,
+    realtime_arrival: Option<i64>,
+    realtime_departure: Option<i64>,
+    skipped: bool,
+}
+
+/// Merges the stop orders of several itineraries into one list of rows.
+/// Stops missing from the merged list are inserted just before the next stop they share with it,
+/// so branches and short turns end up next to the stops they connect to.
+/// A stop visited twice by the same itinerary, like on a loop, gets two rows.
+fn merge_stop_orders(patterns: &[Vec<CompactString>]) -> Vec<CompactString> {
+    let mut sorted_patterns = patterns.iter().collect::<Vec<_>>();
+
+    //longest first, so the main pattern sets the order
+    sorted_patterns.sort_by(|a, b| b.len().cmp(&a.len()));
+
+    let mut merged: Vec<CompactString> = vec![];
+
+    for pattern in sorted_patterns {
+        let mut cursor = 0;
+        let mut pending: Vec<CompactString> = vec![];
+
+        for stop_id in pattern {
+            match merged[cursor..].iter().position(|x| x == stop_id) {
+                Some(offset) => {
+                    let found = cursor + offset;
+                    let pending_len = pending.len();
+
+                    merged.splice(found..found, pending.drain(..));
+
+                    cursor = found + pending_len + 1;
+                }
+                None => pending.push(stop_id.clone()),
+            }
+        }
+
+        merged.extend(pending);
+    }
+
+    merged
+}
+
+/// Row index in `merged` for each stop of `pattern`, which must be part of the merge
+fn assign_rows(merged: &[CompactString], pattern: &[CompactString]) -> Vec<usize> {
+    let mut cursor = 0;
+
+    pattern
+        .iter()
+        .map(|stop_id| {
+            let row = cursor + merged[cursor..].iter().position(|x| x == stop_id).unwrap();
+
+            cursor = row + 1;
+
+            row
+        })
+        .collect()
+}
+
+/// Start times of every run of a trip, in seconds since the start of the service day.
+/// The bool is true if the run comes from a frequency without exact times.
+pub fn trip_instances(trip: &CompressedTrip) -> Vec<(u32, bool)> {
+    let frequencies = trip
+        .frequencies
+        .as_ref()
+        .and_then(|data| {
+            <catenary::gtfs_schedule_protobuf::GtfsFrequenciesProto as prost::Message>::decode(
+                data.as_ref(),
+            )
+            .ok()
+        })
+        .map(|x| protobuf_to_frequencies(&x));
+
+    match frequencies {
+        Some(frequencies) if !frequencies.is_empty() => {
+            let mut instances = vec![];
+
+            for frequency in frequencies {
+                if frequency.headway_secs == 0 {
+                    continue;
+                }
+
+                let frequency_based = !matches!(
+                    frequency.exact_times,
+                    Some(gtfs_structures::ExactTimes::ScheduleBased)
+                );
+
+                let mut start = frequency.start_time;
+
+                while start < frequency.end_time {
+                    instances.push((start, frequency_based));
+                    start += frequency.headway_secs;
+                }
+            }
+
+            instances
+        }
+        _ => vec![(trip.start_time, false)],
+    }
+}
+
+/// Unix seconds of noon minus 12 hours on the date, which GTFS times count from
+pub fn service_day_start(timezone: &chrono_tz::Tz, date: chrono::NaiveDate) -> i64 {
+    timezone
+        .from_local_datetime(&date.and_hms_opt(12, 0, 0).unwrap())
+        .earliest()
+        .map(|noon| noon.timestamp() - 43200)
+        .unwrap_or(0)
+}
+
+fn format_gtfs_time(seconds: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+fn parse_date(date: &str) -> Option<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(date, "%Y%m%d")
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .ok()
+}
+
+/// Writes realtime times into the cells of one run.
+/// Delays carry forward to later stops without their own update.
+fn overlay_trip_update(
+    cells: &mut [Option<TimetableCell>],
+    rows_with_sequence: &[(usize, u32, CompactString)],
+    trip_update: &AspenisedTripUpdate,
+    service_day_start: i64,
+) {
+    let mut carried_delay: Option<i32> = trip_update.delay;
+
+    for (row, gtfs_stop_sequence, stop_id) in rows_with_sequence {
+        let cell = match cells[*row].as_mut() {
+            Some(cell) => cell,
+            None => continue,
+        };
+
+        let stop_time_update =
+            trip_update
+                .stop_time_update
+                .iter()
+                .find(|x| match x.stop_sequence {
+                    Some(stop_sequence) => stop_sequence == *gtfs_stop_sequence,
+                    None => x.stop_id.as_ref() == Some(stop_id),
+                });
+
+        if let Some(stop_time_update) = stop_time_update {
+            if stop_time_update.schedule_relationship == Some(1) {
+                cell.skipped = true;
+                continue;
+            }
+
+            for (event, scheduled, realtime) in [
+                (
+                    &stop_time_update.arrival,
+                    cell.arrival,
+                    &mut cell.realtime_arrival,
+                ),
+                (
+                    &stop_time_update.departure,
+                    cell.departure,
+                    &mut cell.realtime_departure,
+                ),
+            ] {
+                if let Some(event) = event {
+                    if let Some(time) = event.time {
+                        *realtime = Some(time - service_day_start);
+
+                        if let Some(scheduled) = scheduled {
+                            carried_delay =
+                                Some((time - service_day_start - scheduled as i64) as i32);
+                        }
+                    } else if let Some(delay) = event.delay {
+                        *realtime = scheduled.map(|x| x as i64 + delay as i64);
+                        carried_delay = Some(delay);
+                    }
+                }
+            }
+        }
+
+        if let Some(delay) = carried_delay {
+            if cell.realtime_arrival.is_none() {
+                cell.realtime_arrival = cell.arrival.map(|x| x as i64 + delay as i64);
+            }
+
+            if cell.realtime_departure.is_none() {
+                cell.realtime_departure = cell.departure.map(|x| x as i64 + delay as i64);
+            }
+        }
+    }
+}
+
+/// Stop by trip grid of a route in one direction on one service date
+#[actix_web::get("/route_timetable")]
+pub async fn route_timetable(
+    query: web::Query<RouteTimetableQuery>,
+    coordinator: web::Data<Coordinator>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    let query = query.into_inner();
+
+    let direction = query.direction.unwrap_or(0);
+
+    if direction > 1 {
+        return HttpResponse::BadRequest().body("direction must be 0 or 1");
+    }
+
+    let requested_date = match &query.date {
+        Some(date) => match parse_date(date) {
+            Some(date) => Some(date),
+            None => return HttpResponse::BadRequest().body("Invalid date"),
+        },
+        None => None,
+    };
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    // web::Query has already decoded it
+    let route_id = query.route_id.clone();
+
+    let trips = catenary::schema::gtfs::trips_compressed::dsl::trips_compressed
+        .filter(catenary::schema::gtfs::trips_compressed::dsl::chateau.eq(&query.chateau))
+        .filter(catenary::schema::gtfs::trips_compressed::dsl::route_id.eq(&route_id))
+        .select(CompressedTrip::as_select())
+        .load::<CompressedTrip>(conn)
+        .await;
+
+    let trips = match trips {
+        Ok(trips) => trips
+            .into_iter()
+            .filter(|trip| trip.direction_id.unwrap_or(false) == (direction == 1))
+            .filter(|trip| match &query.onestop_feed_id {
+                Some(onestop_feed_id) => &trip.onestop_feed_id == onestop_feed_id,
+                None => true,
+            })
+            .collect::<Vec<CompressedTrip>>(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch trips");
+        }
+    };
+
+    let mut itinerary_pattern_ids = trips
+        .iter()
+        .map(|trip| trip.itinerary_pattern_id.clone())
+        .collect::<Vec<String>>();
+
+    itinerary_pattern_ids.sort();
+    itinerary_pattern_ids.dedup();
+
+    let itinerary_metas =
+        catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta
+            .filter(catenary::schema::gtfs::itinerary_pattern_meta::dsl::chateau.eq(&query.chateau))
+            .filter(
+                catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_id
+                    .eq_any(&itinerary_pattern_ids),
+            )
+            .select(ItineraryPatternMeta::as_select())
+            .load::<ItineraryPatternMeta>(conn)
+            .await;
+
+    // itinerary pattern ids are only unique within a feed
+    let itinerary_metas: HashMap<(String, String), ItineraryPatternMeta> = match itinerary_metas {
+        Ok(itinerary_metas) => itinerary_metas
+            .into_iter()
+            .map(|x| {
+                (
+                    (x.onestop_feed_id.clone(), x.itinerary_pattern_id.clone()),
+                    x,
+                )
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch itineraries");
+        }
+    };
+
+    let timezone = itinerary_metas
+        .values()
+        .next()
+        .and_then(|x| chrono_tz::Tz::from_str(&x.timezone).ok())
+        .unwrap_or(chrono_tz::UTC);
+
+    let today = chrono::Utc::now().with_timezone(&timezone).date_naive();
+    let date = requested_date.unwrap_or(today);
+
+    let service_day_start = service_day_start(&timezone, date);
+
+    // keep trips running on the date
+
+    let mut service_ids = trips
+        .iter()
+        .map(|trip| trip.service_id.to_string())
+        .collect::<Vec<String>>();
+
+    service_ids.sort();
+    service_ids.dedup();
+
+    let calendars = catenary::schema::gtfs::calendar::dsl::calendar
+        .filter(catenary::schema::gtfs::calendar::dsl::chateau.eq(&query.chateau))
+        .filter(catenary::schema::gtfs::calendar::dsl::service_id.eq_any(&service_ids))
+        .select(catenary::models::Calendar::as_select())
+        .load::<catenary::models::Calendar>(conn)
+        .await;
+
+    let calendar_dates = catenary::schema::gtfs::calendar_dates::dsl::calendar_dates
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::chateau.eq(&query.chateau))
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::service_id.eq_any(&service_ids))
+        .select(catenary::models::CalendarDate::as_select())
+        .load::<catenary::models::CalendarDate>(conn)
+        .await;
+
+    let calendar_structure = match (calendars, calendar_dates) {
+        (Ok(calendars), Ok(calendar_dates)) => {
+            calendar_structure_by_feed(calendars, calendar_dates)
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch calendars");
+        }
+    };
+
+    let trips = trips
+        .into_iter()
+        .filter(|trip| {
+            match calendar_structure
+                .get(&trip.onestop_feed_id)
+                .and_then(|services| services.get(trip.service_id.as_str()))
+            {
+                Some(service) => catenary::datetime_in_service(service, date),
+                None => false,
+            }
+        })
+        .collect::<Vec<CompressedTrip>>();
+
+    // stop times of the itineraries used on that date
+
+    let mut itinerary_pattern_ids = trips
+        .iter()
+        .map(|trip| trip.itinerary_pattern_id.clone())
+        .collect::<Vec<String>>();
+
+    itinerary_pattern_ids.sort();
+    itinerary_pattern_ids.dedup();
+
+    let itinerary_rows = catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern
+        .filter(catenary::schema::gtfs::itinerary_pattern::dsl::chateau.eq(&query.chateau))
+        .filter(
+            catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern_id
+                .eq_any(&itinerary_pattern_ids),
+        )
+        .select(ItineraryPatternRow::as_select())
+        .load::<ItineraryPatternRow>(conn)
+        .await;
+
+    let used_itineraries = trips
+        .iter()
+        .map(|trip| {
+            (
+                trip.onestop_feed_id.clone(),
+                trip.itinerary_pattern_id.clone(),
+            )
+        })
+        .collect::<BTreeSet<(String, String)>>();
+
+    let mut itineraries: BTreeMap<(String, String), Vec<ItineraryPatternRow>> = BTreeMap::new();
+
+    match itinerary_rows {
+        Ok(itinerary_rows) => {
+            for row in itinerary_rows {
+                let key = (
+                    row.onestop_feed_id.clone(),
+                    row.itinerary_pattern_id.clone(),
+                );
+
+                // the same id in another feed of the chateau
+                if !used_itineraries.contains(&key) {
+                    continue;
+                }
+
+                itineraries.entry(key).or_default().push(row);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch itineraries");
+        }
+    }
+
+    for rows in itineraries.values_mut() {
+        rows.sort_by_key(|row| row.stop_sequence);
+    }
+
+    let merged_stops = merge_stop_orders(
+        &itineraries
+            .values()
+            .map(|rows| rows.iter().map(|row| row.stop_id.clone()).collect())
+            .collect::<Vec<Vec<CompactString>>>(),
+    );
+
+    let itinerary_row_indices: HashMap<&(String, String), Vec<usize>> = itineraries
+        .iter()
+        .map(|(itinerary_key, rows)| {
+            (
+                itinerary_key,
+                assign_rows(
+                    &merged_stops,
+                    &rows
+                        .iter()
+                        .map(|row| row.stop_id.clone())
+                        .collect::<Vec<CompactString>>(),
+                ),
+            )
+        })
+        .collect();
+
+    // realtime is only relevant for today
+
+    let trip_updates = match date == today {
+        true => match get_node_for_chateau(&coordinator, &query.chateau).await {
+            Some(chateau_metadata) => {
+                match catenary::aspen::lib::spawn_aspen_client_from_ip(&chateau_metadata.socket)
+                    .await
+                {
+                    Ok(aspen_client) => aspen_client
+                        .get_all_trips_with_ids(
+                            tarpc::context::current(),
+                            query.chateau.clone(),
+                            trips.iter().map(|trip| trip.trip_id.clone()).collect(),
+                        )
+                        .await
+                        .ok()
+                        .flatten(),
+                    Err(e) => {
+                        eprintln!("{:?}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        },
+        false => None,
+    };
+
+    let date_string = date.format("%Y%m%d").to_string();
+
+    let mut timetable_trips: Vec<TimetableTrip> = vec![];
+
+    for trip in trips.iter() {
+        let itinerary_key = (
+            trip.onestop_feed_id.clone(),
+            trip.itinerary_pattern_id.clone(),
+        );
+
+        let rows = match itineraries.get(&itinerary_key) {
+            Some(rows) => rows,
+            None => continue,
+        };
+
+        let row_indices = itinerary_row_indices.get(&itinerary_key).unwrap();
+
+        let instances = trip_instances(trip);
+        let has_frequencies = trip.frequencies.is_some();
+
+        for (start_time, frequency_based) in instances {
+            let mut times: Vec<Option<TimetableCell>> = vec![None; merged_stops.len()];
+
+            for (row, row_index) in rows.iter().zip(row_indices.iter()) {
+                let arrival = row
+                    .arrival_time_since_start
+                    .or(row.interpolated_time_since_start);
+                let departure = row
+                    .departure_time_since_start
+                    .or(row.arrival_time_since_start)
+                    .or(row.interpolated_time_since_start);
+
+                times[*row_index] = Some(TimetableCell {
+                    arrival: arrival.map(|x| start_time + x as u32),
+                    departure: departure.map(|x| start_time + x as u32),
+                    interpolated: row.arrival_time_since_start.is_none()
+                        && row.departure_time_since_start.is_none(),
+                    realtime_arrival: None,
+                    realtime_departure: None,
+                    skipped: false,
+                });
+            }
+
+            let mut cancelled = false;
+
+            if let Some(trip_updates) = &trip_updates {
+                let start_time_string = format_gtfs_time(start_time);
+
+                let trip_update = trip_updates
+                    .trip_id_to_trip_update_ids
+                    .get(&trip.trip_id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|trip_update_id| trip_updates.trip_updates.get(trip_update_id))
+                    .find(|trip_update| {
+                        trip_update
+                            .trip
+                            .start_date
+                            .as_ref()
+                            .map(|x| x == &date_string)
+                            .unwrap_or(true)
+                            && (!has_frequencies
+                                || trip_update
+                                    .trip
+                                    .start_time
+                                    .as_ref()
+                                    .map(|x| x == &start_time_string)
+                                    .unwrap_or(true))
+                    });
+
+                if let Some(trip_update) = trip_update {
+                    if trip_update.trip.schedule_relationship == Some(3) {
+                        cancelled = true;
+                    } else {
+                        overlay_trip_update(
+                            &mut times,
+                            &rows
+                                .iter()
+                                .zip(row_indices.iter())
+                                .map(|(row, row_index)| {
+                                    (*row_index, row.gtfs_stop_sequence, row.stop_id.clone())
+                                })
+                                .collect::<Vec<_>>(),
+                            trip_update,
+                            service_day_start,
+                        );
+                    }
+                }
+            }
+
+            timetable_trips.push(TimetableTrip {
+                trip_id: trip.trip_id.clone(),
+                trip_short_name: trip.trip_short_name.clone(),
+                headsign: itinerary_metas
+                    .get(&itinerary_key)
+                    .and_then(|x| x.trip_headsign.clone()),
+                start_time,
+                frequency_based,
+                cancelled,
+                times,
+            });
+        }
+    }
+
+    timetable_trips.sort_by(|a, b| {
+        a.start_time
+            .cmp(&b.start_time)
+            .then_with(|| a.trip_id.cmp(&b.trip_id))
+    });
+
+    // stop names for the rows
+
+    let stops_pg = catenary::schema::gtfs::stops::dsl::stops
+        .filter(catenary::schema::gtfs::stops::dsl::chateau.eq(&query.chateau))
+        .filter(catenary::schema::gtfs::stops::dsl::gtfs_id.eq_any(&merged_stops))
+        .select(catenary::models::Stop::as_select())
+        .load::<catenary::models::Stop>(conn)
+        .await;
+
+    let stops_table: HashMap<String, catenary::models::Stop> = match stops_pg {
+        Ok(stops_pg) => stops_pg
+            .into_iter()
+            .map(|stop| (stop.gtfs_id.clone(), stop))
+            .collect(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch stops");
+        }
+    };
+
+    let stops = merged_stops
+        .iter()
+        .map(|stop_id| {
+            let stop = stops_table.get(stop_id.as_str());
+
+            TimetableStop {
+                stop_id: stop_id.clone(),
+                name: stop.and_then(|x| x.name.clone()),
+                code: stop.and_then(|x| x.code.clone()),
+                platform_code: stop.and_then(|x| x.platform_code.clone()),
+            }
+        })
+        .collect();
+
+    let realtime = trip_updates.is_some();
+
+    HttpResponse::Ok()
+        .append_header((
+            "Cache-Control",
+            match realtime {
+                true => "max-age=10",
+                false => "max-age=600",
+            },
+        ))
+        .json(RouteTimetableResponse {
+            chateau: query.chateau,
+            route_id,
+            date,
+            direction,
+            timezone: timezone.name().to_string(),
+            service_day_start,
+            realtime,
+            stops,
+            trips: timetable_trips,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(stops: &[&str]) -> Vec<CompactString> {
+        stops.iter().map(|x| CompactString::from(*x)).collect()
+    }
+
+    #[test]
+    fn merges_branches_and_short_turns() {
+        let patterns = vec![
+            pattern(&["b", "c"]),
+            pattern(&["a", "b", "c", "d"]),
+            pattern(&["x", "b", "c", "y"]),
+        ];
+
+        let merged = merge_stop_orders(&patterns);
+
+        assert_eq!(merged, pattern(&["a", "x", "b", "c", "d", "y"]));
+
+        for p in &patterns {
+            let rows = assign_rows(&merged, p);
+            assert!(rows.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn loops_repeat_the_stop() {
+        let merged = merge_stop_orders(&[pattern(&["a", "b", "c", "a"])]);
+
+        assert_eq!(merged, pattern(&["a", "b", "c", "a"]));
+        assert_eq!(
+            assign_rows(&merged, &pattern(&["a", "b", "c", "a"])),
+            vec![0, 1, 2, 3]
+        );
+    }
+}
@@ -40,10 +40,11 @@ use postgis_download::*;
 mod departures_at_stop;
 use actix_web::middleware::DefaultHeaders;
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use catenary::coordination::Coordinator;
+use catenary::ip_to_location::index::{keep_ip_geo_lookup_fresh, IpGeoSource, IpGeoState};
 use catenary::models::IpToGeoAddr;
 use catenary::postgis_to_diesel::diesel_multi_polygon_to_geo;
 use catenary::postgres_tools::{make_async_pool, CatenaryPostgresPool};
-use catenary::EtcdConnectionIps;
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use geojson::{Feature, GeoJson, JsonValue};
@@ -57,13 +58,25 @@ use std::sync::{Arc, RwLock};
 use std::time::SystemTime;
 use tilejson::TileJSON;
 mod api_key_management;
+mod aspen_clients;
 mod aspenised_data_over_https;
+mod chateau_coverage;
 mod chicago_proxy;
+mod feed_validity;
 mod get_agencies;
 mod get_vehicle_trip_information;
 mod gtfs_rt_api;
+mod gtfs_validation;
+mod http_metrics;
+mod isochrone;
 mod nearby_departures;
+mod public_api_keys;
+mod rate_limit;
+mod realtime_vehicle_tiles;
 mod route_info;
+mod route_timetable;
+mod service_calendar;
+mod vehicle_history;
 
 #[derive(Clone, Debug)]
 struct ChateauCache {
@@ -415,9 +428,8 @@ async fn chateaus(
 
     //cache it first
     let mut chateau_lock = chateau_cache.write().unwrap();
-    let mut chateau_mut_ref = chateau_lock.as_mut();
 
-    chateau_mut_ref = Some(&mut ChateauCache {
+    *chateau_lock = Some(ChateauCache {
         chateau_geojson: serialized.clone(),
         last_updated_time_ms: SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -481,6 +493,7 @@ pub struct IpToGeoApiResp {
 #[actix_web::get("/ip_addr_to_geo/")]
 async fn ip_addr_to_geo_api(
     pool: web::Data<Arc<CatenaryPostgresPool>>,
+    ip_geo_state: web::Data<Arc<IpGeoState>>,
     req: HttpRequest,
 ) -> impl Responder {
     let connection_info = req.connection_info();
@@ -497,22 +510,38 @@ async fn ip_addr_to_geo_api(
 
             match ipaddrparse {
                 Ok(ipaddrparse) => {
-                    let ip_net_cleaned = match ipaddrparse {
-                        core::net::IpAddr::V4(ip_addr_v4) => {
-                            ipnet::IpNet::new(ipaddrparse, 32).unwrap()
-                        }
-                        core::net::IpAddr::V6(ip_addr_v6) => {
-                            ipnet::IpNet::new(ipaddrparse, 128).unwrap()
+                    // None until the first load finishes at startup
+                    let memory_lookup = ip_geo_state
+                        .read()
+                        .unwrap()
+                        .as_ref()
+                        .map(|lookup| lookup.lookup(ipaddrparse));
+
+                    let lookup: Result<
+                        Option<IpToGeoAddr>,
+                        Box<dyn std::error::Error + Send + Sync>,
+                    > = match memory_lookup {
+                        Some(memory_lookup) => Ok(memory_lookup),
+                        None => {
+                            let ip_net_cleaned = match ipaddrparse {
+                                core::net::IpAddr::V4(_) => {
+                                    ipnet::IpNet::new(ipaddrparse, 32).unwrap()
+                                }
+                                core::net::IpAddr::V6(_) => {
+                                    ipnet::IpNet::new(ipaddrparse, 128).unwrap()
+                                }
+                            };
+
+                            catenary::ip_to_location::lookup_geo_from_ip_addr(
+                                Arc::clone(&pool.into_inner()),
+                                ip_net_cleaned,
+                            )
+                            .await
+                            .map(|pg_lookup| pg_lookup.into_iter().next())
                         }
                     };
 
-                    let pg_lookup = catenary::ip_to_location::lookup_geo_from_ip_addr(
-                        Arc::clone(&pool.into_inner()),
-                        ip_net_cleaned,
-                    )
-                    .await;
-
-                    match pg_lookup {
+                    match lookup {
                         Err(err_a) => {
                             eprintln!("{:#?}", err_a);
                             IpToGeoApiResp {
@@ -522,19 +551,17 @@ async fn ip_addr_to_geo_api(
                                 err_msg: Some(String::from("Lookup error")),
                             }
                         }
-                        Ok(pg_lookup) => match pg_lookup.len() {
-                            0 => IpToGeoApiResp {
-                                data_found: false,
-                                error: false,
-                                geo_resp: None,
-                                err_msg: Some(String::from("no rows found")),
-                            },
-                            _ => IpToGeoApiResp {
-                                data_found: true,
-                                error: false,
-                                geo_resp: Some(pg_lookup[0].clone()),
-                                err_msg: None,
-                            },
+                        Ok(None) => IpToGeoApiResp {
+                            data_found: false,
+                            error: false,
+                            geo_resp: None,
+                            err_msg: Some(String::from("no rows found")),
+                        },
+                        Ok(Some(geo_resp)) => IpToGeoApiResp {
+                            data_found: true,
+                            error: false,
+                            geo_resp: Some(geo_resp),
+                            err_msg: None,
                         },
                     }
                 }
@@ -562,6 +589,14 @@ async fn ip_addr_to_geo_api(
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let coordinator = Coordinator::from_env()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    run(coordinator).await
+}
+
+pub async fn run(coordinator: Coordinator) -> std::io::Result<()> {
     // std::env::set_var("RUST_LOG", "debug");
     // env_logger::init();
 
@@ -580,28 +615,40 @@ async fn main() -> std::io::Result<()> {
             .unwrap(),
     );
 
-    let etcd_urls_original =
-        std::env::var("ETCD_URLS").unwrap_or_else(|_| "localhost:2379".to_string());
-    let etcd_urls = etcd_urls_original
-        .split(',')
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>();
+    // on a port of its own, so metrics are not served to the public
+    actix_web::rt::spawn(catenary::metrics::serve_metrics(
+        catenary::metrics::metrics_addr_from_env(9104),
+    ));
+    actix_web::rt::spawn(catenary::metrics::keep_pool_metrics_fresh(Arc::clone(
+        &pool,
+    )));
 
-    let etcd_connection_ips = Arc::new(EtcdConnectionIps {
-        ip_addresses: etcd_urls,
-    });
+    let rate_limiter_state = Arc::new(rate_limit::RateLimiterState::from_env());
 
-    let etcd_username = std::env::var("ETCD_USERNAME");
+    actix_web::rt::spawn(rate_limit::rate_limit_sync_loop(
+        Arc::clone(&rate_limiter_state),
+        Arc::clone(&pool),
+    ));
 
-    let etcd_password = std::env::var("ETCD_PASSWORD");
+    let ip_geo_state: Arc<IpGeoState> = Arc::new(RwLock::new(None));
 
-    let etcd_connection_options: Option<etcd_client::ConnectOptions> =
-        match (etcd_username, etcd_password) {
-            (Ok(username), Ok(password)) => {
-                Some(etcd_client::ConnectOptions::new().with_user(username, password))
-            }
-            _ => None,
-        };
+    actix_web::rt::spawn(keep_ip_geo_lookup_fresh(
+        Arc::clone(&ip_geo_state),
+        IpGeoSource::from_env(),
+        Arc::clone(&pool),
+        coordinator.clone(),
+    ));
+
+    let feed_validity_state: Arc<feed_validity::FeedValidityState> = Arc::new(RwLock::new(vec![]));
+
+    actix_web::rt::spawn(feed_validity::feed_validity_check_loop(
+        Arc::clone(&feed_validity_state),
+        Arc::clone(&pool),
+    ));
+
+    let chateau_feed_producers = Arc::new(gtfs_rt_api::ChateauFeedProducers::default());
+    let isochrone_cache = Arc::new(isochrone::IsochroneCache::default());
+    let chateau_vehicle_cache = Arc::new(realtime_vehicle_tiles::ChateauVehicleCache::default());
 
     // Create a new HTTP server.
     let builder = HttpServer::new(move || {
@@ -621,17 +668,26 @@ async fn main() -> std::io::Result<()> {
                     .allow_any_method()
                     .allow_any_header(),
             )
+            .wrap(middleware::from_fn(rate_limit::rate_limit_middleware))
             .wrap(actix_block_ai_crawling::BlockAi)
             .wrap(middleware::Compress::default())
+            .wrap(middleware::from_fn(http_metrics::http_metrics_middleware))
+            .app_data(actix_web::web::Data::new(Arc::clone(&rate_limiter_state)))
+            .app_data(actix_web::web::Data::new(Arc::clone(&ip_geo_state)))
+            .app_data(actix_web::web::Data::new(Arc::clone(&feed_validity_state)))
             .app_data(actix_web::web::Data::new(Arc::clone(&sqlx_pool)))
             .app_data(actix_web::web::Data::new(Arc::clone(&pool)))
             .app_data(actix_web::web::Data::new(Arc::new(RwLock::new(
                 None::<ChateauCache>,
             ))))
-            .app_data(actix_web::web::Data::new(Arc::new(
-                etcd_connection_options.clone(),
+            .app_data(actix_web::web::Data::new(coordinator.clone()))
+            .app_data(actix_web::web::Data::new(Arc::clone(
+                &chateau_feed_producers,
+            )))
+            .app_data(actix_web::web::Data::new(Arc::clone(&isochrone_cache)))
+            .app_data(actix_web::web::Data::new(Arc::clone(
+                &chateau_vehicle_cache,
             )))
-            .app_data(actix_web::web::Data::new(Arc::clone(&etcd_connection_ips)))
             .route("/", web::get().to(index))
             .route("robots.txt", web::get().to(robots))
             .service(amtrakproxy)
@@ -656,8 +712,27 @@ async fn main() -> std::io::Result<()> {
             .service(api_key_management::get_realtime_keys)
             .service(api_key_management::set_realtime_key)
             .service(api_key_management::export_realtime_keys)
+            .service(api_key_management::admin_login)
+            .service(api_key_management::admin_logout)
+            .service(api_key_management::get_audit_log)
+            .service(public_api_keys::create_public_api_key)
+            .service(public_api_keys::disable_public_api_key)
+            .service(public_api_keys::list_public_api_keys)
+            .service(public_api_keys::own_api_key_usage)
+            .service(gtfs_validation::gtfs_errors_for_feed)
+            .service(gtfs_validation::gtfs_errors_summary)
             .service(aspenised_data_over_https::get_realtime_locations)
             .service(aspenised_data_over_https::bulk_realtime_fetch_v1)
+            .service(realtime_vehicle_tiles::realtime_vehicles_meta)
+            .service(realtime_vehicle_tiles::realtime_vehicles_tile)
+            .service(realtime_vehicle_tiles::realtime_vehicles_geojson)
+            .service(chateau_coverage::chateaus_at_point)
+            .service(chateau_coverage::chateaus_in_bbox)
+            .service(chateau_coverage::chateau_hulls)
+            .service(chateau_coverage::chateau_boundaries_meta)
+            .service(chateau_coverage::chateau_boundaries_tile)
+            .service(service_calendar::service_calendar)
+            .service(feed_validity::feed_validity)
             .service(chicago_proxy::ttarrivals_proxy)
             .service(nearby_departures::nearby_from_coords)
             .service(departures_at_stop::departures_at_stop)
@@ -669,8 +744,14 @@ async fn main() -> std::io::Result<()> {
             .service(calfireproxy)
             .service(ip_addr_to_geo_api)
             .service(route_info::route_info)
+            .service(route_timetable::route_timetable)
+            .service(isochrone::isochrone)
             .service(proxy_for_watchduty_tiles)
             .service(gtfs_rt_api::gtfs_rt)
+            .service(gtfs_rt_api::gtfs_rt_chateau)
+            .service(gtfs_rt_api::gtfs_rt_chateau_stream)
+            .service(vehicle_history::vehicle_trajectory)
+            .service(vehicle_history::chateau_replay)
             .service(shapes_local_rail)
             .service(shapes_local_rail_meta)
             .service(shapes_intercity_rail)
@@ -0,0 +1,560 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+// Which routes run on which dates, from calendar and calendar_dates of a chateau
+
+use actix_web::{web, HttpResponse, Responder};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::CalendarUnified;
+use diesel::prelude::*;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+
+const MAX_DAYS: u32 = 62;
+
+#[derive(Deserialize)]
+struct ServiceCalendarQuery {
+    chateau: String,
+    /// YYYYMMDD or YYYY-MM-DD, today in the chateau's timezone if missing
+    start_date: Option<String>,
+    /// number of dates from start_date, 1 if missing
+    days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ServiceCalendarResponse {
+    chateau: String,
+    timezone: String,
+    dates: Vec<ServiceDateSummary>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ServiceDateSummary {
+    date: chrono::NaiveDate,
+    /// every run of a frequency based trip counts as a trip
+    trip_count: u64,
+    routes: Vec<RouteServiceSummary>,
+    /// services running only because calendar_dates adds them on this date
+    added_services: Vec<FeedServiceId>,
+    /// services that would run by their weekly calendar but calendar_dates removes
+    removed_services: Vec<FeedServiceId>,
+}
+
+/// Chateaus can hold several feeds, which are free to reuse each other's ids
+#[derive(Serialize, Debug, PartialEq)]
+struct FeedServiceId {
+    onestop_feed_id: String,
+    service_id: String,
+}
+
+/// Trips of a route and service in one feed
+#[derive(Debug, Clone, PartialEq)]
+struct TripCount {
+    onestop_feed_id: String,
+    route_id: String,
+    service_id: String,
+    count: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct RouteServiceSummary {
+    onestop_feed_id: String,
+    route_id: String,
+    short_name: Option<String>,
+    long_name: Option<String>,
+    color: Option<String>,
+    trip_count: u64,
+}
+
+struct RouteNames {
+    short_name: Option<String>,
+    long_name: Option<String>,
+    color: Option<String>,
+}
+
+fn parse_date(date: &str) -> Option<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(date, "%Y%m%d")
+        .or_else(|_| chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .ok()
+}
+
+fn runs_by_weekly_calendar(service: &CalendarUnified, date: chrono::NaiveDate) -> bool {
+    use chrono::Datelike;
+
+    match &service.general_calendar {
+        Some(general_calendar) => {
+            general_calendar.days.contains(&date.weekday())
+                && general_calendar.start_date <= date
+                && general_calendar.end_date >= date
+        }
+        None => false,
+    }
+}
+
+/// `calendar_structure` and `route_names` are keyed by onestop_feed_id first
+fn summarise_date(
+    date: chrono::NaiveDate,
+    calendar_structure: &BTreeMap<String, BTreeMap<String, CalendarUnified>>,
+    trip_counts: &[TripCount],
+    route_names: &HashMap<(String, String), RouteNames>,
+) -> ServiceDateSummary {
+    let mut added_services = vec![];
+    let mut removed_services = vec![];
+
+    for (onestop_feed_id, services) in calendar_structure {
+        for (service_id, service) in services {
+            let exception = service
+                .exceptions
+                .as_ref()
+                .and_then(|exceptions| exceptions.get(&date));
+
+            let feed_service_id = || FeedServiceId {
+                onestop_feed_id: onestop_feed_id.clone(),
+                service_id: service_id.clone(),
+            };
+
+            match exception {
+                Some(gtfs_structures::Exception::Added) => {
+                    if !runs_by_weekly_calendar(service, date) {
+                        added_services.push(feed_service_id());
+                    }
+                }
+                Some(gtfs_structures::Exception::Deleted) => {
+                    if runs_by_weekly_calendar(service, date) {
+                        removed_services.push(feed_service_id());
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    let mut trips_per_route: BTreeMap<(&str, &str), u64> = BTreeMap::new();
+
+    for trip_count in trip_counts {
+        let running = match calendar_structure
+            .get(&trip_count.onestop_feed_id)
+            .and_then(|services| services.get(&trip_count.service_id))
+        {
+            Some(service) => catenary::datetime_in_service(service, date),
+            None => false,
+        };
+
+        if running {
+            *trips_per_route
+                .entry((
+                    trip_count.onestop_feed_id.as_str(),
+                    trip_count.route_id.as_str(),
+                ))
+                .or_default() += trip_count.count;
+        }
+    }
+
+    let routes = trips_per_route
+        .into_iter()
+        .map(|((onestop_feed_id, route_id), trip_count)| {
+            let names = route_names.get(&(onestop_feed_id.to_string(), route_id.to_string()));
+
+            RouteServiceSummary {
+                onestop_feed_id: onestop_feed_id.to_string(),
+                route_id: route_id.to_string(),
+                short_name: names.and_then(|names| names.short_name.clone()),
+                long_name: names.and_then(|names| names.long_name.clone()),
+                color: names.and_then(|names| names.color.clone()),
+                trip_count,
+            }
+        })
+        .collect::<Vec<RouteServiceSummary>>();
+
+    ServiceDateSummary {
+        date,
+        trip_count: routes.iter().map(|route| route.trip_count).sum(),
+        routes,
+        added_services,
+        removed_services,
+    }
+}
+
+/// Services of a chateau by onestop_feed_id then service_id, service ids are only unique within a feed
+pub fn calendar_structure_by_feed(
+    calendars: Vec<catenary::models::Calendar>,
+    calendar_dates: Vec<catenary::models::CalendarDate>,
+) -> BTreeMap<String, BTreeMap<String, CalendarUnified>> {
+    let mut calendars_by_feed: BTreeMap<String, Vec<catenary::models::Calendar>> = BTreeMap::new();
+    let mut calendar_dates_by_feed: BTreeMap<String, Vec<catenary::models::CalendarDate>> =
+        BTreeMap::new();
+
+    for calendar in calendars {
+        calendars_by_feed
+            .entry(calendar.onestop_feed_id.clone())
+            .or_default()
+            .push(calendar);
+    }
+
+    for calendar_date in calendar_dates {
+        calendar_dates_by_feed
+            .entry(calendar_date.onestop_feed_id.clone())
+            .or_default()
+            .push(calendar_date);
+    }
+
+    let onestop_feed_ids = calendars_by_feed
+        .keys()
+        .chain(calendar_dates_by_feed.keys())
+        .cloned()
+        .collect::<std::collections::BTreeSet<String>>();
+
+    onestop_feed_ids
+        .into_iter()
+        .map(|onestop_feed_id| {
+            let services = catenary::make_calendar_structure_from_pg_single_chateau(
+                calendars_by_feed
+                    .remove(&onestop_feed_id)
+                    .unwrap_or_default(),
+                calendar_dates_by_feed
+                    .remove(&onestop_feed_id)
+                    .unwrap_or_default(),
+            );
+
+            (onestop_feed_id, services)
+        })
+        .collect()
+}
+
+fn combine_trip_counts(
+    scheduled_trip_counts: Vec<(String, String, String, i64)>,
+    frequency_trips: &[catenary::models::CompressedTrip],
+) -> Vec<TripCount> {
+    let mut counts: BTreeMap<(String, String, String), u64> = BTreeMap::new();
+
+    for (onestop_feed_id, route_id, service_id, count) in scheduled_trip_counts {
+        *counts
+            .entry((onestop_feed_id, route_id, service_id))
+            .or_default() += count as u64;
+    }
+
+    for trip in frequency_trips {
+        *counts
+            .entry((
+                trip.onestop_feed_id.clone(),
+                trip.route_id.clone(),
+                trip.service_id.to_string(),
+            ))
+            .or_default() += crate::route_timetable::trip_instances(trip).len() as u64;
+    }
+
+    counts
+        .into_iter()
+        .map(
+            |((onestop_feed_id, route_id, service_id), count)| TripCount {
+                onestop_feed_id,
+                route_id,
+                service_id,
+                count,
+            },
+        )
+        .collect()
+}
+
+/// Routes and trip counts running on each date, with the holiday exceptions of that date
+#[actix_web::get("/service_calendar")]
+pub async fn service_calendar(
+    query: web::Query<ServiceCalendarQuery>,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+) -> impl Responder {
+    let query = query.into_inner();
+
+    let days = query.days.unwrap_or(1);
+
+    if days == 0 || days > MAX_DAYS {
+        return HttpResponse::BadRequest().body(format!("days must be from 1 to {}", MAX_DAYS));
+    }
+
+    let requested_date = match &query.start_date {
+        Some(date) => match parse_date(date) {
+            Some(date) => Some(date),
+            None => return HttpResponse::BadRequest().body("Invalid date"),
+        },
+        None => None,
+    };
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    let timezone = catenary::schema::gtfs::agencies::dsl::agencies
+        .filter(catenary::schema::gtfs::agencies::dsl::chateau.eq(&query.chateau))
+        .select(catenary::schema::gtfs::agencies::dsl::agency_timezone)
+        .first::<String>(conn)
+        .await
+        .ok()
+        .and_then(|timezone| chrono_tz::Tz::from_str(&timezone).ok())
+        .unwrap_or(chrono_tz::UTC);
+
+    let start_date =
+        requested_date.unwrap_or_else(|| chrono::Utc::now().with_timezone(&timezone).date_naive());
+
+    let calendars = catenary::schema::gtfs::calendar::dsl::calendar
+        .filter(catenary::schema::gtfs::calendar::dsl::chateau.eq(&query.chateau))
+        .select(catenary::models::Calendar::as_select())
+        .load::<catenary::models::Calendar>(conn)
+        .await;
+
+    let calendar_dates = catenary::schema::gtfs::calendar_dates::dsl::calendar_dates
+        .filter(catenary::schema::gtfs::calendar_dates::dsl::chateau.eq(&query.chateau))
+        .select(catenary::models::CalendarDate::as_select())
+        .load::<catenary::models::CalendarDate>(conn)
+        .await;
+
+    let calendar_structure = match (calendars, calendar_dates) {
+        (Ok(calendars), Ok(calendar_dates)) => {
+            calendar_structure_by_feed(calendars, calendar_dates)
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch calendars");
+        }
+    };
+
+    // counting in postgres keeps the trips themselves out of memory
+    let scheduled_trip_counts = {
+        use catenary::schema::gtfs::trips_compressed::dsl::*;
+
+        trips_compressed
+            .filter(chateau.eq(&query.chateau))
+            .filter(has_frequencies.eq(false))
+            .group_by((onestop_feed_id, route_id, service_id))
+            .select((
+                onestop_feed_id,
+                route_id,
+                service_id,
+                diesel::dsl::count_star(),
+            ))
+            .load::<(String, String, String, i64)>(conn)
+            .await
+    };
+
+    // frequency based trips are one row for many runs, so they are expanded here
+    let frequency_trips = catenary::schema::gtfs::trips_compressed::dsl::trips_compressed
+        .filter(catenary::schema::gtfs::trips_compressed::dsl::chateau.eq(&query.chateau))
+        .filter(catenary::schema::gtfs::trips_compressed::dsl::has_frequencies.eq(true))
+        .select(catenary::models::CompressedTrip::as_select())
+        .load::<catenary::models::CompressedTrip>(conn)
+        .await;
+
+    let trip_counts = match (scheduled_trip_counts, frequency_trips) {
+        (Ok(scheduled_trip_counts), Ok(frequency_trips)) => {
+            combine_trip_counts(scheduled_trip_counts, &frequency_trips)
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch trips");
+        }
+    };
+
+    let routes = catenary::schema::gtfs::routes::dsl::routes
+        .filter(catenary::schema::gtfs::routes::dsl::chateau.eq(&query.chateau))
+        .select((
+            catenary::schema::gtfs::routes::dsl::onestop_feed_id,
+            catenary::schema::gtfs::routes::dsl::route_id,
+            catenary::schema::gtfs::routes::dsl::short_name,
+            catenary::schema::gtfs::routes::dsl::long_name,
+            catenary::schema::gtfs::routes::dsl::color,
+        ))
+        .load::<(
+            String,
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+        )>(conn)
+        .await;
+
+    let route_names: HashMap<(String, String), RouteNames> = match routes {
+        Ok(routes) => routes
+            .into_iter()
+            .map(
+                |(onestop_feed_id, route_id, short_name, long_name, color)| {
+                    (
+                        (onestop_feed_id, route_id),
+                        RouteNames {
+                            short_name,
+                            long_name,
+                            color,
+                        },
+                    )
+                },
+            )
+            .collect(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Could not fetch routes");
+        }
+    };
+
+    let dates = start_date
+        .iter_days()
+        .take(days as usize)
+        .map(|date| summarise_date(date, &calendar_structure, &trip_counts, &route_names))
+        .collect::<Vec<ServiceDateSummary>>();
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "max-age=3600, public"))
+        .json(ServiceCalendarResponse {
+            chateau: query.chateau,
+            timezone: timezone.to_string(),
+            dates,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holiday_exceptions_change_the_routes_of_a_date() {
+        let weekday = CalendarUnified {
+            id: String::from("weekday"),
+            general_calendar: Some(catenary::GeneralCalendar {
+                days: vec![
+                    chrono::Weekday::Mon,
+                    chrono::Weekday::Tue,
+                    chrono::Weekday::Wed,
+                    chrono::Weekday::Thu,
+                    chrono::Weekday::Fri,
+                ],
+                start_date: chrono::NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(),
+                end_date: chrono::NaiveDate::from_ymd_opt(2025, 12, 31).unwrap(),
+            }),
+            exceptions: Some(BTreeMap::from_iter([(
+                chrono::NaiveDate::from_ymd_opt(2025, 12, 25).unwrap(),
+                gtfs_structures::Exception::Deleted,
+            )])),
+        };
+
+        let holiday = CalendarUnified {
+            id: String::from("holiday"),
+            general_calendar: None,
+            exceptions: Some(BTreeMap::from_iter([(
+                chrono::NaiveDate::from_ymd_opt(2025, 12, 25).unwrap(),
+                gtfs_structures::Exception::Added,
+            )])),
+        };
+
+        let calendar_structure = BTreeMap::from_iter([(
+            String::from("f-metro"),
+            BTreeMap::from_iter([
+                (String::from("weekday"), weekday),
+                (String::from("holiday"), holiday),
+            ]),
+        )]);
+
+        let trip_count =
+            |onestop_feed_id: &str, route_id: &str, service_id: &str, count| TripCount {
+                onestop_feed_id: onestop_feed_id.to_string(),
+                route_id: route_id.to_string(),
+                service_id: service_id.to_string(),
+                count,
+            };
+
+        let trip_counts = vec![
+            trip_count("f-metro", "801", "weekday", 120),
+            trip_count("f-metro", "801", "holiday", 40),
+            trip_count("f-metro", "802", "weekday", 90),
+            // another feed of the chateau with the same service id but no calendar of its own
+            trip_count("f-bus", "801", "holiday", 300),
+        ];
+
+        let christmas = summarise_date(
+            chrono::NaiveDate::from_ymd_opt(2025, 12, 25).unwrap(),
+            &calendar_structure,
+            &trip_counts,
+            &HashMap::new(),
+        );
+
+        assert_eq!(christmas.trip_count, 40);
+        assert_eq!(christmas.routes.len(), 1);
+        assert_eq!(christmas.routes[0].onestop_feed_id, "f-metro");
+        assert_eq!(
+            christmas.added_services,
+            vec![FeedServiceId {
+                onestop_feed_id: String::from("f-metro"),
+                service_id: String::from("holiday"),
+            }]
+        );
+        assert_eq!(
+            christmas.removed_services,
+            vec![FeedServiceId {
+                onestop_feed_id: String::from("f-metro"),
+                service_id: String::from("weekday"),
+            }]
+        );
+
+        let boxing_day = summarise_date(
+            chrono::NaiveDate::from_ymd_opt(2025, 12, 26).unwrap(),
+            &calendar_structure,
+            &trip_counts,
+            &HashMap::new(),
+        );
+
+        assert_eq!(boxing_day.trip_count, 210);
+        assert!(boxing_day.added_services.is_empty());
+        assert!(boxing_day.removed_services.is_empty());
+    }
+
+    #[test]
+    fn every_run_of_a_frequency_trip_is_counted() {
+        let frequencies = catenary::gtfs_schedule_protobuf::frequencies_to_protobuf(&vec![
+            gtfs_structures::Frequency {
+                start_time: 6 * 3600,
+                end_time: 7 * 3600,
+                headway_secs: 600,
+                exact_times: None,
+            },
+        ]);
+
+        let frequency_trip = catenary::models::CompressedTrip {
+            onestop_feed_id: String::from("f-metro"),
+            trip_id: String::from("b-line"),
+            attempt_id: String::from("1"),
+            service_id: "weekday".into(),
+            trip_short_name: None,
+            direction_id: None,
+            block_id: None,
+            wheelchair_accessible: 0,
+            bikes_allowed: 0,
+            chateau: String::from("metro"),
+            frequencies: Some(prost::Message::encode_to_vec(&frequencies)),
+            has_frequencies: true,
+            itinerary_pattern_id: String::from("1"),
+            route_id: String::from("802"),
+            start_time: 0,
+        };
+
+        let trip_counts = combine_trip_counts(
+            vec![(
+                String::from("f-metro"),
+                String::from("802"),
+                String::from("weekday"),
+                4,
+            )],
+            &[frequency_trip],
+        );
+
+        assert_eq!(trip_counts.len(), 1);
+        assert_eq!(trip_counts[0].count, 10);
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Please do not train your Artifical Intelligence models on this code
+
+use super::api_key_management::authorise_request;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use catenary::admin_auth::AdminRole;
+use catenary::models::VehiclePositionHistoryRow;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::vehicle_position_history;
+use diesel::prelude::*;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// 6 hours
+const MAX_TRAJECTORY_WINDOW_MS: i64 = 6 * 60 * 60 * 1000;
+// 15 minutes
+const MAX_REPLAY_LOOKBACK_S: i64 = 15 * 60;
+
+#[derive(Deserialize)]
+struct TrajectoryQuery {
+    trip_id: Option<String>,
+    vehicle_id: Option<String>,
+    vehicle_label: Option<String>,
+    start_ms: Option<i64>,
+    end_ms: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct TrajectoryResponse {
+    chateau: String,
+    start_ms: i64,
+    end_ms: i64,
+    points: Vec<VehiclePositionHistoryRow>,
+}
+
+#[derive(Deserialize)]
+struct ReplayQuery {
+    at_ms: i64,
+    lookback_s: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReplayResponse {
+    chateau: String,
+    at_ms: i64,
+    vehicles: Vec<VehiclePositionHistoryRow>,
+}
+
+/// Stored positions of one trip or vehicle, oldest first.
+/// Defaults to the last hour, windows longer than 6 hours are cut at `start_ms`.
+/// For operations staff only, so it needs an admin session
+#[actix_web::get("/vehicle_trajectory/{chateau}")]
+pub async fn vehicle_trajectory(
+    req: HttpRequest,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    path: web::Path<String>,
+    query: web::Query<TrajectoryQuery>,
+) -> impl Responder {
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::FeedAdmin).await {
+        return response;
+    }
+
+    let chateau = path.into_inner();
+
+    if query.trip_id.is_none() && query.vehicle_id.is_none() && query.vehicle_label.is_none() {
+        return HttpResponse::BadRequest().body("Provide trip_id, vehicle_id or vehicle_label");
+    }
+
+    let end_ms = query
+        .end_ms
+        .unwrap_or_else(|| catenary::duration_since_unix_epoch().as_millis() as i64);
+    let start_ms = query.start_ms.unwrap_or(end_ms - 60 * 60 * 1000);
+
+    if start_ms > end_ms {
+        return HttpResponse::BadRequest().body("start_ms is after end_ms");
+    }
+
+    let end_ms = end_ms.min(start_ms + MAX_TRAJECTORY_WINDOW_MS);
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    let mut points_query = vehicle_position_history::dsl::vehicle_position_history
+        .filter(vehicle_position_history::dsl::chateau.eq(&chateau))
+        .filter(vehicle_position_history::dsl::recorded_at_ms.ge(start_ms))
+        .filter(vehicle_position_history::dsl::recorded_at_ms.le(end_ms))
+        .into_boxed();
+
+    if let Some(trip_id) = &query.trip_id {
+        points_query = points_query.filter(vehicle_position_history::dsl::trip_id.eq(trip_id));
+    }
+
+    if let Some(vehicle_id) = &query.vehicle_id {
+        points_query =
+            points_query.filter(vehicle_position_history::dsl::vehicle_id.eq(vehicle_id));
+    }
+
+    if let Some(vehicle_label) = &query.vehicle_label {
+        points_query =
+            points_query.filter(vehicle_position_history::dsl::vehicle_label.eq(vehicle_label));
+    }
+
+    let points = points_query
+        .order((
+            vehicle_position_history::dsl::recorded_at_ms.asc(),
+            vehicle_position_history::dsl::vehicle_id.asc(),
+        ))
+        .select(VehiclePositionHistoryRow::as_select())
+        .load::<VehiclePositionHistoryRow>(conn)
+        .await;
+
+    match points {
+        Ok(points) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "max-age=30"))
+            .json(TrajectoryResponse {
+                chateau,
+                start_ms,
+                end_ms,
+                points,
+            }),
+        Err(e) => {
+            eprintln!("{}", e);
+            HttpResponse::InternalServerError().body("Could not fetch trajectory")
+        }
+    }
+}
+
+/// Last known position of every vehicle in the chateau at `at_ms`,
+/// ignoring positions older than `lookback_s` (default 120 seconds).
+/// For operations staff only, so it needs an admin session
+#[actix_web::get("/chateau_replay/{chateau}")]
+pub async fn chateau_replay(
+    req: HttpRequest,
+    pool: web::Data<Arc<CatenaryPostgresPool>>,
+    path: web::Path<String>,
+    query: web::Query<ReplayQuery>,
+) -> impl Responder {
+    if let Err(response) = authorise_request(pool.as_ref(), &req, AdminRole::FeedAdmin).await {
+        return response;
+    }
+
+    let chateau = path.into_inner();
+
+    let lookback_s = query
+        .lookback_s
+        .unwrap_or(120)
+        .clamp(0, MAX_REPLAY_LOOKBACK_S);
+
+    let conn_pool = pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut match conn_pre {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("{}", e);
+            return HttpResponse::InternalServerError().body("Error connecting to postgres");
+        }
+    };
+
+    let vehicles = vehicle_position_history::dsl::vehicle_position_history
+        .filter(vehicle_position_history::dsl::chateau.eq(&chateau))
+        .filter(vehicle_position_history::dsl::recorded_at_ms.le(query.at_ms))
+        .filter(vehicle_position_history::dsl::recorded_at_ms.ge(query.at_ms - lookback_s * 1000))
+        .distinct_on(vehicle_position_history::dsl::vehicle_id)
+        .order((
+            vehicle_position_history::dsl::vehicle_id.asc(),
+            vehicle_position_history::dsl::recorded_at_ms.desc(),
+        ))
+        .select(VehiclePositionHistoryRow::as_select())
+        .load::<VehiclePositionHistoryRow>(conn)
+        .await;
+
+    match vehicles {
+        Ok(vehicles) => HttpResponse::Ok()
+            .append_header(("Cache-Control", "max-age=60"))
+            .json(ReplayResponse {
+                chateau,
+                at_ms: query.at_ms,
+                vehicles,
+            }),
+        Err(e) => {
+            eprintln!("{}", e);
+            HttpResponse::InternalServerError().body("Could not fetch replay")
+        }
+    }
+}
@@ -0,0 +1,532 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// The coordination layer shared by alpenrose, aspen and birch: a key value store with leases
+// and leader elections. Production uses etcd. The in process implementation keeps everything in
+// memory, for running all three services inside one process without etcd.
+//
+// CATENARY_COORDINATOR=in_process selects the in process implementation, anything else etcd.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+pub type CoordinationResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub trait Coordination: Send + Sync {
+    fn get(&self, key: &str) -> impl Future<Output = CoordinationResult<Option<Vec<u8>>>> + Send;
+
+    // every key starting with prefix, with the full key
+    fn get_prefix(
+        &self,
+        prefix: &str,
+    ) -> impl Future<Output = CoordinationResult<Vec<(String, Vec<u8>)>>> + Send;
+
+    // the key is deleted when the lease expires or is revoked
+    fn put(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        lease_id: Option<i64>,
+    ) -> impl Future<Output = CoordinationResult<()>> + Send;
+
+    fn delete(&self, key: &str) -> impl Future<Output = CoordinationResult<()>> + Send;
+
+    fn grant_lease(
+        &self,
+        lease_id: i64,
+        ttl_seconds: i64,
+    ) -> impl Future<Output = CoordinationResult<()>> + Send;
+
+    fn keep_alive(&self, lease_id: i64) -> impl Future<Output = CoordinationResult<()>> + Send;
+
+    fn revoke_lease(&self, lease_id: i64) -> impl Future<Output = CoordinationResult<()>> + Send;
+
+    // waits until this lease holds the election
+    fn campaign(
+        &self,
+        election: &str,
+        value: Vec<u8>,
+        lease_id: i64,
+    ) -> impl Future<Output = CoordinationResult<()>> + Send;
+
+    // value the current leader campaigned with
+    fn leader(
+        &self,
+        election: &str,
+    ) -> impl Future<Output = CoordinationResult<Option<Vec<u8>>>> + Send;
+
+    // discards the history of old revisions, where the store keeps any
+    fn compact_history(&self) -> impl Future<Output = CoordinationResult<()>> + Send;
+}
+
+#[derive(Clone)]
+pub struct EtcdCoordination {
+    client: etcd_client::Client,
+}
+
+impl EtcdCoordination {
+    pub async fn connect(
+        addresses: &[String],
+        connect_options: Option<etcd_client::ConnectOptions>,
+    ) -> CoordinationResult<EtcdCoordination> {
+        let client = etcd_client::Client::connect(addresses, connect_options).await?;
+
+        Ok(EtcdCoordination { client })
+    }
+
+    // ETCD_URLS, ETCD_USERNAME and ETCD_PASSWORD
+    pub async fn connect_from_env() -> CoordinationResult<EtcdCoordination> {
+        let etcd_urls_original =
+            std::env::var("ETCD_URLS").unwrap_or_else(|_| "localhost:2379".to_string());
+        let etcd_urls = etcd_urls_original
+            .split(',')
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>();
+
+        let etcd_connect_options: Option<etcd_client::ConnectOptions> = match (
+            std::env::var("ETCD_USERNAME"),
+            std::env::var("ETCD_PASSWORD"),
+        ) {
+            (Ok(username), Ok(password)) => {
+                Some(etcd_client::ConnectOptions::new().with_user(username, password))
+            }
+            _ => None,
+        };
+
+        EtcdCoordination::connect(&etcd_urls, etcd_connect_options).await
+    }
+}
+
+impl Coordination for EtcdCoordination {
+    async fn get(&self, key: &str) -> CoordinationResult<Option<Vec<u8>>> {
+        let resp = self.client.clone().get(key, None).await?;
+
+        Ok(resp.kvs().first().map(|kv| kv.value().to_vec()))
+    }
+
+    async fn get_prefix(&self, prefix: &str) -> CoordinationResult<Vec<(String, Vec<u8>)>> {
+        let resp = self
+            .client
+            .clone()
+            .get(prefix, Some(etcd_client::GetOptions::new().with_prefix()))
+            .await?;
+
+        let mut results = vec![];
+
+        for kv in resp.kvs() {
+            results.push((kv.key_str()?.to_string(), kv.value().to_vec()));
+        }
+
+        Ok(results)
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        lease_id: Option<i64>,
+    ) -> CoordinationResult<()> {
+        let options = lease_id.map(|lease_id| etcd_client::PutOptions::new().with_lease(lease_id));
+
+        self.client.clone().put(key, value, options).await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> CoordinationResult<()> {
+        self.client.clone().delete(key, None).await?;
+
+        Ok(())
+    }
+
+    async fn grant_lease(&self, lease_id: i64, ttl_seconds: i64) -> CoordinationResult<()> {
+        self.client
+            .clone()
+            .lease_grant(
+                ttl_seconds,
+                Some(etcd_client::LeaseGrantOptions::new().with_id(lease_id)),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn keep_alive(&self, lease_id: i64) -> CoordinationResult<()> {
+        self.client.clone().lease_keep_alive(lease_id).await?;
+
+        Ok(())
+    }
+
+    async fn revoke_lease(&self, lease_id: i64) -> CoordinationResult<()> {
+        self.client.clone().lease_revoke(lease_id).await?;
+
+        Ok(())
+    }
+
+    async fn campaign(
+        &self,
+        election: &str,
+        value: Vec<u8>,
+        lease_id: i64,
+    ) -> CoordinationResult<()> {
+        self.client
+            .clone()
+            .election_client()
+            .campaign(election, value, lease_id)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn leader(&self, election: &str) -> CoordinationResult<Option<Vec<u8>>> {
+        let resp = self
+            .client
+            .clone()
+            .election_client()
+            .leader(election)
+            .await?;
+
+        Ok(resp.kv().map(|kv| kv.value().to_vec()))
+    }
+
+    async fn compact_history(&self) -> CoordinationResult<()> {
+        let mut client = self.client.clone();
+
+        let revision = client
+            .get("/", None)
+            .await?
+            .header()
+            .map(|header| header.revision());
+
+        if let Some(revision) = revision {
+            client.compact(revision, None).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct InProcessState {
+    values: BTreeMap<String, (Vec<u8>, Option<i64>)>,
+    // lease id -> (ttl, deadline)
+    leases: HashMap<i64, (Duration, Instant)>,
+    // election -> (leader value, leader lease id)
+    elections: HashMap<String, (Vec<u8>, i64)>,
+}
+
+impl InProcessState {
+    fn expire_leases(&mut self) {
+        let now = Instant::now();
+
+        let expired = self
+            .leases
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(lease_id, _)| *lease_id)
+            .collect::<Vec<i64>>();
+
+        for lease_id in expired {
+            self.drop_lease(lease_id);
+        }
+    }
+
+    fn drop_lease(&mut self, lease_id: i64) {
+        self.leases.remove(&lease_id);
+        self.values
+            .retain(|_, (_, value_lease_id)| *value_lease_id != Some(lease_id));
+        self.elections
+            .retain(|_, (_, leader_lease_id)| *leader_lease_id != lease_id);
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct InProcessCoordination {
+    state: Arc<Mutex<InProcessState>>,
+}
+
+impl InProcessCoordination {
+    pub fn new() -> InProcessCoordination {
+        InProcessCoordination::default()
+    }
+}
+
+impl Coordination for InProcessCoordination {
+    async fn get(&self, key: &str) -> CoordinationResult<Option<Vec<u8>>> {
+        let mut state = self.state.lock().await;
+        state.expire_leases();
+
+        Ok(state.values.get(key).map(|(value, _)| value.clone()))
+    }
+
+    async fn get_prefix(&self, prefix: &str) -> CoordinationResult<Vec<(String, Vec<u8>)>> {
+        let mut state = self.state.lock().await;
+        state.expire_leases();
+
+        Ok(state
+            .values
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (value, _))| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        lease_id: Option<i64>,
+    ) -> CoordinationResult<()> {
+        let mut state = self.state.lock().await;
+        state.expire_leases();
+
+        if let Some(lease_id) = lease_id {
+            if !state.leases.contains_key(&lease_id) {
+                return Err(format!("lease {} does not exist", lease_id).into());
+            }
+        }
+
+        state.values.insert(key.to_string(), (value, lease_id));
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> CoordinationResult<()> {
+        let mut state = self.state.lock().await;
+        state.values.remove(key);
+
+        Ok(())
+    }
+
+    async fn grant_lease(&self, lease_id: i64, ttl_seconds: i64) -> CoordinationResult<()> {
+        let mut state = self.state.lock().await;
+        state.expire_leases();
+
+        let ttl = Duration::from_secs(ttl_seconds.max(1) as u64);
+
+        state.leases.insert(lease_id, (ttl, Instant::now() + ttl));
+
+        Ok(())
+    }
+
+    async fn keep_alive(&self, lease_id: i64) -> CoordinationResult<()> {
+        let mut state = self.state.lock().await;
+        state.expire_leases();
+
+        match state.leases.get_mut(&lease_id) {
+            Some((ttl, deadline)) => {
+                *deadline = Instant::now() + *ttl;
+                Ok(())
+            }
+            None => Err(format!("lease {} does not exist", lease_id).into()),
+        }
+    }
+
+    async fn revoke_lease(&self, lease_id: i64) -> CoordinationResult<()> {
+        let mut state = self.state.lock().await;
+        state.drop_lease(lease_id);
+
+        Ok(())
+    }
+
+    async fn campaign(
+        &self,
+        election: &str,
+        value: Vec<u8>,
+        lease_id: i64,
+    ) -> CoordinationResult<()> {
+        loop {
+            {
+                let mut state = self.state.lock().await;
+                state.expire_leases();
+
+                if !state.leases.contains_key(&lease_id) {
+                    return Err(format!("lease {} does not exist", lease_id).into());
+                }
+
+                let is_free = match state.elections.get(election) {
+                    None => true,
+                    Some((_, leader_lease_id)) => *leader_lease_id == lease_id,
+                };
+
+                if is_free {
+                    state
+                        .elections
+                        .insert(election.to_string(), (value, lease_id));
+                    return Ok(());
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+
+    async fn leader(&self, election: &str) -> CoordinationResult<Option<Vec<u8>>> {
+        let mut state = self.state.lock().await;
+        state.expire_leases();
+
+        Ok(state
+            .elections
+            .get(election)
+            .map(|(value, _)| value.clone()))
+    }
+
+    async fn compact_history(&self) -> CoordinationResult<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub enum Coordinator {
+    Etcd(EtcdCoordination),
+    InProcess(InProcessCoordination),
+}
+
+impl Coordinator {
+    pub async fn from_env() -> CoordinationResult<Coordinator> {
+        match std::env::var("CATENARY_COORDINATOR").as_deref() {
+            Ok("in_process") => Ok(Coordinator::InProcess(InProcessCoordination::new())),
+            _ => Ok(Coordinator::Etcd(
+                EtcdCoordination::connect_from_env().await?,
+            )),
+        }
+    }
+}
+
+impl Coordination for Coordinator {
+    async fn get(&self, key: &str) -> CoordinationResult<Option<Vec<u8>>> {
+        match self {
+            Coordinator::Etcd(c) => c.get(key).await,
+            Coordinator::InProcess(c) => c.get(key).await,
+        }
+    }
+
+    async fn get_prefix(&self, prefix: &str) -> CoordinationResult<Vec<(String, Vec<u8>)>> {
+        match self {
+            Coordinator::Etcd(c) => c.get_prefix(prefix).await,
+            Coordinator::InProcess(c) => c.get_prefix(prefix).await,
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        lease_id: Option<i64>,
+    ) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.put(key, value, lease_id).await,
+            Coordinator::InProcess(c) => c.put(key, value, lease_id).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.delete(key).await,
+            Coordinator::InProcess(c) => c.delete(key).await,
+        }
+    }
+
+    async fn grant_lease(&self, lease_id: i64, ttl_seconds: i64) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.grant_lease(lease_id, ttl_seconds).await,
+            Coordinator::InProcess(c) => c.grant_lease(lease_id, ttl_seconds).await,
+        }
+    }
+
+    async fn keep_alive(&self, lease_id: i64) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.keep_alive(lease_id).await,
+            Coordinator::InProcess(c) => c.keep_alive(lease_id).await,
+        }
+    }
+
+    async fn revoke_lease(&self, lease_id: i64) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.revoke_lease(lease_id).await,
+            Coordinator::InProcess(c) => c.revoke_lease(lease_id).await,
+        }
+    }
+
+    async fn campaign(
+        &self,
+        election: &str,
+        value: Vec<u8>,
+        lease_id: i64,
+    ) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.campaign(election, value, lease_id).await,
+            Coordinator::InProcess(c) => c.campaign(election, value, lease_id).await,
+        }
+    }
+
+    async fn leader(&self, election: &str) -> CoordinationResult<Option<Vec<u8>>> {
+        match self {
+            Coordinator::Etcd(c) => c.leader(election).await,
+            Coordinator::InProcess(c) => c.leader(election).await,
+        }
+    }
+
+    async fn compact_history(&self) -> CoordinationResult<()> {
+        match self {
+            Coordinator::Etcd(c) => c.compact_history().await,
+            Coordinator::InProcess(c) => c.compact_history().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_process_values_expire_with_their_lease() {
+        let coordinator = InProcessCoordination::new();
+
+        coordinator.grant_lease(7, 30).await.unwrap();
+        coordinator
+            .put("/aspen_workers/a", vec![1], Some(7))
+            .await
+            .unwrap();
+        coordinator
+            .put("/aspen_workers/b", vec![2], None)
+            .await
+            .unwrap();
+        coordinator
+            .put("/alpenrose_workers/c", vec![3], None)
+            .await
+            .unwrap();
+
+        coordinator
+            .campaign("/aspen_leader", vec![9], 7)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            coordinator.get_prefix("/aspen_workers/").await.unwrap(),
+            vec![
+                ("/aspen_workers/a".to_string(), vec![1]),
+                ("/aspen_workers/b".to_string(), vec![2])
+            ]
+        );
+        assert_eq!(
+            coordinator.leader("/aspen_leader").await.unwrap(),
+            Some(vec![9])
+        );
+
+        coordinator.revoke_lease(7).await.unwrap();
+
+        assert_eq!(coordinator.get("/aspen_workers/a").await.unwrap(), None);
+        assert_eq!(
+            coordinator.get("/aspen_workers/b").await.unwrap(),
+            Some(vec![2])
+        );
+        assert_eq!(coordinator.leader("/aspen_leader").await.unwrap(), None);
+    }
+}
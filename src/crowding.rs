@@ -0,0 +1,218 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Expected crowding of a departure.
+// Live occupancy from the vehicle is used when the feed has it, otherwise the average of what
+// aspen has observed for the same route, direction and stop at the same hour of the week.
+
+use crate::models::CrowdingProfileRow;
+use ahash::AHashMap;
+use chrono::{Datelike, TimeZone, Timelike};
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel::SelectableHelper;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use serde::{Deserialize, Serialize};
+
+/// direction_id stored for trips without one
+pub const UNKNOWN_DIRECTION: i16 = -1;
+/// profiles with fewer observations than this are not shown
+pub const MIN_OBSERVATIONS: i64 = 3;
+
+// EMPTY to NOT_ACCEPTING_PASSENGERS, NO_DATA_AVAILABLE and NOT_BOARDABLE say nothing about load
+const MAX_OCCUPANCY_STATUS: i32 = 6;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CrowdingSource {
+    Live,
+    Historical,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ExpectedCrowding {
+    /// gtfs-rt OccupancyStatus
+    pub occupancy_status: Option<i32>,
+    pub occupancy_percentage: Option<i32>,
+    pub source: CrowdingSource,
+    /// number of past observations the historical value is averaged from
+    pub observation_count: Option<i64>,
+}
+
+// (route_id, direction_id, stop_id, time_of_week_bucket)
+pub type CrowdingProfileKey = (String, i16, String, i16);
+
+/// Hour of the week in local time, 0 is Monday 00:00 to 01:00
+pub fn time_of_week_bucket<T: TimeZone>(local_time: &chrono::DateTime<T>) -> i16 {
+    (local_time.weekday().num_days_from_monday() * 24 + local_time.hour()) as i16
+}
+
+/// Every bucket touched between start and end, used to fetch only the profiles a query needs
+pub fn time_of_week_buckets_between(
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    timezone: &chrono_tz::Tz,
+) -> Vec<i16> {
+    let mut buckets = vec![];
+    let mut time = start;
+
+    while time <= end + chrono::TimeDelta::hours(1) && buckets.len() < 168 {
+        let bucket = time_of_week_bucket(&time.with_timezone(timezone));
+
+        if !buckets.contains(&bucket) {
+            buckets.push(bucket);
+        }
+
+        time += chrono::TimeDelta::hours(1);
+    }
+
+    buckets
+}
+
+pub fn direction_key(direction_id: Option<bool>) -> i16 {
+    match direction_id {
+        Some(true) => 1,
+        Some(false) => 0,
+        None => UNKNOWN_DIRECTION,
+    }
+}
+
+pub fn valid_occupancy_status(occupancy_status: Option<i32>) -> Option<i32> {
+    occupancy_status.filter(|status| (0..=MAX_OCCUPANCY_STATUS).contains(status))
+}
+
+pub fn valid_occupancy_percentage(occupancy_percentage: Option<i32>) -> Option<i32> {
+    // percentages over 100 are allowed, crush loads exceed the seated capacity
+    occupancy_percentage.filter(|percentage| *percentage >= 0)
+}
+
+pub fn live_crowding(
+    occupancy_status: Option<i32>,
+    occupancy_percentage: Option<i32>,
+) -> Option<ExpectedCrowding> {
+    let occupancy_status = valid_occupancy_status(occupancy_status);
+    let occupancy_percentage = valid_occupancy_percentage(occupancy_percentage);
+
+    if occupancy_status.is_none() && occupancy_percentage.is_none() {
+        return None;
+    }
+
+    Some(ExpectedCrowding {
+        occupancy_status,
+        occupancy_percentage,
+        source: CrowdingSource::Live,
+        observation_count: None,
+    })
+}
+
+pub fn expected_crowding_from_profile(profile: &CrowdingProfileRow) -> Option<ExpectedCrowding> {
+    let observation_count = profile
+        .occupancy_status_count
+        .max(profile.occupancy_percentage_count);
+
+    if observation_count < MIN_OBSERVATIONS {
+        return None;
+    }
+
+    let mean = |sum: i64, count: i64| match count {
+        0 => None,
+        count => Some((sum as f64 / count as f64).round() as i32),
+    };
+
+    Some(ExpectedCrowding {
+        occupancy_status: mean(profile.occupancy_status_sum, profile.occupancy_status_count),
+        occupancy_percentage: mean(
+            profile.occupancy_percentage_sum,
+            profile.occupancy_percentage_count,
+        ),
+        source: CrowdingSource::Historical,
+        observation_count: Some(observation_count),
+    })
+}
+
+/// Profiles of these stops and hours of the week, keyed by route, direction, stop and bucket
+pub async fn load_crowding_profiles(
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    chateau_id: &str,
+    stop_ids: &[String],
+    buckets: &[i16],
+) -> Result<AHashMap<CrowdingProfileKey, CrowdingProfileRow>, diesel::result::Error> {
+    use crate::schema::gtfs::crowding_profiles::dsl as crowding_profiles;
+
+    let rows = crowding_profiles::crowding_profiles
+        .filter(crowding_profiles::chateau.eq(chateau_id))
+        .filter(crowding_profiles::stop_id.eq_any(stop_ids))
+        .filter(crowding_profiles::time_of_week_bucket.eq_any(buckets))
+        .select(CrowdingProfileRow::as_select())
+        .load::<CrowdingProfileRow>(conn)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                (
+                    row.route_id.clone(),
+                    row.direction_id,
+                    row.stop_id.clone(),
+                    row.time_of_week_bucket,
+                ),
+                row,
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(status_sum: i64, status_count: i64) -> CrowdingProfileRow {
+        CrowdingProfileRow {
+            chateau: String::from("metro~losangeles"),
+            route_id: String::from("720"),
+            direction_id: 0,
+            stop_id: String::from("3094"),
+            time_of_week_bucket: 8,
+            occupancy_status_sum: status_sum,
+            occupancy_status_count: status_count,
+            occupancy_percentage_sum: 0,
+            occupancy_percentage_count: 0,
+            last_observed_ms: 0,
+        }
+    }
+
+    #[test]
+    fn buckets_are_local_hours_of_the_week() {
+        let monday_morning = chrono_tz::America::Los_Angeles
+            .with_ymd_and_hms(2025, 4, 14, 8, 30, 0)
+            .unwrap();
+        let sunday_night = chrono_tz::America::Los_Angeles
+            .with_ymd_and_hms(2025, 4, 13, 23, 59, 0)
+            .unwrap();
+
+        assert_eq!(time_of_week_bucket(&monday_morning), 8);
+        assert_eq!(time_of_week_bucket(&sunday_night), 167);
+    }
+
+    #[test]
+    fn historical_crowding_needs_enough_observations() {
+        assert_eq!(expected_crowding_from_profile(&profile(6, 2)), None);
+
+        let expected = expected_crowding_from_profile(&profile(11, 4)).unwrap();
+
+        assert_eq!(expected.occupancy_status, Some(3));
+        assert_eq!(expected.occupancy_percentage, None);
+        assert_eq!(expected.source, CrowdingSource::Historical);
+        assert_eq!(expected.observation_count, Some(4));
+
+        assert_eq!(live_crowding(Some(7), None), None);
+        assert_eq!(
+            live_crowding(Some(2), Some(55)).map(|x| x.source),
+            Some(CrowdingSource::Live)
+        );
+    }
+}
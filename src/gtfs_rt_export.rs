@@ -0,0 +1,466 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Serialises Aspen's merged, cleaned state for a chateau back into GTFS Realtime, so other apps
+// can consume the corrected route ids, enriched vehicle positions and merged alerts in the
+// standard format.
+// Platforms (like Metrolink track numbers) have no field in GTFS Realtime, so they are exported as the
+// assigned_stop_id of the station's child stop with that platform_code, when the static feed has one.
+// Entities Aspen made up itself rather than received from the operator have ids starting with
+// SYNTHETIC_ENTITY_ID_PREFIX.
+
+use crate::aspen_dataset::*;
+use crate::duration_since_unix_epoch;
+use ahash::AHashMap;
+use gtfs_realtime::{FeedEntity, FeedHeader, FeedMessage};
+use prost::Message;
+
+pub const SYNTHETIC_ENTITY_ID_PREFIX: &str = "catenary-synthetic:";
+
+/// (parent station stop id, platform code) -> stop id of the platform
+pub type PlatformStops = AHashMap<(String, String), String>;
+
+fn entity_id(id: &str, synthetic: bool) -> String {
+    match synthetic {
+        true => format!("{}{}", SYNTHETIC_ENTITY_ID_PREFIX, id),
+        false => id.to_string(),
+    }
+}
+
+fn assigned_stop_id(
+    stu: &AspenisedStopTimeUpdate,
+    platform_stops: &PlatformStops,
+) -> Option<String> {
+    if let Some(assigned_stop_id) = stu
+        .stop_time_properties
+        .as_ref()
+        .and_then(|properties| properties.assigned_stop_id.clone())
+    {
+        return Some(assigned_stop_id);
+    }
+
+    let stop_id = stu.stop_id.as_ref()?;
+    let platform = stu.platform_assignment.as_ref()?;
+
+    platform_stops
+        .get(&(stop_id.to_string(), platform.platform.clone()))
+        .cloned()
+}
+
+fn translated_string(input: &AspenTranslatedString) -> gtfs_realtime::TranslatedString {
+    gtfs_realtime::TranslatedString {
+        translation: input
+            .translation
+            .iter()
+            .map(
+                |translation| gtfs_realtime::translated_string::Translation {
+                    text: translation.text.clone(),
+                    language: translation.language.clone(),
+                },
+            )
+            .collect(),
+    }
+}
+
+fn translated_image(input: &AspenTranslatedImage) -> gtfs_realtime::TranslatedImage {
+    gtfs_realtime::TranslatedImage {
+        localized_image: input
+            .localised_image
+            .iter()
+            .map(|image| gtfs_realtime::translated_image::LocalizedImage {
+                url: image.url.clone(),
+                media_type: image.media_type.clone(),
+                language: image.language.clone(),
+            })
+            .collect(),
+    }
+}
+
+fn trip_descriptor(input: &AspenRawTripInfo) -> gtfs_realtime::TripDescriptor {
+    gtfs_realtime::TripDescriptor {
+        trip_id: input.trip_id.clone(),
+        route_id: input.route_id.clone(),
+        direction_id: input.direction_id,
+        start_time: input.start_time.clone(),
+        start_date: input.start_date.clone(),
+        schedule_relationship: input.schedule_relationship,
+        modified_trip: input.modified_trip.as_ref().map(|modified_trip| {
+            gtfs_realtime::trip_descriptor::ModifiedTripSelector {
+                modifications_id: modified_trip.modifications_id.clone(),
+                affected_trip_id: modified_trip.affected_trip_id.clone(),
+                ..Default::default()
+            }
+        }),
+    }
+}
+
+fn vehicle_descriptor(input: &AspenisedVehicleDescriptor) -> gtfs_realtime::VehicleDescriptor {
+    gtfs_realtime::VehicleDescriptor {
+        id: input.id.clone(),
+        label: input.label.clone(),
+        license_plate: input.license_plate.clone(),
+        wheelchair_accessible: input.wheelchair_accessible,
+    }
+}
+
+fn stop_time_event(input: &AspenStopTimeEvent) -> gtfs_realtime::trip_update::StopTimeEvent {
+    gtfs_realtime::trip_update::StopTimeEvent {
+        delay: input.delay,
+        time: input.time,
+        uncertainty: input.uncertainty,
+        ..Default::default()
+    }
+}
+
+pub fn vehicle_position_to_gtfs_rt(
+    input: &AspenisedVehiclePosition,
+) -> gtfs_realtime::VehiclePosition {
+    gtfs_realtime::VehiclePosition {
+        trip: input
+            .trip
+            .as_ref()
+            .map(|trip| gtfs_realtime::TripDescriptor {
+                trip_id: trip.trip_id.clone(),
+                route_id: trip.route_id.clone(),
+                direction_id: trip.direction_id,
+                start_time: trip.start_time.clone(),
+                start_date: trip.start_date.clone(),
+                schedule_relationship: trip.schedule_relationship,
+                modified_trip: None,
+            }),
+        vehicle: input.vehicle.as_ref().map(vehicle_descriptor),
+        position: input
+            .position
+            .as_ref()
+            .map(|position| gtfs_realtime::Position {
+                latitude: position.latitude,
+                longitude: position.longitude,
+                // a bearing inferred from the shape is better than none
+                bearing: position.bearing.or_else(|| {
+                    input
+                        .trip_progress
+                        .as_ref()
+                        .and_then(|progress| progress.inferred_bearing)
+                }),
+                odometer: position.odometer,
+                speed: position.speed,
+            }),
+        current_stop_sequence: input.current_stop_sequence,
+        current_status: input.current_status,
+        timestamp: input.timestamp,
+        congestion_level: input.congestion_level,
+        occupancy_status: input.occupancy_status,
+        occupancy_percentage: input.occupancy_percentage,
+        ..Default::default()
+    }
+}
+
+pub fn trip_update_to_gtfs_rt(
+    input: &AspenisedTripUpdate,
+    platform_stops: &PlatformStops,
+) -> gtfs_realtime::TripUpdate {
+    gtfs_realtime::TripUpdate {
+        trip: trip_descriptor(&input.trip),
+        vehicle: input.vehicle.as_ref().map(vehicle_descriptor),
+        stop_time_update: input
+            .stop_time_update
+            .iter()
+            .map(|stu| gtfs_realtime::trip_update::StopTimeUpdate {
+                stop_sequence: stu.stop_sequence,
+                stop_id: stu.stop_id.as_ref().map(|stop_id| stop_id.to_string()),
+                arrival: stu.arrival.as_ref().map(stop_time_event),
+                departure: stu.departure.as_ref().map(stop_time_event),
+                departure_occupancy_status: stu.departure_occupancy_status,
+                schedule_relationship: stu.schedule_relationship,
+                stop_time_properties: assigned_stop_id(stu, platform_stops).map(
+                    |assigned_stop_id| {
+                        gtfs_realtime::trip_update::stop_time_update::StopTimeProperties {
+                            assigned_stop_id: Some(assigned_stop_id),
+                            ..Default::default()
+                        }
+                    },
+                ),
+            })
+            .collect(),
+        timestamp: input.timestamp,
+        delay: input.delay,
+        trip_properties: input.trip_properties.as_ref().map(|properties| {
+            gtfs_realtime::trip_update::TripProperties {
+                trip_id: properties.trip_id.clone(),
+                start_date: properties.start_date.clone(),
+                start_time: properties.start_time.clone(),
+                shape_id: properties.shape_id.clone(),
+                ..Default::default()
+            }
+        }),
+    }
+}
+
+pub fn alert_to_gtfs_rt(input: &AspenisedAlert) -> gtfs_realtime::Alert {
+    gtfs_realtime::Alert {
+        active_period: input
+            .active_period
+            .iter()
+            .map(|period| gtfs_realtime::TimeRange {
+                start: period.start,
+                end: period.end,
+            })
+            .collect(),
+        informed_entity: input
+            .informed_entity
+            .iter()
+            .map(|entity| gtfs_realtime::EntitySelector {
+                agency_id: entity.agency_id.clone(),
+                route_id: entity.route_id.clone(),
+                route_type: entity.route_type,
+                trip: entity.trip.as_ref().map(trip_descriptor),
+                stop_id: entity.stop_id.clone(),
+                direction_id: entity.direction_id,
+            })
+            .collect(),
+        cause: input.cause,
+        effect: input.effect,
+        url: input.url.as_ref().map(translated_string),
+        header_text: input.header_text.as_ref().map(translated_string),
+        description_text: input.description_text.as_ref().map(translated_string),
+        tts_header_text: input.tts_header_text.as_ref().map(translated_string),
+        tts_description_text: input.tts_description_text.as_ref().map(translated_string),
+        severity_level: input.severity_level,
+        image: input.image.as_ref().map(translated_image),
+        image_alternative_text: input.image_alternative_text.as_ref().map(translated_string),
+        cause_detail: input.cause_detail.as_ref().map(translated_string),
+        effect_detail: input.effect_detail.as_ref().map(translated_string),
+    }
+}
+
+fn header(
+    incrementality: gtfs_realtime::feed_header::Incrementality,
+    timestamp: u64,
+) -> FeedHeader {
+    FeedHeader {
+        gtfs_realtime_version: "2.0".to_string(),
+        incrementality: Some(incrementality as i32),
+        timestamp: Some(timestamp),
+    }
+}
+
+// One feed type of a chateau as a full dataset, sorted by entity id so equal states encode the same
+pub fn chateau_feed_message(
+    data: &AspenisedData,
+    feed_type: GtfsRtType,
+    platform_stops: &PlatformStops,
+) -> FeedMessage {
+    let mut entities: Vec<FeedEntity> = match feed_type {
+        GtfsRtType::VehiclePositions => data
+            .vehicle_positions
+            .iter()
+            .map(|(id, vehicle)| FeedEntity {
+                id: id.clone(),
+                vehicle: Some(vehicle_position_to_gtfs_rt(vehicle)),
+                ..Default::default()
+            })
+            .collect(),
+        GtfsRtType::TripUpdates => data
+            .trip_updates
+            .iter()
+            .map(|(id, trip_update)| FeedEntity {
+                id: entity_id(id, trip_update.block_delay_prediction.is_some()),
+                trip_update: Some(trip_update_to_gtfs_rt(trip_update, platform_stops)),
+                ..Default::default()
+            })
+            .collect(),
+        GtfsRtType::Alerts => data
+            .aspenised_alerts
+            .iter()
+            .map(|(id, alert)| FeedEntity {
+                id: entity_id(id, id.starts_with(HEADWAY_ALERT_ID_PREFIX)),
+                alert: Some(alert_to_gtfs_rt(alert)),
+                ..Default::default()
+            })
+            .collect(),
+    };
+
+    entities.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let timestamp = match data.last_updated_time_ms {
+        0 => duration_since_unix_epoch().as_secs(),
+        last_updated_time_ms => last_updated_time_ms / 1000,
+    };
+
+    FeedMessage {
+        header: header(
+            gtfs_realtime::feed_header::Incrementality::FullDataset,
+            timestamp,
+        ),
+        entity: entities,
+    }
+}
+
+// Turns a series of full datasets into a DIFFERENTIAL stream for one client.
+// The first message is the full dataset, every later one only has the entities that were added or
+// changed since, plus a deleted entity for each one that disappeared.
+#[derive(Default)]
+pub struct DifferentialFeed {
+    // entity id -> hash of the entity as last sent
+    sent: Option<AHashMap<String, u64>>,
+}
+
+impl DifferentialFeed {
+    pub fn new() -> DifferentialFeed {
+        DifferentialFeed::default()
+    }
+
+    pub fn next_message(&mut self, full_dataset: &FeedMessage) -> FeedMessage {
+        let current = full_dataset
+            .entity
+            .iter()
+            .map(|entity| {
+                (
+                    entity.id.clone(),
+                    crate::ahash_fast_hash(&entity.encode_to_vec()),
+                )
+            })
+            .collect::<AHashMap<String, u64>>();
+
+        let timestamp = full_dataset
+            .header
+            .timestamp
+            .unwrap_or_else(|| duration_since_unix_epoch().as_secs());
+
+        let previous = match self.sent.replace(current.clone()) {
+            None => return full_dataset.clone(),
+            Some(previous) => previous,
+        };
+
+        let mut entities = full_dataset
+            .entity
+            .iter()
+            .filter(|entity| previous.get(&entity.id) != current.get(&entity.id))
+            .cloned()
+            .collect::<Vec<FeedEntity>>();
+
+        let mut deleted_ids = previous
+            .keys()
+            .filter(|id| !current.contains_key(*id))
+            .cloned()
+            .collect::<Vec<String>>();
+
+        deleted_ids.sort();
+
+        entities.extend(deleted_ids.into_iter().map(|id| FeedEntity {
+            id,
+            is_deleted: Some(true),
+            ..Default::default()
+        }));
+
+        FeedMessage {
+            header: header(
+                gtfs_realtime::feed_header::Incrementality::Differential,
+                timestamp,
+            ),
+            entity: entities,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert_entity(id: &str, header_text: &str) -> FeedEntity {
+        FeedEntity {
+            id: id.to_string(),
+            alert: Some(gtfs_realtime::Alert {
+                header_text: Some(gtfs_realtime::TranslatedString {
+                    translation: vec![gtfs_realtime::translated_string::Translation {
+                        text: header_text.to_string(),
+                        language: None,
+                    }],
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn full_dataset(entities: Vec<FeedEntity>) -> FeedMessage {
+        FeedMessage {
+            header: header(gtfs_realtime::feed_header::Incrementality::FullDataset, 100),
+            entity: entities,
+        }
+    }
+
+    #[test]
+    fn differential_only_sends_changes_and_deletions() {
+        let mut differential = DifferentialFeed::new();
+
+        let first = differential.next_message(&full_dataset(vec![
+            alert_entity("a", "Delays"),
+            alert_entity("b", "Elevator out"),
+        ]));
+
+        assert_eq!(
+            first.header.incrementality,
+            Some(gtfs_realtime::feed_header::Incrementality::FullDataset as i32)
+        );
+        assert_eq!(first.entity.len(), 2);
+
+        let second = differential.next_message(&full_dataset(vec![
+            alert_entity("a", "Delays"),
+            alert_entity("c", "Detour"),
+        ]));
+
+        assert_eq!(
+            second.header.incrementality,
+            Some(gtfs_realtime::feed_header::Incrementality::Differential as i32)
+        );
+        assert_eq!(
+            second
+                .entity
+                .iter()
+                .map(|entity| (entity.id.as_str(), entity.is_deleted))
+                .collect::<Vec<_>>(),
+            vec![("c", None), ("b", Some(true))]
+        );
+
+        let unchanged = differential.next_message(&full_dataset(vec![
+            alert_entity("a", "Delays"),
+            alert_entity("c", "Detour"),
+        ]));
+
+        assert!(unchanged.entity.is_empty());
+    }
+
+    #[test]
+    fn platform_is_exported_as_the_child_stop() {
+        let stu = |platform: Option<&str>| AspenisedStopTimeUpdate {
+            stop_sequence: Some(3),
+            stop_id: Some("LAUS".into()),
+            arrival: None,
+            departure: None,
+            departure_occupancy_status: None,
+            schedule_relationship: None,
+            stop_time_properties: None,
+            platform_string: platform.map(|platform| platform.to_string()),
+            platform_assignment: platform.map(|platform| PlatformAssignment {
+                platform: platform.to_string(),
+                confidence: PlatformConfidence::Posted,
+                changed_from_scheduled: false,
+            }),
+        };
+
+        let platform_stops = PlatformStops::from_iter([(
+            ("LAUS".to_string(), "6".to_string()),
+            "LAUS-6".to_string(),
+        )]);
+
+        assert_eq!(
+            assigned_stop_id(&stu(Some("6")), &platform_stops),
+            Some("LAUS-6".to_string())
+        );
+        // a track the static feed has no platform for stays out of the export
+        assert_eq!(assigned_stop_id(&stu(Some("12")), &platform_stops), None);
+        assert_eq!(assigned_stop_id(&stu(None), &platform_stops), None);
+    }
+}
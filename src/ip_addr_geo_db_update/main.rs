@@ -1,3 +1,5 @@
+use catenary::coordination::{Coordination, Coordinator};
+use catenary::ip_to_location::index::IP_GEO_DB_VERSION_KEY;
 use catenary::ip_to_location::insert_ip_db_into_postgres;
 use catenary::postgres_tools::make_async_pool;
 use catenary::postgres_tools::CatenaryPostgresPool;
@@ -10,16 +12,26 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     let conn_pool: CatenaryPostgresPool = make_async_pool().await?;
     let arc_conn_pool: Arc<CatenaryPostgresPool> = Arc::new(conn_pool);
 
-    let conn_pool = arc_conn_pool.as_ref();
-    let conn_pre = conn_pool.get().await;
-    let conn = &mut conn_pre?;
-
     println!("Insert Geocoding from IP address db");
 
     let status_ip_db_insert = insert_ip_db_into_postgres(Arc::clone(&arc_conn_pool)).await;
 
-    if let Err(err) = &status_ip_db_insert {
-        eprintln!("{:#?}", err);
+    match &status_ip_db_insert {
+        Err(err) => {
+            eprintln!("{:#?}", err);
+        }
+        Ok(()) => {
+            // tell running birch instances to reload their in-memory index
+            let coordinator = Coordinator::from_env().await?;
+
+            let version = catenary::duration_since_unix_epoch()
+                .as_millis()
+                .to_string();
+
+            coordinator
+                .put(IP_GEO_DB_VERSION_KEY, version.into_bytes(), None)
+                .await?;
+        }
     }
 
     Ok(())
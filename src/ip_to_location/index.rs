@@ -0,0 +1,385 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// In-memory IP to location lookups, so a request never has to range scan Postgres.
+// Either the ranges from gtfs.ip_addr_to_geo are sorted into arrays and binary searched,
+// or a MaxMind format database is read straight from disk.
+
+use crate::coordination::{Coordination, Coordinator};
+use crate::models::IpToGeoAddr;
+use crate::postgres_tools::CatenaryPostgresPool;
+use ahash::AHashMap;
+use diesel::SelectableHelper;
+use diesel_async::RunQueryDsl;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Bumped by setup_ip_db after it replaces the table, so running birch instances reload
+pub const IP_GEO_DB_VERSION_KEY: &str = "/ip_geo_db_version";
+
+pub type IpGeoState = RwLock<Option<IpGeoLookup>>;
+
+// Many ranges share a location, so each distinct one is only stored once
+#[derive(Clone, Debug, PartialEq)]
+struct IpGeoLocation {
+    country_code: Option<String>,
+    geo_state: Option<String>,
+    geo_state2: Option<String>,
+    city: Option<String>,
+    postcode: Option<String>,
+    latitude: f64,
+    longitude: f64,
+    timezone: Option<String>,
+}
+
+type LocationKey = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    u64,
+    u64,
+    Option<String>,
+);
+
+/// Ranges sorted by their first address. GeoLite ranges never overlap,
+/// so the only candidate for an address is the last range starting at or before it.
+#[derive(Default)]
+pub struct IpRangeIndex {
+    v4: Vec<(u32, u32, u32)>,
+    v6: Vec<(u128, u128, u32)>,
+    locations: Vec<IpGeoLocation>,
+}
+
+fn find_range<T: Ord + Copy>(ranges: &[(T, T, u32)], ip: T) -> Option<(T, T, u32)> {
+    let after = ranges.partition_point(|(start, _, _)| *start <= ip);
+
+    if after == 0 {
+        return None;
+    }
+
+    let candidate = ranges[after - 1];
+
+    match candidate.1 >= ip {
+        true => Some(candidate),
+        false => None,
+    }
+}
+
+impl IpRangeIndex {
+    pub fn from_rows(rows: impl IntoIterator<Item = IpToGeoAddr>) -> IpRangeIndex {
+        let mut index = IpRangeIndex::default();
+        let mut location_ids: AHashMap<LocationKey, u32> = AHashMap::new();
+
+        for row in rows {
+            let location_key: LocationKey = (
+                row.country_code.clone(),
+                row.geo_state.clone(),
+                row.geo_state2.clone(),
+                row.city.clone(),
+                row.postcode.clone(),
+                row.latitude.to_bits(),
+                row.longitude.to_bits(),
+                row.timezone.clone(),
+            );
+
+            let location_id = *location_ids.entry(location_key).or_insert_with(|| {
+                index.locations.push(IpGeoLocation {
+                    country_code: row.country_code,
+                    geo_state: row.geo_state,
+                    geo_state2: row.geo_state2,
+                    city: row.city,
+                    postcode: row.postcode,
+                    latitude: row.latitude,
+                    longitude: row.longitude,
+                    timezone: row.timezone,
+                });
+
+                (index.locations.len() - 1) as u32
+            });
+
+            match (row.range_start.addr(), row.range_end.addr()) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => {
+                    index
+                        .v4
+                        .push((u32::from(start), u32::from(end), location_id))
+                }
+                (IpAddr::V6(start), IpAddr::V6(end)) => {
+                    index
+                        .v6
+                        .push((u128::from(start), u128::from(end), location_id))
+                }
+                _ => {}
+            }
+        }
+
+        index.v4.sort_unstable_by_key(|(start, _, _)| *start);
+        index.v6.sort_unstable_by_key(|(start, _, _)| *start);
+
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<IpToGeoAddr> {
+        let (range_start, range_end, location_id) = match ip {
+            IpAddr::V4(ip) => {
+                let (start, end, location_id) = find_range(&self.v4, u32::from(ip))?;
+                (
+                    ipnet::IpNet::from(IpAddr::V4(Ipv4Addr::from(start))),
+                    ipnet::IpNet::from(IpAddr::V4(Ipv4Addr::from(end))),
+                    location_id,
+                )
+            }
+            IpAddr::V6(ip) => {
+                let (start, end, location_id) = find_range(&self.v6, u128::from(ip))?;
+                (
+                    ipnet::IpNet::from(IpAddr::V6(Ipv6Addr::from(start))),
+                    ipnet::IpNet::from(IpAddr::V6(Ipv6Addr::from(end))),
+                    location_id,
+                )
+            }
+        };
+
+        let location = self.locations.get(location_id as usize)?.clone();
+
+        Some(IpToGeoAddr {
+            is_ipv6: ip.is_ipv6(),
+            range_start,
+            range_end,
+            country_code: location.country_code,
+            geo_state: location.geo_state,
+            geo_state2: location.geo_state2,
+            city: location.city,
+            postcode: location.postcode,
+            latitude: location.latitude,
+            longitude: location.longitude,
+            timezone: location.timezone,
+        })
+    }
+
+    pub async fn load_from_postgres(
+        arc_conn_pool: Arc<CatenaryPostgresPool>,
+    ) -> Result<IpRangeIndex, Box<dyn Error + Send + Sync>> {
+        let conn_pool = arc_conn_pool.as_ref();
+        let conn_pre = conn_pool.get().await;
+        let conn = &mut conn_pre?;
+
+        let rows = crate::schema::gtfs::ip_addr_to_geo::dsl::ip_addr_to_geo
+            .select(IpToGeoAddr::as_select())
+            .load::<IpToGeoAddr>(conn)
+            .await?;
+
+        Ok(IpRangeIndex::from_rows(rows))
+    }
+}
+
+/// Where birch gets its IP locations from, picked with IP_GEO_SOURCE
+#[derive(Clone, Debug)]
+pub enum IpGeoSource {
+    // the ranges setup_ip_db loads into gtfs.ip_addr_to_geo
+    Postgres,
+    // a GeoLite2 or GeoIP2 City database, path from IP_GEO_MMDB_PATH
+    Mmdb(PathBuf),
+}
+
+impl IpGeoSource {
+    pub fn from_env() -> IpGeoSource {
+        match std::env::var("IP_GEO_SOURCE").as_deref() {
+            Ok("mmdb") => match std::env::var("IP_GEO_MMDB_PATH") {
+                Ok(path) => IpGeoSource::Mmdb(PathBuf::from(path)),
+                Err(_) => {
+                    eprintln!(
+                        "IP_GEO_SOURCE is mmdb but IP_GEO_MMDB_PATH is not set, using postgres"
+                    );
+                    IpGeoSource::Postgres
+                }
+            },
+            _ => IpGeoSource::Postgres,
+        }
+    }
+}
+
+pub enum IpGeoLookup {
+    Ranges(IpRangeIndex),
+    Mmdb(maxminddb::Reader<Vec<u8>>),
+}
+
+impl IpGeoLookup {
+    pub async fn load(
+        source: &IpGeoSource,
+        arc_conn_pool: Arc<CatenaryPostgresPool>,
+    ) -> Result<IpGeoLookup, Box<dyn Error + Send + Sync>> {
+        match source {
+            IpGeoSource::Postgres => Ok(IpGeoLookup::Ranges(
+                IpRangeIndex::load_from_postgres(arc_conn_pool).await?,
+            )),
+            IpGeoSource::Mmdb(path) => {
+                Ok(IpGeoLookup::Mmdb(maxminddb::Reader::open_readfile(path)?))
+            }
+        }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<IpToGeoAddr> {
+        match self {
+            IpGeoLookup::Ranges(index) => index.lookup(ip),
+            IpGeoLookup::Mmdb(reader) => lookup_mmdb(reader, ip),
+        }
+    }
+}
+
+fn english_name(names: &Option<std::collections::BTreeMap<&str, &str>>) -> Option<String> {
+    names
+        .as_ref()
+        .and_then(|names| names.get("en"))
+        .map(|name| name.to_string())
+}
+
+fn lookup_mmdb(reader: &maxminddb::Reader<Vec<u8>>, ip: IpAddr) -> Option<IpToGeoAddr> {
+    let (city, prefix_len): (maxminddb::geoip2::City, usize) = reader.lookup_prefix(ip).ok()?;
+
+    let network = ipnet::IpNet::new(ip, prefix_len as u8).ok()?.trunc();
+
+    let location = city.location.as_ref()?;
+
+    let subdivisions = city.subdivisions.as_deref().unwrap_or(&[]);
+
+    Some(IpToGeoAddr {
+        is_ipv6: ip.is_ipv6(),
+        range_start: ipnet::IpNet::from(network.network()),
+        range_end: ipnet::IpNet::from(network.broadcast()),
+        country_code: city
+            .country
+            .as_ref()
+            .and_then(|country| country.iso_code)
+            .map(|iso_code| iso_code.to_string()),
+        geo_state: subdivisions
+            .first()
+            .and_then(|subdivision| english_name(&subdivision.names)),
+        geo_state2: subdivisions
+            .get(1)
+            .and_then(|subdivision| english_name(&subdivision.names)),
+        city: city
+            .city
+            .as_ref()
+            .and_then(|city| english_name(&city.names)),
+        postcode: city
+            .postal
+            .as_ref()
+            .and_then(|postal| postal.code)
+            .map(|code| code.to_string()),
+        latitude: location.latitude?,
+        longitude: location.longitude?,
+        timezone: location.time_zone.map(|time_zone| time_zone.to_string()),
+    })
+}
+
+/// Loads the lookup once, then reloads it whenever setup_ip_db bumps IP_GEO_DB_VERSION_KEY
+pub async fn keep_ip_geo_lookup_fresh(
+    state: Arc<IpGeoState>,
+    source: IpGeoSource,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+    coordinator: Coordinator,
+) {
+    let mut loaded_version: Option<Option<Vec<u8>>> = None;
+
+    loop {
+        let version = match coordinator.get(IP_GEO_DB_VERSION_KEY).await {
+            Ok(version) => Some(version),
+            Err(e) => {
+                eprintln!("Could not read the ip geo db version: {}", e);
+                None
+            }
+        };
+
+        let should_load = match (&version, &loaded_version) {
+            (_, None) => true,
+            (Some(version), Some(loaded_version)) => version != loaded_version,
+            (None, Some(_)) => false,
+        };
+
+        if should_load {
+            match IpGeoLookup::load(&source, Arc::clone(&arc_conn_pool)).await {
+                Ok(lookup) => {
+                    if let IpGeoLookup::Ranges(index) = &lookup {
+                        println!("Loaded {} ip ranges into memory", index.len());
+                    }
+
+                    *state.write().unwrap() = Some(lookup);
+                    loaded_version = Some(version.flatten());
+                }
+                Err(e) => {
+                    eprintln!("Could not load the ip geo lookup from {:?}: {}", source, e);
+                }
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(start: &str, end: &str, city: &str) -> IpToGeoAddr {
+        let start: IpAddr = start.parse().unwrap();
+        let end: IpAddr = end.parse().unwrap();
+
+        IpToGeoAddr {
+            is_ipv6: start.is_ipv6(),
+            range_start: ipnet::IpNet::from(start),
+            range_end: ipnet::IpNet::from(end),
+            country_code: Some("US".to_string()),
+            geo_state: None,
+            geo_state2: None,
+            city: Some(city.to_string()),
+            postcode: None,
+            latitude: 34.0,
+            longitude: -118.0,
+            timezone: None,
+        }
+    }
+
+    #[test]
+    fn finds_the_range_containing_an_address_for_both_families() {
+        let index = IpRangeIndex::from_rows(vec![
+            row("1.0.16.0", "1.0.31.255", "Los Angeles"),
+            row("1.0.0.0", "1.0.0.255", "Irvine"),
+            row(
+                "2001:418:1401:1f::",
+                "2001:418:1401:1f:ffff:ffff:ffff:ffff",
+                "Los Angeles",
+            ),
+        ]);
+
+        assert_eq!(index.len(), 3);
+
+        let city = |ip: &str| {
+            index
+                .lookup(ip.parse().unwrap())
+                .and_then(|found| found.city)
+        };
+
+        assert_eq!(city("1.0.0.7"), Some("Irvine".to_string()));
+        assert_eq!(city("1.0.20.1"), Some("Los Angeles".to_string()));
+        assert_eq!(city("1.0.1.1"), None);
+        assert_eq!(city("0.255.255.255"), None);
+        assert_eq!(
+            city("2001:418:1401:1f::42"),
+            Some("Los Angeles".to_string())
+        );
+        assert_eq!(city("2001:418:1401:20::1"), None);
+    }
+}
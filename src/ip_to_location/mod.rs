@@ -1,3 +1,5 @@
+pub mod index;
+
 use crate::models::IpToGeoAddr;
 use crate::postgres_tools::CatenaryPostgresPool;
 use ahash::AHashMap;
@@ -133,7 +135,7 @@ pub async fn insert_ip_db_into_postgres(
     arc_conn_pool: Arc<CatenaryPostgresPool>,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
     let mut ipv6_download = download_and_process_url(true).await?;
-    let mut ipv4_download = download_and_process_url(true).await?;
+    let mut ipv4_download = download_and_process_url(false).await?;
 
     let mut joined_downloads = vec![];
     joined_downloads.append(&mut ipv4_download);
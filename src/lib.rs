@@ -38,20 +38,27 @@ extern crate diesel_derive_newtype;
 #[macro_use]
 extern crate serde;
 
+pub mod admin_auth;
 pub mod agency_secret;
 pub mod aspen;
 pub mod cholla;
+pub mod coordination;
+pub mod crowding;
 pub mod custom_pg_types;
 pub mod enum_to_int;
+pub mod gtfs_rt_export;
 pub mod gtfs_rt_handlers;
 pub mod gtfs_rt_rough_hash;
 pub mod id_cleanup;
 pub mod ip_to_location;
 pub mod maple_syrup;
+pub mod metrics;
 pub mod models;
 pub mod postgis_to_diesel;
 pub mod postgres_tools;
+pub mod realtime_fan_out;
 pub mod schema;
+pub mod timetable_graph;
 pub mod validate_gtfs_rt;
 use crate::aspen::lib::RealtimeFeedMetadataEtcd;
 pub mod custom_alerts;
@@ -68,8 +75,10 @@ use std::hash::Hasher;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 pub mod metrolink_ptc_to_stop_id;
 pub mod rt_recent_history;
+pub mod rt_recording;
 use crate::rt_recent_history::*;
 pub mod schedule_filtering;
+pub mod shape_progress;
 pub mod tile_save_and_get;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -196,6 +205,32 @@ pub mod aspen_dataset {
         }
     }
 
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct ShapeProgressInternalCache {
+        // shape_id -> shape prepared for projection
+        pub shapes: AHashMap<String, crate::shape_progress::ProjectedShape>,
+        // itinerary_pattern_id -> stops in order with their distance along the shape
+        pub itinerary_stop_distances: AHashMap<String, Vec<ShapeProgressStop>>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct ShapeProgressStop {
+        pub stop_id: CompactString,
+        pub gtfs_stop_sequence: u32,
+        pub distance_along_shape_m: Option<f64>,
+        // scheduled seconds after the start of the trip, interpolated if the feed left it blank
+        pub time_since_start: Option<i32>,
+    }
+
+    impl ShapeProgressInternalCache {
+        pub fn new() -> Self {
+            ShapeProgressInternalCache {
+                shapes: AHashMap::new(),
+                itinerary_stop_distances: AHashMap::new(),
+            }
+        }
+    }
+
     #[derive(Clone, Serialize, Deserialize)]
     pub struct AspenisedData {
         pub vehicle_positions: AHashMap<String, AspenisedVehiclePosition>,
@@ -213,6 +248,9 @@ pub mod aspen_dataset {
         pub last_updated_time_ms: u64,
         pub itinerary_pattern_internal_cache: ItineraryPatternInternalCache,
         pub compressed_trip_internal_cache: CompressedTripInternalCache,
+        pub shape_progress_internal_cache: ShapeProgressInternalCache,
+        // route_id -> spacing between vehicles on frequent routes
+        pub route_headway_health: AHashMap<String, AspenisedRouteHeadwayHealth>,
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
@@ -371,6 +409,16 @@ pub mod aspen_dataset {
         pub stop_time_update: Vec<AspenisedStopTimeUpdate>,
         pub trip_properties: Option<AspenTripProperties>,
         pub trip_headsign: Option<CompactString>,
+        // set on trip updates Aspen made up from a late vehicle earlier on the same block
+        pub block_delay_prediction: Option<BlockDelayPrediction>,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct BlockDelayPrediction {
+        pub from_trip_id: String,
+        pub from_trip_delay: i32,
+        // scheduled time between the end of the earlier trip and the start of this one
+        pub layover_seconds: i32,
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -433,6 +481,24 @@ pub mod aspen_dataset {
         pub schedule_relationship: Option<i32>,
         pub stop_time_properties: Option<AspenisedStopTimeProperties>,
         pub platform_string: Option<String>,
+        pub platform_assignment: Option<PlatformAssignment>,
+    }
+
+    /// How far along the operator is in committing to a realtime platform
+    #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum PlatformConfidence {
+        // assigned by the operator's systems but not yet shown to riders
+        Predicted,
+        // shown on station signs or in the operator's own app
+        Posted,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct PlatformAssignment {
+        pub platform: String,
+        pub confidence: PlatformConfidence,
+        // false when the provider has no way of knowing the scheduled platform
+        pub changed_from_scheduled: bool,
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -480,9 +546,153 @@ pub mod aspen_dataset {
         pub congestion_level: Option<i32>,
         pub occupancy_status: Option<i32>,
         pub occupancy_percentage: Option<u32>,
+        pub trip_progress: Option<AspenisedTripProgress>,
+        pub multi_carriage_details: Vec<AspenisedCarriageDetails>,
+        // number of cars actually running, and the number the schedule calls for
+        pub consist_length: Option<u32>,
+        pub scheduled_consist_length: Option<u32>,
+    }
+
+    /// One car of a vehicle, ordered by carriage_sequence starting at 1
+    /// The amenity fields are not part of GTFS-RT and are only set by operator specific adapters
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct AspenisedCarriageDetails {
+        pub id: Option<String>,
+        pub label: Option<String>,
+        pub occupancy_status: Option<i32>,
+        pub occupancy_percentage: Option<i32>,
+        pub carriage_sequence: Option<u32>,
+        pub car_type: Option<String>,
+        pub restroom: Option<bool>,
+        pub bike_capacity: Option<i32>,
+        pub locomotive: Option<bool>,
+        pub revenue: Option<bool>,
+    }
+
+    use gtfs_realtime::vehicle_position::CarriageDetails;
+
+    impl From<CarriageDetails> for AspenisedCarriageDetails {
+        fn from(carriage_details: CarriageDetails) -> Self {
+            AspenisedCarriageDetails {
+                id: carriage_details.id,
+                label: carriage_details.label,
+                occupancy_status: carriage_details.occupancy_status,
+                // -1 is the GTFS-RT way of saying there is no data
+                occupancy_percentage: carriage_details
+                    .occupancy_percentage
+                    .filter(|percentage| *percentage >= 0),
+                carriage_sequence: carriage_details.carriage_sequence,
+                car_type: None,
+                restroom: None,
+                bike_capacity: None,
+                locomotive: None,
+                revenue: None,
+            }
+        }
+    }
+
+    /// How a record from a secondary source finds the primary vehicle or trip it belongs to
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    pub enum SupplementaryJoinKey {
+        VehicleId(String),
+        VehicleLabel(String),
+        TripId(String),
+        TripShortName(String),
+    }
+
+    /// One entity of a secondary source, sent by alpenrose and merged by aspen onto the primary feed
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct SupplementaryRecord {
+        pub join_key: SupplementaryJoinKey,
+        pub position: Option<CatenaryRtVehiclePosition>,
+        // metres per second
+        pub speed: Option<f32>,
+        pub occupancy_status: Option<i32>,
+        pub occupancy_percentage: Option<u32>,
+        // by gtfs stop id
+        pub platforms: AHashMap<String, PlatformAssignment>,
+    }
+
+    #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum SupplementaryField {
+        Position,
+        Speed,
+        Occupancy,
+        Platform,
     }
 
+    #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum SupplementaryMergeMode {
+        // only used when the primary feed left the field empty
+        FillMissing,
+        // replaces whatever the primary feed said
+        Override,
+    }
+
+    /// Consist data that GTFS-RT has no fields for, sent by alpenrose next to the vehicle positions
+    /// Keyed by the id of the vehicle's FeedEntity
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct VehicleConsistSupplement {
+        pub consist_length: Option<u32>,
+        pub scheduled_consist_length: Option<u32>,
+        pub carriages: Vec<AspenisedCarriageDetails>,
+    }
+
+    /// Where the vehicle is along the shape of its trip
     #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct AspenisedTripProgress {
+        pub shape_id: String,
+        pub distance_along_shape_m: f64,
+        pub snapped_latitude: f32,
+        pub snapped_longitude: f32,
+        pub distance_from_shape_m: f64,
+        // only set when the feed did not provide a bearing
+        pub inferred_bearing: Option<f32>,
+        pub previous_stop_id: Option<CompactString>,
+        pub next_stop_id: Option<CompactString>,
+        pub fraction_between_stops: Option<f32>,
+    }
+
+    #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum HeadwayStatus {
+        Normal,
+        Bunched,
+        Gap,
+    }
+
+    /// Spacing between a vehicle and the one ahead of it on the same direction pattern,
+    /// measured as the scheduled running time between their positions
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct AspenisedHeadway {
+        pub leading_vehicle_id: String,
+        pub following_vehicle_id: String,
+        pub leading_trip_id: String,
+        pub following_trip_id: String,
+        pub headway_seconds: u32,
+        pub scheduled_headway_seconds: u32,
+        pub status: HeadwayStatus,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct AspenisedDirectionHeadways {
+        pub direction_pattern_id: Option<String>,
+        pub shape_id: String,
+        // leading vehicle first
+        pub headways: Vec<AspenisedHeadway>,
+    }
+
+    // alerts Aspen makes itself from vehicle spacing have ids starting with this
+    pub const HEADWAY_ALERT_ID_PREFIX: &str = "catenary-headway-";
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+    pub struct AspenisedRouteHeadwayHealth {
+        pub route_id: String,
+        pub bunched_count: u32,
+        pub gap_count: u32,
+        pub directions: Vec<AspenisedDirectionHeadways>,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
     pub struct CatenaryRtVehiclePosition {
         pub latitude: f32,
         pub longitude: f32,
@@ -632,32 +842,50 @@ pub fn route_id_transform(feed_id: &str, route_id: String) -> String {
 }
 
 pub async fn get_node_for_realtime_feed_id(
-    etcd: &mut etcd_client::Client,
+    coordinator: &coordination::Coordinator,
     realtime_feed_id: &str,
 ) -> Option<RealtimeFeedMetadataEtcd> {
-    let node = etcd
-        .get(
-            format!("/aspen_assigned_realtime_feed_ids/{}", realtime_feed_id).as_str(),
-            None,
-        )
+    use coordination::Coordination;
+
+    let node = coordinator
+        .get(format!("/aspen_assigned_realtime_feed_ids/{}", realtime_feed_id).as_str())
+        .await;
+
+    match node {
+        Ok(Some(value)) => {
+            let data = bincode::deserialize::<RealtimeFeedMetadataEtcd>(&value);
+
+            match data {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    println!("Error deserializing RealtimeFeedMetadataEtcd: {:?}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+pub async fn get_node_for_chateau(
+    coordinator: &coordination::Coordinator,
+    chateau_id: &str,
+) -> Option<crate::aspen::lib::ChateauMetadataEtcd> {
+    use coordination::Coordination;
+
+    let node = coordinator
+        .get(format!("/aspen_assigned_chateaus/{}", chateau_id).as_str())
         .await;
 
     match node {
-        Ok(resp) => {
-            let kvs = resp.kvs();
-
-            match kvs.len() {
-                0 => None,
-                _ => {
-                    let data = bincode::deserialize::<RealtimeFeedMetadataEtcd>(kvs[0].value());
-
-                    match data {
-                        Ok(data) => Some(data),
-                        Err(e) => {
-                            println!("Error deserializing RealtimeFeedMetadataEtcd: {:?}", e);
-                            None
-                        }
-                    }
+        Ok(Some(value)) => {
+            let data = bincode::deserialize::<crate::aspen::lib::ChateauMetadataEtcd>(&value);
+
+            match data {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    println!("Error deserializing ChateauMetadataEtcd: {:?}", e);
+                    None
                 }
             }
         }
@@ -716,10 +944,6 @@ mod unzip_uk_test {
     }
 }
 
-pub struct EtcdConnectionIps {
-    pub ip_addresses: Vec<String>,
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SerializableStop {
     pub id: String,
@@ -797,23 +1021,91 @@ pub fn make_weekdays(calendar: &crate::models::Calendar) -> Vec<chrono::Weekday>
         .collect()
 }
 
+/// `None` for exception types GTFS does not define, so bad feed data is skipped instead of crashing
+pub fn exception_from_calendar_date(
+    x: &crate::models::CalendarDate,
+) -> Option<gtfs_structures::Exception> {
+    match x.exception_type {
+        1 => Some(gtfs_structures::Exception::Added),
+        2 => Some(gtfs_structures::Exception::Deleted),
+        _ => {
+            eprintln!(
+                "Skipping calendar date of service {} on {} with unknown exception type {}",
+                x.service_id, x.gtfs_date, x.exception_type
+            );
+            None
+        }
+    }
+}
+
 impl CalendarUnified {
-    pub fn empty_exception_from_calendar_date(x: &crate::models::CalendarDate) -> Self {
+    pub fn empty_exception_from_calendar_date(
+        x: &crate::models::CalendarDate,
+        exception: gtfs_structures::Exception,
+    ) -> Self {
         CalendarUnified {
             id: x.service_id.clone(),
             general_calendar: None,
             exceptions: Some(std::collections::BTreeMap::from_iter([(
                 x.gtfs_date,
-                match x.exception_type {
-                    1 => gtfs_structures::Exception::Added,
-                    2 => gtfs_structures::Exception::Deleted,
-                    _ => panic!("WHAT IS THIS!!!!!!"),
-                },
+                exception,
             )])),
         }
     }
 }
 
+pub fn make_calendar_structure_from_pg_single_chateau(
+    services_calendar_lookup_queries_to_perform: Vec<crate::models::Calendar>,
+    services_calendar_dates_lookup_queries_to_perform: Vec<crate::models::CalendarDate>,
+) -> std::collections::BTreeMap<String, crate::CalendarUnified> {
+    let mut calendar_structures: std::collections::BTreeMap<String, crate::CalendarUnified> =
+        std::collections::BTreeMap::new();
+
+    for calendar in services_calendar_lookup_queries_to_perform {
+        calendar_structures.insert(
+            calendar.service_id.clone(),
+            crate::CalendarUnified {
+                id: calendar.service_id.clone(),
+                general_calendar: Some(crate::GeneralCalendar {
+                    days: make_weekdays(&calendar),
+                    start_date: calendar.gtfs_start_date,
+                    end_date: calendar.gtfs_end_date,
+                }),
+                exceptions: None,
+            },
+        );
+    }
+
+    for calendar_date in services_calendar_dates_lookup_queries_to_perform {
+        let Some(exception_number) = exception_from_calendar_date(&calendar_date) else {
+            continue;
+        };
+
+        match calendar_structures.entry(calendar_date.service_id.clone()) {
+            std::collections::btree_map::Entry::Occupied(mut oe) => {
+                let mut calendar_unified = oe.get_mut();
+
+                if let Some(entry) = &mut calendar_unified.exceptions {
+                    entry.insert(calendar_date.gtfs_date, exception_number);
+                } else {
+                    calendar_unified.exceptions = Some(std::collections::BTreeMap::from_iter([(
+                        calendar_date.gtfs_date,
+                        exception_number,
+                    )]));
+                }
+            }
+            std::collections::btree_map::Entry::Vacant(mut ve) => {
+                ve.insert(CalendarUnified::empty_exception_from_calendar_date(
+                    &calendar_date,
+                    exception_number,
+                ));
+            }
+        }
+    }
+
+    calendar_structures
+}
+
 pub struct TripToFindScheduleFor {
     pub trip_id: String,
     pub chateau: String,
@@ -888,7 +1180,7 @@ pub fn find_service_ranges(
     results
 }
 
-fn datetime_in_service(service: &CalendarUnified, input_date: chrono::NaiveDate) -> bool {
+pub fn datetime_in_service(service: &CalendarUnified, input_date: chrono::NaiveDate) -> bool {
     let mut answer = false;
 
     if let Some(calendar_general) = &service.general_calendar {
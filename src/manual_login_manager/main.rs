@@ -1,21 +1,62 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 struct Flags {
-    #[clap(long)]
-    email: String,
-    #[clap(long)]
-    password: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Add a new admin, or reset the password of an existing one
+    Add {
+        #[clap(long)]
+        email: String,
+        #[clap(long)]
+        password: String,
+        /// key-viewer, key-editor or feed-admin
+        #[clap(long, default_value = "key-viewer")]
+        role: String,
+    },
+    /// List all admins and their roles
+    List,
+    /// Disable an admin and end all of their sessions
+    Disable {
+        #[clap(long)]
+        email: String,
+    },
+    /// Re-enable a disabled admin
+    Enable {
+        #[clap(long)]
+        email: String,
+    },
+    /// Change the role of an admin
+    SetRole {
+        #[clap(long)]
+        email: String,
+        /// key-viewer, key-editor or feed-admin
+        #[clap(long)]
+        role: String,
+    },
+    /// Allow or forbid exporting the sealed realtime credentials in bulk
+    SetExportSecrets {
+        #[clap(long)]
+        email: String,
+        #[clap(long)]
+        allow: bool,
+    },
 }
 
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
     Argon2,
 };
+use catenary::admin_auth::AdminRole;
 use catenary::postgres_tools::{make_async_pool, CatenaryPostgresPool};
 use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use std::error::Error;
+use std::str::FromStr;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
@@ -26,49 +67,147 @@ async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
     println!("Connected to postgres");
 
-    let email = flags.email;
-    let password = flags.password;
-
-    //generate a salted Password
-    let password_bytes = password.as_bytes();
-    let salt = SaltString::generate(&mut OsRng);
-
-    let argon2 = Argon2::default();
-
-    let password_hash = argon2.hash_password(password_bytes, &salt).unwrap();
-
-    let serialised_hash = password_hash.to_string();
-    let serialised_salt = salt.to_string();
-
-    /*
-    CREATE TABLE gtfs.admin_credentials (
-    email text NOT NULL PRIMARY KEY,
-    hash text NOT NULL,
-    salt text NOT NULL,
-    last_updated_ms bigint NOT NULL
-    );
-    */
-
     use catenary::models::AdminCredentials;
     use catenary::schema::gtfs::admin_credentials as ac_table;
 
     let unix_time = catenary::duration_since_unix_epoch().as_millis() as i64;
 
-    let new_admin = AdminCredentials {
-        email,
-        hash: serialised_hash,
-        salt: serialised_salt,
-        last_updated_ms: unix_time,
-    };
-
-    let insert_result = diesel::insert_into(ac_table::dsl::admin_credentials)
-        .values(&new_admin)
-        .execute(conn)
-        .await;
-
-    match insert_result {
-        Ok(_) => println!("Successfully inserted new admin"),
-        Err(e) => println!("Error inserting new admin: {:?}", e),
+    match flags.command {
+        Command::Add {
+            email,
+            password,
+            role,
+        } => {
+            let role = AdminRole::from_str(&role)?;
+
+            //generate a salted Password
+            let password_bytes = password.as_bytes();
+            let salt = SaltString::generate(&mut OsRng);
+
+            let argon2 = Argon2::default();
+
+            let password_hash = argon2.hash_password(password_bytes, &salt).unwrap();
+
+            let serialised_hash = password_hash.to_string();
+            let serialised_salt = salt.to_string();
+
+            let new_admin = AdminCredentials {
+                email,
+                hash: serialised_hash,
+                salt: serialised_salt,
+                last_updated_ms: unix_time,
+                role: role.as_str().to_string(),
+                disabled: false,
+                can_export_secrets: false,
+            };
+
+            let insert_result = diesel::insert_into(ac_table::dsl::admin_credentials)
+                .values(&new_admin)
+                .on_conflict(ac_table::email)
+                .do_update()
+                .set((
+                    ac_table::hash.eq(&new_admin.hash),
+                    ac_table::salt.eq(&new_admin.salt),
+                    ac_table::last_updated_ms.eq(unix_time),
+                    ac_table::role.eq(&new_admin.role),
+                ))
+                .execute(conn)
+                .await;
+
+            match insert_result {
+                Ok(_) => {
+                    // a reset password has to end the sessions opened with the old one
+                    catenary::admin_auth::delete_all_sessions_for_admin(conn, &new_admin.email)
+                        .await?;
+
+                    println!("Successfully inserted new admin")
+                }
+                Err(e) => println!("Error inserting new admin: {:?}", e),
+            }
+        }
+        Command::List => {
+            let admins = ac_table::dsl::admin_credentials
+                .select(AdminCredentials::as_select())
+                .order(ac_table::email.asc())
+                .load::<AdminCredentials>(conn)
+                .await?;
+
+            for admin in admins {
+                println!(
+                    "{}\t{}\t{}{}",
+                    admin.email,
+                    admin.role,
+                    match admin.disabled {
+                        true => "disabled",
+                        false => "active",
+                    },
+                    match admin.can_export_secrets {
+                        true => "\tcan export secrets",
+                        false => "",
+                    }
+                );
+            }
+        }
+        Command::Disable { email } => {
+            let updated = diesel::update(ac_table::dsl::admin_credentials.find(&email))
+                .set((
+                    ac_table::disabled.eq(true),
+                    ac_table::last_updated_ms.eq(unix_time),
+                ))
+                .execute(conn)
+                .await?;
+
+            catenary::admin_auth::delete_all_sessions_for_admin(conn, &email).await?;
+
+            match updated {
+                0 => println!("No admin found with email {}", email),
+                _ => println!("Disabled {} and ended their sessions", email),
+            }
+        }
+        Command::Enable { email } => {
+            let updated = diesel::update(ac_table::dsl::admin_credentials.find(&email))
+                .set((
+                    ac_table::disabled.eq(false),
+                    ac_table::last_updated_ms.eq(unix_time),
+                ))
+                .execute(conn)
+                .await?;
+
+            match updated {
+                0 => println!("No admin found with email {}", email),
+                _ => println!("Enabled {}", email),
+            }
+        }
+        Command::SetRole { email, role } => {
+            let role = AdminRole::from_str(&role)?;
+
+            let updated = diesel::update(ac_table::dsl::admin_credentials.find(&email))
+                .set((
+                    ac_table::role.eq(role.as_str()),
+                    ac_table::last_updated_ms.eq(unix_time),
+                ))
+                .execute(conn)
+                .await?;
+
+            match updated {
+                0 => println!("No admin found with email {}", email),
+                _ => println!("Set role of {} to {}", email, role.as_str()),
+            }
+        }
+        Command::SetExportSecrets { email, allow } => {
+            let updated = diesel::update(ac_table::dsl::admin_credentials.find(&email))
+                .set((
+                    ac_table::can_export_secrets.eq(allow),
+                    ac_table::last_updated_ms.eq(unix_time),
+                ))
+                .execute(conn)
+                .await?;
+
+            match updated {
+                0 => println!("No admin found with email {}", email),
+                _ => println!("Set secret export of {} to {}", email, allow),
+            }
+        }
     }
 
     Ok(())
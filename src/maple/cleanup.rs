@@ -176,6 +176,18 @@ pub async fn delete_attempt_objects(
     .execute(conn)
     .await?;
 
+    use catenary::schema::gtfs::static_file_hashes;
+
+    let _ = diesel::delete(
+        static_file_hashes::dsl::static_file_hashes.filter(
+            static_file_hashes::dsl::onestop_feed_id
+                .eq(&feed_id)
+                .and(static_file_hashes::dsl::attempt_id.eq(&attempt_id)),
+        ),
+    )
+    .execute(conn)
+    .await?;
+
     let _ = diesel::update(
         catenary::schema::gtfs::ingested_static::dsl::ingested_static
             .filter(catenary::schema::gtfs::ingested_static::dsl::onestop_feed_id.eq(&feed_id))
@@ -296,6 +308,23 @@ pub async fn wipe_whole_feed(
     .execute(conn)
     .await?;
 
+    use catenary::schema::gtfs::gtfs_errors;
+
+    let _ = diesel::delete(
+        gtfs_errors::dsl::gtfs_errors.filter(gtfs_errors::dsl::onestop_feed_id.eq(&feed_id)),
+    )
+    .execute(conn)
+    .await?;
+
+    use catenary::schema::gtfs::static_file_hashes;
+
+    let _ = diesel::delete(
+        static_file_hashes::dsl::static_file_hashes
+            .filter(static_file_hashes::dsl::onestop_feed_id.eq(&feed_id)),
+    )
+    .execute(conn)
+    .await?;
+
     let _ = diesel::update(
         catenary::schema::gtfs::ingested_static::dsl::ingested_static
             .filter(catenary::schema::gtfs::ingested_static::dsl::onestop_feed_id.eq(&feed_id)),
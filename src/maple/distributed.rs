@@ -0,0 +1,515 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Splits ingestion between one coordinator and any number of workers, through the same
+// coordination store alpenrose and aspen use.
+//
+// The coordinator puts one job per feed under /maple_jobs/. A worker claims a job by winning the
+// election /maple_job_leases/{feed_id} with a lease of its own, which it keeps alive while
+// ingesting. If the worker dies the lease runs out and another worker picks the job up, after
+// deleting the attempts in_progress_static_ingests says were never finished. Every claim counts
+// as an attempt, so a feed that crashes its worker is given up on like one that fails, after
+// MAX_ATTEMPTS claims.
+// Results go to /maple_results/{feed_id}. Once no job of a chateau is left, the coordinator assigns
+// that chateau's new attempts to production.
+//
+// The coordinator stops waiting after MAPLE_COORDINATOR_TIMEOUT_SECONDS, leaving the chateaus that
+// did not finish on their previous attempts.
+//
+// Workers read the unzipped feeds from GTFS_UNCOMPRESSED_TEMP, so it has to be storage shared
+// with the coordinator.
+
+use crate::assign_production_tables::assign_production_tables;
+use crate::cleanup::delete_attempt_objects;
+use crate::ingest_feed::{clear_in_progress, ingest_feed, unfinished_attempts};
+use crate::transitland_download::DownloadedFeedsInformation;
+use catenary::coordination::{Coordination, Coordinator};
+use catenary::postgres_tools::make_async_pool;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+const JOBS_PREFIX: &str = "/maple_jobs/";
+const RESULTS_PREFIX: &str = "/maple_results/";
+const JOB_LEASES_PREFIX: &str = "/maple_job_leases/";
+
+const MAX_ATTEMPTS: u32 = 3;
+const JOB_LEASE_TTL_SECONDS: i64 = 30;
+const DEFAULT_COORDINATOR_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapleJob {
+    pub feed_id: String,
+    pub chateau_id: String,
+    pub download_data: DownloadedFeedsInformation,
+    pub delete_everything_in_feed_before_ingest: bool,
+    /// how many times a worker has claimed the job
+    pub attempts: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapleJobResult {
+    pub chateau_id: String,
+    /// None when the feed failed MAX_ATTEMPTS times
+    pub attempt_id: Option<String>,
+}
+
+/// Chateaus with no job left that have not been assigned yet
+fn chateaus_ready_for_production(
+    remaining_jobs: &[MapleJob],
+    results: &[(String, MapleJobResult)],
+    assigned_chateaus: &HashSet<String>,
+) -> Vec<String> {
+    let busy_chateaus = remaining_jobs
+        .iter()
+        .map(|job| job.chateau_id.as_str())
+        .collect::<HashSet<&str>>();
+
+    let mut ready = results
+        .iter()
+        .map(|(_, result)| result.chateau_id.as_str())
+        .filter(|chateau_id| !busy_chateaus.contains(chateau_id))
+        .filter(|chateau_id| !assigned_chateaus.contains(*chateau_id))
+        .map(|chateau_id| chateau_id.to_string())
+        .collect::<Vec<String>>();
+
+    ready.sort();
+    ready.dedup();
+
+    ready
+}
+
+async fn clear_prefix(
+    coordinator: &Coordinator,
+    prefix: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for (key, _) in coordinator.get_prefix(prefix).await? {
+        coordinator.delete(&key).await?;
+    }
+
+    Ok(())
+}
+
+async fn load_remaining_jobs(
+    coordinator: &Coordinator,
+) -> Result<Vec<MapleJob>, Box<dyn Error + Send + Sync>> {
+    Ok(coordinator
+        .get_prefix(JOBS_PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|(_, value)| bincode::deserialize::<MapleJob>(&value).ok())
+        .collect())
+}
+
+async fn load_results(
+    coordinator: &Coordinator,
+) -> Result<Vec<(String, MapleJobResult)>, Box<dyn Error + Send + Sync>> {
+    Ok(coordinator
+        .get_prefix(RESULTS_PREFIX)
+        .await?
+        .into_iter()
+        .filter_map(|(key, value)| {
+            bincode::deserialize::<MapleJobResult>(&value)
+                .ok()
+                .map(|result| (key.replace(RESULTS_PREFIX, ""), result))
+        })
+        .collect())
+}
+
+fn coordinator_timeout() -> Duration {
+    std::env::var("MAPLE_COORDINATOR_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_COORDINATOR_TIMEOUT)
+}
+
+/// Hands the feeds to workers and waits for all of them, or until the coordinator timeout
+pub async fn run_coordinator_jobs(
+    coordinator: &Coordinator,
+    jobs: Vec<MapleJob>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // anything left over belongs to a coordinator that did not finish
+    clear_prefix(coordinator, JOBS_PREFIX).await?;
+    clear_prefix(coordinator, RESULTS_PREFIX).await?;
+
+    let total_jobs = jobs.len();
+
+    for job in &jobs {
+        coordinator
+            .put(
+                format!("{}{}", JOBS_PREFIX, job.feed_id).as_str(),
+                bincode::serialize(job).unwrap(),
+                None,
+            )
+            .await?;
+    }
+
+    println!("Queued {} feeds for maple workers", total_jobs);
+
+    let mut assigned_chateaus: HashSet<String> = HashSet::new();
+
+    let started = Instant::now();
+    let timeout = coordinator_timeout();
+
+    loop {
+        // jobs before results: workers write the result before deleting the job
+        let remaining_jobs = load_remaining_jobs(coordinator).await?;
+        let results = load_results(coordinator).await?;
+
+        for chateau_id in
+            chateaus_ready_for_production(&remaining_jobs, &results, &assigned_chateaus)
+        {
+            for (feed_id, result) in results
+                .iter()
+                .filter(|(_, result)| result.chateau_id == chateau_id)
+            {
+                match &result.attempt_id {
+                    Some(attempt_id) => {
+                        if let Err(e) = assign_production_tables(
+                            feed_id,
+                            attempt_id,
+                            Arc::clone(&arc_conn_pool),
+                        )
+                        .await
+                        {
+                            eprintln!("Could not assign production tables for {}: {}", feed_id, e);
+                        }
+                    }
+                    None => {
+                        eprintln!("Giving up on {} after {} attempts", feed_id, MAX_ATTEMPTS);
+                    }
+                }
+            }
+
+            println!("Chateau {} assigned to production", chateau_id);
+
+            assigned_chateaus.insert(chateau_id);
+        }
+
+        println!(
+            "Completion progress: {}/{}",
+            total_jobs - remaining_jobs.len(),
+            total_jobs
+        );
+
+        if remaining_jobs.is_empty() {
+            break;
+        }
+
+        if started.elapsed() > timeout {
+            // workers stop picking these up, their chateaus keep the attempts already in production
+            clear_prefix(coordinator, JOBS_PREFIX).await?;
+            clear_prefix(coordinator, RESULTS_PREFIX).await?;
+
+            return Err(format!(
+                "Gave up waiting for maple workers after {:?}, {} feeds left: {}",
+                timeout,
+                remaining_jobs.len(),
+                remaining_jobs
+                    .iter()
+                    .map(|job| job.feed_id.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )
+            .into());
+        }
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+
+    clear_prefix(coordinator, RESULTS_PREFIX).await?;
+
+    Ok(())
+}
+
+async fn claim_next_job(
+    coordinator: &Coordinator,
+    this_worker_id: &str,
+) -> Result<Option<(MapleJob, i64)>, Box<dyn Error + Send + Sync>> {
+    for (key, _) in coordinator.get_prefix(JOBS_PREFIX).await? {
+        let election = key.replace(JOBS_PREFIX, JOB_LEASES_PREFIX);
+
+        if let Ok(Some(_)) = coordinator.leader(&election).await {
+            continue;
+        }
+
+        let job_lease_id: i64 = rand::rng().random_range(0..i64::MAX);
+
+        coordinator
+            .grant_lease(job_lease_id, JOB_LEASE_TTL_SECONDS)
+            .await?;
+
+        // campaigning waits while someone else holds the job
+        let campaign = tokio::time::timeout(
+            Duration::from_secs(2),
+            coordinator.campaign(
+                &election,
+                bincode::serialize(this_worker_id).unwrap(),
+                job_lease_id,
+            ),
+        )
+        .await;
+
+        if let Ok(Ok(())) = campaign {
+            // read again, the job may have finished or changed since it was listed
+            if let Some(value) = coordinator.get(&key).await? {
+                if let Ok(mut job) = bincode::deserialize::<MapleJob>(&value) {
+                    // counted before ingesting, a worker that dies halfway never gets to count it
+                    if job.attempts >= MAX_ATTEMPTS {
+                        finish_job(coordinator, &job, None).await?;
+                    } else {
+                        job.attempts += 1;
+
+                        coordinator
+                            .put(&key, bincode::serialize(&job).unwrap(), None)
+                            .await?;
+
+                        return Ok(Some((job, job_lease_id)));
+                    }
+                }
+            }
+        }
+
+        coordinator.revoke_lease(job_lease_id).await?;
+    }
+
+    Ok(None)
+}
+
+/// Stores the result and removes the job, `None` when the feed is given up on
+async fn finish_job(
+    coordinator: &Coordinator,
+    job: &MapleJob,
+    attempt_id: Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let result = MapleJobResult {
+        chateau_id: job.chateau_id.clone(),
+        attempt_id,
+    };
+
+    coordinator
+        .put(
+            format!("{}{}", RESULTS_PREFIX, job.feed_id).as_str(),
+            bincode::serialize(&result).unwrap(),
+            None,
+        )
+        .await?;
+    coordinator
+        .delete(format!("{}{}", JOBS_PREFIX, job.feed_id).as_str())
+        .await?;
+
+    Ok(())
+}
+
+async fn run_job(
+    coordinator: &Coordinator,
+    job: MapleJob,
+    job_lease_id: i64,
+    gtfs_uncompressed_temp_storage: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let feed_id = job.feed_id.clone();
+
+    // attempts of workers that died or lost their lease halfway
+    for attempt_id in unfinished_attempts(&feed_id, Arc::clone(&arc_conn_pool)).await? {
+        println!("Deleting unfinished attempt {}", attempt_id);
+
+        delete_attempt_objects(&feed_id, &attempt_id, Arc::clone(&arc_conn_pool)).await?;
+        clear_in_progress(&feed_id, &attempt_id, Arc::clone(&arc_conn_pool)).await?;
+    }
+
+    let attempt_id = format!("{}-{}", feed_id, chrono::Utc::now().timestamp_millis());
+
+    println!("Ingesting {} as {}", feed_id, attempt_id);
+
+    let ingest = ingest_feed(
+        gtfs_uncompressed_temp_storage,
+        &feed_id,
+        &attempt_id,
+        &job.chateau_id,
+        &job.download_data,
+        job.delete_everything_in_feed_before_ingest,
+        Arc::clone(&arc_conn_pool),
+    );
+
+    // a task of its own, so a long ingest step cannot hold up the renewals
+    let (lease_lost, lease_lost_receiver) = tokio::sync::oneshot::channel::<String>();
+
+    let keep_lease = tokio::spawn({
+        let coordinator = coordinator.clone();
+
+        async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+
+                if let Err(e) = coordinator.keep_alive(job_lease_id).await {
+                    let _ = lease_lost.send(e.to_string());
+                    return;
+                }
+            }
+        }
+    });
+
+    // stop writing as soon as the lease is gone, another worker may already have the job.
+    // Dropping the ingest abandons it before its attempt is committed.
+    let ingested = tokio::select! {
+        ingested = ingest => match ingested {
+            Ok(ingested) => ingested,
+            Err(e) => {
+                eprintln!("Ingesting {} failed: {}", feed_id, e);
+                false
+            }
+        },
+        Ok(lease_err) = lease_lost_receiver => {
+            keep_lease.abort();
+            return Err(format!("Lost the lease on {}: {}", feed_id, lease_err).into());
+        }
+    };
+
+    keep_lease.abort();
+
+    match (ingested, job.attempts < MAX_ATTEMPTS) {
+        (true, _) => finish_job(coordinator, &job, Some(attempt_id)).await,
+        // left in place for the next claim
+        (false, true) => Ok(()),
+        (false, false) => finish_job(coordinator, &job, None).await,
+    }
+}
+
+async fn worker_slot(
+    coordinator: Coordinator,
+    this_worker_id: String,
+    gtfs_uncompressed_temp_storage: String,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) {
+    loop {
+        match claim_next_job(&coordinator, &this_worker_id).await {
+            Ok(Some((job, job_lease_id))) => {
+                let feed_id = job.feed_id.clone();
+
+                if let Err(e) = run_job(
+                    &coordinator,
+                    job,
+                    job_lease_id,
+                    &gtfs_uncompressed_temp_storage,
+                    Arc::clone(&arc_conn_pool),
+                )
+                .await
+                {
+                    eprintln!("Job for {} failed: {}", feed_id, e);
+                }
+
+                let _ = coordinator.revoke_lease(job_lease_id).await;
+            }
+            Ok(None) => {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+            Err(e) => {
+                eprintln!("Could not claim a maple job: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Runs THREADS_GTFS jobs at a time until the process is stopped
+pub async fn run_worker() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let coordinator = Coordinator::from_env().await?;
+
+    let gtfs_uncompressed_temp_storage = std::env::var("GTFS_UNCOMPRESSED_TEMP").expect("Missing GTFS_UNCOMPRESSED_TEMP env variable. Please give the path the coordinator unzips feeds to.");
+
+    let conn_pool: CatenaryPostgresPool = make_async_pool().await?;
+    let arc_conn_pool: Arc<CatenaryPostgresPool> = Arc::new(conn_pool);
+
+    tokio::spawn(catenary::metrics::keep_pool_metrics_fresh(Arc::clone(
+        &arc_conn_pool,
+    )));
+
+    let this_worker_id = Uuid::new_v4().to_string();
+
+    println!("Maple worker {} started", this_worker_id);
+
+    let spawn_slot = |slots: &mut JoinSet<()>| {
+        slots.spawn(worker_slot(
+            coordinator.clone(),
+            this_worker_id.clone(),
+            gtfs_uncompressed_temp_storage.clone(),
+            Arc::clone(&arc_conn_pool),
+        ));
+    };
+
+    let mut slots = JoinSet::new();
+
+    for _ in 0..crate::get_threads_gtfs() {
+        spawn_slot(&mut slots);
+    }
+
+    // slots only stop by panicking, the job they held comes back once its lease runs out
+    while let Some(stopped) = slots.join_next().await {
+        if let Err(e) = stopped {
+            eprintln!("Maple worker slot stopped: {}", e);
+        }
+
+        spawn_slot(&mut slots);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(feed_id: &str, chateau_id: &str) -> MapleJob {
+        MapleJob {
+            feed_id: feed_id.to_string(),
+            chateau_id: chateau_id.to_string(),
+            download_data: DownloadedFeedsInformation {
+                feed_id: feed_id.to_string(),
+                url: String::new(),
+                hash: Some(1),
+                download_timestamp_ms: 0,
+                operation_success: true,
+                ingest: true,
+                byte_size: None,
+                duration_download: None,
+                http_response_code: None,
+            },
+            delete_everything_in_feed_before_ingest: false,
+            attempts: 0,
+        }
+    }
+
+    fn result(chateau_id: &str, attempt_id: Option<&str>) -> MapleJobResult {
+        MapleJobResult {
+            chateau_id: chateau_id.to_string(),
+            attempt_id: attempt_id.map(|x| x.to_string()),
+        }
+    }
+
+    #[test]
+    fn chateau_waits_for_all_of_its_feeds() {
+        let remaining_jobs = vec![job("f-b", "north")];
+        let results = vec![
+            ("f-a".to_string(), result("north", Some("f-a-1"))),
+            ("f-c".to_string(), result("south", None)),
+            ("f-d".to_string(), result("west", Some("f-d-1"))),
+        ];
+
+        let assigned = HashSet::from_iter(["west".to_string()]);
+
+        assert_eq!(
+            chateaus_ready_for_production(&remaining_jobs, &results, &assigned),
+            vec!["south".to_string()]
+        );
+    }
+}
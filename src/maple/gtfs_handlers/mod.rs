@@ -7,5 +7,6 @@ pub mod hull_from_gtfs;
 pub mod rename_route_labels;
 pub mod shape_colour_calculator;
 pub mod stops_associated_items;
+pub mod validation;
 
 pub const MAPLE_INGESTION_VERSION: i32 = 12;
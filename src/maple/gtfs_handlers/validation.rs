@@ -0,0 +1,510 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Checks run against the raw feed before anything is filtered or fixed, so row numbers match
+// the files the agency published.
+
+use gtfs_structures::RawGtfs;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// one bad feed can have millions of broken stop times, the first few are enough to debug it
+const MAX_FINDINGS_PER_CODE: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    // the data is unusable or will be dropped
+    Error,
+    // the data is used but probably isn't what the agency meant
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidationFinding {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub file: &'static str,
+    pub row_number: Option<i64>,
+    pub entity_id: Option<String>,
+    pub message: String,
+}
+
+/// How often a code was found, including findings past `MAX_FINDINGS_PER_CODE`
+#[derive(Clone, Debug)]
+pub struct FindingCount {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub file: &'static str,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub findings: Vec<ValidationFinding>,
+    pub counts: Vec<FindingCount>,
+}
+
+impl ValidationReport {
+    pub fn from_findings(findings: Vec<ValidationFinding>) -> Self {
+        let mut collector = FindingCollector::default();
+
+        for finding in findings {
+            collector.push(finding);
+        }
+
+        collector.finish()
+    }
+}
+
+#[derive(Default)]
+struct FindingCollector {
+    findings: Vec<ValidationFinding>,
+    // some codes are raised for more than one file, they are counted per file
+    count_per_code: BTreeMap<(&'static str, &'static str), usize>,
+    // (code, file) -> severity, so the overflow summary has it
+    code_info: BTreeMap<(&'static str, &'static str), Severity>,
+}
+
+impl FindingCollector {
+    fn push(&mut self, finding: ValidationFinding) {
+        let count = self
+            .count_per_code
+            .entry((finding.code, finding.file))
+            .or_insert(0);
+        *count += 1;
+
+        self.code_info
+            .insert((finding.code, finding.file), finding.severity);
+
+        if *count <= MAX_FINDINGS_PER_CODE {
+            self.findings.push(finding);
+        }
+    }
+
+    fn finish(mut self) -> ValidationReport {
+        for ((code, file), count) in self.count_per_code.iter() {
+            if *count > MAX_FINDINGS_PER_CODE {
+                let severity = self.code_info[&(*code, *file)];
+
+                self.findings.push(ValidationFinding {
+                    severity,
+                    code,
+                    file,
+                    row_number: None,
+                    entity_id: None,
+                    message: format!(
+                        "{} more {} findings in {} not shown",
+                        count - MAX_FINDINGS_PER_CODE,
+                        code,
+                        file
+                    ),
+                });
+            }
+        }
+
+        let counts = self
+            .count_per_code
+            .iter()
+            .map(|((code, file), count)| {
+                let severity = self.code_info[&(*code, *file)];
+
+                FindingCount {
+                    severity,
+                    code,
+                    file,
+                    count: *count,
+                }
+            })
+            .collect();
+
+        ValidationReport {
+            findings: self.findings,
+            counts,
+        }
+    }
+}
+
+// csv row of the nth record, the header takes row 1
+fn row_number(index: usize) -> Option<i64> {
+    Some(index as i64 + 2)
+}
+
+fn unreadable_file(
+    collector: &mut FindingCollector,
+    file: &'static str,
+    e: &gtfs_structures::Error,
+) {
+    collector.push(ValidationFinding {
+        severity: Severity::Error,
+        code: "unreadable_file",
+        file,
+        row_number: None,
+        entity_id: None,
+        message: format!("Could not read {}: {}", file, e),
+    });
+}
+
+pub fn validate_raw_gtfs(raw_gtfs: &RawGtfs) -> ValidationReport {
+    let mut collector = FindingCollector::default();
+
+    // stops.txt
+
+    let mut stop_ids: HashSet<&str> = HashSet::new();
+
+    match &raw_gtfs.stops {
+        Ok(stops) => {
+            for (index, stop) in stops.iter().enumerate() {
+                stop_ids.insert(stop.id.as_str());
+
+                // nodes and boarding areas are allowed to have no location
+                let location_required = matches!(
+                    stop.location_type,
+                    gtfs_structures::LocationType::StopPoint
+                        | gtfs_structures::LocationType::StopArea
+                        | gtfs_structures::LocationType::StationEntrance
+                );
+
+                match (stop.longitude, stop.latitude) {
+                    (Some(lon), Some(lat)) => {
+                        if catenary::is_null_island(lon, lat) {
+                            collector.push(ValidationFinding {
+                                severity: Severity::Error,
+                                code: "null_island_stop",
+                                file: "stops.txt",
+                                row_number: row_number(index),
+                                entity_id: Some(stop.id.clone()),
+                                message: format!(
+                                    "Stop {} is at ({}, {}), which is in the Gulf of Guinea",
+                                    stop.id, lat, lon
+                                ),
+                            });
+                        }
+                    }
+                    _ => {
+                        if location_required {
+                            collector.push(ValidationFinding {
+                                severity: Severity::Error,
+                                code: "stop_without_location",
+                                file: "stops.txt",
+                                row_number: row_number(index),
+                                entity_id: Some(stop.id.clone()),
+                                message: format!("Stop {} has no stop_lat or stop_lon", stop.id),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => unreadable_file(&mut collector, "stops.txt", e),
+    }
+
+    // routes.txt
+
+    let route_ids: HashSet<&str> = match &raw_gtfs.routes {
+        Ok(routes) => routes.iter().map(|route| route.id.as_str()).collect(),
+        Err(e) => {
+            unreadable_file(&mut collector, "routes.txt", e);
+            HashSet::new()
+        }
+    };
+
+    // calendar.txt and calendar_dates.txt
+
+    let mut service_ids: HashSet<&str> = HashSet::new();
+    // service_id -> has at least one added date
+    let mut services_with_added_dates: HashSet<&str> = HashSet::new();
+
+    if let Some(calendar_dates) = &raw_gtfs.calendar_dates {
+        match calendar_dates {
+            Ok(calendar_dates) => {
+                for calendar_date in calendar_dates {
+                    service_ids.insert(calendar_date.service_id.as_str());
+
+                    if matches!(
+                        calendar_date.exception_type,
+                        gtfs_structures::Exception::Added
+                    ) {
+                        services_with_added_dates.insert(calendar_date.service_id.as_str());
+                    }
+                }
+            }
+            Err(e) => unreadable_file(&mut collector, "calendar_dates.txt", e),
+        }
+    }
+
+    if let Some(calendar) = &raw_gtfs.calendar {
+        match calendar {
+            Ok(calendar) => {
+                for (index, service) in calendar.iter().enumerate() {
+                    service_ids.insert(service.id.as_str());
+
+                    let runs_on_any_day = service.monday
+                        || service.tuesday
+                        || service.wednesday
+                        || service.thursday
+                        || service.friday
+                        || service.saturday
+                        || service.sunday;
+
+                    let has_added_dates = services_with_added_dates.contains(service.id.as_str());
+
+                    if service.start_date > service.end_date {
+                        collector.push(ValidationFinding {
+                            severity: Severity::Error,
+                            code: "calendar_end_before_start",
+                            file: "calendar.txt",
+                            row_number: row_number(index),
+                            entity_id: Some(service.id.clone()),
+                            message: format!(
+                                "Service {} ends on {} before it starts on {}",
+                                service.id, service.end_date, service.start_date
+                            ),
+                        });
+                    } else if !runs_on_any_day && !has_added_dates {
+                        collector.push(ValidationFinding {
+                            severity: Severity::Warning,
+                            code: "calendar_never_active",
+                            file: "calendar.txt",
+                            row_number: row_number(index),
+                            entity_id: Some(service.id.clone()),
+                            message: format!(
+                                "Service {} has no days of the week between {} and {} and no added dates",
+                                service.id, service.start_date, service.end_date
+                            ),
+                        });
+                    }
+                }
+            }
+            Err(e) => unreadable_file(&mut collector, "calendar.txt", e),
+        }
+    }
+
+    // services that only exist in calendar_dates.txt and only ever remove days
+    if let Some(Ok(calendar_dates)) = &raw_gtfs.calendar_dates {
+        let services_in_calendar: HashSet<&str> = match &raw_gtfs.calendar {
+            Some(Ok(calendar)) => calendar.iter().map(|service| service.id.as_str()).collect(),
+            _ => HashSet::new(),
+        };
+
+        let mut reported: HashSet<&str> = HashSet::new();
+
+        for (index, calendar_date) in calendar_dates.iter().enumerate() {
+            let service_id = calendar_date.service_id.as_str();
+
+            if !services_in_calendar.contains(service_id)
+                && !services_with_added_dates.contains(service_id)
+                && reported.insert(service_id)
+            {
+                collector.push(ValidationFinding {
+                    severity: Severity::Warning,
+                    code: "calendar_never_active",
+                    file: "calendar_dates.txt",
+                    row_number: row_number(index),
+                    entity_id: Some(service_id.to_string()),
+                    message: format!(
+                        "Service {} is only in calendar_dates.txt and every date removes service",
+                        service_id
+                    ),
+                });
+            }
+        }
+    }
+
+    // trips.txt
+
+    let mut trip_ids: HashMap<&str, usize> = HashMap::new();
+
+    match &raw_gtfs.trips {
+        Ok(trips) => {
+            for (index, trip) in trips.iter().enumerate() {
+                trip_ids.insert(trip.id.as_str(), index);
+
+                if !route_ids.is_empty() && !route_ids.contains(trip.route_id.as_str()) {
+                    collector.push(ValidationFinding {
+                        severity: Severity::Error,
+                        code: "unknown_route_id",
+                        file: "trips.txt",
+                        row_number: row_number(index),
+                        entity_id: Some(trip.id.clone()),
+                        message: format!(
+                            "Trip {} references route_id {} which is not in routes.txt",
+                            trip.id, trip.route_id
+                        ),
+                    });
+                }
+
+                if !service_ids.contains(trip.service_id.as_str()) {
+                    collector.push(ValidationFinding {
+                        severity: Severity::Error,
+                        code: "unknown_service_id",
+                        file: "trips.txt",
+                        row_number: row_number(index),
+                        entity_id: Some(trip.id.clone()),
+                        message: format!(
+                            "Trip {} references service_id {} which is not in calendar.txt or calendar_dates.txt",
+                            trip.id, trip.service_id
+                        ),
+                    });
+                }
+            }
+        }
+        Err(e) => unreadable_file(&mut collector, "trips.txt", e),
+    }
+
+    // stop_times.txt
+
+    match &raw_gtfs.stop_times {
+        Ok(stop_times) => {
+            // trip_id -> indices of its stop times in the file
+            let mut stop_times_by_trip: HashMap<&str, Vec<usize>> = HashMap::new();
+
+            for (index, stop_time) in stop_times.iter().enumerate() {
+                stop_times_by_trip
+                    .entry(stop_time.trip_id.as_str())
+                    .or_default()
+                    .push(index);
+
+                if !stop_ids.is_empty() && !stop_ids.contains(stop_time.stop_id.as_str()) {
+                    collector.push(ValidationFinding {
+                        severity: Severity::Error,
+                        code: "unknown_stop_id",
+                        file: "stop_times.txt",
+                        row_number: row_number(index),
+                        entity_id: Some(stop_time.trip_id.clone()),
+                        message: format!(
+                            "Trip {} stops at stop_id {} which is not in stops.txt",
+                            stop_time.trip_id, stop_time.stop_id
+                        ),
+                    });
+                }
+
+                if !trip_ids.is_empty() && !trip_ids.contains_key(stop_time.trip_id.as_str()) {
+                    collector.push(ValidationFinding {
+                        severity: Severity::Warning,
+                        code: "unknown_trip_id",
+                        file: "stop_times.txt",
+                        row_number: row_number(index),
+                        entity_id: Some(stop_time.trip_id.clone()),
+                        message: format!(
+                            "Stop time references trip_id {} which is not in trips.txt",
+                            stop_time.trip_id
+                        ),
+                    });
+                }
+            }
+
+            // go through trips in file order so the report reads top to bottom
+            let mut trips_in_stop_times = stop_times_by_trip
+                .iter_mut()
+                .collect::<Vec<(&&str, &mut Vec<usize>)>>();
+
+            trips_in_stop_times.sort_by_key(|(_, indices)| indices[0]);
+
+            for (trip_id, indices) in trips_in_stop_times {
+                indices.sort_by_key(|index| stop_times[*index].stop_sequence);
+
+                // latest time the vehicle has been at so far
+                let mut previous: Option<u32> = None;
+
+                for index in indices.iter() {
+                    let stop_time = &stop_times[*index];
+
+                    let times = [stop_time.arrival_time, stop_time.departure_time]
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<u32>>();
+
+                    let mut goes_back_in_time = false;
+
+                    for time in times {
+                        if let Some(previous_time) = previous {
+                            if time < previous_time {
+                                goes_back_in_time = true;
+                            }
+                        }
+
+                        previous = Some(previous.map_or(time, |previous| previous.max(time)));
+                    }
+
+                    if goes_back_in_time {
+                        collector.push(ValidationFinding {
+                            severity: Severity::Error,
+                            code: "decreasing_stop_times",
+                            file: "stop_times.txt",
+                            row_number: row_number(*index),
+                            entity_id: Some(trip_id.to_string()),
+                            message: format!(
+                                "Trip {} goes back in time at stop_sequence {}",
+                                trip_id, stop_time.stop_sequence
+                            ),
+                        });
+                    }
+                }
+            }
+
+            let mut trips_without_stop_times = trip_ids
+                .iter()
+                .filter(|(trip_id, _)| !stop_times_by_trip.contains_key(*trip_id))
+                .map(|(trip_id, index)| (*index, *trip_id))
+                .collect::<Vec<(usize, &str)>>();
+
+            trips_without_stop_times.sort();
+
+            for (index, trip_id) in trips_without_stop_times {
+                collector.push(ValidationFinding {
+                    severity: Severity::Error,
+                    code: "trip_without_stop_times",
+                    file: "trips.txt",
+                    row_number: row_number(index),
+                    entity_id: Some(trip_id.to_string()),
+                    message: format!("Trip {} has no stop times", trip_id),
+                });
+            }
+        }
+        Err(e) => unreadable_file(&mut collector, "stop_times.txt", e),
+    }
+
+    collector.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finding(file: &'static str) -> ValidationFinding {
+        ValidationFinding {
+            severity: Severity::Warning,
+            code: "calendar_never_active",
+            file,
+            row_number: None,
+            entity_id: None,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn codes_are_counted_per_file() {
+        let report = ValidationReport::from_findings(vec![
+            finding("calendar.txt"),
+            finding("calendar_dates.txt"),
+            finding("calendar_dates.txt"),
+        ]);
+
+        let counts = report
+            .counts
+            .iter()
+            .map(|count| (count.file, count.count))
+            .collect::<Vec<(&str, usize)>>();
+
+        assert_eq!(counts, vec![("calendar.txt", 1), ("calendar_dates.txt", 2)]);
+    }
+}
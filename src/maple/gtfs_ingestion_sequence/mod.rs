@@ -4,5 +4,7 @@
 
 pub mod calendar_into_postgres;
 pub mod extra_stop_to_stop_shapes_into_postgres;
+pub mod reuse_previous_attempt;
 pub mod shapes_into_postgres;
 pub mod stops_into_postgres;
+pub mod validation_findings_into_postgres;
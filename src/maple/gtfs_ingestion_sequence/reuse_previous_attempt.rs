@@ -0,0 +1,443 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Incremental ingestion.
+// Every file of an ingested feed is hashed and saved next to the attempt. When a new zip arrives,
+// tables whose source files all hash the same as in the last successful attempt are copied over
+// inside postgres instead of being rebuilt, which for patterns and trips also skips maple_syrup.
+// Feed specific filters and the colour fixes live in code, so only attempts made with the same
+// MAPLE_INGESTION_VERSION are reused.
+// The date filter in gtfs_process_feed is not part of the hashes, so reused tables keep trips and
+// services that ended since the attempt they come from until one of their files changes. The filter
+// only trims service more than 30 days in the past, so nothing running today is affected.
+
+use crate::gtfs_handlers::MAPLE_INGESTION_VERSION;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::ingested_static;
+use catenary::schema::gtfs::static_file_hashes;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::ExpressionMethods;
+use diesel::OptionalExtension;
+use diesel::QueryDsl;
+use diesel::QueryResult;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::AsyncPgConnection;
+use diesel_async::RunQueryDsl;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+use std::sync::Arc;
+
+// trips that stopped running are filtered out before stops and shapes are built,
+// so the calendars are part of every group
+const STOPS_SOURCE_FILES: &[&str] = &[
+    "stops.txt",
+    "routes.txt",
+    "trips.txt",
+    "stop_times.txt",
+    "calendar.txt",
+    "calendar_dates.txt",
+];
+
+const SHAPES_SOURCE_FILES: &[&str] = &[
+    "shapes.txt",
+    "routes.txt",
+    "trips.txt",
+    "calendar.txt",
+    "calendar_dates.txt",
+];
+
+// direction patterns point at shapes by gtfs_shape_id, or at a generated shape when there is none
+const PATTERNS_SOURCE_FILES: &[&str] = &[
+    "agency.txt",
+    "stops.txt",
+    "shapes.txt",
+    "routes.txt",
+    "trips.txt",
+    "stop_times.txt",
+    "frequencies.txt",
+    "calendar.txt",
+    "calendar_dates.txt",
+];
+
+// attempt_id, chateau and allowed_spatial_query are written by copy_rows
+const STOPS_COLUMNS: &str = "onestop_feed_id, gtfs_id, name, name_translations, displayname, \
+code, gtfs_desc, gtfs_desc_translations, location_type, parent_station, zone_id, url, point, \
+timezone, wheelchair_boarding, primary_route_type, level_id, platform_code, \
+platform_code_translations, routes, route_types, children_ids, children_route_types, \
+station_feature, hidden, location_alias, tts_name, tts_name_translations";
+
+const SHAPES_COLUMNS: &str = "onestop_feed_id, shape_id, linestring, color, routes, route_type, \
+route_label, route_label_translations, text_color, stop_to_stop_generated";
+
+const DIRECTION_PATTERN_META_COLUMNS: &str = "onestop_feed_id, direction_pattern_id, \
+headsign_or_destination, gtfs_shape_id, fake_shape, route_id, route_type, direction_id";
+
+const DIRECTION_PATTERN_COLUMNS: &str = "onestop_feed_id, direction_pattern_id, stop_id, \
+stop_sequence, arrival_time_since_start, departure_time_since_start, \
+interpolated_time_since_start";
+
+const ITINERARY_PATTERN_META_COLUMNS: &str = "onestop_feed_id, route_id, trip_ids, \
+itinerary_pattern_id, trip_headsign, trip_headsign_translations, shape_id, timezone, \
+direction_pattern_id";
+
+const ITINERARY_PATTERN_COLUMNS: &str = "onestop_feed_id, itinerary_pattern_id, stop_sequence, \
+arrival_time_since_start, departure_time_since_start, stop_id, gtfs_stop_sequence, \
+interpolated_time_since_start";
+
+const TRIPS_COMPRESSED_COLUMNS: &str = "onestop_feed_id, trip_id, service_id, trip_short_name, \
+direction_id, block_id, wheelchair_accessible, bikes_allowed, frequencies, has_frequencies, \
+itinerary_pattern_id, route_id, start_time";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReusePlan {
+    pub stops: bool,
+    pub shapes: bool,
+    /// direction patterns, itineraries and trips
+    pub patterns: bool,
+}
+
+pub struct PreviousAttempt {
+    pub attempt_id: String,
+    pub file_hashes: BTreeMap<String, String>,
+}
+
+pub fn hash_feed_files(
+    feed_path: &str,
+) -> Result<BTreeMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let mut file_hashes = BTreeMap::new();
+
+    for entry in std::fs::read_dir(feed_path)? {
+        let entry = entry?;
+
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        // read in pieces, stop_times.txt of the larger feeds runs into gigabytes
+        let mut reader = std::io::BufReader::new(std::fs::File::open(entry.path())?);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 16];
+
+        loop {
+            let read = reader.read(&mut buffer)?;
+
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+        }
+
+        file_hashes.insert(
+            entry.file_name().to_string_lossy().to_string(),
+            format!("{:x}", hasher.finalize()),
+        );
+    }
+
+    Ok(file_hashes)
+}
+
+fn unchanged(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+    files: &[&str],
+) -> bool {
+    // a file missing from both counts as unchanged
+    files
+        .iter()
+        .all(|file| previous.get(*file) == current.get(*file))
+}
+
+pub fn plan_reuse(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> ReusePlan {
+    // attempts from before file hashing have nothing to compare against
+    if previous.is_empty() {
+        return ReusePlan::default();
+    }
+
+    ReusePlan {
+        stops: unchanged(previous, current, STOPS_SOURCE_FILES),
+        shapes: unchanged(previous, current, SHAPES_SOURCE_FILES),
+        patterns: unchanged(previous, current, PATTERNS_SOURCE_FILES),
+    }
+}
+
+/// The newest attempt of the feed that finished and has not been cleaned up yet
+pub async fn find_previous_attempt(
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<Option<PreviousAttempt>, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let attempt_id: Option<String> = ingested_static::dsl::ingested_static
+        .filter(ingested_static::dsl::onestop_feed_id.eq(feed_id))
+        .filter(ingested_static::dsl::ingestion_successfully_finished.eq(true))
+        .filter(ingested_static::dsl::deleted.eq(false))
+        .filter(ingested_static::dsl::ingestion_version.eq(MAPLE_INGESTION_VERSION))
+        .order(ingested_static::dsl::ingest_start_unix_time_ms.desc())
+        .select(ingested_static::dsl::attempt_id)
+        .first::<String>(conn)
+        .await
+        .optional()?;
+
+    let attempt_id = match attempt_id {
+        Some(attempt_id) => attempt_id,
+        None => return Ok(None),
+    };
+
+    let file_hashes = static_file_hashes::dsl::static_file_hashes
+        .filter(static_file_hashes::dsl::onestop_feed_id.eq(feed_id))
+        .filter(static_file_hashes::dsl::attempt_id.eq(&attempt_id))
+        .select((
+            static_file_hashes::dsl::file_name,
+            static_file_hashes::dsl::file_hash,
+        ))
+        .load::<(String, String)>(conn)
+        .await?
+        .into_iter()
+        .collect::<BTreeMap<String, String>>();
+
+    Ok(Some(PreviousAttempt {
+        attempt_id,
+        file_hashes,
+    }))
+}
+
+pub async fn file_hashes_into_postgres(
+    feed_id: &str,
+    attempt_id: &str,
+    file_hashes: &BTreeMap<String, String>,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let rows = file_hashes
+        .iter()
+        .map(|(file_name, file_hash)| catenary::models::StaticFileHash {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            file_name: file_name.clone(),
+            file_hash: file_hash.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    diesel::insert_into(static_file_hashes::dsl::static_file_hashes)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+struct CopyRequest<'a> {
+    feed_id: &'a str,
+    chateau_id: &'a str,
+    from_attempt_id: &'a str,
+    to_attempt_id: &'a str,
+}
+
+async fn copy_rows(
+    conn: &mut AsyncPgConnection,
+    table: &str,
+    columns: &str,
+    has_spatial_flag: bool,
+    extra_filter: &str,
+    request: &CopyRequest<'_>,
+) -> QueryResult<usize> {
+    // copied rows start hidden from spatial queries, like freshly inserted ones
+    let (spatial_column, spatial_value) = match has_spatial_flag {
+        true => (", allowed_spatial_query", ", false"),
+        false => ("", ""),
+    };
+
+    let query = format!(
+        "INSERT INTO gtfs.{table} (attempt_id, chateau{spatial_column}, {columns}) \
+        SELECT $1, $2{spatial_value}, {columns} FROM gtfs.{table} \
+        WHERE onestop_feed_id = $3 AND attempt_id = $4{extra_filter}"
+    );
+
+    sql_query(query)
+        .bind::<Text, _>(request.to_attempt_id)
+        .bind::<Text, _>(request.chateau_id)
+        .bind::<Text, _>(request.feed_id)
+        .bind::<Text, _>(request.from_attempt_id)
+        .execute(conn)
+        .await
+}
+
+pub async fn copy_stops(
+    feed_id: &str,
+    chateau_id: &str,
+    from_attempt_id: &str,
+    to_attempt_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let request = CopyRequest {
+        feed_id,
+        chateau_id,
+        from_attempt_id,
+        to_attempt_id,
+    };
+
+    Ok(copy_rows(conn, "stops", STOPS_COLUMNS, true, "", &request).await?)
+}
+
+/// Shapes from shapes.txt. The shapes generated from stop positions belong to the direction
+/// patterns and are copied by `copy_patterns`.
+pub async fn copy_shapes(
+    feed_id: &str,
+    chateau_id: &str,
+    from_attempt_id: &str,
+    to_attempt_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let request = CopyRequest {
+        feed_id,
+        chateau_id,
+        from_attempt_id,
+        to_attempt_id,
+    };
+
+    Ok(copy_rows(
+        conn,
+        "shapes",
+        SHAPES_COLUMNS,
+        true,
+        " AND stop_to_stop_generated IS NOT TRUE",
+        &request,
+    )
+    .await?)
+}
+
+/// Direction patterns with their generated shapes, itineraries and trips, all or nothing
+pub async fn copy_patterns(
+    feed_id: &str,
+    chateau_id: &str,
+    from_attempt_id: &str,
+    to_attempt_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let request = CopyRequest {
+        feed_id,
+        chateau_id,
+        from_attempt_id,
+        to_attempt_id,
+    };
+
+    let copied = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            async move {
+                let mut copied = copy_rows(
+                    conn,
+                    "shapes",
+                    SHAPES_COLUMNS,
+                    true,
+                    " AND stop_to_stop_generated IS TRUE",
+                    &request,
+                )
+                .await?;
+
+                for (table, columns) in [
+                    ("direction_pattern_meta", DIRECTION_PATTERN_META_COLUMNS),
+                    ("direction_pattern", DIRECTION_PATTERN_COLUMNS),
+                    ("itinerary_pattern_meta", ITINERARY_PATTERN_META_COLUMNS),
+                    ("itinerary_pattern", ITINERARY_PATTERN_COLUMNS),
+                    ("trips_compressed", TRIPS_COMPRESSED_COLUMNS),
+                ] {
+                    copied += copy_rows(conn, table, columns, false, "", &request).await?;
+                }
+
+                Ok(copied)
+            }
+            .scope_boxed()
+        })
+        .await?;
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(files: &[(&str, &str)]) -> BTreeMap<String, String> {
+        files
+            .iter()
+            .map(|(file, hash)| (file.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn feed_info_change_reuses_everything() {
+        let previous = hashes(&[
+            ("agency.txt", "1"),
+            ("stops.txt", "2"),
+            ("routes.txt", "3"),
+            ("trips.txt", "4"),
+            ("stop_times.txt", "5"),
+            ("shapes.txt", "6"),
+            ("feed_info.txt", "7"),
+        ]);
+
+        let mut current = previous.clone();
+        current.insert("feed_info.txt".to_string(), "8".to_string());
+
+        assert_eq!(
+            plan_reuse(&previous, &current),
+            ReusePlan {
+                stops: true,
+                shapes: true,
+                patterns: true,
+            }
+        );
+
+        current.insert("shapes.txt".to_string(), "9".to_string());
+
+        assert_eq!(
+            plan_reuse(&previous, &current),
+            ReusePlan {
+                stops: true,
+                shapes: false,
+                patterns: false,
+            }
+        );
+
+        current.insert("shapes.txt".to_string(), "6".to_string());
+        current.insert("frequencies.txt".to_string(), "10".to_string());
+
+        assert_eq!(
+            plan_reuse(&previous, &current),
+            ReusePlan {
+                stops: true,
+                shapes: true,
+                patterns: false,
+            }
+        );
+
+        assert_eq!(plan_reuse(&BTreeMap::new(), &current), ReusePlan::default());
+    }
+}
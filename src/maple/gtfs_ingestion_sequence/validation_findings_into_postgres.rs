@@ -0,0 +1,168 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+use crate::gtfs_handlers::validation::ValidationReport;
+use catenary::models::{GtfsValidationCount, GtfsValidationFinding, GtfsValidationReport};
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::gtfs_errors;
+use catenary::schema::gtfs::gtfs_validation_counts;
+use catenary::schema::gtfs::gtfs_validation_reports;
+use diesel::ExpressionMethods;
+use diesel::QueryDsl;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::AsyncConnection;
+use diesel_async::RunQueryDsl;
+use std::error::Error;
+use std::sync::Arc;
+
+// findings from older attempts are kept around long enough to compare against
+const FINDINGS_RETENTION_MS: i64 = 1000 * 60 * 60 * 24 * 30;
+
+pub async fn validation_findings_into_postgres(
+    report: &ValidationReport,
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+    chateau_id: &str,
+    attempt_id: &str,
+    file_hash: Option<String>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let validated_ms = catenary::duration_since_unix_epoch().as_millis() as i64;
+
+    let report_pg = GtfsValidationReport {
+        onestop_feed_id: feed_id.to_string(),
+        attempt_id: attempt_id.to_string(),
+        chateau: chateau_id.to_string(),
+        file_hash: file_hash.clone(),
+        validated_ms,
+    };
+
+    let counts_pg = report
+        .counts
+        .iter()
+        .map(|count| GtfsValidationCount {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            code: count.code.to_string(),
+            severity: count.severity.as_str().to_string(),
+            file: count.file.to_string(),
+            finding_count: count.count as i64,
+        })
+        .collect::<Vec<GtfsValidationCount>>();
+
+    let findings_pg = report
+        .findings
+        .iter()
+        .enumerate()
+        .map(|(index, finding)| GtfsValidationFinding {
+            onestop_feed_id: feed_id.to_string(),
+            attempt_id: attempt_id.to_string(),
+            error_index: index as i32,
+            chateau: chateau_id.to_string(),
+            file_hash: file_hash.clone(),
+            severity: finding.severity.as_str().to_string(),
+            code: finding.code.to_string(),
+            file: finding.file.to_string(),
+            row_number: finding.row_number,
+            entity_id: finding.entity_id.clone(),
+            error: finding.message.clone(),
+            validated_ms,
+        })
+        .collect::<Vec<GtfsValidationFinding>>();
+
+    // readers never see a report without its findings
+    conn.transaction::<_, diesel::result::Error, _>(|conn| {
+        async move {
+            let expired_attempts = gtfs_validation_reports::table
+                .filter(gtfs_validation_reports::dsl::onestop_feed_id.eq(feed_id))
+                .filter(
+                    gtfs_validation_reports::dsl::validated_ms
+                        .lt(validated_ms - FINDINGS_RETENTION_MS),
+                )
+                .select(gtfs_validation_reports::dsl::attempt_id)
+                .load::<String>(conn)
+                .await?;
+
+            let _ = diesel::delete(
+                gtfs_validation_counts::table
+                    .filter(gtfs_validation_counts::dsl::onestop_feed_id.eq(feed_id))
+                    .filter(gtfs_validation_counts::dsl::attempt_id.eq_any(&expired_attempts)),
+            )
+            .execute(conn)
+            .await?;
+
+            let _ = diesel::delete(
+                gtfs_validation_reports::table
+                    .filter(gtfs_validation_reports::dsl::onestop_feed_id.eq(feed_id))
+                    .filter(gtfs_validation_reports::dsl::attempt_id.eq_any(&expired_attempts)),
+            )
+            .execute(conn)
+            .await?;
+
+            let _ = diesel::delete(
+                gtfs_errors::table
+                    .filter(gtfs_errors::dsl::onestop_feed_id.eq(feed_id))
+                    .filter(
+                        gtfs_errors::dsl::validated_ms.lt(validated_ms - FINDINGS_RETENTION_MS),
+                    ),
+            )
+            .execute(conn)
+            .await?;
+
+            // a redo of the same attempt replaces its old report
+            let _ = diesel::delete(
+                gtfs_errors::table
+                    .filter(gtfs_errors::dsl::onestop_feed_id.eq(feed_id))
+                    .filter(gtfs_errors::dsl::attempt_id.eq(attempt_id)),
+            )
+            .execute(conn)
+            .await?;
+
+            let _ = diesel::delete(
+                gtfs_validation_counts::table
+                    .filter(gtfs_validation_counts::dsl::onestop_feed_id.eq(feed_id))
+                    .filter(gtfs_validation_counts::dsl::attempt_id.eq(attempt_id)),
+            )
+            .execute(conn)
+            .await?;
+
+            let _ = diesel::insert_into(gtfs_validation_reports::table)
+                .values(&report_pg)
+                .on_conflict((
+                    gtfs_validation_reports::dsl::onestop_feed_id,
+                    gtfs_validation_reports::dsl::attempt_id,
+                ))
+                .do_update()
+                .set((
+                    gtfs_validation_reports::dsl::file_hash.eq(&report_pg.file_hash),
+                    gtfs_validation_reports::dsl::validated_ms.eq(validated_ms),
+                ))
+                .execute(conn)
+                .await?;
+
+            for counts_chunk in counts_pg.chunks(400) {
+                let _ = diesel::insert_into(gtfs_validation_counts::table)
+                    .values(counts_chunk)
+                    .execute(conn)
+                    .await?;
+            }
+
+            for findings_chunk in findings_pg.chunks(400) {
+                let _ = diesel::insert_into(gtfs_errors::table)
+                    .values(findings_chunk)
+                    .execute(conn)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await?;
+
+    Ok(())
+}
@@ -9,10 +9,15 @@ use crate::gtfs_handlers::colour_correction::fix_foreground_colour_rgb_feed;
 use crate::gtfs_handlers::shape_colour_calculator::shape_to_colour;
 use crate::gtfs_handlers::shape_colour_calculator::ShapeToColourResponse;
 use crate::gtfs_handlers::stops_associated_items::*;
+use crate::gtfs_handlers::validation::{
+    validate_raw_gtfs, Severity, ValidationFinding, ValidationReport,
+};
 use crate::gtfs_ingestion_sequence::calendar_into_postgres::calendar_into_postgres;
 use crate::gtfs_ingestion_sequence::extra_stop_to_stop_shapes_into_postgres::insert_stop_to_stop_geometry;
+use crate::gtfs_ingestion_sequence::reuse_previous_attempt::*;
 use crate::gtfs_ingestion_sequence::shapes_into_postgres::shapes_into_postgres;
 use crate::gtfs_ingestion_sequence::stops_into_postgres::stops_into_postgres;
+use crate::gtfs_ingestion_sequence::validation_findings_into_postgres::validation_findings_into_postgres;
 use crate::DownloadedFeedsInformation;
 use catenary::enum_to_int::*;
 use catenary::gtfs_schedule_protobuf::frequencies_to_protobuf;
@@ -50,24 +55,11 @@ pub struct GtfsSummary {
 }
 
 // take a feed id and throw it into postgres
-pub async fn gtfs_process_feed(
-    gtfs_unzipped_path: &str,
+fn convert_and_filter(
     feed_id: &str,
-    arc_conn_pool: Arc<CatenaryPostgresPool>,
-    chateau_id: &str,
-    attempt_id: &str,
-    this_download_data: &DownloadedFeedsInformation,
-) -> Result<GtfsSummary, Box<dyn Error + Send + Sync>> {
-    println!("Begin feed {} processing", feed_id);
-    let start = Instant::now();
-    let conn_pool = arc_conn_pool.as_ref();
-    let conn_pre = conn_pool.get().await;
-    let conn = &mut conn_pre?;
-
-    //read the GTFS zip file
-    let path = format!("{}/{}", gtfs_unzipped_path, feed_id);
-
-    let gtfs = gtfs_structures::Gtfs::new(path.as_str())?;
+    raw_gtfs: gtfs_structures::RawGtfs,
+) -> Result<Gtfs, Box<dyn Error + Send + Sync>> {
+    let gtfs = gtfs_structures::Gtfs::try_from(raw_gtfs)?;
 
     let gtfs: Gtfs = match feed_id {
         "f-dpz8-ttc" => {
@@ -138,6 +130,106 @@ pub async fn gtfs_process_feed(
 
     let gtfs = minimum_day_filter(gtfs, today - chrono::Duration::days(30));
 
+    Ok(gtfs)
+}
+
+pub async fn gtfs_process_feed(
+    gtfs_unzipped_path: &str,
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+    chateau_id: &str,
+    attempt_id: &str,
+    this_download_data: &DownloadedFeedsInformation,
+) -> Result<GtfsSummary, Box<dyn Error + Send + Sync>> {
+    println!("Begin feed {} processing", feed_id);
+    let start = Instant::now();
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    //read the GTFS zip file
+    let path = format!("{}/{}", gtfs_unzipped_path, feed_id);
+
+    let file_hash = this_download_data.hash.map(|hash| format!("{}", hash));
+
+    // parsing, validating, hashing and reducing a feed are long synchronous work, they run on the
+    // blocking pool so the runtime can keep serving the lease and other feeds meanwhile
+    let raw_gtfs = match tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || gtfs_structures::RawGtfs::new(path.as_str())
+    })
+    .await?
+    {
+        Ok(raw_gtfs) => raw_gtfs,
+        Err(e) => {
+            let report = ValidationReport::from_findings(vec![ValidationFinding {
+                severity: Severity::Error,
+                code: "unreadable_feed",
+                file: "",
+                row_number: None,
+                entity_id: None,
+                message: format!("Could not read the feed: {}", e),
+            }]);
+
+            if let Err(save_err) = validation_findings_into_postgres(
+                &report,
+                feed_id,
+                Arc::clone(&arc_conn_pool),
+                chateau_id,
+                attempt_id,
+                file_hash,
+            )
+            .await
+            {
+                eprintln!(
+                    "Could not save validation findings for {}: {}",
+                    feed_id, save_err
+                );
+            }
+
+            return Err(Box::new(e));
+        }
+    };
+
+    let (raw_gtfs, validation_report) = tokio::task::spawn_blocking(move || {
+        let validation_report = validate_raw_gtfs(&raw_gtfs);
+        (raw_gtfs, validation_report)
+    })
+    .await?;
+
+    println!(
+        "Validated {}, {} findings",
+        feed_id,
+        validation_report
+            .counts
+            .iter()
+            .map(|count| count.count)
+            .sum::<usize>()
+    );
+
+    // saved before converting so feeds that fail below still have a report
+    if let Err(save_err) = validation_findings_into_postgres(
+        &validation_report,
+        feed_id,
+        Arc::clone(&arc_conn_pool),
+        chateau_id,
+        attempt_id,
+        file_hash,
+    )
+    .await
+    {
+        eprintln!(
+            "Could not save validation findings for {}: {}",
+            feed_id, save_err
+        );
+    }
+
+    let gtfs = tokio::task::spawn_blocking({
+        let feed_id = feed_id.to_string();
+        move || convert_and_filter(&feed_id, raw_gtfs)
+    })
+    .await??;
+
     println!(
         "Finished reading GTFS for {}, took {:?}",
         feed_id, gtfs.read_duration
@@ -165,20 +257,66 @@ pub async fn gtfs_process_feed(
         },
     };
 
-    let start_reduction_timer = Instant::now();
-    let reduction = maple_syrup::reduce(&gtfs);
-    println!(
-        "Reduced schedule for {} in {:?}",
-        feed_id,
-        start_reduction_timer.elapsed()
-    );
+    let file_hashes = match tokio::task::spawn_blocking({
+        let path = path.clone();
+        move || hash_feed_files(path.as_str())
+    })
+    .await?
+    {
+        Ok(file_hashes) => Some(file_hashes),
+        Err(e) => {
+            eprintln!("Could not hash files of {}: {}", feed_id, e);
+            None
+        }
+    };
+
+    let previous_attempt = match &file_hashes {
+        Some(_) => match find_previous_attempt(feed_id, Arc::clone(&arc_conn_pool)).await {
+            Ok(previous_attempt) => previous_attempt,
+            Err(e) => {
+                eprintln!("Could not look up previous attempt of {}: {}", feed_id, e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let reuse_plan = match (&file_hashes, &previous_attempt) {
+        (Some(file_hashes), Some(previous_attempt)) => {
+            plan_reuse(&previous_attempt.file_hashes, file_hashes)
+        }
+        _ => ReusePlan::default(),
+    };
+
     println!(
-        "{} itineraries, {} trips, {:.2} ratio",
-        reduction.itineraries.len(),
-        reduction.trips_to_itineraries.len(),
-        reduction.trips_to_itineraries.len() as f64 / reduction.itineraries.len() as f64
+        "Reusing from previous attempt for {}: {:?}",
+        feed_id, reuse_plan
     );
 
+    let (gtfs, reduction) = match (reuse_plan.patterns, &previous_attempt) {
+        (true, Some(_)) => (gtfs, None),
+        _ => {
+            let start_reduction_timer = Instant::now();
+            let (gtfs, reduction) = tokio::task::spawn_blocking(move || {
+                let reduction = maple_syrup::reduce(&gtfs);
+                (gtfs, reduction)
+            })
+            .await?;
+            println!(
+                "Reduced schedule for {} in {:?}",
+                feed_id,
+                start_reduction_timer.elapsed()
+            );
+            println!(
+                "{} itineraries, {} trips, {:.2} ratio",
+                reduction.itineraries.len(),
+                reduction.trips_to_itineraries.len(),
+                reduction.trips_to_itineraries.len() as f64 / reduction.itineraries.len() as f64
+            );
+            (gtfs, Some(reduction))
+        }
+    };
+
     let feed_info: Option<FeedInfo> = match !gtfs.feed_info.is_empty() {
         true => Some(gtfs.feed_info[0].clone()),
         false => None,
@@ -205,23 +343,6 @@ pub async fn gtfs_process_feed(
         }
     }
 
-    println!(
-        "Making stop to route type and route id hashmaps for {}",
-        feed_id
-    );
-    let timer_stop_id_table = Instant::now();
-    let (stop_ids_to_route_types, stop_ids_to_route_ids) =
-        make_hashmap_stops_to_route_types_and_ids(&gtfs);
-
-    let (stop_id_to_children_ids, stop_ids_to_children_route_types) =
-        make_hashmaps_of_children_stop_info(&gtfs, &stop_ids_to_route_types);
-
-    println!(
-        "Finished making stop to route type and route id hashmaps in {:?} for {}",
-        timer_stop_id_table.elapsed(),
-        feed_id
-    );
-
     //identify colours of shapes based on trip id's route id
     // also make reverse lookup for route ids to shape ids
     let ShapeToColourResponse {
@@ -273,17 +394,36 @@ pub async fn gtfs_process_feed(
 
     //shove raw geometry into postgresql
 
-    shapes_into_postgres(
-        &gtfs,
-        &shape_to_color_lookup,
-        &shape_to_text_color_lookup,
-        feed_id,
-        Arc::clone(&arc_conn_pool),
-        chateau_id,
-        attempt_id,
-        &shape_id_to_route_ids_lookup,
-    )
-    .await?;
+    match (reuse_plan.shapes, &previous_attempt) {
+        (true, Some(previous_attempt)) => {
+            let copied = copy_shapes(
+                feed_id,
+                chateau_id,
+                &previous_attempt.attempt_id,
+                attempt_id,
+                Arc::clone(&arc_conn_pool),
+            )
+            .await?;
+
+            println!(
+                "Copied {} shapes from {} for {}",
+                copied, previous_attempt.attempt_id, feed_id
+            );
+        }
+        _ => {
+            shapes_into_postgres(
+                &gtfs,
+                &shape_to_color_lookup,
+                &shape_to_text_color_lookup,
+                feed_id,
+                Arc::clone(&arc_conn_pool),
+                chateau_id,
+                attempt_id,
+                &shape_id_to_route_ids_lookup,
+            )
+            .await?;
+        }
+    }
 
     println!("Shapes inserted for {}", feed_id);
 
@@ -304,243 +444,309 @@ pub async fn gtfs_process_feed(
     println!("Inserting stops for {}", feed_id);
 
     //insert stops
-    stops_into_postgres(
-        &gtfs,
-        feed_id,
-        Arc::clone(&arc_conn_pool),
-        chateau_id,
-        attempt_id,
-        &stop_ids_to_route_types,
-        &stop_ids_to_route_ids,
-        &stop_id_to_children_ids,
-        &stop_ids_to_children_route_types,
-    )
-    .await?;
-
-    println!("Stops inserted for {}", feed_id);
-
-    // insert trip and itineraries
+    match (reuse_plan.stops, &previous_attempt) {
+        (true, Some(previous_attempt)) => {
+            let copied = copy_stops(
+                feed_id,
+                chateau_id,
+                &previous_attempt.attempt_id,
+                attempt_id,
+                Arc::clone(&arc_conn_pool),
+            )
+            .await?;
 
-    println!("Inserting directions for {}", feed_id);
+            println!(
+                "Copied {} stops from {} for {}",
+                copied, previous_attempt.attempt_id, feed_id
+            );
+        }
+        _ => {
+            println!(
+                "Making stop to route type and route id hashmaps for {}",
+                feed_id
+            );
+            let timer_stop_id_table = Instant::now();
+            let (stop_ids_to_route_types, stop_ids_to_route_ids) =
+                make_hashmap_stops_to_route_types_and_ids(&gtfs);
 
-    for (direction_pattern_id, direction_pattern) in &reduction.direction_patterns {
-        let gtfs_shape_id = match &direction_pattern.gtfs_shape_id {
-            Some(gtfs_shape_id) => gtfs_shape_id.clone(),
-            None => direction_pattern_id.to_string(),
-        };
+            let (stop_id_to_children_ids, stop_ids_to_children_route_types) =
+                make_hashmaps_of_children_stop_info(&gtfs, &stop_ids_to_route_types);
 
-        let first_itin_id = reduction
-            .direction_pattern_id_to_itineraries
-            .get(direction_pattern_id)
-            .unwrap()
-            .iter()
-            .next()
-            .expect("Expected Itin for direction id");
+            println!(
+                "Finished making stop to route type and route id hashmaps in {:?} for {}",
+                timer_stop_id_table.elapsed(),
+                feed_id
+            );
 
-        let itin_pattern = reduction
-            .itineraries
-            .get(first_itin_id)
-            .expect("Did not find itin pattern, crashing....");
+            stops_into_postgres(
+                &gtfs,
+                feed_id,
+                Arc::clone(&arc_conn_pool),
+                chateau_id,
+                attempt_id,
+                &stop_ids_to_route_types,
+                &stop_ids_to_route_ids,
+                &stop_id_to_children_ids,
+                &stop_ids_to_children_route_types,
+            )
+            .await?;
+        }
+    }
 
-        if direction_pattern.gtfs_shape_id.is_none() {
-            //: postgis_diesel::types::LineString<postgis_diesel::types::Point>
+    println!("Stops inserted for {}", feed_id);
 
-            let stop_points = direction_pattern
-                .stop_sequence
-                .iter()
-                .filter_map(|stop_id| gtfs.stops.get(stop_id.as_str()))
-                .filter_map(|stop| match (stop.latitude, stop.longitude) {
-                    (Some(latitude), Some(longitude)) => Some(postgis_diesel::types::Point {
-                        y: latitude,
-                        x: longitude,
-                        srid: Some(4326),
-                    }),
-                    _ => None,
-                })
-                .collect::<Vec<postgis_diesel::types::Point>>();
-
-            if stop_points.len() > 2 {
-                let linestring: postgis_diesel::types::LineString<postgis_diesel::types::Point> =
-                    postgis_diesel::types::LineString {
-                        points: stop_points,
-                        srid: Some(4326),
-                    };
-
-                //insert into shapes and shapes_not_bus
-
-                let route = gtfs.routes.get(direction_pattern.route_id.as_str());
-
-                if let Some(route) = route {
-                    let _ = insert_stop_to_stop_geometry(
-                        feed_id,
-                        attempt_id,
-                        chateau_id,
-                        route,
-                        *direction_pattern_id,
-                        &linestring,
-                        Arc::clone(&arc_conn_pool),
-                    )
-                    .await;
-                }
-            }
-        }
+    // insert trip and itineraries
 
-        let direction_pattern_meta = DirectionPatternMeta {
-            chateau: chateau_id.to_string(),
-            direction_pattern_id: direction_pattern_id.to_string(),
-            headsign_or_destination: direction_pattern
-                .headsign_or_destination
-                .clone()
-                .unwrap_or_else(|| "".to_string()),
-            gtfs_shape_id: Some(gtfs_shape_id.clone()),
-            fake_shape: direction_pattern.gtfs_shape_id.is_none(),
-            onestop_feed_id: feed_id.to_string(),
-            attempt_id: attempt_id.to_string(),
-            route_id: Some(itin_pattern.route_id.clone()),
-            route_type: Some(itin_pattern.route_type),
-            direction_id: itin_pattern.direction_id,
-        };
+    match (&reduction, &previous_attempt) {
+        (Some(reduction), _) => {
+            println!("Inserting directions for {}", feed_id);
 
-        //insert stop list into DirectionPatternRow
+            for (direction_pattern_id, direction_pattern) in &reduction.direction_patterns {
+                let gtfs_shape_id = match &direction_pattern.gtfs_shape_id {
+                    Some(gtfs_shape_id) => gtfs_shape_id.clone(),
+                    None => direction_pattern_id.to_string(),
+                };
 
-        diesel::insert_into(
-            catenary::schema::gtfs::direction_pattern_meta::dsl::direction_pattern_meta,
-        )
-        .values(direction_pattern_meta)
-        .execute(conn)
-        .await?;
+                let first_itin_id = reduction
+                    .direction_pattern_id_to_itineraries
+                    .get(direction_pattern_id)
+                    .unwrap()
+                    .iter()
+                    .next()
+                    .expect("Expected Itin for direction id");
 
-        let direction_pattern_rows: Vec<DirectionPatternRow> = itin_pattern
-            .stop_sequences
-            .iter()
-            .enumerate()
-            .map(|(stop_idx, stop_time)| DirectionPatternRow {
-                attempt_id: attempt_id.to_string(),
-                chateau: chateau_id.to_string(),
-                direction_pattern_id: direction_pattern_id.to_string(),
-                stop_id: stop_time.stop_id.clone(),
-                stop_sequence: stop_idx as u32,
-                onestop_feed_id: feed_id.to_string(),
-                arrival_time_since_start: stop_time.arrival_time_since_start,
-                departure_time_since_start: stop_time.departure_time_since_start,
-                interpolated_time_since_start: stop_time.interpolated_time_since_start,
-            })
-            .collect();
+                let itin_pattern = reduction
+                    .itineraries
+                    .get(first_itin_id)
+                    .expect("Did not find itin pattern, crashing....");
+
+                if direction_pattern.gtfs_shape_id.is_none() {
+                    //: postgis_diesel::types::LineString<postgis_diesel::types::Point>
+
+                    let stop_points = direction_pattern
+                        .stop_sequence
+                        .iter()
+                        .filter_map(|stop_id| gtfs.stops.get(stop_id.as_str()))
+                        .filter_map(|stop| match (stop.latitude, stop.longitude) {
+                            (Some(latitude), Some(longitude)) => {
+                                Some(postgis_diesel::types::Point {
+                                    y: latitude,
+                                    x: longitude,
+                                    srid: Some(4326),
+                                })
+                            }
+                            _ => None,
+                        })
+                        .collect::<Vec<postgis_diesel::types::Point>>();
+
+                    if stop_points.len() > 2 {
+                        let linestring: postgis_diesel::types::LineString<
+                            postgis_diesel::types::Point,
+                        > = postgis_diesel::types::LineString {
+                            points: stop_points,
+                            srid: Some(4326),
+                        };
+
+                        //insert into shapes and shapes_not_bus
+
+                        let route = gtfs.routes.get(direction_pattern.route_id.as_str());
+
+                        if let Some(route) = route {
+                            let _ = insert_stop_to_stop_geometry(
+                                feed_id,
+                                attempt_id,
+                                chateau_id,
+                                route,
+                                *direction_pattern_id,
+                                &linestring,
+                                Arc::clone(&arc_conn_pool),
+                            )
+                            .await;
+                        }
+                    }
+                }
 
-        for dir_chunk in direction_pattern_rows.chunks(50) {
-            diesel::insert_into(catenary::schema::gtfs::direction_pattern::dsl::direction_pattern)
-                .values(dir_chunk)
+                let direction_pattern_meta = DirectionPatternMeta {
+                    chateau: chateau_id.to_string(),
+                    direction_pattern_id: direction_pattern_id.to_string(),
+                    headsign_or_destination: direction_pattern
+                        .headsign_or_destination
+                        .clone()
+                        .unwrap_or_else(|| "".to_string()),
+                    gtfs_shape_id: Some(gtfs_shape_id.clone()),
+                    fake_shape: direction_pattern.gtfs_shape_id.is_none(),
+                    onestop_feed_id: feed_id.to_string(),
+                    attempt_id: attempt_id.to_string(),
+                    route_id: Some(itin_pattern.route_id.clone()),
+                    route_type: Some(itin_pattern.route_type),
+                    direction_id: itin_pattern.direction_id,
+                };
+
+                //insert stop list into DirectionPatternRow
+
+                diesel::insert_into(
+                    catenary::schema::gtfs::direction_pattern_meta::dsl::direction_pattern_meta,
+                )
+                .values(direction_pattern_meta)
                 .execute(conn)
                 .await?;
-        }
-    }
-
-    println!("Directions inserted for {}", feed_id);
-    println!("Inserting itineraries for {}", feed_id);
-
-    for (itinerary_id, itinerary) in &reduction.itineraries {
-        let itinerary_pg_meta = ItineraryPatternMeta {
-            onestop_feed_id: feed_id.to_string(),
-            chateau: chateau_id.to_string(),
-            attempt_id: attempt_id.to_string(),
-            timezone: itinerary.timezone.clone(),
-            trip_headsign: itinerary
-                .trip_headsign
-                .clone()
-                .map(|x| x.replace(" - Funded in part by/SB County Measure A", "")),
-            trip_headsign_translations: None,
-            itinerary_pattern_id: itinerary_id.to_string(),
-            trip_ids: reduction
-                .itineraries_to_trips
-                .get(itinerary_id)
-                .as_ref()
-                .unwrap()
-                .iter()
-                .map(|trip_under_itin| Some(trip_under_itin.trip_id.to_string()))
-                .collect::<Vec<Option<String>>>(),
-            shape_id: itinerary.shape_id.clone(),
-            route_id: itinerary.route_id.clone(),
-            direction_pattern_id: Some(itinerary.direction_pattern_id.to_string()),
-        };
-
-        diesel::insert_into(
-            catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta,
-        )
-        .values(itinerary_pg_meta)
-        .execute(conn)
-        .await?;
 
-        let itinerary_pg = itinerary
-            .stop_sequences
-            .iter()
-            .enumerate()
-            .map(|(stop_index, stop_sequence)| ItineraryPatternRow {
-                onestop_feed_id: feed_id.to_string(),
-                chateau: chateau_id.to_string(),
-                attempt_id: attempt_id.to_string(),
-                itinerary_pattern_id: itinerary_id.to_string(),
-                stop_sequence: stop_index as i32,
-                stop_id: stop_sequence.stop_id.clone(),
-                gtfs_stop_sequence: stop_sequence.gtfs_stop_sequence as u32,
-                arrival_time_since_start: stop_sequence.arrival_time_since_start,
-                departure_time_since_start: stop_sequence.departure_time_since_start,
-                interpolated_time_since_start: stop_sequence.interpolated_time_since_start,
-            })
-            .collect::<Vec<_>>();
+                let direction_pattern_rows: Vec<DirectionPatternRow> = itin_pattern
+                    .stop_sequences
+                    .iter()
+                    .enumerate()
+                    .map(|(stop_idx, stop_time)| DirectionPatternRow {
+                        attempt_id: attempt_id.to_string(),
+                        chateau: chateau_id.to_string(),
+                        direction_pattern_id: direction_pattern_id.to_string(),
+                        stop_id: stop_time.stop_id.clone(),
+                        stop_sequence: stop_idx as u32,
+                        onestop_feed_id: feed_id.to_string(),
+                        arrival_time_since_start: stop_time.arrival_time_since_start,
+                        departure_time_since_start: stop_time.departure_time_since_start,
+                        interpolated_time_since_start: stop_time.interpolated_time_since_start,
+                    })
+                    .collect();
+
+                for dir_chunk in direction_pattern_rows.chunks(50) {
+                    diesel::insert_into(
+                        catenary::schema::gtfs::direction_pattern::dsl::direction_pattern,
+                    )
+                    .values(dir_chunk)
+                    .execute(conn)
+                    .await?;
+                }
+            }
 
-        for itinerary_chunk in itinerary_pg.chunks(50) {
-            diesel::insert_into(catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern)
-                .values(itinerary_chunk)
+            println!("Directions inserted for {}", feed_id);
+            println!("Inserting itineraries for {}", feed_id);
+
+            for (itinerary_id, itinerary) in &reduction.itineraries {
+                let itinerary_pg_meta = ItineraryPatternMeta {
+                    onestop_feed_id: feed_id.to_string(),
+                    chateau: chateau_id.to_string(),
+                    attempt_id: attempt_id.to_string(),
+                    timezone: itinerary.timezone.clone(),
+                    trip_headsign: itinerary
+                        .trip_headsign
+                        .clone()
+                        .map(|x| x.replace(" - Funded in part by/SB County Measure A", "")),
+                    trip_headsign_translations: None,
+                    itinerary_pattern_id: itinerary_id.to_string(),
+                    trip_ids: reduction
+                        .itineraries_to_trips
+                        .get(itinerary_id)
+                        .as_ref()
+                        .unwrap()
+                        .iter()
+                        .map(|trip_under_itin| Some(trip_under_itin.trip_id.to_string()))
+                        .collect::<Vec<Option<String>>>(),
+                    shape_id: itinerary.shape_id.clone(),
+                    route_id: itinerary.route_id.clone(),
+                    direction_pattern_id: Some(itinerary.direction_pattern_id.to_string()),
+                };
+
+                diesel::insert_into(
+                    catenary::schema::gtfs::itinerary_pattern_meta::dsl::itinerary_pattern_meta,
+                )
+                .values(itinerary_pg_meta)
                 .execute(conn)
                 .await?;
-        }
-    }
-
-    println!("Itineraries inserted for {}", feed_id);
 
-    //insert trips
-
-    println!("Inserting trips for {}", feed_id);
+                let itinerary_pg = itinerary
+                    .stop_sequences
+                    .iter()
+                    .enumerate()
+                    .map(|(stop_index, stop_sequence)| ItineraryPatternRow {
+                        onestop_feed_id: feed_id.to_string(),
+                        chateau: chateau_id.to_string(),
+                        attempt_id: attempt_id.to_string(),
+                        itinerary_pattern_id: itinerary_id.to_string(),
+                        stop_sequence: stop_index as i32,
+                        stop_id: stop_sequence.stop_id.clone(),
+                        gtfs_stop_sequence: stop_sequence.gtfs_stop_sequence as u32,
+                        arrival_time_since_start: stop_sequence.arrival_time_since_start,
+                        departure_time_since_start: stop_sequence.departure_time_since_start,
+                        interpolated_time_since_start: stop_sequence.interpolated_time_since_start,
+                    })
+                    .collect::<Vec<_>>();
+
+                for itinerary_chunk in itinerary_pg.chunks(50) {
+                    diesel::insert_into(
+                        catenary::schema::gtfs::itinerary_pattern::dsl::itinerary_pattern,
+                    )
+                    .values(itinerary_chunk)
+                    .execute(conn)
+                    .await?;
+                }
+            }
 
-    for (itinerary_id, compressed_trip_list) in &reduction.itineraries_to_trips {
-        let trip_pg = compressed_trip_list
-            .iter()
-            .map(|compressed_trip_raw| catenary::models::CompressedTrip {
-                onestop_feed_id: feed_id.to_string(),
-                chateau: chateau_id.to_string(),
-                attempt_id: attempt_id.to_string(),
-                itinerary_pattern_id: itinerary_id.to_string(),
-                trip_id: compressed_trip_raw.trip_id.to_string(),
-                service_id: compressed_trip_raw.service_id.clone(),
-                direction_id: reduction
-                    .itineraries
-                    .get(itinerary_id)
-                    .unwrap()
-                    .direction_id,
-                start_time: compressed_trip_raw.start_time,
-                trip_short_name: compressed_trip_raw.trip_short_name.clone(),
-                block_id: compressed_trip_raw.block_id.clone(),
-                wheelchair_accessible: compressed_trip_raw.wheelchair_accessible,
-                bikes_allowed: compressed_trip_raw.bikes_allowed,
-                has_frequencies: !compressed_trip_raw.frequencies.is_empty(),
-                route_id: route_id_transform(feed_id, compressed_trip_raw.route_id.clone()),
-                frequencies: match !compressed_trip_raw.frequencies.is_empty() {
-                    true => {
-                        let prost_message =
-                            frequencies_to_protobuf(&compressed_trip_raw.frequencies);
-                        Some(prost_message.encode_to_vec())
-                    }
-                    false => None,
-                },
-            })
-            .collect::<Vec<_>>();
+            println!("Itineraries inserted for {}", feed_id);
+
+            //insert trips
+
+            println!("Inserting trips for {}", feed_id);
+
+            for (itinerary_id, compressed_trip_list) in &reduction.itineraries_to_trips {
+                let trip_pg = compressed_trip_list
+                    .iter()
+                    .map(|compressed_trip_raw| catenary::models::CompressedTrip {
+                        onestop_feed_id: feed_id.to_string(),
+                        chateau: chateau_id.to_string(),
+                        attempt_id: attempt_id.to_string(),
+                        itinerary_pattern_id: itinerary_id.to_string(),
+                        trip_id: compressed_trip_raw.trip_id.to_string(),
+                        service_id: compressed_trip_raw.service_id.clone(),
+                        direction_id: reduction
+                            .itineraries
+                            .get(itinerary_id)
+                            .unwrap()
+                            .direction_id,
+                        start_time: compressed_trip_raw.start_time,
+                        trip_short_name: compressed_trip_raw.trip_short_name.clone(),
+                        block_id: compressed_trip_raw.block_id.clone(),
+                        wheelchair_accessible: compressed_trip_raw.wheelchair_accessible,
+                        bikes_allowed: compressed_trip_raw.bikes_allowed,
+                        has_frequencies: !compressed_trip_raw.frequencies.is_empty(),
+                        route_id: route_id_transform(feed_id, compressed_trip_raw.route_id.clone()),
+                        frequencies: match !compressed_trip_raw.frequencies.is_empty() {
+                            true => {
+                                let prost_message =
+                                    frequencies_to_protobuf(&compressed_trip_raw.frequencies);
+                                Some(prost_message.encode_to_vec())
+                            }
+                            false => None,
+                        },
+                    })
+                    .collect::<Vec<_>>();
+
+                for trip_chunk in trip_pg.chunks(50) {
+                    diesel::insert_into(
+                        catenary::schema::gtfs::trips_compressed::dsl::trips_compressed,
+                    )
+                    .values(trip_chunk)
+                    .execute(conn)
+                    .await?;
+                }
+            }
+        }
+        (None, Some(previous_attempt)) => {
+            // the stop to stop shapes were made from these patterns, so they come along
+            let copied = copy_patterns(
+                feed_id,
+                chateau_id,
+                &previous_attempt.attempt_id,
+                attempt_id,
+                Arc::clone(&arc_conn_pool),
+            )
+            .await?;
 
-        for trip_chunk in trip_pg.chunks(50) {
-            diesel::insert_into(catenary::schema::gtfs::trips_compressed::dsl::trips_compressed)
-                .values(trip_chunk)
-                .execute(conn)
-                .await?;
+            println!(
+                "Copied {} generated shape, direction, itinerary and trip rows from {} for {}",
+                copied, previous_attempt.attempt_id, feed_id
+            );
         }
+        (None, None) => unreachable!("patterns are only skipped when a previous attempt exists"),
     }
 
     //insert routes
@@ -679,6 +885,15 @@ pub async fn gtfs_process_feed(
         .execute(conn)
         .await?;
 
+    if let Some(file_hashes) = &file_hashes {
+        if let Err(e) =
+            file_hashes_into_postgres(feed_id, attempt_id, file_hashes, Arc::clone(&arc_conn_pool))
+                .await
+        {
+            eprintln!("Could not save file hashes for {}: {}", feed_id, e);
+        }
+    }
+
     let ingest_duration = start.elapsed();
     println!(
         "Finished {}, took {:.3}s",
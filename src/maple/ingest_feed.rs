@@ -0,0 +1,197 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Runs one feed through gtfs_process_feed and records how it went. Used by the local thread pool
+// and by distributed workers, so putting the attempt into production is left to the caller.
+
+use crate::cleanup::delete_attempt_objects;
+use crate::cleanup::wipe_whole_feed;
+use crate::gtfs_handlers::MAPLE_INGESTION_VERSION;
+use crate::gtfs_process::gtfs_process_feed;
+use crate::transitland_download::DownloadedFeedsInformation;
+use catenary::postgres_tools::CatenaryPostgresPool;
+use catenary::schema::gtfs::in_progress_static_ingests;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Attempts of this feed that were started but never finished or cleaned up
+pub async fn unfinished_attempts(
+    feed_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    let attempt_ids = in_progress_static_ingests::dsl::in_progress_static_ingests
+        .filter(in_progress_static_ingests::dsl::onestop_feed_id.eq(feed_id))
+        .select(in_progress_static_ingests::dsl::attempt_id)
+        .load::<String>(conn)
+        .await?;
+
+    Ok(attempt_ids)
+}
+
+pub async fn clear_in_progress(
+    feed_id: &str,
+    attempt_id: &str,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    diesel::delete(
+        in_progress_static_ingests::dsl::in_progress_static_ingests
+            .filter(in_progress_static_ingests::dsl::onestop_feed_id.eq(feed_id))
+            .filter(in_progress_static_ingests::dsl::attempt_id.eq(attempt_id)),
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Returns true once the attempt is saved in ingested_static and can be assigned to production
+pub async fn ingest_feed(
+    gtfs_uncompressed_temp_storage: &str,
+    feed_id: &str,
+    attempt_id: &str,
+    chateau_id: &str,
+    this_download_data: &DownloadedFeedsInformation,
+    delete_everything_in_feed_before_ingest: bool,
+    arc_conn_pool: Arc<CatenaryPostgresPool>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let conn_pool = arc_conn_pool.as_ref();
+    let conn_pre = conn_pool.get().await;
+    let conn = &mut conn_pre?;
+
+    if delete_everything_in_feed_before_ingest {
+        let wipe_whole_feed_result = wipe_whole_feed(feed_id, Arc::clone(&arc_conn_pool)).await;
+
+        if wipe_whole_feed_result.is_ok() {
+            println!("Wiped whole feed of {} prior to ingestion", feed_id);
+        } else {
+            eprintln!(
+                "Failed to wipe whole feed of {} prior to ingestion",
+                feed_id
+            );
+        }
+    }
+
+    let start_time = chrono::Utc::now().timestamp_millis();
+
+    // lets a later run find and clean up this attempt if the process dies halfway
+    diesel::insert_into(in_progress_static_ingests::dsl::in_progress_static_ingests)
+        .values(catenary::models::InProgressStaticIngest {
+            onestop_feed_id: feed_id.to_string(),
+            file_hash: this_download_data
+                .hash
+                .map(|hash| format!("{}", hash))
+                .unwrap_or_default(),
+            attempt_id: attempt_id.to_string(),
+            ingest_start_unix_time_ms: start_time,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)
+        .await?;
+
+    // call function to process GTFS feed, accepting feed_id, diesel pool args, chateau_id, attempt_id
+    let gtfs_process_result = gtfs_process_feed(
+        gtfs_uncompressed_temp_storage,
+        feed_id,
+        Arc::clone(&arc_conn_pool),
+        chateau_id,
+        attempt_id,
+        this_download_data,
+    )
+    .await;
+
+    use catenary::schema::gtfs::static_download_attempts::dsl as static_download_attempts_columns;
+    use catenary::schema::gtfs::static_download_attempts::dsl::static_download_attempts;
+
+    match gtfs_process_result {
+        Ok(gtfs_summary) => {
+            // at the end, UPDATE gtfs.static_download_attempts where onstop_feed_id and download_unix_time_ms match as ingested
+            let _ = diesel::update(static_download_attempts)
+                .filter(static_download_attempts_columns::onestop_feed_id.eq(feed_id))
+                .filter(
+                    static_download_attempts_columns::downloaded_unix_time_ms
+                        .eq(this_download_data.download_timestamp_ms as i64),
+                )
+                .set(static_download_attempts_columns::ingested.eq(true))
+                .execute(conn)
+                .await;
+
+            use catenary::schema::gtfs::ingested_static::dsl::ingested_static;
+
+            let ingested_static_pq = catenary::models::IngestedStatic {
+                onestop_feed_id: feed_id.to_string(),
+                attempt_id: attempt_id.to_string(),
+                languages_avaliable: vec![],
+                file_hash: format!("{}", this_download_data.hash.unwrap()),
+                ingest_start_unix_time_ms: start_time,
+                ingest_end_unix_time_ms: chrono::Utc::now().timestamp_millis(),
+                ingest_duration_ms: (chrono::Utc::now().timestamp_millis() - start_time) as i32,
+                ingesting_in_progress: false,
+                ingestion_errored: false,
+                ingestion_successfully_finished: true,
+                deleted: false,
+                default_lang: gtfs_summary.default_lang,
+                production: false,
+                feed_expiration_date: None,
+                feed_start_date: None,
+                ingestion_version: MAPLE_INGESTION_VERSION,
+            };
+
+            let ingested_static_result = diesel::insert_into(ingested_static)
+                .values(&ingested_static_pq)
+                .on_conflict_do_nothing()
+                .execute(conn)
+                .await;
+
+            if ingested_static_result.is_err() {
+                return Ok(false);
+            }
+
+            clear_in_progress(feed_id, attempt_id, Arc::clone(&arc_conn_pool)).await?;
+
+            Ok(true)
+        }
+        Err(gtfs_process_err) => {
+            //print output
+            eprintln!(
+                "GTFS process failed for feed {},\n {:?}",
+                feed_id, gtfs_process_err
+            );
+
+            //UPDATE gtfs.static_download_attempts where onstop_feed_id and download_unix_time_ms match as failure
+            let update_as_failed = diesel::update(
+                static_download_attempts
+                    .filter(static_download_attempts_columns::onestop_feed_id.eq(feed_id))
+                    .filter(
+                        static_download_attempts_columns::downloaded_unix_time_ms
+                            .eq(this_download_data.download_timestamp_ms as i64),
+                    ),
+            )
+            .set(static_download_attempts_columns::failed.eq(true))
+            .execute(conn)
+            .await;
+
+            if update_as_failed.is_ok() {
+                //Delete objects from the attempt
+                let delete_attempt =
+                    delete_attempt_objects(feed_id, attempt_id, Arc::clone(&arc_conn_pool)).await;
+
+                if delete_attempt.is_ok() {
+                    clear_in_progress(feed_id, attempt_id, Arc::clone(&arc_conn_pool)).await?;
+                }
+            }
+
+            Ok(false)
+        }
+    }
+}
@@ -44,21 +44,21 @@ use std::fs;
 mod delete_overlapping_feeds_dmfr;
 use std::sync::Arc;
 
-use crate::cleanup::delete_attempt_objects;
 use crate::cleanup::wipe_whole_feed;
+use catenary::coordination::Coordinator;
 
 mod assign_production_tables;
 mod chateau_postprocess;
 mod cleanup;
+mod distributed;
 mod gtfs_handlers;
 mod gtfs_ingestion_sequence;
 mod gtfs_process;
+mod ingest_feed;
 mod refresh_metadata_tables;
 mod transitland_download;
 mod update_schedules_with_new_chateau_id;
 
-use gtfs_process::gtfs_process_feed;
-
 use chateau::chateau;
 use dmfr_dataset_reader::read_folders;
 
@@ -124,6 +124,12 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
         println!("Each feed will be wiped before ingestion");
     }
 
+    // hand the feeds to maple workers instead of the local thread pool
+    let distributed_coordinator = match std::env::var("MAPLE_DISTRIBUTED").as_deref() {
+        Ok("true") => Some(Coordinator::from_env().await?),
+        _ => None,
+    };
+
     //Ensure git submodule transitland-atlas downloads and updates correctly, if not, pass the error
     update_transitland_submodule()?;
     let feeds_to_discard: HashSet<String> = HashSet::from_iter(
@@ -178,6 +184,10 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
     let conn_pool: CatenaryPostgresPool = make_async_pool().await?;
     let arc_conn_pool: Arc<CatenaryPostgresPool> = Arc::new(conn_pool);
 
+    tokio::spawn(catenary::metrics::keep_pool_metrics_fresh(Arc::clone(
+        &arc_conn_pool,
+    )));
+
     let conn_pool = arc_conn_pool.as_ref();
     let conn_pre = conn_pool.get().await;
     let conn = &mut conn_pre?;
@@ -269,8 +279,7 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
                 .filter(|download_feed_info| download_feed_info.ingest)
                 .collect::<Vec<&DownloadedFeedsInformation>>();
 
-            // for now, use a thread pool
-            // in the future, map reduce this job out to worker servers
+            // a local thread pool, or maple workers when MAPLE_DISTRIBUTED=true
 
             // Process looks like this
             // Unzip the file and handle any folder nesting
@@ -460,156 +469,94 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
 
             let total_feeds_to_process = feeds_to_process.len() as u16;
 
-            futures::stream::iter(
-                feeds_to_process
-                .into_iter()
-                .map(|(feed_id, attempt_id, chateau_id)| {
-                            let gtfs_uncompressed_temp_storage = gtfs_uncompressed_temp_storage.clone();
+            match &distributed_coordinator {
+                Some(coordinator) => {
+                    // workers make their own attempt ids, so retries do not collide
+                    let jobs = feeds_to_process
+                        .into_iter()
+                        .map(|(feed_id, _, chateau_id)| distributed::MapleJob {
+                            download_data: download_feed_info_hashmap
+                                .get(&feed_id)
+                                .unwrap()
+                                .clone(),
+                            feed_id,
+                            chateau_id,
+                            delete_everything_in_feed_before_ingest,
+                            attempts: 0,
+                        })
+                        .collect::<Vec<distributed::MapleJob>>();
+
+                    // a timed out run still goes on to the cleanup and refreshes below
+                    if let Err(e) = distributed::run_coordinator_jobs(
+                        coordinator,
+                        jobs,
+                        Arc::clone(&arc_conn_pool),
+                    )
+                    .await
+                    {
+                        eprintln!("Distributed ingest did not finish: {}", e);
+                    }
+                }
+                None => {
+                    futures::stream::iter(feeds_to_process.into_iter().map(
+                        |(feed_id, attempt_id, chateau_id)| {
+                            let gtfs_uncompressed_temp_storage =
+                                gtfs_uncompressed_temp_storage.clone();
                             //clone the smart reference to the connection pool
                             let arc_conn_pool = Arc::clone(&arc_conn_pool);
-                            let download_feed_info_hashmap = Arc::clone(&download_feed_info_hashmap);
+                            let download_feed_info_hashmap =
+                                Arc::clone(&download_feed_info_hashmap);
                             let ingest_progress = Arc::clone(&ingest_progress);
                             async move {
-                                //connect to postgres
-                                let conn_pool = arc_conn_pool.as_ref();
-                                let conn_pre = conn_pool.get().await;
-                                let conn = &mut conn_pre.unwrap();
-        
-                                let this_download_data = download_feed_info_hashmap.get(&feed_id).unwrap();
-                                
-                                    if delete_everything_in_feed_before_ingest {
-                                        let wipe_whole_feed_result = wipe_whole_feed(
-                                            &feed_id,
-                                            Arc::clone(&arc_conn_pool)
-                                        ).await;
-
-                                        if wipe_whole_feed_result.is_ok() {
-                                            println!("Wiped whole feed of {} prior to ingestion", feed_id);
-                                        } else {
-                                            eprintln!("Failed to wipe whole feed of {} prior to ingestion", feed_id);
-                                        }
-                                    }
-
-                                    let start_time = chrono::Utc::now().timestamp_millis();
-                                
-                                    // call function to process GTFS feed, accepting feed_id, diesel pool args, chateau_id, attempt_id
-                                    let gtfs_process_result = gtfs_process_feed(
-                                        &gtfs_uncompressed_temp_storage,
-                                        &feed_id,
-                                        Arc::clone(&arc_conn_pool),
-                                        &chateau_id,
-                                        &attempt_id,
-                                        this_download_data,
-                                    )
-                                    .await;
-
-                                    let mut ingest_progress = ingest_progress.lock().unwrap();
-                                    *ingest_progress += 1;
-            
-                                    println!(
-                                        "Completion progress: {}/{} [{:.2}%]",
-                                        ingest_progress,
-                                        total_feeds_to_process,
-                                        (*ingest_progress as f32/total_feeds_to_process as f32) * 100.0
-                                    );
-
-                                    std::mem::drop(ingest_progress);
-        
-                                    if gtfs_process_result.is_ok() {
-                                        // at the end, UPDATE gtfs.static_download_attempts where onstop_feed_id and download_unix_time_ms match as ingested
-        
-                                        use catenary::schema::gtfs::static_download_attempts::dsl::static_download_attempts;
-        
-                                        let _ = diesel::update(static_download_attempts)
-                                            .filter(catenary::schema::gtfs::static_download_attempts::dsl::onestop_feed_id.eq(&feed_id))
-                                            .filter(catenary::schema::gtfs::static_download_attempts::dsl::downloaded_unix_time_ms.eq(this_download_data.download_timestamp_ms as i64))
-                                            .set(catenary::schema::gtfs::static_download_attempts::dsl::ingested.eq(true))
-                                            .execute(conn)
-                                            .await;
-
-                                        use catenary::schema::gtfs::ingested_static::dsl::ingested_static;
-
-                                        let ingested_static_pq = catenary::models::IngestedStatic {
-                                            onestop_feed_id: feed_id.clone(),
-                                            attempt_id: attempt_id.clone(),
-                                            languages_avaliable: vec![],
-                                            file_hash: format!("{}",download_feed_info_hashmap.get(&feed_id).unwrap().hash.unwrap()),
-                                            ingest_start_unix_time_ms: start_time,
-                                            ingest_end_unix_time_ms: chrono::Utc::now().timestamp_millis(),
-                                            ingest_duration_ms: (chrono::Utc::now().timestamp_millis() - start_time) as i32,
-                                            ingesting_in_progress: false,
-                                            ingestion_errored: false,
-                                            ingestion_successfully_finished: true,
-                                            deleted: false,
-                                            default_lang: gtfs_process_result.unwrap().default_lang,
-                                            production: false,
-                                            feed_expiration_date: None,
-                                            feed_start_date: None,
-                                            ingestion_version: MAPLE_INGESTION_VERSION,
-                                        };
-
-                                        let ingested_static_result = diesel::insert_into(ingested_static)
-                                            .values(&ingested_static_pq)
-                                            .on_conflict_do_nothing()
-                                            .execute(conn)
-                                            .await;
-
-                                        if ingested_static_result.is_ok() {
-                                            
-                                        let assign_prod_tables = assign_production_tables::assign_production_tables(
+                                let this_download_data =
+                                    download_feed_info_hashmap.get(&feed_id).unwrap();
+
+                                let ingest_result = ingest_feed::ingest_feed(
+                                    &gtfs_uncompressed_temp_storage,
+                                    &feed_id,
+                                    &attempt_id,
+                                    &chateau_id,
+                                    this_download_data,
+                                    delete_everything_in_feed_before_ingest,
+                                    Arc::clone(&arc_conn_pool),
+                                )
+                                .await;
+
+                                let mut ingest_progress = ingest_progress.lock().unwrap();
+                                *ingest_progress += 1;
+
+                                println!(
+                                    "Completion progress: {}/{} [{:.2}%]",
+                                    ingest_progress,
+                                    total_feeds_to_process,
+                                    (*ingest_progress as f32 / total_feeds_to_process as f32)
+                                        * 100.0
+                                );
+
+                                std::mem::drop(ingest_progress);
+
+                                match ingest_result {
+                                    Ok(true) => {
+                                        let _ = assign_production_tables::assign_production_tables(
                                             &feed_id,
                                             &attempt_id,
                                             Arc::clone(&arc_conn_pool),
-                                        ).await;
-
-                                    use catenary::schema::gtfs::in_progress_static_ingests::dsl::in_progress_static_ingests;
-
-                                    let _ = diesel::delete(in_progress_static_ingests.filter(catenary::schema::gtfs::in_progress_static_ingests::dsl::onestop_feed_id.eq(&feed_id))
-                                    .filter(catenary::schema::gtfs::in_progress_static_ingests::dsl::attempt_id.eq(&attempt_id)))
-                                .execute(conn).await;
-
-                                        }
-
-                                    } else {
-                                        //print output
-                                        eprintln!("GTFS process failed for feed {},\n {:?}", feed_id, gtfs_process_result.unwrap_err());
-    
-                                        //UPDATE gtfs.static_download_attempts where onstop_feed_id and download_unix_time_ms match as failure
-                                        use catenary::schema::gtfs::static_download_attempts::dsl::static_download_attempts;
-                                        
-                                        let update_as_failed = diesel::update(static_download_attempts
-                                        .filter(catenary::schema::gtfs::static_download_attempts::dsl::onestop_feed_id.eq(&feed_id))
-                                        .filter(catenary::schema::gtfs::static_download_attempts::dsl::downloaded_unix_time_ms.eq(this_download_data.download_timestamp_ms as i64))
-                                        ).set(catenary::schema::gtfs::static_download_attempts::dsl::failed.eq(true))
-                                        .execute(conn)
+                                        )
                                         .await;
-
-                                        if update_as_failed.is_err() {
-
-                                        } else {    
-                                        //Delete objects from the attempt
-                                        let delete_attempt = delete_attempt_objects(&feed_id, &attempt_id, Arc::clone(&arc_conn_pool)).await;
-
-                                        if delete_attempt.is_ok() {
-                                            use catenary::schema::gtfs::in_progress_static_ingests::dsl::in_progress_static_ingests;
-
-                                            let _ = diesel::delete(in_progress_static_ingests.filter(catenary::schema::gtfs::in_progress_static_ingests::dsl::onestop_feed_id.eq(&feed_id))
-                                            .filter(catenary::schema::gtfs::in_progress_static_ingests::dsl::attempt_id.eq(&attempt_id)))
-                                        .execute(conn).await;
-                                        }
-                                        }
                                     }
-                                    
-                                    
-                                
+                                    Ok(false) => {}
+                                    Err(e) => {
+                                        eprintln!("Ingesting {} failed: {}", feed_id, e);
+                                    }
+                                }
                             }
-                        
-                    }
-                
-            ))
-            .buffer_unordered(get_threads_gtfs())
-            .collect::<Vec<()>>()
-            .await;
+                        },
+                    ))
+                    .buffer_unordered(get_threads_gtfs())
+                    .collect::<Vec<()>>()
+                    .await;
+                }
+            }
 
             // delete static feeds that no longer exist
 
@@ -669,5 +616,18 @@ async fn run_ingest() -> Result<(), Box<dyn Error + std::marker::Send + Sync>> {
 
 #[tokio::main]
 async fn main() {
-    let _ = run_ingest().await;
+    tokio::spawn(catenary::metrics::serve_metrics(
+        catenary::metrics::metrics_addr_from_env(9103),
+    ));
+
+    match std::env::var("MAPLE_ROLE").as_deref() {
+        Ok("worker") => {
+            if let Err(e) = distributed::run_worker().await {
+                eprintln!("Maple worker stopped: {}", e);
+            }
+        }
+        _ => {
+            let _ = run_ingest().await;
+        }
+    }
 }
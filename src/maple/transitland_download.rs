@@ -1,11 +1,15 @@
 use crate::gtfs_handlers::MAPLE_INGESTION_VERSION;
 use crate::CatenaryPostgresPool;
+use catenary::metrics::{
+    STATIC_DOWNLOAD_DURATION, STATIC_DOWNLOAD_RESPONSES, STATIC_PAYLOAD_BYTES,
+};
 use catenary::models::StaticDownloadAttempt;
 use diesel::prelude::*;
 use diesel_async::{AsyncConnection, RunQueryDsl};
 use dmfr_dataset_reader::ReturnDmfrAnalysis;
 use reqwest::redirect::Policy;
 use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
@@ -97,7 +101,7 @@ async fn try_to_download(
 //It's giving UC Berkeley lab assignment!!! 🐻💅🐻💅
 //context for this joke: https://inst.eecs.berkeley.edu/~cs162/fa22/static/hw/hw-map-reduce-rs/
 // UC Berkeley has exercises from their Rust computing courses that pack massive structs as result
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DownloadedFeedsInformation {
     pub feed_id: String,
     pub url: String,
@@ -222,6 +226,8 @@ pub async fn download_return_eligible_feeds(
                                 .expect("Time went backwards");
 
                             let duration_ms = duration.as_millis();
+
+                            STATIC_DOWNLOAD_DURATION.observe(duration.as_secs_f64());
             
                             // say that the download state was unsuccessful by default, and insert the duration
                             let mut answer = DownloadedFeedsInformation {
@@ -241,6 +247,10 @@ pub async fn download_return_eligible_feeds(
                                 Ok(response) => {
                                     answer.http_response_code = Some(response.status().as_str().to_string());
 
+                                    STATIC_DOWNLOAD_RESPONSES
+                                        .with_label_values(&[response.status().as_str()])
+                                        .inc();
+
                                     if response.status().is_success() {
                                     // get raw bytes
                                     let bytes_result = response.bytes().await;
@@ -253,6 +263,8 @@ pub async fn download_return_eligible_feeds(
             
                                         answer.hash = Some(hash);
                                         answer.byte_size = Some(byte_length as u64);
+
+                                        STATIC_PAYLOAD_BYTES.observe(byte_length as f64);
             
                                         // stringify the hash
                                         let hash_str = hash.to_string();
@@ -331,6 +343,7 @@ pub async fn download_return_eligible_feeds(
                                     }
                                 }
                                 Err(error) => {
+                                    STATIC_DOWNLOAD_RESPONSES.with_label_values(&["error"]).inc();
 
                                     let mut download_progress  = download_progress.lock().unwrap();
                                     *download_progress += 1;
@@ -0,0 +1,198 @@
+// Copyright: Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Removal of the attribution is not allowed, as covered under the AGPL license
+
+// Metrics shared by every service, in the Prometheus text format.
+// Every service calls `serve_metrics` to serve them on an internal port.
+
+use crate::postgres_tools::{CatenaryPostgresPool, POOL_MAX_SIZE};
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+lazy_static! {
+    pub static ref REALTIME_FETCH_DURATION: HistogramVec = register_histogram_vec!(
+        "catenary_realtime_fetch_duration_seconds",
+        "Time taken by alpenrose to fetch and hand off one realtime feed",
+        &["feed_id"]
+    )
+    .unwrap();
+    pub static ref REALTIME_FETCH_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "catenary_realtime_fetch_responses_total",
+        "Responses to realtime fetches, status is the http status code or error",
+        &["feed_id", "url_type", "status"]
+    )
+    .unwrap();
+    pub static ref REALTIME_PAYLOAD_BYTES: HistogramVec = register_histogram_vec!(
+        "catenary_realtime_payload_bytes",
+        "Size of realtime feed bodies",
+        &["feed_id", "url_type"],
+        exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref STATIC_DOWNLOAD_DURATION: Histogram = register_histogram!(
+        "catenary_static_download_duration_seconds",
+        "Time taken by maple to download one static feed",
+        exponential_buckets(0.25, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref STATIC_DOWNLOAD_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "catenary_static_download_responses_total",
+        "Responses to static feed downloads, status is the http status code or error",
+        &["status"]
+    )
+    .unwrap();
+    pub static ref STATIC_PAYLOAD_BYTES: Histogram = register_histogram!(
+        "catenary_static_payload_bytes",
+        "Size of downloaded static feed zips",
+        exponential_buckets(16384.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref ASPEN_INGEST_DURATION: HistogramVec = register_histogram_vec!(
+        "catenary_aspen_ingest_duration_seconds",
+        "Time taken by aspen to process new realtime data for a chateau",
+        &["chateau"]
+    )
+    .unwrap();
+    pub static ref ASPEN_ALPENROSE_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "catenary_aspen_alpenrose_queue_depth",
+        "Chateaus waiting in alpenrose_to_process_queue"
+    )
+    .unwrap();
+    pub static ref POSTGRES_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "catenary_postgres_pool_connections",
+        "Connections of the postgres pool, state is idle, in_use or max",
+        &["state"]
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "catenary_http_request_duration_seconds",
+        "Latency of http requests, endpoint is the matched route pattern",
+        &["method", "endpoint", "status"]
+    )
+    .unwrap();
+    pub static ref NEARBY_DEPARTURES_STAGE_DURATION: HistogramVec = register_histogram_vec!(
+        "catenary_nearby_departures_stage_duration_seconds",
+        "Time spent in each stage of a nearby departures query",
+        &["stage"]
+    )
+    .unwrap();
+}
+
+/// Every registered metric in the Prometheus text format
+pub fn encode_metrics() -> String {
+    let mut buffer = Vec::new();
+
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        eprintln!("Could not encode metrics: {}", e);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+pub fn observe_pool(pool: &CatenaryPostgresPool) {
+    let state = pool.state();
+
+    POSTGRES_POOL_CONNECTIONS
+        .with_label_values(&["idle"])
+        .set(state.idle_connections as i64);
+    POSTGRES_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set(state.connections.saturating_sub(state.idle_connections) as i64);
+    POSTGRES_POOL_CONNECTIONS
+        .with_label_values(&["max"])
+        .set(POOL_MAX_SIZE as i64);
+}
+
+pub async fn keep_pool_metrics_fresh(pool: Arc<CatenaryPostgresPool>) {
+    loop {
+        observe_pool(&pool);
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Address from METRICS_ADDR, or all interfaces on `default_port`
+pub fn metrics_addr_from_env(default_port: u16) -> SocketAddr {
+    std::env::var("METRICS_ADDR")
+        .ok()
+        .and_then(|addr| addr.parse().ok())
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], default_port)))
+}
+
+// Prometheus only ever asks for GET /metrics, which does not need a whole http framework.
+// A port that is already taken is logged, the service keeps running without metrics.
+pub async fn serve_metrics(addr: SocketAddr) {
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not serve metrics on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    println!("Serving metrics on {}", addr);
+
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Could not accept metrics connection: {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+
+            let Ok(length) = stream.read(&mut request).await else {
+                return;
+            };
+
+            let request = String::from_utf8_lossy(&request[..length]);
+            let mut request_line = request.split_whitespace();
+
+            let response = match (request_line.next(), request_line.next()) {
+                (Some("GET"), Some("/metrics")) => {
+                    let body = encode_metrics();
+
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        prometheus::TEXT_FORMAT,
+                        body.len(),
+                        body
+                    )
+                }
+                _ => String::from(
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                ),
+            };
+
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_metrics_are_encoded() {
+        REALTIME_FETCH_RESPONSES
+            .with_label_values(&["f-test~rt", "vehicles", "200"])
+            .inc();
+
+        let encoded = encode_metrics();
+
+        assert!(encoded.contains("catenary_realtime_fetch_responses_total"));
+        assert!(encoded.contains("feed_id=\"f-test~rt\""));
+    }
+}
@@ -333,9 +333,9 @@ pub struct FeedInfo {
 #[derive(Queryable, Selectable, Insertable, Debug, Clone)]
 #[diesel(table_name = crate::schema::gtfs::static_passwords)]
 pub struct StaticPasswordRow {
-    onestop_feed_id: String,
-    passwords: Option<Value>,
-    last_updated_ms: i64,
+    pub onestop_feed_id: String,
+    pub passwords: Option<Value>,
+    pub last_updated_ms: i64,
 }
 
 #[derive(Queryable, Selectable, Insertable, Debug, Clone)]
@@ -360,6 +360,32 @@ pub struct AdminCredentials {
     pub hash: String,
     pub salt: String,
     pub last_updated_ms: i64,
+    pub role: String,
+    pub disabled: bool,
+    // bulk export of sealed credentials, independent of the role
+    pub can_export_secrets: bool,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name = crate::schema::gtfs::admin_sessions)]
+pub struct AdminSession {
+    // sha256 of the session token, the token itself is only ever held by the client
+    pub token_hash: String,
+    pub email: String,
+    pub created_ms: i64,
+    pub expires_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::admin_audit_log)]
+pub struct AdminAuditLogRow {
+    pub audit_id: String,
+    pub email: String,
+    pub onestop_feed_id: String,
+    pub action: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub changed_ms: i64,
 }
 
 #[derive(
@@ -423,3 +449,131 @@ pub struct TileStorage {
     pub mvt_data: Vec<u8>,
     pub added_time: chrono::DateTime<chrono::Utc>,
 }
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::public_api_keys)]
+pub struct PublicApiKey {
+    pub api_key: String,
+    pub name: String,
+    pub owner_email: String,
+    pub daily_quota: Option<i64>,
+    pub tiles_per_minute: Option<i32>,
+    pub realtime_per_minute: Option<i32>,
+    pub departures_per_minute: Option<i32>,
+    pub disabled: bool,
+    pub created_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::public_api_key_usage)]
+pub struct PublicApiKeyUsage {
+    pub api_key: String,
+    pub usage_date: chrono::NaiveDate,
+    pub endpoint_class: String,
+    pub request_count: i64,
+    pub rejected_count: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::gtfs_errors)]
+pub struct GtfsValidationFinding {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub error_index: i32,
+    pub chateau: String,
+    pub file_hash: Option<String>,
+    /// error or warning
+    pub severity: String,
+    pub code: String,
+    pub file: String,
+    /// 1 is the header row, so the first record is row 2
+    pub row_number: Option<i64>,
+    pub entity_id: Option<String>,
+    pub error: String,
+    pub validated_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::gtfs_validation_reports)]
+pub struct GtfsValidationReport {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub chateau: String,
+    pub file_hash: Option<String>,
+    pub validated_ms: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::gtfs_validation_counts)]
+pub struct GtfsValidationCount {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub code: String,
+    /// error or warning
+    pub severity: String,
+    pub file: String,
+    /// every finding of this code, including the ones not stored in gtfs_errors
+    pub finding_count: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::realtime_fan_out_rules)]
+pub struct RealtimeFanOutRule {
+    pub aggregate_feed_id: String,
+    pub rule_index: i32,
+    /// agency, route_prefix or static_trip_ownership
+    pub rule_type: String,
+    pub match_value: Option<String>,
+    pub chateau: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::supplementary_merge_rules)]
+pub struct SupplementaryMergeRuleRow {
+    pub chateau: String,
+    pub rule_index: i32,
+    pub source_feed_id: String,
+    /// position, speed, occupancy or platform
+    pub field: String,
+    /// fill_missing or override
+    pub mode: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::vehicle_position_history)]
+pub struct VehiclePositionHistoryRow {
+    pub chateau: String,
+    pub vehicle_id: String,
+    pub recorded_at_ms: i64,
+    pub vehicle_label: Option<String>,
+    pub trip_id: Option<String>,
+    pub route_id: Option<String>,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub bearing: Option<f32>,
+    pub speed: Option<f32>,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::static_file_hashes)]
+pub struct StaticFileHash {
+    pub onestop_feed_id: String,
+    pub attempt_id: String,
+    pub file_name: String,
+    pub file_hash: String,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::gtfs::crowding_profiles)]
+pub struct CrowdingProfileRow {
+    pub chateau: String,
+    pub route_id: String,
+    pub direction_id: i16,
+    pub stop_id: String,
+    pub time_of_week_bucket: i16,
+    pub occupancy_status_sum: i64,
+    pub occupancy_status_count: i64,
+    pub occupancy_percentage_sum: i64,
+    pub occupancy_percentage_count: i64,
+    pub last_observed_ms: i64,
+}
@@ -12,6 +12,9 @@ use std::env;
 pub type CatenaryPostgresPool =
     bb8::Pool<AsyncDieselConnectionManager<diesel_async::AsyncPgConnection>>;
 
+/// Largest number of connections a pool opens, reported with the pool metrics
+pub const POOL_MAX_SIZE: u32 = 10;
+
 /// Type alias to the pooled connection
 /// This must be used in a single thread, since it is mutable
 pub type CatenaryConn<'a> = &'a mut bb8::PooledConnection<
@@ -33,7 +36,10 @@ pub async fn make_async_pool() -> Result<
             database_url_for_env(),
             custom_conf,
         );
-    let pool = Pool::builder().build(config).await?;
+    let pool = Pool::builder()
+        .max_size(POOL_MAX_SIZE)
+        .build(config)
+        .await?;
 
     Ok(pool)
 }
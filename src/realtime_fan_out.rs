@@ -0,0 +1,297 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Splitting aggregate realtime feeds (one payload covering many operators, such as the
+// Great Britain BODS feed) into one partition per chateau that owns the matching static data.
+// Each partition is delivered to aspen under its own realtime feed id so that chateaus
+// assigned to the same aspen worker do not overwrite each other.
+
+use ahash::AHashMap;
+use gtfs_realtime::{FeedEntity, FeedMessage, TripDescriptor};
+use std::collections::BTreeMap;
+
+pub const PARTITION_SEPARATOR: char = '|';
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FanOutRule {
+    // route_id of the entity resolves to this agency_id
+    Agency { agency_id: String, chateau: String },
+    // route_id of the entity starts with this prefix
+    RoutePrefix { prefix: String, chateau: String },
+    // trip_id of the entity exists in the static data of this chateau
+    StaticTripOwnership { chateau: String },
+}
+
+impl FanOutRule {
+    pub fn from_row(row: &crate::models::RealtimeFanOutRule) -> Option<FanOutRule> {
+        match (row.rule_type.as_str(), &row.match_value) {
+            ("agency", Some(agency_id)) => Some(FanOutRule::Agency {
+                agency_id: agency_id.clone(),
+                chateau: row.chateau.clone(),
+            }),
+            ("route_prefix", Some(prefix)) => Some(FanOutRule::RoutePrefix {
+                prefix: prefix.clone(),
+                chateau: row.chateau.clone(),
+            }),
+            ("static_trip_ownership", _) => Some(FanOutRule::StaticTripOwnership {
+                chateau: row.chateau.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn chateau(&self) -> &str {
+        match self {
+            FanOutRule::Agency { chateau, .. } => chateau,
+            FanOutRule::RoutePrefix { chateau, .. } => chateau,
+            FanOutRule::StaticTripOwnership { chateau } => chateau,
+        }
+    }
+}
+
+// Static data needed to evaluate the rules, looked up by the caller
+#[derive(Clone, Debug, Default)]
+pub struct FanOutLookups {
+    pub route_id_to_agency_id: AHashMap<String, String>,
+    pub trip_id_to_chateau: AHashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionedFeed {
+    // chateau id -> entities owned by that chateau
+    pub partitions: BTreeMap<String, FeedMessage>,
+    // entities no rule matched, left with the chateau the aggregate feed is assigned to
+    pub unmatched: FeedMessage,
+}
+
+pub fn partition_feed_id(aggregate_feed_id: &str, chateau_id: &str) -> String {
+    format!("{}{}{}", aggregate_feed_id, PARTITION_SEPARATOR, chateau_id)
+}
+
+// Returns the aggregate feed id for a partition id, or the id itself for ordinary feeds
+pub fn aggregate_feed_id(realtime_feed_id: &str) -> &str {
+    match realtime_feed_id.split_once(PARTITION_SEPARATOR) {
+        Some((aggregate, _)) => aggregate,
+        None => realtime_feed_id,
+    }
+}
+
+fn chateau_for_trip(
+    trip: &TripDescriptor,
+    rules: &[FanOutRule],
+    lookups: &FanOutLookups,
+) -> Option<String> {
+    for rule in rules {
+        match rule {
+            FanOutRule::StaticTripOwnership { chateau } => {
+                if let Some(trip_id) = &trip.trip_id {
+                    if lookups.trip_id_to_chateau.get(trip_id) == Some(chateau) {
+                        return Some(chateau.clone());
+                    }
+                }
+            }
+            FanOutRule::RoutePrefix { prefix, chateau } => {
+                if let Some(route_id) = &trip.route_id {
+                    if route_id.starts_with(prefix.as_str()) {
+                        return Some(chateau.clone());
+                    }
+                }
+            }
+            FanOutRule::Agency { agency_id, chateau } => {
+                if let Some(route_id) = &trip.route_id {
+                    if lookups.route_id_to_agency_id.get(route_id) == Some(agency_id) {
+                        return Some(chateau.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn chateaus_for_entity(
+    entity: &FeedEntity,
+    rules: &[FanOutRule],
+    lookups: &FanOutLookups,
+) -> Vec<String> {
+    if let Some(trip_update) = &entity.trip_update {
+        return chateau_for_trip(&trip_update.trip, rules, lookups)
+            .into_iter()
+            .collect();
+    }
+
+    if let Some(vehicle) = &entity.vehicle {
+        return match &vehicle.trip {
+            Some(trip) => chateau_for_trip(trip, rules, lookups).into_iter().collect(),
+            None => vec![],
+        };
+    }
+
+    // an alert is copied to every chateau one of its informed entities belongs to
+    if let Some(alert) = &entity.alert {
+        let mut chateaus: Vec<String> = vec![];
+
+        for informed_entity in alert.informed_entity.iter() {
+            let chateau = match (&informed_entity.trip, &informed_entity.route_id) {
+                (Some(trip), _) => chateau_for_trip(trip, rules, lookups),
+                (None, Some(route_id)) => {
+                    let trip = TripDescriptor {
+                        route_id: Some(route_id.clone()),
+                        ..Default::default()
+                    };
+                    chateau_for_trip(&trip, rules, lookups)
+                }
+                (None, None) => informed_entity.agency_id.as_ref().and_then(|agency_id| {
+                    rules.iter().find_map(|rule| match rule {
+                        FanOutRule::Agency {
+                            agency_id: rule_agency_id,
+                            chateau,
+                        } if rule_agency_id == agency_id => Some(chateau.clone()),
+                        _ => None,
+                    })
+                }),
+            };
+
+            if let Some(chateau) = chateau {
+                if !chateaus.contains(&chateau) {
+                    chateaus.push(chateau);
+                }
+            }
+        }
+
+        return chateaus;
+    }
+
+    vec![]
+}
+
+// Every chateau named by a rule gets a partition, even an empty one, so stale data is cleared
+pub fn partition_feed_message(
+    feed: &FeedMessage,
+    rules: &[FanOutRule],
+    lookups: &FanOutLookups,
+) -> PartitionedFeed {
+    let mut partitions: BTreeMap<String, FeedMessage> = BTreeMap::new();
+
+    for rule in rules {
+        partitions
+            .entry(rule.chateau().to_string())
+            .or_insert_with(|| FeedMessage {
+                header: feed.header.clone(),
+                entity: vec![],
+            });
+    }
+
+    let mut unmatched = FeedMessage {
+        header: feed.header.clone(),
+        entity: vec![],
+    };
+
+    for entity in feed.entity.iter() {
+        let chateaus = chateaus_for_entity(entity, rules, lookups);
+
+        if chateaus.is_empty() {
+            unmatched.entity.push(entity.clone());
+            continue;
+        }
+
+        for chateau in chateaus {
+            if let Some(partition) = partitions.get_mut(&chateau) {
+                partition.entity.push(entity.clone());
+            }
+        }
+    }
+
+    PartitionedFeed {
+        partitions,
+        unmatched,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_realtime::{TripUpdate, VehiclePosition};
+
+    fn vehicle(id: &str, trip_id: &str, route_id: &str) -> FeedEntity {
+        FeedEntity {
+            id: id.to_string(),
+            vehicle: Some(VehiclePosition {
+                trip: Some(TripDescriptor {
+                    trip_id: Some(trip_id.to_string()),
+                    route_id: Some(route_id.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn partitions_by_trip_ownership_then_route_prefix() {
+        let feed = FeedMessage {
+            header: Default::default(),
+            entity: vec![
+                vehicle("1", "trip-a", "OP1:10"),
+                vehicle("2", "trip-b", "OP2:20"),
+                vehicle("3", "trip-c", "OP3:30"),
+                FeedEntity {
+                    id: "4".to_string(),
+                    trip_update: Some(TripUpdate {
+                        trip: TripDescriptor {
+                            trip_id: Some("trip-a".to_string()),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+        };
+
+        let rules = vec![
+            FanOutRule::StaticTripOwnership {
+                chateau: "north".to_string(),
+            },
+            FanOutRule::RoutePrefix {
+                prefix: "OP2:".to_string(),
+                chateau: "south".to_string(),
+            },
+            FanOutRule::Agency {
+                agency_id: "OP4".to_string(),
+                chateau: "east".to_string(),
+            },
+        ];
+
+        let mut lookups = FanOutLookups::default();
+        lookups
+            .trip_id_to_chateau
+            .insert("trip-a".to_string(), "north".to_string());
+
+        let partitioned = partition_feed_message(&feed, &rules, &lookups);
+
+        let ids = |chateau: &str| {
+            partitioned.partitions[chateau]
+                .entity
+                .iter()
+                .map(|entity| entity.id.clone())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(ids("north"), vec!["1", "4"]);
+        assert_eq!(ids("south"), vec!["2"]);
+        assert!(ids("east").is_empty());
+        assert_eq!(partitioned.unmatched.entity.len(), 1);
+        assert_eq!(partitioned.unmatched.entity[0].id, "3");
+    }
+
+    #[test]
+    fn partition_ids_round_trip() {
+        let id = partition_feed_id("f-bus~dft~gov~uk~rt", "arriva~uk");
+        assert_eq!(aggregate_feed_id(&id), "f-bus~dft~gov~uk~rt");
+        assert_eq!(aggregate_feed_id("f-amtrak~rt"), "f-amtrak~rt");
+    }
+}
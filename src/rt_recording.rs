@@ -0,0 +1,383 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Recording of everything alpenrose fetches and submits to aspen, so a session can be replayed offline.
+// A session is a directory with one bincode file per submission (.bin) and one per raw response (.response),
+// named so that sorting the file names sorts them by the time they were fetched.
+// Files start with RECORDING_MAGIC and a format version, so sessions keep loading after fields are added.
+// Files without the header are from before versioning.
+
+use crate::aspen::lib::AspenRpcClient;
+use crate::aspen_dataset::{SupplementaryRecord, VehicleConsistSupplement};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const RECORD_DIR_ENV: &str = "ALPENROSE_RECORD_DIR";
+
+const RECORDING_MAGIC: &[u8; 4] = b"CRRF";
+// bump when RecordedFetch or RecordedResponse change, and keep decoding the old versions
+const RECORDING_FORMAT_VERSION: u16 = 1;
+
+// The arguments of AspenRpc::from_alpenrose, as they were sent
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RecordedFetch {
+    pub chateau_id: String,
+    pub realtime_feed_id: String,
+    pub vehicles: Option<Vec<u8>>,
+    pub trips: Option<Vec<u8>>,
+    pub alerts: Option<Vec<u8>>,
+    pub has_vehicles: bool,
+    pub has_trips: bool,
+    pub has_alerts: bool,
+    pub vehicles_response_code: Option<u16>,
+    pub trips_response_code: Option<u16>,
+    pub alerts_response_code: Option<u16>,
+    pub vehicle_consists: Option<HashMap<String, VehicleConsistSupplement>>,
+    pub supplementary: Option<Vec<SupplementaryRecord>>,
+    pub fetched_at_ms: u64,
+}
+
+// RecordedFetch as the first sessions were written, before consists and supplementary data
+#[derive(Deserialize)]
+struct LegacyRecordedFetch {
+    chateau_id: String,
+    realtime_feed_id: String,
+    vehicles: Option<Vec<u8>>,
+    trips: Option<Vec<u8>>,
+    alerts: Option<Vec<u8>>,
+    has_vehicles: bool,
+    has_trips: bool,
+    has_alerts: bool,
+    vehicles_response_code: Option<u16>,
+    trips_response_code: Option<u16>,
+    alerts_response_code: Option<u16>,
+    fetched_at_ms: u64,
+}
+
+impl From<LegacyRecordedFetch> for RecordedFetch {
+    fn from(legacy: LegacyRecordedFetch) -> Self {
+        RecordedFetch {
+            chateau_id: legacy.chateau_id,
+            realtime_feed_id: legacy.realtime_feed_id,
+            vehicles: legacy.vehicles,
+            trips: legacy.trips,
+            alerts: legacy.alerts,
+            has_vehicles: legacy.has_vehicles,
+            has_trips: legacy.has_trips,
+            has_alerts: legacy.has_alerts,
+            vehicles_response_code: legacy.vehicles_response_code,
+            trips_response_code: legacy.trips_response_code,
+            alerts_response_code: legacy.alerts_response_code,
+            vehicle_consists: None,
+            supplementary: None,
+            fetched_at_ms: legacy.fetched_at_ms,
+        }
+    }
+}
+
+fn encode_versioned<T: Serialize>(
+    value: &T,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut bytes = RECORDING_MAGIC.to_vec();
+    bytes.extend_from_slice(&RECORDING_FORMAT_VERSION.to_le_bytes());
+    bytes.extend(bincode::serialize(value)?);
+
+    Ok(bytes)
+}
+
+pub fn decode_recorded_fetch(bytes: &[u8]) -> Result<RecordedFetch, Box<dyn std::error::Error>> {
+    match bytes.strip_prefix(RECORDING_MAGIC.as_slice()) {
+        Some(rest) if rest.len() >= 2 => {
+            let version = u16::from_le_bytes([rest[0], rest[1]]);
+
+            match version {
+                1 => Ok(bincode::deserialize::<RecordedFetch>(&rest[2..])?),
+                _ => Err(format!("unknown recording format version {}", version).into()),
+            }
+        }
+        // unversioned files were written with or without the consist and supplementary fields,
+        // decoding strictly keeps one layout from being misread as the other
+        _ => {
+            let strict = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes();
+
+            match strict.deserialize::<RecordedFetch>(bytes) {
+                Ok(fetch) => Ok(fetch),
+                Err(_) => Ok(strict.deserialize::<LegacyRecordedFetch>(bytes)?.into()),
+            }
+        }
+    }
+}
+
+// One response as alpenrose received it, including the ones that were never sent on to aspen
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RecordedResponse {
+    pub realtime_feed_id: String,
+    // vehicles, trips or alerts
+    pub url_type: String,
+    // None when the request failed before a response arrived
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+    pub body: Option<Vec<u8>>,
+    // the body was identical to the previous fetch, so it was not sent to aspen
+    pub unchanged: bool,
+    pub fetched_at_ms: u64,
+}
+
+pub struct FetchRecorder {
+    dir: PathBuf,
+    sequence: AtomicU64,
+}
+
+impl FetchRecorder {
+    pub fn new(dir: PathBuf) -> std::io::Result<FetchRecorder> {
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(FetchRecorder {
+            dir,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    // None unless ALPENROSE_RECORD_DIR is set
+    pub fn from_env() -> Option<FetchRecorder> {
+        let dir = std::env::var(RECORD_DIR_ENV).ok()?;
+
+        match FetchRecorder::new(PathBuf::from(&dir)) {
+            Ok(recorder) => {
+                println!("Recording realtime fetches to {}", dir);
+                Some(recorder)
+            }
+            Err(e) => {
+                eprintln!("Could not create recording directory {}: {}", dir, e);
+                None
+            }
+        }
+    }
+
+    pub async fn record(
+        &self,
+        fetch: &RecordedFetch,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let bytes = encode_versioned(fetch)?;
+
+        self.write(&fetch.realtime_feed_id, fetch.fetched_at_ms, "bin", bytes)
+            .await
+    }
+
+    pub async fn record_response(
+        &self,
+        response: &RecordedResponse,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let bytes = encode_versioned(response)?;
+
+        self.write(
+            &response.realtime_feed_id,
+            response.fetched_at_ms,
+            "response",
+            bytes,
+        )
+        .await
+    }
+
+    async fn write(
+        &self,
+        realtime_feed_id: &str,
+        fetched_at_ms: u64,
+        extension: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+
+        let file_name = format!(
+            "{:013}_{:08}_{}.{}",
+            fetched_at_ms,
+            sequence,
+            sanitise_for_file_name(realtime_feed_id),
+            extension
+        );
+
+        // write then rename so a replay never sees a half written file
+        let tmp_path = self.dir.join(format!("{}.tmp", file_name));
+        tokio::fs::write(&tmp_path, bytes).await?;
+        tokio::fs::rename(&tmp_path, self.dir.join(file_name)).await?;
+
+        Ok(())
+    }
+}
+
+fn sanitise_for_file_name(input: &str) -> String {
+    input
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '~' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+// Every submission of a session, in the order they were fetched
+pub fn read_session(dir: &Path) -> Result<Vec<RecordedFetch>, Box<dyn std::error::Error>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|ext| ext == "bin").unwrap_or(false))
+        .collect::<Vec<PathBuf>>();
+
+    paths.sort();
+
+    let mut fetches = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let bytes = std::fs::read(&path)?;
+        fetches.push(decode_recorded_fetch(&bytes)?);
+    }
+
+    fetches.sort_by_key(|fetch| fetch.fetched_at_ms);
+
+    Ok(fetches)
+}
+
+pub async fn send_to_aspen(
+    aspen_client: &AspenRpcClient,
+    fetch: RecordedFetch,
+) -> Result<bool, tarpc::client::RpcError> {
+    aspen_client
+        .from_alpenrose(
+            tarpc::context::current(),
+            fetch.chateau_id,
+            fetch.realtime_feed_id,
+            fetch.vehicles,
+            fetch.trips,
+            fetch.alerts,
+            fetch.has_vehicles,
+            fetch.has_trips,
+            fetch.has_alerts,
+            fetch.vehicles_response_code,
+            fetch.trips_response_code,
+            fetch.alerts_response_code,
+            fetch.vehicle_consists,
+            fetch.supplementary,
+            fetch.fetched_at_ms,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn recorded_session_reads_back_in_fetch_order() {
+        let dir =
+            std::env::temp_dir().join(format!("catenary_rt_recording_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let recorder = FetchRecorder::new(dir.clone()).unwrap();
+
+        let fetch = |feed: &str, fetched_at_ms: u64| RecordedFetch {
+            chateau_id: "metrolinktrains".to_string(),
+            realtime_feed_id: feed.to_string(),
+            vehicles: Some(vec![1, 2, 3]),
+            trips: None,
+            alerts: None,
+            has_vehicles: true,
+            has_trips: false,
+            has_alerts: false,
+            vehicles_response_code: Some(200),
+            trips_response_code: None,
+            alerts_response_code: None,
+            vehicle_consists: None,
+            supplementary: None,
+            fetched_at_ms,
+        };
+
+        recorder.record(&fetch("f-b|split", 2_000)).await.unwrap();
+        recorder.record(&fetch("f-a", 1_000)).await.unwrap();
+
+        // raw responses sit next to the submissions without being replayed
+        recorder
+            .record_response(&RecordedResponse {
+                realtime_feed_id: "f-a".to_string(),
+                url_type: "vehicles".to_string(),
+                http_status: Some(503),
+                error: None,
+                body: Some(vec![4, 5]),
+                unchanged: false,
+                fetched_at_ms: 1_500,
+            })
+            .await
+            .unwrap();
+
+        let session = read_session(&dir).unwrap();
+
+        assert_eq!(
+            session,
+            vec![fetch("f-a", 1_000), fetch("f-b|split", 2_000)]
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[derive(Serialize)]
+    struct UnversionedFetch<'a> {
+        chateau_id: &'a str,
+        realtime_feed_id: &'a str,
+        vehicles: Option<Vec<u8>>,
+        trips: Option<Vec<u8>>,
+        alerts: Option<Vec<u8>>,
+        has_vehicles: bool,
+        has_trips: bool,
+        has_alerts: bool,
+        vehicles_response_code: Option<u16>,
+        trips_response_code: Option<u16>,
+        alerts_response_code: Option<u16>,
+        fetched_at_ms: u64,
+    }
+
+    #[test]
+    fn sessions_from_before_versioning_still_load() {
+        let bytes = bincode::serialize(&UnversionedFetch {
+            chateau_id: "amtrak",
+            realtime_feed_id: "f-amtrak~rt",
+            vehicles: Some(vec![1]),
+            trips: None,
+            alerts: None,
+            has_vehicles: true,
+            has_trips: false,
+            has_alerts: false,
+            vehicles_response_code: Some(200),
+            trips_response_code: None,
+            alerts_response_code: None,
+            fetched_at_ms: 1_000,
+        })
+        .unwrap();
+
+        let fetch = decode_recorded_fetch(&bytes).unwrap();
+
+        assert_eq!(fetch.realtime_feed_id, "f-amtrak~rt");
+        assert_eq!(fetch.vehicles, Some(vec![1]));
+        assert_eq!(fetch.vehicle_consists, None);
+        assert_eq!(fetch.fetched_at_ms, 1_000);
+
+        let current = RecordedFetch {
+            vehicle_consists: Some(HashMap::new()),
+            ..fetch
+        };
+
+        assert_eq!(
+            decode_recorded_fetch(&bincode::serialize(&current).unwrap()).unwrap(),
+            current
+        );
+
+        let mut versioned = RECORDING_MAGIC.to_vec();
+        versioned.extend_from_slice(&99u16.to_le_bytes());
+
+        assert!(decode_recorded_fetch(&versioned).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Feeds a session recorded by alpenrose (ALPENROSE_RECORD_DIR) into a running aspen instance,
+// optionally saving or checking snapshots of what aspen produced from it.
+
+use catenary::rt_recording::{read_session, send_to_aspen};
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser)]
+struct Flags {
+    /// Directory written by alpenrose with ALPENROSE_RECORD_DIR set
+    #[clap(long)]
+    session: PathBuf,
+    /// tarpc address of the aspen instance to replay into
+    #[clap(long, default_value = "[::1]:40427")]
+    aspen: SocketAddr,
+    /// 1 replays at the recorded pace, 10 ten times faster, 0 sends everything without waiting
+    #[clap(long, default_value_t = 1.0)]
+    speed: f64,
+    /// How long aspen gets to finish processing before snapshots are taken
+    #[clap(long, default_value_t = 5_000)]
+    settle_ms: u64,
+    /// Write one snapshot per chateau into this directory
+    #[clap(long)]
+    snapshot_dir: Option<PathBuf>,
+    /// Compare the snapshots against golden files in this directory, exiting non zero on a mismatch
+    #[clap(long)]
+    compare_to: Option<PathBuf>,
+}
+
+// Set from the clock of the machine running aspen, or from the feed's own clock at fetch time.
+// Neither says anything about what aspen made of the data, so they are left out of snapshots.
+const WALL_CLOCK_FIELDS: [&str; 2] = ["timestamp", "last_updated_time_ms"];
+
+fn strip_wall_clock_fields(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for field in WALL_CLOCK_FIELDS {
+                map.remove(field);
+            }
+
+            for value in map.values_mut() {
+                strip_wall_clock_fields(value);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values.iter_mut() {
+                strip_wall_clock_fields(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(Serialize)]
+struct ChateauSnapshot {
+    vehicle_positions: BTreeMap<String, serde_json::Value>,
+    // trip id -> trip updates, sorted so the snapshot is stable between runs
+    trip_updates: BTreeMap<String, Vec<serde_json::Value>>,
+}
+
+async fn take_snapshot(
+    aspen_client: &catenary::aspen::lib::AspenRpcClient,
+    chateau_id: &str,
+) -> Result<serde_json::Value, Box<dyn Error + Sync + Send>> {
+    let vehicles = aspen_client
+        .get_vehicle_locations(tarpc::context::current(), chateau_id.to_string(), None)
+        .await?;
+
+    let mut snapshot = ChateauSnapshot {
+        vehicle_positions: BTreeMap::new(),
+        trip_updates: BTreeMap::new(),
+    };
+
+    let mut trip_ids: BTreeSet<String> = BTreeSet::new();
+
+    if let Some(vehicles) = vehicles {
+        for (vehicle_id, vehicle) in vehicles.vehicle_positions {
+            if let Some(trip_id) = vehicle.trip.as_ref().and_then(|trip| trip.trip_id.as_ref()) {
+                trip_ids.insert(trip_id.to_string());
+            }
+
+            snapshot
+                .vehicle_positions
+                .insert(vehicle_id, serde_json::to_value(&vehicle)?);
+        }
+    }
+
+    for trip_id in trip_ids {
+        let trip_updates = aspen_client
+            .get_trip_updates_from_trip_id(
+                tarpc::context::current(),
+                chateau_id.to_string(),
+                trip_id.clone(),
+            )
+            .await?;
+
+        if let Some(trip_updates) = trip_updates {
+            let mut trip_updates = trip_updates
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<Result<Vec<serde_json::Value>, _>>()?;
+
+            trip_updates.sort_by_key(|trip_update| trip_update.to_string());
+
+            snapshot.trip_updates.insert(trip_id, trip_updates);
+        }
+    }
+
+    let mut snapshot = serde_json::to_value(&snapshot)?;
+
+    strip_wall_clock_fields(&mut snapshot);
+
+    Ok(snapshot)
+}
+
+fn snapshot_file_name(chateau_id: &str) -> String {
+    format!("{}.json", chateau_id.replace(['/', '|'], "_"))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let flags: Flags = Flags::parse();
+
+    let session = read_session(&flags.session).map_err(|e| e.to_string())?;
+
+    if session.is_empty() {
+        return Err(format!("No recorded fetches in {}", flags.session.display()).into());
+    }
+
+    println!(
+        "Replaying {} fetches into aspen at {}",
+        session.len(),
+        flags.aspen
+    );
+
+    let aspen_client = catenary::aspen::lib::spawn_aspen_client_from_ip(&flags.aspen).await?;
+
+    let chateaus = session
+        .iter()
+        .map(|fetch| fetch.chateau_id.clone())
+        .collect::<BTreeSet<String>>();
+
+    let first_fetched_at_ms = session[0].fetched_at_ms;
+    let replay_start = std::time::Instant::now();
+
+    for fetch in session {
+        if flags.speed > 0.0 {
+            let offset_ms = (fetch.fetched_at_ms - first_fetched_at_ms) as f64 / flags.speed;
+            let due = Duration::from_millis(offset_ms as u64);
+            let elapsed = replay_start.elapsed();
+
+            if due > elapsed {
+                tokio::time::sleep(due - elapsed).await;
+            }
+        }
+
+        let realtime_feed_id = fetch.realtime_feed_id.clone();
+
+        if let Err(e) = send_to_aspen(&aspen_client, fetch).await {
+            eprintln!("{}: Error sending recorded fetch: {}", realtime_feed_id, e);
+        }
+    }
+
+    println!("Replay finished in {:?}", replay_start.elapsed());
+
+    if flags.snapshot_dir.is_none() && flags.compare_to.is_none() {
+        return Ok(());
+    }
+
+    tokio::time::sleep(Duration::from_millis(flags.settle_ms)).await;
+
+    let mut mismatches: Vec<String> = vec![];
+
+    for chateau_id in chateaus.iter() {
+        let snapshot = take_snapshot(&aspen_client, chateau_id).await?;
+
+        if let Some(snapshot_dir) = &flags.snapshot_dir {
+            std::fs::create_dir_all(snapshot_dir)?;
+            std::fs::write(
+                snapshot_dir.join(snapshot_file_name(chateau_id)),
+                serde_json::to_string_pretty(&snapshot)?,
+            )?;
+        }
+
+        if let Some(compare_to) = &flags.compare_to {
+            let golden_path = compare_to.join(snapshot_file_name(chateau_id));
+
+            match std::fs::read_to_string(&golden_path) {
+                Ok(golden) => {
+                    let golden = serde_json::from_str::<serde_json::Value>(&golden)?;
+
+                    if golden != snapshot {
+                        mismatches.push(chateau_id.clone());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Could not read {}: {}", golden_path.display(), e);
+                    mismatches.push(chateau_id.clone());
+                }
+            }
+        }
+    }
+
+    if !mismatches.is_empty() {
+        return Err(format!("Snapshots differ for chateaus: {}", mismatches.join(", ")).into());
+    }
+
+    println!("Snapshots taken for {} chateaus", chateaus.len());
+
+    Ok(())
+}
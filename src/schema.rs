@@ -1,6 +1,22 @@
 // @generated automatically by Diesel CLI.
 
 pub mod gtfs {
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.admin_audit_log (audit_id) {
+            audit_id -> Text,
+            email -> Text,
+            onestop_feed_id -> Text,
+            action -> Text,
+            old_value -> Nullable<Text>,
+            new_value -> Nullable<Text>,
+            changed_ms -> Int8,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -11,6 +27,22 @@ pub mod gtfs {
             hash -> Text,
             salt -> Text,
             last_updated_ms -> Int8,
+            role -> Text,
+            disabled -> Bool,
+            can_export_secrets -> Bool,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.admin_sessions (token_hash) {
+            token_hash -> Text,
+            email -> Text,
+            created_ms -> Int8,
+            expires_ms -> Int8,
         }
     }
 
@@ -84,6 +116,25 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.crowding_profiles (chateau, route_id, direction_id, stop_id, time_of_week_bucket) {
+            chateau -> Text,
+            route_id -> Text,
+            direction_id -> Int2,
+            stop_id -> Text,
+            time_of_week_bucket -> Int2,
+            occupancy_status_sum -> Int8,
+            occupancy_status_count -> Int8,
+            occupancy_percentage_sum -> Int8,
+            occupancy_percentage_count -> Int8,
+            last_observed_ms -> Int8,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -172,12 +223,48 @@ pub mod gtfs {
         use diesel::sql_types::*;
         use crate::custom_pg_types::*;
 
-        gtfs.gtfs_errors (onestop_feed_id, attempt_id) {
+        gtfs.gtfs_errors (onestop_feed_id, attempt_id, error_index) {
             onestop_feed_id -> Text,
-            error -> Text,
             attempt_id -> Text,
+            error_index -> Int4,
+            chateau -> Text,
             file_hash -> Nullable<Text>,
+            severity -> Text,
+            code -> Text,
+            file -> Text,
+            row_number -> Nullable<Int8>,
+            entity_id -> Nullable<Text>,
+            error -> Text,
+            validated_ms -> Int8,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.gtfs_validation_counts (onestop_feed_id, attempt_id, code, file) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            code -> Text,
+            severity -> Text,
+            file -> Text,
+            finding_count -> Int8,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.gtfs_validation_reports (onestop_feed_id, attempt_id) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
             chateau -> Text,
+            file_hash -> Nullable<Text>,
+            validated_ms -> Int8,
         }
     }
 
@@ -278,6 +365,38 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.public_api_key_usage (api_key, usage_date, endpoint_class) {
+            api_key -> Text,
+            usage_date -> Date,
+            endpoint_class -> Text,
+            request_count -> Int8,
+            rejected_count -> Int8,
+        }
+    }
+
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.public_api_keys (api_key) {
+            api_key -> Text,
+            name -> Text,
+            owner_email -> Text,
+            daily_quota -> Nullable<Int8>,
+            tiles_per_minute -> Nullable<Int4>,
+            realtime_per_minute -> Nullable<Int4>,
+            departures_per_minute -> Nullable<Int4>,
+            disabled -> Bool,
+            created_ms -> Int8,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -307,6 +426,20 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.realtime_fan_out_rules (aggregate_feed_id, rule_index) {
+            aggregate_feed_id -> Text,
+            rule_index -> Int4,
+            rule_type -> Text,
+            match_value -> Nullable<Text>,
+            chateau -> Text,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -376,6 +509,19 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.static_file_hashes (onestop_feed_id, attempt_id, file_name) {
+            onestop_feed_id -> Text,
+            attempt_id -> Text,
+            file_name -> Text,
+            file_hash -> Text,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -457,6 +603,20 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.supplementary_merge_rules (chateau, rule_index) {
+            chateau -> Text,
+            rule_index -> Int4,
+            source_feed_id -> Text,
+            field -> Text,
+            mode -> Text,
+        }
+    }
+
     diesel::table! {
         use postgis_diesel::sql_types::*;
         use diesel::sql_types::*;
@@ -513,34 +673,64 @@ pub mod gtfs {
         }
     }
 
+    diesel::table! {
+        use postgis_diesel::sql_types::*;
+        use diesel::sql_types::*;
+        use crate::custom_pg_types::*;
+
+        gtfs.vehicle_position_history (chateau, vehicle_id, recorded_at_ms) {
+            chateau -> Text,
+            vehicle_id -> Text,
+            recorded_at_ms -> Int8,
+            vehicle_label -> Nullable<Text>,
+            trip_id -> Nullable<Text>,
+            route_id -> Nullable<Text>,
+            latitude -> Float4,
+            longitude -> Float4,
+            bearing -> Nullable<Float4>,
+            speed -> Nullable<Float4>,
+        }
+    }
+
     diesel::allow_tables_to_appear_in_same_query!(
+        admin_audit_log,
         admin_credentials,
+        admin_sessions,
         agencies,
         calendar,
         calendar_dates,
         chateau_metadata_last_updated_time,
         chateaus,
+        crowding_profiles,
         direction_pattern,
         direction_pattern_meta,
         f_test,
         feed_info,
         gtfs_errors,
+        gtfs_validation_counts,
+        gtfs_validation_reports,
         in_progress_static_ingests,
         ingested_static,
         ip_addr_to_geo,
         itinerary_pattern,
         itinerary_pattern_meta,
+        public_api_key_usage,
+        public_api_keys,
+        realtime_fan_out_rules,
         realtime_feeds,
         realtime_passwords,
         routes,
         shapes,
         static_download_attempts,
         static_feeds,
+        static_file_hashes,
         static_passwords,
         stops,
         stopsforroute,
+        supplementary_merge_rules,
         tile_storage,
         trip_frequencies,
         trips_compressed,
+        vehicle_position_history,
     );
 }
@@ -0,0 +1,260 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Projecting realtime vehicle positions onto the static shape of their trip.
+// Distances use an equirectangular approximation per segment, which is accurate to well under
+// a metre at the segment lengths found in GTFS shapes.
+
+use serde::{Deserialize, Serialize};
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectedShape {
+    // (lon, lat) in degrees
+    pub points: Vec<(f64, f64)>,
+    // distance in metres from the first point to each point
+    pub cumulative_distance_m: Vec<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShapeProjection {
+    pub distance_along_shape_m: f64,
+    pub snapped_lon: f64,
+    pub snapped_lat: f64,
+    pub distance_from_shape_m: f64,
+    // degrees clockwise from north, direction of travel along the shape at the snapped point
+    pub bearing: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressBetweenStops {
+    // index into the stop list handed to stop_distances_along_shape
+    pub previous_stop_index: Option<usize>,
+    pub next_stop_index: Option<usize>,
+    pub fraction_between_stops: Option<f32>,
+}
+
+fn local_xy_m(origin_lat: f64, origin_lon: f64, lon: f64, lat: f64) -> (f64, f64) {
+    let cos_lat = origin_lat.to_radians().cos();
+
+    (
+        (lon - origin_lon).to_radians() * cos_lat * EARTH_RADIUS_M,
+        (lat - origin_lat).to_radians() * EARTH_RADIUS_M,
+    )
+}
+
+pub fn approx_distance_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let mid_lat = (a.1 + b.1) / 2.0;
+
+    let x = (b.0 - a.0).to_radians() * mid_lat.to_radians().cos() * EARTH_RADIUS_M;
+    let y = (b.1 - a.1).to_radians() * EARTH_RADIUS_M;
+
+    (x * x + y * y).sqrt()
+}
+
+pub fn bearing_degrees(from: (f64, f64), to: (f64, f64)) -> f32 {
+    let (lon1, lat1) = (from.0.to_radians(), from.1.to_radians());
+    let (lon2, lat2) = (to.0.to_radians(), to.1.to_radians());
+
+    let d_lon = lon2 - lon1;
+
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+
+    (y.atan2(x).to_degrees().rem_euclid(360.0)) as f32
+}
+
+impl ProjectedShape {
+    pub fn from_coords(points: Vec<(f64, f64)>) -> Option<ProjectedShape> {
+        if points.len() < 2 {
+            return None;
+        }
+
+        let mut cumulative_distance_m = Vec::with_capacity(points.len());
+        let mut total = 0.0;
+
+        cumulative_distance_m.push(0.0);
+
+        for window in points.windows(2) {
+            total += approx_distance_m(window[0], window[1]);
+            cumulative_distance_m.push(total);
+        }
+
+        Some(ProjectedShape {
+            points,
+            cumulative_distance_m,
+        })
+    }
+
+    pub fn from_linestring(
+        linestring: &postgis_diesel::types::LineString<postgis_diesel::types::Point>,
+    ) -> Option<ProjectedShape> {
+        ProjectedShape::from_coords(linestring.points.iter().map(|p| (p.x, p.y)).collect())
+    }
+
+    pub fn total_length_m(&self) -> f64 {
+        *self.cumulative_distance_m.last().unwrap_or(&0.0)
+    }
+
+    /// Snaps a coordinate to the closest point on the shape.
+    /// `min_distance_along_m` skips everything before that distance, which keeps vehicles on
+    /// loop routes from jumping back to the start of the shape.
+    pub fn project(
+        &self,
+        lon: f64,
+        lat: f64,
+        min_distance_along_m: Option<f64>,
+    ) -> Option<ShapeProjection> {
+        let mut best: Option<(f64, usize, f64)> = None;
+
+        for i in 0..self.points.len().saturating_sub(1) {
+            let segment_length = self.cumulative_distance_m[i + 1] - self.cumulative_distance_m[i];
+
+            // lowest fraction of this segment that is still past min_distance_along_m
+            let min_t = match min_distance_along_m {
+                Some(min_distance_along_m) => {
+                    if self.cumulative_distance_m[i + 1] < min_distance_along_m {
+                        continue;
+                    }
+
+                    match segment_length > 0.0 {
+                        true => ((min_distance_along_m - self.cumulative_distance_m[i])
+                            / segment_length)
+                            .clamp(0.0, 1.0),
+                        false => 0.0,
+                    }
+                }
+                None => 0.0,
+            };
+
+            let a = local_xy_m(lat, lon, self.points[i].0, self.points[i].1);
+            let b = local_xy_m(lat, lon, self.points[i + 1].0, self.points[i + 1].1);
+
+            let seg = (b.0 - a.0, b.1 - a.1);
+            let seg_len_sq = seg.0 * seg.0 + seg.1 * seg.1;
+
+            let t = match seg_len_sq > 0.0 {
+                true => (-(a.0 * seg.0 + a.1 * seg.1) / seg_len_sq).clamp(min_t, 1.0),
+                false => min_t,
+            };
+
+            let closest = (a.0 + seg.0 * t, a.1 + seg.1 * t);
+            let dist_sq = closest.0 * closest.0 + closest.1 * closest.1;
+
+            match best {
+                Some((best_dist_sq, _, _)) if best_dist_sq <= dist_sq => {}
+                _ => best = Some((dist_sq, i, t)),
+            }
+        }
+
+        let (dist_sq, i, t) = best?;
+
+        let start = self.points[i];
+        let end = self.points[i + 1];
+
+        let segment_length = self.cumulative_distance_m[i + 1] - self.cumulative_distance_m[i];
+
+        Some(ShapeProjection {
+            distance_along_shape_m: self.cumulative_distance_m[i] + segment_length * t,
+            snapped_lon: start.0 + (end.0 - start.0) * t,
+            snapped_lat: start.1 + (end.1 - start.1) * t,
+            distance_from_shape_m: dist_sq.sqrt(),
+            bearing: bearing_degrees(start, end),
+        })
+    }
+
+    /// Distance along the shape of each stop, in stop order.
+    /// Each stop is only searched for after the previous one so that loops and out-and-back
+    /// shapes resolve to the correct pass.
+    pub fn stop_distances_along_shape(&self, stops: &[Option<(f64, f64)>]) -> Vec<Option<f64>> {
+        let mut previous = 0.0;
+
+        stops
+            .iter()
+            .map(|stop| {
+                let (lon, lat) = (*stop)?;
+
+                let projection = self.project(lon, lat, Some(previous))?;
+
+                previous = projection.distance_along_shape_m;
+
+                Some(projection.distance_along_shape_m)
+            })
+            .collect()
+    }
+}
+
+/// Finds the stops on either side of a distance along the shape and how far between them the
+/// vehicle is. Stops with no known distance are skipped.
+pub fn progress_between_stops(
+    stop_distances: &[Option<f64>],
+    distance_along_shape_m: f64,
+) -> ProgressBetweenStops {
+    let mut previous_stop: Option<(usize, f64)> = None;
+    let mut next_stop: Option<(usize, f64)> = None;
+
+    for (index, stop_distance) in stop_distances.iter().enumerate() {
+        if let Some(stop_distance) = stop_distance {
+            if *stop_distance <= distance_along_shape_m {
+                previous_stop = Some((index, *stop_distance));
+            } else {
+                next_stop = Some((index, *stop_distance));
+                break;
+            }
+        }
+    }
+
+    let fraction_between_stops = match (previous_stop, next_stop) {
+        (Some((_, previous)), Some((_, next))) if next > previous => {
+            Some(((distance_along_shape_m - previous) / (next - previous)).clamp(0.0, 1.0) as f32)
+        }
+        _ => None,
+    };
+
+    ProgressBetweenStops {
+        previous_stop_index: previous_stop.map(|(index, _)| index),
+        next_stop_index: next_stop.map(|(index, _)| index),
+        fraction_between_stops,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_projection_on_straight_shape() {
+        // roughly 1.1 km due east along the equator
+        let shape = ProjectedShape::from_coords(vec![(0.0, 0.0), (0.01, 0.0)]).unwrap();
+
+        let projection = shape.project(0.005, 0.0001, None).unwrap();
+
+        assert!((projection.distance_along_shape_m - shape.total_length_m() / 2.0).abs() < 1.0);
+        assert!((projection.distance_from_shape_m - 11.1).abs() < 0.5);
+        assert!((projection.bearing - 90.0).abs() < 0.01);
+        assert_eq!(projection.snapped_lat, 0.0);
+    }
+
+    #[test]
+    fn test_stops_on_out_and_back_shape() {
+        let shape = ProjectedShape::from_coords(vec![(0.0, 0.0), (0.01, 0.0), (0.0, 0.0)]).unwrap();
+
+        let stop_distances = shape.stop_distances_along_shape(&[
+            Some((0.0, 0.0)),
+            Some((0.01, 0.0)),
+            Some((0.005, 0.0)),
+        ]);
+
+        let half = shape.total_length_m() / 2.0;
+
+        assert!(stop_distances[2].unwrap() > half);
+
+        let progress = progress_between_stops(&stop_distances, half * 1.25);
+
+        assert_eq!(progress.previous_stop_index, Some(1));
+        assert_eq!(progress.next_stop_index, Some(2));
+        assert!((progress.fraction_between_stops.unwrap() - 0.5).abs() < 0.01);
+    }
+}
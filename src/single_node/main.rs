@@ -0,0 +1,59 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// Runs alpenrose, aspen and birch inside one process, sharing an in process coordinator instead
+// of etcd. Meant for small deployments and local development.
+// Each service reads the same environment variables as its own binary.
+
+use catenary::coordination::{Coordinator, InProcessCoordination};
+use std::error::Error;
+
+#[allow(dead_code)]
+#[path = "../alpenrose/main.rs"]
+mod alpenrose;
+
+#[allow(dead_code)]
+#[path = "../aspen/main.rs"]
+mod aspen;
+
+#[allow(dead_code)]
+#[path = "../birch/server.rs"]
+mod birch;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let coordinator = Coordinator::InProcess(InProcessCoordination::new());
+
+    //birch runs on its own actix system
+    let birch_thread = std::thread::spawn({
+        let coordinator = coordinator.clone();
+
+        move || actix_web::rt::System::new().block_on(birch::run(coordinator))
+    });
+
+    let aspen_handle = tokio::spawn({
+        let coordinator = coordinator.clone();
+
+        async move { aspen::run(coordinator).await }
+    });
+
+    let alpenrose_handle = tokio::spawn(alpenrose::run(coordinator));
+
+    tokio::select! {
+        result = aspen_handle => {
+            result?.map_err(|e| e.to_string())?;
+            Err("aspen stopped".into())
+        }
+        result = alpenrose_handle => {
+            result??;
+            Err("alpenrose stopped".into())
+        }
+        result = tokio::task::spawn_blocking(move || birch_thread.join()) => {
+            result?
+                .map_err(|_| "birch panicked")?
+                .map_err(|e| e.to_string())?;
+            Err("birch stopped".into())
+        }
+    }
+}
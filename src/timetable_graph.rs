@@ -0,0 +1,231 @@
+// Copyright Kyler Chin <kyler@catenarymaps.org>
+// Catenary Transit Initiatives
+// Attribution cannot be removed
+
+// In memory timetable for earliest arrival searches.
+// Each pattern is one itinerary pattern: a fixed list of stops with times relative to the trip
+// start, plus the absolute start times of every run of it. Since all runs of a pattern share the
+// same offsets they never overtake each other, which lets a round scan each pattern once, like
+// RAPTOR does with its routes.
+
+use ahash::{AHashMap, AHashSet};
+use compact_str::CompactString;
+
+pub type StopKey = (String, CompactString);
+
+#[derive(Clone, Debug)]
+pub struct PatternStop {
+    pub stop: usize,
+    pub arrival_offset: i64,
+    pub departure_offset: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    pub stops: Vec<PatternStop>,
+    /// unix seconds, sorted
+    pub trip_starts: Vec<i64>,
+}
+
+#[derive(Default)]
+pub struct TimetableGraph {
+    stops: Vec<StopKey>,
+    stop_lookup: AHashMap<StopKey, usize>,
+    patterns: Vec<Pattern>,
+    // stop -> (pattern, position in the pattern)
+    patterns_at_stop: Vec<Vec<(usize, usize)>>,
+    // (chateau, onestop_feed_id, itinerary_pattern_id), pattern ids are only unique within a feed
+    loaded_patterns: AHashSet<(String, String, String)>,
+}
+
+impl TimetableGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stop_index(&mut self, chateau: &str, stop_id: &str) -> usize {
+        let key: StopKey = (chateau.to_string(), stop_id.into());
+
+        match self.stop_lookup.get(&key) {
+            Some(index) => *index,
+            None => {
+                let index = self.stops.len();
+
+                self.stops.push(key.clone());
+                self.stop_lookup.insert(key, index);
+                self.patterns_at_stop.push(vec![]);
+
+                index
+            }
+        }
+    }
+
+    pub fn stop_key(&self, index: usize) -> &StopKey {
+        &self.stops[index]
+    }
+
+    pub fn has_pattern(
+        &self,
+        chateau: &str,
+        onestop_feed_id: &str,
+        itinerary_pattern_id: &str,
+    ) -> bool {
+        self.loaded_patterns.contains(&(
+            chateau.to_string(),
+            onestop_feed_id.to_string(),
+            itinerary_pattern_id.to_string(),
+        ))
+    }
+
+    /// `stops` are (stop id, arrival offset, departure offset) in travel order
+    pub fn add_pattern(
+        &mut self,
+        chateau: &str,
+        onestop_feed_id: &str,
+        itinerary_pattern_id: &str,
+        stops: Vec<(CompactString, i64, i64)>,
+        mut trip_starts: Vec<i64>,
+    ) {
+        if !self.loaded_patterns.insert((
+            chateau.to_string(),
+            onestop_feed_id.to_string(),
+            itinerary_pattern_id.to_string(),
+        )) {
+            return;
+        }
+
+        if stops.len() < 2 || trip_starts.is_empty() {
+            return;
+        }
+
+        trip_starts.sort_unstable();
+        trip_starts.dedup();
+
+        let pattern_index = self.patterns.len();
+
+        let stops = stops
+            .into_iter()
+            .enumerate()
+            .map(|(position, (stop_id, arrival_offset, departure_offset))| {
+                let stop = self.stop_index(chateau, &stop_id);
+
+                self.patterns_at_stop[stop].push((pattern_index, position));
+
+                PatternStop {
+                    stop,
+                    arrival_offset,
+                    departure_offset,
+                }
+            })
+            .collect();
+
+        self.patterns.push(Pattern { stops, trip_starts });
+    }
+
+    /// One round of riding: boards any pattern at a marked stop and returns the stops whose
+    /// arrival improves on `best_arrival`, arriving no later than `deadline`.
+    pub fn transit_round(
+        &self,
+        marked: &AHashSet<usize>,
+        best_arrival: &AHashMap<usize, i64>,
+        deadline: i64,
+    ) -> AHashMap<usize, i64> {
+        // earliest marked position of every pattern touched
+        let mut patterns_to_scan: AHashMap<usize, usize> = AHashMap::new();
+
+        for stop in marked {
+            if let Some(patterns) = self.patterns_at_stop.get(*stop) {
+                for (pattern, position) in patterns {
+                    patterns_to_scan
+                        .entry(*pattern)
+                        .and_modify(|x| *x = (*x).min(*position))
+                        .or_insert(*position);
+                }
+            }
+        }
+
+        let mut improved: AHashMap<usize, i64> = AHashMap::new();
+
+        for (pattern_index, first_position) in patterns_to_scan {
+            let pattern = &self.patterns[pattern_index];
+
+            let mut current_trip_start: Option<i64> = None;
+
+            for pattern_stop in pattern.stops.iter().skip(first_position) {
+                if let Some(trip_start) = current_trip_start {
+                    let arrival = trip_start + pattern_stop.arrival_offset;
+
+                    let best = improved
+                        .get(&pattern_stop.stop)
+                        .or(best_arrival.get(&pattern_stop.stop));
+
+                    if arrival <= deadline && best.map(|x| arrival < *x).unwrap_or(true) {
+                        improved.insert(pattern_stop.stop, arrival);
+                    }
+                }
+
+                // catch an earlier run here if we were already at this stop
+                if !marked.contains(&pattern_stop.stop) {
+                    continue;
+                }
+
+                if let Some(ready) = best_arrival.get(&pattern_stop.stop) {
+                    let earliest_start = ready - pattern_stop.departure_offset;
+
+                    let index = pattern
+                        .trip_starts
+                        .partition_point(|start| *start < earliest_start);
+
+                    if let Some(trip_start) = pattern.trip_starts.get(index) {
+                        if current_trip_start
+                            .map(|current| *trip_start < current)
+                            .unwrap_or(true)
+                        {
+                            current_trip_start = Some(*trip_start);
+                        }
+                    }
+                }
+            }
+        }
+
+        improved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rides_the_first_catchable_run() {
+        let mut graph = TimetableGraph::new();
+
+        graph.add_pattern(
+            "c",
+            "f",
+            "1",
+            vec![
+                ("a".into(), 0, 0),
+                ("b".into(), 300, 330),
+                ("c".into(), 600, 600),
+            ],
+            vec![1000, 1600, 2200],
+        );
+
+        let a = graph.stop_index("c", "a");
+        let b = graph.stop_index("c", "b");
+        let c = graph.stop_index("c", "c");
+
+        let best_arrival = AHashMap::from_iter([(a, 1100)]);
+        let marked = AHashSet::from_iter([a]);
+
+        let improved = graph.transit_round(&marked, &best_arrival, 10_000);
+
+        assert_eq!(improved.get(&b), Some(&1900));
+        assert_eq!(improved.get(&c), Some(&2200));
+
+        let improved = graph.transit_round(&marked, &best_arrival, 2000);
+
+        assert_eq!(improved.get(&c), None);
+    }
+}